@@ -1,10 +1,34 @@
-use crate::{Key, KeyboardControllable};
+//! A small scripting language for keyboard and mouse input.
+//!
+//! Text is typed as is, `{{` and `}}` stand for literal braces. Tags in braces
+//! are actions:
+//!
+//! - `{+SHIFT}`, `{-SHIFT}`: press or release a key, `{ENTER}`: click it. See
+//!   [`key_of`] for the key names.
+//! - `{+UNICODE}`, `{-UNICODE}`: type the text with
+//!   [key_sequence](../trait.KeyboardControllable.html#tymethod.key_sequence)
+//!   instead of layout keys.
+//! - `{MOVE x y}`, `{MOVEREL dx dy}`: move the mouse.
+//! - `{CLICK}`, `{MOUSEDOWN RIGHT}`, `{MOUSEUP RIGHT}`: mouse buttons, `LEFT`
+//!   if omitted, `LEFT`, `MIDDLE`, `RIGHT`, `BACK` or `FORWARD`.
+//! - `{SCROLL n}`, `{HSCROLL n}`: scroll down or right, up or left if negative.
+//! - `{WAIT ms}`: wait.
+//! - `{REPEAT n}...{/REPEAT}`: repeat, may be nested.
+//! - `{SET name value}`: set a variable, `{$name}` types its value and `$name`
+//!   can be used for any number above.
+//!
+//! For example `{MOVE 100 200}{CLICK}{REPEAT 3}{+CTRL}v{-CTRL}{ENTER}{WAIT 500}{/REPEAT}`.
+//! The whole script is parsed before anything is played.
+
+use crate::{Key, KeyboardControllable, MouseButton, MouseControllable};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::time::Duration;
 
-/// An error that can occur when parsing DSL
-#[derive(Debug, PartialEq, Eq)]
-pub enum ParseError {
+/// What went wrong, see [`ParseError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
     /// When a tag doesn't exist.
     /// Example: {+TEST}{-TEST}
     ///            ^^^^   ^^^^
@@ -24,161 +48,697 @@ pub enum ParseError {
     /// Example: +SHIFT}Hello{-SHIFT}
     ///         ^
     UnmatchedClose,
+
+    /// A missing, extra or invalid argument of a tag.
+    /// Example: {MOVE 10}
+    InvalidArgument(String),
+
+    /// A {/REPEAT} without a {REPEAT n}.
+    UnmatchedEnd,
+
+    /// A {REPEAT n} without a {/REPEAT}.
+    UnclosedRepeat,
+
+    /// A variable used before being set.
+    UnknownVariable(String),
+
+    /// An action the target cannot do, e.g. mouse actions with
+    /// [`eval`].
+    Unsupported(String),
 }
-impl Error for ParseError {
-    fn description(&self) -> &str {
-        match *self {
-            ParseError::UnknownTag(_) => "Unknown tag",
-            ParseError::UnexpectedOpen => "Unescaped open bracket ({) found inside tag name",
-            ParseError::UnmatchedOpen => "Unmatched open bracket ({). No matching close (})",
-            ParseError::UnmatchedClose => "Unmatched close bracket (}). No previous open ({)",
-        }
+
+/// An error that can occur when parsing or running DSL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+    /// Character offset in the input of the tag or text at fault.
+    pub position: usize,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, position: usize) -> Self {
+        Self { kind, position }
     }
 }
+
+impl Error for ParseError {}
+
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&self.to_string())
+        match &self.kind {
+            ParseErrorKind::UnknownTag(tag) => write!(f, "Unknown tag {}", tag)?,
+            ParseErrorKind::UnexpectedOpen => {
+                f.write_str("Unescaped open bracket ({) found inside tag name")?
+            }
+            ParseErrorKind::UnmatchedOpen => {
+                f.write_str("Unmatched open bracket ({). No matching close (})")?
+            }
+            ParseErrorKind::UnmatchedClose => {
+                f.write_str("Unmatched close bracket (}). No previous open ({)")?
+            }
+            ParseErrorKind::InvalidArgument(s) => write!(f, "Invalid argument: {}", s)?,
+            ParseErrorKind::UnmatchedEnd => f.write_str("{/REPEAT} without {REPEAT n}")?,
+            ParseErrorKind::UnclosedRepeat => f.write_str("{REPEAT n} without {/REPEAT}")?,
+            ParseErrorKind::UnknownVariable(name) => write!(f, "Unknown variable {}", name)?,
+            ParseErrorKind::Unsupported(s) => write!(f, "Unsupported action {}", s)?,
+        }
+        write!(f, " at {}", self.position)
     }
 }
 
-/// Evaluate the DSL. This tokenizes the input and presses the keys.
+/// Evaluate the DSL with keyboard actions only. This parses the input and
+/// presses the keys, mouse actions are [`ParseErrorKind::Unsupported`].
 pub fn eval<K>(enigo: &mut K, input: &str) -> Result<(), ParseError>
 where
     K: KeyboardControllable,
 {
-    for token in tokenize(input)? {
-        match token {
-            Token::Sequence(buffer) => {
-                for key in buffer.chars() {
-                    enigo.key_click(Key::Layout(key));
-                }
+    let nodes = parse(tokenize(input)?)?;
+    exec(&nodes, &mut HashMap::new(), &mut |pos, action| {
+        if !play_key(enigo, &action) {
+            if let Action::Wait(d) = action {
+                std::thread::sleep(d);
+            } else {
+                return Err(ParseError::new(
+                    ParseErrorKind::Unsupported(format!("{:?}", action)),
+                    pos,
+                ));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Evaluate the DSL with keyboard and mouse actions. `vars` are the initial
+/// variables, updated by `{SET name value}`.
+pub fn eval_script<C>(
+    enigo: &mut C,
+    input: &str,
+    vars: &mut HashMap<String, String>,
+) -> Result<(), ParseError>
+where
+    C: KeyboardControllable + MouseControllable,
+{
+    let nodes = parse(tokenize(input)?)?;
+    exec(&nodes, vars, &mut |_, action| {
+        if !play_key(enigo, &action) {
+            match action {
+                Action::MouseMoveTo(x, y) => enigo.mouse_move_to(x, y),
+                Action::MouseMoveRelative(x, y) => enigo.mouse_move_relative(x, y),
+                Action::MouseDown(b) => enigo.mouse_down(b).unwrap_or(()),
+                Action::MouseUp(b) => enigo.mouse_up(b),
+                Action::MouseClick(b) => enigo.mouse_click(b),
+                Action::ScrollX(n) => enigo.mouse_scroll_x(n),
+                Action::ScrollY(n) => enigo.mouse_scroll_y(n),
+                Action::Wait(d) => std::thread::sleep(d),
+                _ => {}
+            }
+        }
+        Ok(())
+    })
+}
+
+fn play_key<K: KeyboardControllable>(enigo: &mut K, action: &Action) -> bool {
+    match action {
+        Action::Sequence(buffer) => {
+            for key in buffer.chars() {
+                enigo.key_click(Key::Layout(key));
             }
-            Token::Unicode(buffer) => enigo.key_sequence(&buffer),
-            Token::KeyUp(key) => enigo.key_up(key),
-            Token::KeyDown(key) => enigo.key_down(key).unwrap_or(()),
         }
+        Action::Unicode(buffer) => enigo.key_sequence(buffer),
+        Action::KeyUp(key) => enigo.key_up(*key),
+        Action::KeyDown(key) => enigo.key_down(*key).unwrap_or(()),
+        Action::KeyClick(key) => enigo.key_click(*key),
+        _ => return false,
     }
-    Ok(())
+    true
+}
+
+/// The key of a tag name, e.g. `ENTER` or `F5`.
+pub fn key_of(name: &str) -> Option<Key> {
+    Some(match name {
+        "SHIFT" => Key::Shift,
+        "CTRL" => Key::Control,
+        "META" => Key::Meta,
+        "ALT" => Key::Alt,
+        "ENTER" | "RETURN" => Key::Return,
+        "TAB" => Key::Tab,
+        "ESC" | "ESCAPE" => Key::Escape,
+        "BACKSPACE" => Key::Backspace,
+        "DEL" | "DELETE" => Key::Delete,
+        "INSERT" => Key::Insert,
+        "HOME" => Key::Home,
+        "END" => Key::End,
+        "PGUP" | "PAGEUP" => Key::PageUp,
+        "PGDN" | "PAGEDOWN" => Key::PageDown,
+        "UP" => Key::UpArrow,
+        "DOWN" => Key::DownArrow,
+        "LEFT" => Key::LeftArrow,
+        "RIGHT" => Key::RightArrow,
+        "SPACE" => Key::Space,
+        "CAPSLOCK" => Key::CapsLock,
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        _ => return None,
+    })
+}
+
+fn button_of(name: &str) -> Option<MouseButton> {
+    Some(match name {
+        "LEFT" => MouseButton::Left,
+        "MIDDLE" => MouseButton::Middle,
+        "RIGHT" => MouseButton::Right,
+        "BACK" => MouseButton::Back,
+        "FORWARD" => MouseButton::Forward,
+        _ => return None,
+    })
+}
+
+/// A number, or a variable holding one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Arg {
+    Int(i32),
+    Var(String),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Arg {
+    fn value(&self, vars: &HashMap<String, String>, pos: usize) -> Result<i32, ParseError> {
+        match self {
+            Arg::Int(v) => Ok(*v),
+            Arg::Var(name) => match vars.get(name) {
+                Some(v) => v.trim().parse().map_err(|_| {
+                    ParseError::new(
+                        ParseErrorKind::InvalidArgument(format!("${} is {}", name, v)),
+                        pos,
+                    )
+                }),
+                None => Err(ParseError::new(
+                    ParseErrorKind::UnknownVariable(name.clone()),
+                    pos,
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 enum Token {
     Sequence(String),
     Unicode(String),
     KeyUp(Key),
     KeyDown(Key),
+    KeyClick(Key),
+    /// Typed value of a variable, as unicode if true.
+    Var(String, bool),
+    Set(String, String),
+    MouseMoveTo(Arg, Arg),
+    MouseMoveRelative(Arg, Arg),
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    MouseClick(MouseButton),
+    ScrollX(Arg),
+    ScrollY(Arg),
+    Wait(Arg),
+    Repeat(Arg),
+    EndRepeat,
 }
 
-fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
     let mut unicode = false;
 
     let mut tokens = Vec::new();
     let mut buffer = String::new();
-    let mut iter = input.chars().peekable();
+    let mut start = 0;
+    let mut iter = input.chars().enumerate().peekable();
 
-    fn flush(tokens: &mut Vec<Token>, buffer: String, unicode: bool) {
+    fn flush(tokens: &mut Vec<(usize, Token)>, start: usize, buffer: String, unicode: bool) {
         if !buffer.is_empty() {
             if unicode {
-                tokens.push(Token::Unicode(buffer));
+                tokens.push((start, Token::Unicode(buffer)));
             } else {
-                tokens.push(Token::Sequence(buffer));
+                tokens.push((start, Token::Sequence(buffer)));
             }
         }
     }
 
-    while let Some(c) = iter.next() {
+    while let Some((pos, c)) = iter.next() {
+        if buffer.is_empty() {
+            start = pos;
+        }
         if c == '{' {
             match iter.next() {
-                Some('{') => buffer.push('{'),
-                Some(mut c) => {
-                    flush(&mut tokens, buffer, unicode);
+                Some((_, '{')) => buffer.push('{'),
+                Some((_, mut c)) => {
+                    flush(&mut tokens, start, buffer, unicode);
                     buffer = String::new();
 
                     let mut tag = String::new();
                     loop {
                         tag.push(c);
                         match iter.next() {
-                            Some('{') => match iter.peek() {
-                                Some(&'{') => {
+                            Some((p, '{')) => match iter.peek() {
+                                Some(&(_, '{')) => {
                                     iter.next();
                                     c = '{'
                                 }
-                                _ => return Err(ParseError::UnexpectedOpen),
+                                _ => {
+                                    return Err(ParseError::new(ParseErrorKind::UnexpectedOpen, p))
+                                }
                             },
-                            Some('}') => match iter.peek() {
-                                Some(&'}') => {
+                            Some((_, '}')) => match iter.peek() {
+                                Some(&(_, '}')) => {
                                     iter.next();
                                     c = '}'
                                 }
                                 _ => break,
                             },
-                            Some(new) => c = new,
-                            None => return Err(ParseError::UnmatchedOpen),
+                            Some((_, new)) => c = new,
+                            None => {
+                                return Err(ParseError::new(ParseErrorKind::UnmatchedOpen, pos))
+                            }
                         }
                     }
                     match &*tag {
                         "+UNICODE" => unicode = true,
                         "-UNICODE" => unicode = false,
-                        "+SHIFT" => tokens.push(Token::KeyDown(Key::Shift)),
-                        "-SHIFT" => tokens.push(Token::KeyUp(Key::Shift)),
-                        "+CTRL" => tokens.push(Token::KeyDown(Key::Control)),
-                        "-CTRL" => tokens.push(Token::KeyUp(Key::Control)),
-                        "+META" => tokens.push(Token::KeyDown(Key::Meta)),
-                        "-META" => tokens.push(Token::KeyUp(Key::Meta)),
-                        "+ALT" => tokens.push(Token::KeyDown(Key::Alt)),
-                        "-ALT" => tokens.push(Token::KeyUp(Key::Alt)),
-                        _ => return Err(ParseError::UnknownTag(tag)),
+                        _ => tokens.push((pos, parse_tag(&tag, unicode, pos)?)),
                     }
                 }
-                None => return Err(ParseError::UnmatchedOpen),
+                None => return Err(ParseError::new(ParseErrorKind::UnmatchedOpen, pos)),
             }
         } else if c == '}' {
             match iter.next() {
-                Some('}') => buffer.push('}'),
-                _ => return Err(ParseError::UnmatchedClose),
+                Some((_, '}')) => buffer.push('}'),
+                _ => return Err(ParseError::new(ParseErrorKind::UnmatchedClose, pos)),
             }
         } else {
             buffer.push(c);
         }
     }
 
-    flush(&mut tokens, buffer, unicode);
+    flush(&mut tokens, start, buffer, unicode);
 
     Ok(tokens)
 }
 
+fn parse_tag(tag: &str, unicode: bool, pos: usize) -> Result<Token, ParseError> {
+    let invalid = |s: String| ParseError::new(ParseErrorKind::InvalidArgument(s), pos);
+    let mut words = tag.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let arg = |i: usize| -> Result<Arg, ParseError> {
+        let s = args
+            .get(i)
+            .ok_or_else(|| invalid(format!("{} needs {} argument(s)", name, i + 1)))?;
+        if let Some(var) = s.strip_prefix('$') {
+            return Ok(Arg::Var(var.to_owned()));
+        }
+        s.parse()
+            .map(Arg::Int)
+            .map_err(|_| invalid(format!("{} is not a number", s)))
+    };
+    let arity = |n: usize| {
+        if args.len() > n {
+            Err(invalid(format!("{} takes {} argument(s)", name, n)))
+        } else {
+            Ok(())
+        }
+    };
+    let button = || match args.first() {
+        None => Ok(MouseButton::Left),
+        Some(s) => button_of(s).ok_or_else(|| invalid(format!("unknown button {}", s))),
+    };
+    let token = match name {
+        "MOVE" => {
+            arity(2)?;
+            Token::MouseMoveTo(arg(0)?, arg(1)?)
+        }
+        "MOVEREL" => {
+            arity(2)?;
+            Token::MouseMoveRelative(arg(0)?, arg(1)?)
+        }
+        "CLICK" => {
+            arity(1)?;
+            Token::MouseClick(button()?)
+        }
+        "MOUSEDOWN" => {
+            arity(1)?;
+            Token::MouseDown(button()?)
+        }
+        "MOUSEUP" => {
+            arity(1)?;
+            Token::MouseUp(button()?)
+        }
+        "SCROLL" => {
+            arity(1)?;
+            Token::ScrollY(arg(0)?)
+        }
+        "HSCROLL" => {
+            arity(1)?;
+            Token::ScrollX(arg(0)?)
+        }
+        "WAIT" => {
+            arity(1)?;
+            Token::Wait(arg(0)?)
+        }
+        "REPEAT" => {
+            arity(1)?;
+            Token::Repeat(arg(0)?)
+        }
+        "/REPEAT" => {
+            arity(0)?;
+            Token::EndRepeat
+        }
+        "SET" => {
+            let var = args
+                .first()
+                .ok_or_else(|| invalid("SET needs a name".to_owned()))?;
+            // The value is the rest of the tag, spaces included.
+            let value = tag
+                .trim_start()
+                .splitn(3, char::is_whitespace)
+                .nth(2)
+                .unwrap_or_default()
+                .trim_start();
+            Token::Set(var.to_string(), value.to_owned())
+        }
+        _ if args.is_empty() => {
+            if let Some(var) = name.strip_prefix('$') {
+                Token::Var(var.to_owned(), unicode)
+            } else if let Some(key) = name.strip_prefix('+').and_then(key_of) {
+                Token::KeyDown(key)
+            } else if let Some(key) = name.strip_prefix('-').and_then(key_of) {
+                Token::KeyUp(key)
+            } else if let Some(key) = key_of(name) {
+                Token::KeyClick(key)
+            } else {
+                return Err(ParseError::new(
+                    ParseErrorKind::UnknownTag(tag.to_owned()),
+                    pos,
+                ));
+            }
+        }
+        _ => {
+            return Err(ParseError::new(
+                ParseErrorKind::UnknownTag(tag.to_owned()),
+                pos,
+            ))
+        }
+    };
+    Ok(token)
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Token(usize, Token),
+    Repeat(usize, Arg, Vec<Node>),
+}
+
+fn parse(tokens: Vec<(usize, Token)>) -> Result<Vec<Node>, ParseError> {
+    // Open blocks, the outermost being the script.
+    let mut stack: Vec<(usize, Option<Arg>, Vec<Node>)> = vec![(0, None, Vec::new())];
+    for (pos, token) in tokens {
+        match token {
+            Token::Repeat(n) => stack.push((pos, Some(n), Vec::new())),
+            Token::EndRepeat => {
+                if stack.len() < 2 {
+                    return Err(ParseError::new(ParseErrorKind::UnmatchedEnd, pos));
+                }
+                if let Some((start, Some(n), body)) = stack.pop() {
+                    if let Some(parent) = stack.last_mut() {
+                        parent.2.push(Node::Repeat(start, n, body));
+                    }
+                }
+            }
+            token => {
+                if let Some(block) = stack.last_mut() {
+                    block.2.push(Node::Token(pos, token));
+                }
+            }
+        }
+    }
+    match stack.pop() {
+        Some((_, None, nodes)) => Ok(nodes),
+        Some((pos, _, _)) => Err(ParseError::new(ParseErrorKind::UnclosedRepeat, pos)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// What a script does, in order.
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Sequence(String),
+    Unicode(String),
+    KeyUp(Key),
+    KeyDown(Key),
+    KeyClick(Key),
+    MouseMoveTo(i32, i32),
+    MouseMoveRelative(i32, i32),
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    MouseClick(MouseButton),
+    ScrollX(i32),
+    ScrollY(i32),
+    Wait(Duration),
+}
+
+fn exec(
+    nodes: &[Node],
+    vars: &mut HashMap<String, String>,
+    f: &mut dyn FnMut(usize, Action) -> Result<(), ParseError>,
+) -> Result<(), ParseError> {
+    for node in nodes {
+        match node {
+            Node::Repeat(pos, n, body) => {
+                for _ in 0..n.value(vars, *pos)?.max(0) {
+                    exec(body, vars, f)?;
+                }
+            }
+            Node::Token(pos, token) => {
+                let pos = *pos;
+                let action = match token {
+                    Token::Sequence(s) => Action::Sequence(s.clone()),
+                    Token::Unicode(s) => Action::Unicode(s.clone()),
+                    Token::KeyUp(k) => Action::KeyUp(*k),
+                    Token::KeyDown(k) => Action::KeyDown(*k),
+                    Token::KeyClick(k) => Action::KeyClick(*k),
+                    Token::Var(name, unicode) => {
+                        let v = vars.get(name).cloned().ok_or_else(|| {
+                            ParseError::new(ParseErrorKind::UnknownVariable(name.clone()), pos)
+                        })?;
+                        if *unicode {
+                            Action::Unicode(v)
+                        } else {
+                            Action::Sequence(v)
+                        }
+                    }
+                    Token::Set(name, value) => {
+                        vars.insert(name.clone(), value.clone());
+                        continue;
+                    }
+                    Token::MouseMoveTo(x, y) => {
+                        Action::MouseMoveTo(x.value(vars, pos)?, y.value(vars, pos)?)
+                    }
+                    Token::MouseMoveRelative(x, y) => {
+                        Action::MouseMoveRelative(x.value(vars, pos)?, y.value(vars, pos)?)
+                    }
+                    Token::MouseDown(b) => Action::MouseDown(*b),
+                    Token::MouseUp(b) => Action::MouseUp(*b),
+                    Token::MouseClick(b) => Action::MouseClick(*b),
+                    Token::ScrollX(n) => Action::ScrollX(n.value(vars, pos)?),
+                    Token::ScrollY(n) => Action::ScrollY(n.value(vars, pos)?),
+                    Token::Wait(ms) => {
+                        Action::Wait(Duration::from_millis(ms.value(vars, pos)?.max(0) as _))
+                    }
+                    Token::Repeat(_) | Token::EndRepeat => continue,
+                };
+                f(pos, action)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Records what it is asked to do.
+    #[derive(Default)]
+    struct Mock {
+        log: Vec<String>,
+    }
+
+    impl KeyboardControllable for Mock {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn key_sequence(&mut self, sequence: &str) {
+            self.log.push(format!("seq {}", sequence));
+        }
+        fn key_down(&mut self, key: Key) -> crate::ResultType {
+            self.log.push(format!("down {:?}", key));
+            Ok(())
+        }
+        fn key_up(&mut self, key: Key) {
+            self.log.push(format!("up {:?}", key));
+        }
+        fn key_click(&mut self, key: Key) {
+            self.log.push(format!("click {:?}", key));
+        }
+        fn get_key_state(&mut self, _: Key) -> bool {
+            false
+        }
+    }
+
+    impl MouseControllable for Mock {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn mouse_move_to(&mut self, x: i32, y: i32) {
+            self.log.push(format!("move {} {}", x, y));
+        }
+        fn mouse_move_relative(&mut self, x: i32, y: i32) {
+            self.log.push(format!("moverel {} {}", x, y));
+        }
+        fn mouse_down(&mut self, button: MouseButton) -> crate::ResultType {
+            self.log.push(format!("mousedown {:?}", button));
+            Ok(())
+        }
+        fn mouse_up(&mut self, button: MouseButton) {
+            self.log.push(format!("mouseup {:?}", button));
+        }
+        fn mouse_click(&mut self, button: MouseButton) {
+            self.log.push(format!("mouseclick {:?}", button));
+        }
+        fn mouse_scroll_x(&mut self, length: i32) {
+            self.log.push(format!("hscroll {}", length));
+        }
+        fn mouse_scroll_y(&mut self, length: i32) {
+            self.log.push(format!("scroll {}", length));
+        }
+    }
+
+    fn err(input: &str) -> ParseError {
+        tokenize(input).and_then(parse).unwrap_err()
+    }
+
     #[test]
     fn success() {
         assert_eq!(
             tokenize("{{Hello World!}} {+CTRL}hi{-CTRL}"),
             Ok(vec![
-                Token::Sequence("{Hello World!} ".into()),
-                Token::KeyDown(Key::Control),
-                Token::Sequence("hi".into()),
-                Token::KeyUp(Key::Control)
+                (0, Token::Sequence("{Hello World!} ".into())),
+                (17, Token::KeyDown(Key::Control)),
+                (24, Token::Sequence("hi".into())),
+                (26, Token::KeyUp(Key::Control))
             ])
         );
     }
     #[test]
     fn unexpected_open() {
-        assert_eq!(tokenize("{hello{}world}"), Err(ParseError::UnexpectedOpen));
+        assert_eq!(
+            err("{hello{}world}"),
+            ParseError::new(ParseErrorKind::UnexpectedOpen, 6)
+        );
     }
     #[test]
     fn unmatched_open() {
         assert_eq!(
-            tokenize("{this is going to fail"),
-            Err(ParseError::UnmatchedOpen)
+            err("{this is going to fail"),
+            ParseError::new(ParseErrorKind::UnmatchedOpen, 0)
         );
     }
     #[test]
     fn unmatched_close() {
         assert_eq!(
-            tokenize("{+CTRL}{{this}} is going to fail}"),
-            Err(ParseError::UnmatchedClose)
+            err("{+CTRL}{{this}} is going to fail}"),
+            ParseError::new(ParseErrorKind::UnmatchedClose, 32)
+        );
+    }
+
+    #[test]
+    fn script() {
+        let mut mock = Mock::default();
+        let mut vars = HashMap::new();
+        vars.insert("x".to_owned(), "10".to_owned());
+        eval_script(
+            &mut mock,
+            "{SET name Jane Doe}{MOVE $x 20}{CLICK RIGHT}\
+             {REPEAT 2}{SCROLL -1}{REPEAT 2}a{/REPEAT}{/REPEAT}\
+             {+UNICODE}{$name}{-UNICODE}{ENTER}{WAIT 0}{HSCROLL 1}",
+            &mut vars,
+        )
+        .unwrap();
+        assert_eq!(
+            mock.log,
+            [
+                "move 10 20",
+                "mouseclick Right",
+                "scroll -1",
+                "click Layout('a')",
+                "click Layout('a')",
+                "scroll -1",
+                "click Layout('a')",
+                "click Layout('a')",
+                "seq Jane Doe",
+                "click Return",
+                "hscroll 1",
+            ]
+        );
+        assert_eq!(vars["name"], "Jane Doe");
+
+        let mut mock = Mock::default();
+        assert_eq!(
+            eval(&mut mock, "ab{CLICK}").unwrap_err().kind,
+            ParseErrorKind::Unsupported("MouseClick(Left)".to_owned())
+        );
+        assert_eq!(mock.log, ["click Layout('a')", "click Layout('b')"]);
+        assert_eq!(
+            eval_script(&mut mock, "{MOVE $y 1}", &mut HashMap::new()).unwrap_err(),
+            ParseError::new(ParseErrorKind::UnknownVariable("y".to_owned()), 0)
+        );
+    }
+
+    #[test]
+    fn script_errors() {
+        assert_eq!(err("{REPEAT 2}a").kind, ParseErrorKind::UnclosedRepeat);
+        assert_eq!(
+            err("a{/REPEAT}"),
+            ParseError::new(ParseErrorKind::UnmatchedEnd, 1)
+        );
+        assert_eq!(err("ab{MOVE 1}").position, 2);
+        assert!(matches!(
+            err("{MOVE 1 x}").kind,
+            ParseErrorKind::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            err("{CLICK TOP}").kind,
+            ParseErrorKind::InvalidArgument(_)
+        ));
+        assert_eq!(
+            err("{WAIT 1 2}").to_string(),
+            "Invalid argument: WAIT takes 1 argument(s) at 0"
+        );
+        assert_eq!(
+            err("{+TEST}").kind,
+            ParseErrorKind::UnknownTag("+TEST".to_owned())
         );
     }
 }
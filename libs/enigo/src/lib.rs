@@ -447,8 +447,8 @@ pub trait KeyboardControllable {
 
     /// Types the string parsed with DSL.
     ///
-    /// Typing {+SHIFT}hello{-SHIFT} becomes HELLO.
-    /// TODO: Full documentation
+    /// Typing {+SHIFT}hello{-SHIFT} becomes HELLO. See [dsl](dsl/index.html)
+    /// for the syntax, mouse actions need [dsl::eval_script].
     fn key_sequence_parse(&mut self, sequence: &str)
     where
         Self: Sized,
@@ -0,0 +1,608 @@
+//! Playback of the `.webm` files written by [`super::record`].
+//!
+//! Only the subset of Matroska produced by the recorder is understood: one video
+//! track of VP8, VP9 or AV1, `SimpleBlock`s or `BlockGroup`s without lacing.
+//! Files cut off by a crash (unknown or wrong segment sizes) are read up to the
+//! last complete block.
+//!
+//! Session events written by [`Recorder::write_event`](super::record::Recorder::write_event)
+//! are read from the sidecar file, see [`read_events`] and [`find_events`].
+
+use crate::{
+    codec::Decoder,
+    record::{events_path, RecordEvent},
+    CodecFormat, ImageFormat, ImageRgb, ImageTexture,
+};
+use hbb_common::{
+    bail,
+    bytes::Bytes,
+    log,
+    message_proto::{EncodedVideoFrame, EncodedVideoFrames, VideoFrame},
+    serde_json, ResultType,
+};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const ID_SEGMENT: u64 = 0x18538067;
+const ID_INFO: u64 = 0x1549A966;
+const ID_TIMECODE_SCALE: u64 = 0x2AD7B1;
+const ID_DURATION: u64 = 0x4489;
+const ID_TRACKS: u64 = 0x1654AE6B;
+const ID_TRACK_ENTRY: u64 = 0xAE;
+const ID_TRACK_NUMBER: u64 = 0xD7;
+const ID_TRACK_TYPE: u64 = 0x83;
+const ID_CODEC_ID: u64 = 0x86;
+const ID_VIDEO: u64 = 0xE0;
+const ID_PIXEL_WIDTH: u64 = 0xB0;
+const ID_PIXEL_HEIGHT: u64 = 0xBA;
+const ID_CLUSTER: u64 = 0x1F43B675;
+const ID_TIMECODE: u64 = 0xE7;
+const ID_SIMPLE_BLOCK: u64 = 0xA3;
+const ID_BLOCK_GROUP: u64 = 0xA0;
+const ID_BLOCK: u64 = 0xA1;
+const ID_REFERENCE_BLOCK: u64 = 0xFB;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// Metadata of a recording, the peer and display come from the file name
+/// given by `RecorderContext2::set_filename`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RecordingInfo {
+    pub path: String,
+    /// `incoming` (recorded on the controlled side) or `outgoing`.
+    pub direction: String,
+    pub id: String,
+    /// Local start time as `%Y%m%d%H%M%S%3f`.
+    pub start: String,
+    pub camera: bool,
+    pub display: usize,
+    pub codec: String,
+    /// Codec of the audio track, empty without audio.
+    pub audio_codec: String,
+    pub width: usize,
+    pub height: usize,
+    /// Milliseconds.
+    pub duration: i64,
+    pub frames: usize,
+    pub key_frames: usize,
+    pub events: usize,
+}
+
+impl RecordingInfo {
+    fn parse_filename(&mut self, path: &Path) {
+        self.path = path.to_string_lossy().to_string();
+        let Some(stem) = path.file_stem() else {
+            return;
+        };
+        let stem = stem.to_string_lossy();
+        let parts: Vec<&str> = stem.split('_').collect();
+        // direction _ id _ time _ display0 _ codec, the id may contain '_'.
+        if parts.len() < 5 {
+            return;
+        }
+        let n = parts.len();
+        self.direction = parts[0].to_owned();
+        self.id = parts[1..n - 3].join("_");
+        self.start = parts[n - 3].to_owned();
+        let display = parts[n - 2];
+        if let Some(idx) = display.strip_prefix("camera") {
+            self.camera = true;
+            self.display = idx.parse().unwrap_or_default();
+        } else if let Some(idx) = display.strip_prefix("display") {
+            self.display = idx.parse().unwrap_or_default();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    // Milliseconds.
+    pts: i64,
+    key: bool,
+    offset: u64,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Track {
+    number: u64,
+    kind: u64,
+    codec: String,
+    width: usize,
+    height: usize,
+}
+
+struct Index {
+    video: Track,
+    audio: Option<Track>,
+    // In timecode scale units.
+    duration: f64,
+    scale: u64,
+    // Of the video track.
+    packets: Vec<Packet>,
+}
+
+struct Demuxer<R> {
+    reader: R,
+    pos: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> Demuxer<R> {
+    fn new(mut reader: R) -> ResultType<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            reader,
+            pos: 0,
+            len,
+        })
+    }
+
+    fn read_u8(&mut self) -> ResultType<u8> {
+        let mut b = [0u8; 1];
+        self.reader.read_exact(&mut b)?;
+        self.pos += 1;
+        Ok(b[0])
+    }
+
+    // Element ids keep their length marker, sizes don't.
+    fn read_vint(&mut self, keep_marker: bool) -> ResultType<u64> {
+        let first = self.read_u8()?;
+        if first == 0 {
+            bail!("Invalid EBML vint at {}", self.pos - 1);
+        }
+        let n = first.leading_zeros() as usize + 1;
+        let mask = (0xFFu16 >> n) as u8;
+        let mut value = if keep_marker {
+            first as u64
+        } else {
+            (first & mask) as u64
+        };
+        let mut all_ones = first & mask == mask;
+        for _ in 1..n {
+            let b = self.read_u8()?;
+            all_ones &= b == 0xFF;
+            value = (value << 8) | b as u64;
+        }
+        if !keep_marker && all_ones {
+            return Ok(UNKNOWN_SIZE);
+        }
+        Ok(value)
+    }
+
+    fn read_payload(&mut self, size: u64) -> ResultType<Vec<u8>> {
+        if size > 64 * 1024 {
+            bail!("Unexpected large element of {} bytes", size);
+        }
+        let mut buf = vec![0u8; size as usize];
+        self.reader.read_exact(&mut buf)?;
+        self.pos += size;
+        Ok(buf)
+    }
+
+    fn read_uint(&mut self, size: u64) -> ResultType<u64> {
+        Ok(self
+            .read_payload(size)?
+            .iter()
+            .fold(0, |v, b| (v << 8) | *b as u64))
+    }
+
+    fn read_float(&mut self, size: u64) -> ResultType<f64> {
+        let buf = self.read_payload(size)?;
+        Ok(match buf.len() {
+            4 => f32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            8 => f64::from_be_bytes([
+                buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
+            ]),
+            _ => 0.0,
+        })
+    }
+
+    fn skip(&mut self, size: u64) -> ResultType<()> {
+        self.reader.seek(SeekFrom::Current(size as i64))?;
+        self.pos += size;
+        Ok(())
+    }
+
+    fn read_block(&mut self, size: u64, cluster_timecode: i64) -> ResultType<(u64, i64, u8, u64)> {
+        let start = self.pos;
+        let track = self.read_vint(false)?;
+        let timecode = i16::from_be_bytes([self.read_u8()?, self.read_u8()?]) as i64;
+        let flags = self.read_u8()?;
+        let header = self.pos - start;
+        if header > size {
+            bail!("Invalid block at {}", start);
+        }
+        Ok((track, cluster_timecode + timecode, flags, header))
+    }
+
+    /// Scans the file and returns the video track and an index of its frames.
+    fn scan(&mut self) -> ResultType<Index> {
+        let mut tracks: Vec<Track> = Vec::new();
+        let mut scale = DEFAULT_TIMECODE_SCALE;
+        let mut duration = 0.0;
+        let mut cluster_timecode = 0i64;
+        // (track, timecode, offset, len, key)
+        let mut blocks: Vec<(u64, i64, u64, usize, bool)> = Vec::new();
+        let mut warned_lacing = false;
+        while self.pos < self.len {
+            let res = (|| -> ResultType<bool> {
+                let id = self.read_vint(true)?;
+                let size = self.read_vint(false)?;
+                if size != UNKNOWN_SIZE && self.pos + size > self.len {
+                    if id == ID_SEGMENT || id == ID_CLUSTER {
+                        // A crashed recorder leaves stale sizes, read what is there.
+                        return Ok(true);
+                    }
+                    log::warn!("truncated element {:#x} at {}", id, self.pos);
+                    return Ok(false);
+                }
+                match id {
+                    // Master elements are entered in place, so children are read
+                    // by this loop whether or not their parent size is known.
+                    ID_SEGMENT | ID_INFO | ID_TRACKS | ID_VIDEO | ID_CLUSTER | ID_BLOCK_GROUP => {}
+                    ID_TRACK_ENTRY => tracks.push(Track::default()),
+                    _ if size == UNKNOWN_SIZE => {
+                        bail!("Unknown size of element {:#x}", id);
+                    }
+                    ID_TIMECODE_SCALE => scale = self.read_uint(size)?,
+                    ID_DURATION => duration = self.read_float(size)?,
+                    ID_TRACK_NUMBER | ID_TRACK_TYPE | ID_PIXEL_WIDTH | ID_PIXEL_HEIGHT
+                    | ID_CODEC_ID => {
+                        let Some(track) = tracks.last_mut() else {
+                            bail!("Track field outside of a track entry");
+                        };
+                        match id {
+                            ID_TRACK_NUMBER => track.number = self.read_uint(size)?,
+                            ID_TRACK_TYPE => track.kind = self.read_uint(size)?,
+                            ID_PIXEL_WIDTH => track.width = self.read_uint(size)? as _,
+                            ID_PIXEL_HEIGHT => track.height = self.read_uint(size)? as _,
+                            _ => {
+                                let codec = self.read_payload(size)?;
+                                track.codec = String::from_utf8_lossy(&codec)
+                                    .trim_end_matches('\0')
+                                    .to_owned();
+                            }
+                        }
+                    }
+                    ID_TIMECODE => cluster_timecode = self.read_uint(size)? as i64,
+                    ID_SIMPLE_BLOCK | ID_BLOCK => {
+                        let (track, timecode, flags, header) =
+                            self.read_block(size, cluster_timecode)?;
+                        if flags & 0x06 != 0 {
+                            if !warned_lacing {
+                                log::warn!("laced blocks are not supported, skipped");
+                                warned_lacing = true;
+                            }
+                        } else {
+                            // A `Block` is a key frame unless a `ReferenceBlock` follows.
+                            let key = id == ID_BLOCK || flags & 0x80 != 0;
+                            let len = (size - header) as usize;
+                            blocks.push((track, timecode, self.pos, len, key));
+                        }
+                        self.skip(size - header)?;
+                    }
+                    ID_REFERENCE_BLOCK => {
+                        if let Some(last) = blocks.last_mut() {
+                            last.4 = false;
+                        }
+                        self.skip(size)?;
+                    }
+                    _ => self.skip(size)?,
+                }
+                Ok(true)
+            })();
+            match res {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    if blocks.is_empty() {
+                        return Err(err);
+                    }
+                    log::warn!("stop reading at {}: {}", self.pos, err);
+                    break;
+                }
+            }
+        }
+        let Some(video) = tracks.iter().position(|t| t.kind == TRACK_TYPE_VIDEO) else {
+            bail!("No video track");
+        };
+        let video = tracks.swap_remove(video);
+        let audio = tracks.into_iter().find(|t| t.kind == TRACK_TYPE_AUDIO);
+        let packets = blocks
+            .into_iter()
+            .filter(|b| b.0 == video.number)
+            .map(|(_, timecode, offset, len, key)| Packet {
+                pts: (timecode as i128 * scale as i128 / 1_000_000) as i64,
+                key,
+                offset,
+                len,
+            })
+            .collect();
+        Ok(Index {
+            video,
+            audio,
+            duration,
+            scale,
+            packets,
+        })
+    }
+}
+
+/// Reads the session events of a recording, empty if it has none.
+pub fn read_events(path: impl AsRef<Path>) -> ResultType<Vec<RecordEvent>> {
+    let file = match File::open(events_path(path)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // The last line may be cut off if the recorder crashed.
+        match serde_json::from_str::<RecordEvent>(&line) {
+            Ok(event) => events.push(event),
+            Err(err) => log::warn!("skip invalid record event: {}", err),
+        }
+    }
+    events.sort_by_key(|e| e.ts);
+    Ok(events)
+}
+
+/// Events whose kind is `query` or whose detail contains it, ignoring case,
+/// e.g. `file` or `report.pdf`.
+pub fn find_events<'a>(events: &'a [RecordEvent], query: &str) -> Vec<&'a RecordEvent> {
+    let query = query.to_lowercase();
+    events
+        .iter()
+        .filter(|e| {
+            e.kind.to_lowercase() == query || e.detail.to_string().to_lowercase().contains(&query)
+        })
+        .collect()
+}
+
+/// Decodes a recording frame by frame, with seeking and paced playback.
+pub struct Player {
+    file: BufReader<File>,
+    info: RecordingInfo,
+    events: Vec<RecordEvent>,
+    format: CodecFormat,
+    packets: Vec<Packet>,
+    decoder: Decoder,
+    rgb: ImageRgb,
+    texture: ImageTexture,
+    next: usize,
+    position: i64,
+}
+
+impl Player {
+    pub fn open(path: impl AsRef<Path>) -> ResultType<Self> {
+        let path = path.as_ref();
+        let Index {
+            video: track,
+            audio,
+            duration,
+            scale,
+            packets,
+        } = Demuxer::new(BufReader::new(File::open(path)?))?.scan()?;
+        let format = match track.codec.as_str() {
+            "V_VP8" => CodecFormat::VP8,
+            "V_VP9" => CodecFormat::VP9,
+            "V_AV1" => CodecFormat::AV1,
+            codec => bail!("Unsupported codec {}", codec),
+        };
+        if !packets.iter().any(|p| p.key) {
+            bail!("No key frame in {}", path.display());
+        }
+        let mut info = RecordingInfo::default();
+        info.parse_filename(path);
+        info.codec = format.to_string();
+        info.audio_codec = audio.map(|t| t.codec).unwrap_or_default();
+        info.width = track.width;
+        info.height = track.height;
+        info.frames = packets.len();
+        info.key_frames = packets.iter().filter(|p| p.key).count();
+        let first = packets.first().map(|p| p.pts).unwrap_or_default();
+        let last = packets.last().map(|p| p.pts).unwrap_or_default();
+        // The duration is only written when the recorder finalized the file.
+        info.duration = if duration > 0.0 {
+            (duration * scale as f64 / 1_000_000.0) as i64
+        } else {
+            last - first
+        };
+        let events = read_events(path)?;
+        info.events = events.len();
+        let decoder = Decoder::new(format, None);
+        if !decoder.valid() {
+            bail!("Failed to create {} decoder", info.codec);
+        }
+        Ok(Self {
+            file: BufReader::new(File::open(path)?),
+            info,
+            events,
+            format,
+            packets,
+            decoder,
+            rgb: ImageRgb::new(ImageFormat::ABGR, 1),
+            texture: Default::default(),
+            next: 0,
+            position: first,
+        })
+    }
+
+    pub fn info(&self) -> &RecordingInfo {
+        &self.info
+    }
+
+    pub fn events(&self) -> &[RecordEvent] {
+        &self.events
+    }
+
+    /// Milliseconds of the last decoded frame.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Decodes up to the frame shown at `pts` milliseconds, starting from the
+    /// nearest key frame before it.
+    pub fn seek(&mut self, pts: i64) -> ResultType<()> {
+        let first = self.packets.iter().position(|p| p.key).unwrap_or_default();
+        let key = self
+            .packets
+            .iter()
+            .rposition(|p| p.key && p.pts <= pts)
+            .unwrap_or(first);
+        self.decoder = Decoder::new(self.format, None);
+        self.next = key;
+        self.next_frame()?;
+        while self.next < self.packets.len() && self.packets[self.next].pts <= pts {
+            self.next_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Decodes the next frame and returns its milliseconds, `None` at the end.
+    pub fn next_frame(&mut self) -> ResultType<Option<i64>> {
+        while self.next < self.packets.len() {
+            let packet = self.packets[self.next].clone();
+            self.next += 1;
+            if self.decode(&packet)? {
+                self.position = packet.pts;
+                return Ok(Some(packet.pts));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The last decoded frame as tightly packed RGBA.
+    pub fn rgba(&self) -> (usize, usize, Vec<u8>) {
+        let (w, h) = (self.rgb.w, self.rgb.h);
+        if w == 0 || h == 0 {
+            return (0, 0, Vec::new());
+        }
+        let stride = self.rgb.raw.len() / h;
+        let mut out = Vec::with_capacity(w * h * 4);
+        for row in self.rgb.raw.chunks(stride).take(h) {
+            out.extend_from_slice(&row[..w * 4]);
+        }
+        (w, h, out)
+    }
+
+    fn decode(&mut self, packet: &Packet) -> ResultType<bool> {
+        let mut data = vec![0u8; packet.len];
+        self.file.seek(SeekFrom::Start(packet.offset))?;
+        self.file.read_exact(&mut data)?;
+        let frame = EncodedVideoFrame {
+            data: Bytes::from(data),
+            key: packet.key,
+            pts: packet.pts,
+            ..Default::default()
+        };
+        let frames = EncodedVideoFrames {
+            frames: vec![frame].into(),
+            ..Default::default()
+        };
+        let mut vf = VideoFrame::new();
+        match self.format {
+            CodecFormat::VP8 => vf.set_vp8s(frames),
+            CodecFormat::VP9 => vf.set_vp9s(frames),
+            CodecFormat::AV1 => vf.set_av1s(frames),
+            _ => bail!("Unsupported codec {}", self.info.codec),
+        }
+        let Some(frame) = vf.union else {
+            bail!("Empty video frame");
+        };
+        let mut pixelbuffer = true;
+        let mut chroma = None;
+        self.decoder.handle_video_frame(
+            &frame,
+            &mut self.rgb,
+            &mut self.texture,
+            &mut pixelbuffer,
+            &mut chroma,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webm::mux::{self, Track};
+
+    #[test]
+    fn test_demux_recording() {
+        let dir = std::env::temp_dir().join("rustdesk_playback_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("incoming_123_456_20240102030405006_display1_vp9.webm");
+        {
+            let file = File::create(&path).unwrap();
+            let mut webm = mux::Segment::new(mux::Writer::new(file)).unwrap();
+            let mut vt = webm.add_video_track(640, 480, None, mux::VideoCodecId::VP9);
+            for i in 0..10u64 {
+                let data = vec![i as u8; 100 + i as usize];
+                assert!(vt.add_frame(&data, i * 40 * 1_000_000, i % 5 == 0));
+            }
+            assert!(webm.finalize(None));
+        }
+        let mut demuxer = Demuxer::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let Index {
+            video: track,
+            audio,
+            scale,
+            packets,
+            ..
+        } = demuxer.scan().unwrap();
+        assert!(audio.is_none());
+        assert_eq!(track.codec, "V_VP9");
+        assert_eq!((track.width, track.height), (640, 480));
+        assert_eq!(scale, DEFAULT_TIMECODE_SCALE);
+        assert_eq!(packets.len(), 10);
+        let mut file = File::open(&path).unwrap();
+        for (i, p) in packets.iter().enumerate() {
+            assert_eq!(p.pts, i as i64 * 40);
+            assert_eq!(p.key, i % 5 == 0);
+            let mut data = vec![0u8; p.len];
+            file.seek(SeekFrom::Start(p.offset)).unwrap();
+            file.read_exact(&mut data).unwrap();
+            assert_eq!(data, vec![i as u8; 100 + i]);
+        }
+        let mut info = RecordingInfo::default();
+        info.parse_filename(&path);
+        assert_eq!(info.direction, "incoming");
+        assert_eq!(info.id, "123_456");
+        assert_eq!(info.start, "20240102030405006");
+        assert_eq!((info.camera, info.display), (false, 1));
+
+        std::fs::write(
+            events_path(&path),
+            concat!(
+                r#"{"ts":320,"time":0,"kind":"file","detail":{"path":"C:\\report.pdf"}}"#,
+                "\n",
+                r#"{"ts":40,"time":0,"kind":"login","detail":{"peer":"123"}}"#,
+                "\n",
+                r#"{"ts":400,"time":0,"kind":"cli"#,
+            ),
+        )
+        .unwrap();
+        let events = read_events(&path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, "login");
+        let found = find_events(&events, "REPORT.PDF");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ts, 320);
+        assert_eq!(find_events(&events, "login").len(), 1);
+        std::fs::remove_file(events_path(&path)).ok();
+        std::fs::remove_file(&path).ok();
+    }
+}
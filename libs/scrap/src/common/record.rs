@@ -4,21 +4,25 @@ use hbb_common::anyhow::anyhow;
 use hbb_common::{
     bail, chrono, log,
     message_proto::{message, video_frame, EncodedVideoFrame, Message},
-    ResultType,
+    serde_json, ResultType,
 };
 #[cfg(feature = "hwcodec")]
 use hwcodec::mux::{MuxContext, Muxer};
+use serde::{Deserialize, Serialize};
 use std::{
     fs::{File, OpenOptions},
-    io,
+    io::{self, Write},
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::mpsc::Sender,
     time::Instant,
 };
-use webm::mux::{self, Segment, Track, VideoTrack, Writer};
+use webm::mux::{self, AudioTrack, Segment, Track, VideoTrack, Writer};
 
 const MIN_SECS: u64 = 1;
+// Audio further off the video clock than this is re-aligned, e.g. after the
+// sender skipped silence.
+const MAX_AUDIO_DRIFT_NS: i64 = 200_000_000;
 
 #[derive(Debug, Clone)]
 pub struct RecorderContext {
@@ -36,6 +40,14 @@ pub struct RecorderContext2 {
     pub width: usize,
     pub height: usize,
     pub format: CodecFormat,
+    pub audio: Option<RecordAudioFormat>,
+}
+
+/// Format of the Opus frames passed to [`Recorder::write_audio`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordAudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
 }
 
 impl RecorderContext2 {
@@ -77,6 +89,28 @@ pub trait RecorderApi {
     where
         Self: Sized;
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool;
+    /// Writes one Opus packet at `ts` nanoseconds of the video timeline.
+    fn write_audio(&mut self, _data: &[u8], _ts: u64) -> bool {
+        false
+    }
+}
+
+/// A session event stored next to a recording, see [`events_path`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordEvent {
+    /// Milliseconds on the video timeline, as taken by `playback::Player::seek`.
+    pub ts: i64,
+    /// Unix time in milliseconds.
+    pub time: i64,
+    pub kind: String,
+    #[serde(default)]
+    pub detail: serde_json::Value,
+}
+
+/// The sidecar of a recording holding one [`RecordEvent`] per line,
+/// `x.webm` -> `x.events.jsonl`.
+pub fn events_path(filename: impl AsRef<Path>) -> PathBuf {
+    filename.as_ref().with_extension("events.jsonl")
 }
 
 #[derive(Debug)]
@@ -93,6 +127,13 @@ pub struct Recorder {
     ctx2: Option<RecorderContext2>,
     pts: Option<i64>,
     check_failed: bool,
+    audio: Option<RecordAudioFormat>,
+    // Wall clock of the last video frame and its pts, to place audio frames.
+    video_clock: Option<(Instant, i64)>,
+    // Nanoseconds where the next audio frame continues the previous one.
+    audio_ts: Option<i64>,
+    // Events before the first file is created.
+    pending_events: Vec<RecordEvent>,
 }
 
 impl Deref for Recorder {
@@ -117,9 +158,22 @@ impl Recorder {
             ctx2: None,
             pts: None,
             check_failed: false,
+            audio: None,
+            video_clock: None,
+            audio_ts: None,
+            pending_events: Vec::new(),
         })
     }
 
+    /// Sets the format of the audio to record, it applies from the next file if
+    /// one is being written.
+    pub fn set_audio_format(&mut self, format: RecordAudioFormat) {
+        if self.audio != Some(format) {
+            log::info!("record audio format: {:?}", format);
+            self.audio = Some(format);
+        }
+    }
+
     fn check(&mut self, w: usize, h: usize, format: CodecFormat) -> ResultType<()> {
         match self.ctx2 {
             Some(ref ctx2) => {
@@ -129,6 +183,7 @@ impl Recorder {
                         height: h,
                         format,
                         filename: Default::default(),
+                        audio: self.audio,
                     };
                     ctx2.set_filename(&self.ctx)?;
                     self.ctx2 = Some(ctx2);
@@ -141,6 +196,7 @@ impl Recorder {
                     height: h,
                     format,
                     filename: Default::default(),
+                    audio: self.audio,
                 };
                 ctx2.set_filename(&self.ctx)?;
                 self.ctx2 = Some(ctx2);
@@ -165,7 +221,13 @@ impl Recorder {
             };
             // pts is None when new inner is created
             self.pts = None;
-            self.send_state(RecordState::NewFile(ctx2.filename.clone()));
+            self.video_clock = None;
+            self.audio_ts = None;
+            let filename = ctx2.filename.clone();
+            for event in std::mem::take(&mut self.pending_events) {
+                Self::append_event(&filename, event);
+            }
+            self.send_state(RecordState::NewFile(filename));
         }
         Ok(())
     }
@@ -250,6 +312,7 @@ impl Recorder {
         }
         let old_pts = self.pts;
         self.pts = Some(pts);
+        self.video_clock = Some((Instant::now(), pts));
         if old_pts.clone().unwrap_or_default() > pts {
             log::info!("pts {:?} -> {}, change record filename", old_pts, pts);
             self.inner = None;
@@ -261,10 +324,65 @@ impl Recorder {
                 res?;
             }
             self.pts = Some(pts);
+            self.video_clock = Some((Instant::now(), pts));
         }
         Ok(())
     }
 
+    /// Writes an Opus packet received or captured now. It is placed on the video
+    /// timeline by the wall clock, then continues by the packet durations so that
+    /// network jitter does not leave gaps or overlaps.
+    pub fn write_audio(&mut self, data: &[u8]) {
+        // A file starts with a video key frame.
+        let Some((instant, pts)) = self.video_clock else {
+            return;
+        };
+        let Some(duration) = opus_packet_duration(data) else {
+            return;
+        };
+        let now = pts * 1_000_000 + instant.elapsed().as_nanos() as i64;
+        let ts = match self.audio_ts {
+            Some(ts) if (now - ts).abs() <= MAX_AUDIO_DRIFT_NS => ts,
+            // Never go back, the muxer requires increasing timestamps.
+            Some(ts) => now.max(ts),
+            None => now,
+        };
+        self.audio_ts = Some(ts + duration);
+        self.as_mut().map(|x| x.write_audio(data, ts as u64));
+    }
+
+    /// Adds a session event at the current position of the recording.
+    pub fn write_event(&mut self, kind: &str, detail: serde_json::Value) {
+        let ts = self
+            .video_clock
+            .map(|(instant, pts)| pts + instant.elapsed().as_millis() as i64)
+            .unwrap_or_default();
+        let event = RecordEvent {
+            ts,
+            time: chrono::Utc::now().timestamp_millis(),
+            kind: kind.to_owned(),
+            detail,
+        };
+        match (&self.inner, &self.ctx2) {
+            (Some(_), Some(ctx2)) => Self::append_event(&ctx2.filename, event),
+            _ => self.pending_events.push(event),
+        }
+    }
+
+    fn append_event(filename: &str, event: RecordEvent) {
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(events_path(filename))
+            .and_then(|mut f| {
+                let line = serde_json::to_string(&event).unwrap_or_default();
+                writeln!(f, "{}", line)
+            });
+        if let Err(err) = res {
+            log::error!("Failed to write record event: {}", err);
+        }
+    }
+
     fn send_state(&self, state: RecordState) {
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
     }
@@ -272,6 +390,7 @@ impl Recorder {
 
 struct WebmRecorder {
     vt: VideoTrack,
+    at: Option<AudioTrack>,
     webm: Option<Segment<Writer<File>>>,
     ctx: RecorderContext,
     ctx2: RecorderContext2,
@@ -315,8 +434,24 @@ impl RecorderApi for WebmRecorder {
                 bail!("Failed to set codec private");
             }
         }
+        let at = match ctx2.audio {
+            Some(audio) => {
+                let at = webm.add_audio_track(
+                    audio.sample_rate as _,
+                    audio.channels as _,
+                    None,
+                    mux::AudioCodecId::Opus,
+                );
+                if !webm.set_codec_private(at.track_number(), &opus_head(audio)) {
+                    bail!("Failed to set opus codec private");
+                }
+                Some(at)
+            }
+            None => None,
+        };
         Ok(WebmRecorder {
             vt,
+            at,
             webm: Some(webm),
             ctx,
             ctx2,
@@ -342,6 +477,42 @@ impl RecorderApi for WebmRecorder {
             false
         }
     }
+
+    fn write_audio(&mut self, data: &[u8], ts: u64) -> bool {
+        match self.at.as_mut() {
+            Some(at) if self.written => at.add_frame(data, ts, true),
+            _ => false,
+        }
+    }
+}
+
+/// `OpusHead` of RFC 7845, the codec private data of an Opus track.
+fn opus_head(format: RecordAudioFormat) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(format.channels as u8);
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&format.sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
+}
+
+/// Duration in nanoseconds of an Opus packet, from its TOC byte (RFC 6716 3.1).
+fn opus_packet_duration(data: &[u8]) -> Option<i64> {
+    let toc = *data.first()?;
+    let config = toc >> 3;
+    let frame_us: i64 = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config as usize % 4],
+        12..=15 => [10_000, 20_000][config as usize % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config as usize % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*data.get(1)? & 0x3F) as i64,
+    };
+    Some(frames * frame_us * 1_000)
 }
 
 impl Drop for WebmRecorder {
@@ -350,6 +521,7 @@ impl Drop for WebmRecorder {
         let mut state = RecordState::WriteTail;
         if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
             std::fs::remove_file(&self.ctx2.filename).ok();
+            std::fs::remove_file(events_path(&self.ctx2.filename)).ok();
             state = RecordState::RemoveFile;
         }
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
@@ -416,6 +588,7 @@ impl Drop for HwRecorder {
             // The process cannot access the file because it is being used by another process
             self.muxer = None;
             std::fs::remove_file(&self.ctx2.filename).ok();
+            std::fs::remove_file(events_path(&self.ctx2.filename)).ok();
             state = RecordState::RemoveFile;
         }
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
@@ -2,12 +2,20 @@ use hbb_common::{
     anyhow::anyhow,
     bail,
     config::Config,
-    get_time,
-    password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    get_time, log,
+    password_security::{
+        decrypt_str_or_original, decrypt_vec_or_original, encrypt_str_or_original,
+        encrypt_vec_or_original,
+    },
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+    time::Duration,
+};
 use totp_rs::{Algorithm, Secret, TOTP};
 
 lazy_static::lazy_static! {
@@ -16,6 +24,7 @@ lazy_static::lazy_static! {
 
 const ISSUER: &str = "RustDesk";
 const TAG_LOGIN: &str = "Connection";
+pub const OPTION_2FA_NOTIFIERS: &str = "2fa-notifiers";
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TOTPInfo {
@@ -202,3 +211,368 @@ pub fn get_chatid_telegram(bot_token: &str) -> ResultType<Option<String>> {
 
     Ok(chat_id)
 }
+
+/// A login attempt waiting for the second factor, sent to the notifiers.
+#[derive(Debug, Clone, Default)]
+pub struct LoginAttempt {
+    pub id: String,
+    pub ip: String,
+    pub peer_id: String,
+    pub peer_name: String,
+    pub code: Option<String>,
+    pub approve_url: Option<String>,
+    pub deny_url: Option<String>,
+}
+
+impl LoginAttempt {
+    fn title(&self) -> String {
+        format!("RustDesk login to {}", self.id)
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        if let Some(code) = self.code.as_ref() {
+            text += &format!("2FA code: {}\n\n", code);
+        }
+        text += &format!(
+            "A new connection has been established to your device with ID {}. The source IP address is {}.",
+            self.id, self.ip
+        );
+        if !self.peer_id.is_empty() {
+            text += &format!(" The remote ID is {} ({}).", self.peer_id, self.peer_name);
+        }
+        if let (Some(approve), Some(deny)) = (self.approve_url.as_ref(), self.deny_url.as_ref()) {
+            text += &format!("\n\nApprove: {}\nDeny: {}", approve, deny);
+        }
+        text
+    }
+}
+
+/// Where 2FA codes and approve links are sent, configured as a JSON list in
+/// the `2fa-notifiers` option, e.g.
+/// `[{"type": "ntfy", "url": "https://ntfy.sh/my-topic"}]`.
+/// Secrets may be given in plain text, they are encrypted on first use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Notifier {
+    Email(EmailNotifier),
+    Webhook(WebhookNotifier),
+    Ntfy(NtfyNotifier),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailNotifier {
+    pub server: String,
+    /// 587 with `starttls`, 465 with `tls`, 25 with `none` if not set.
+    pub port: u16,
+    /// `starttls`, `tls` or `none`, `starttls` if empty.
+    pub security: String,
+    pub username: String,
+    pub password: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Posts `{"text": ...}`, which Slack, Mattermost, Teams and Matrix hookshot
+/// webhooks accept, together with the fields of the attempt.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookNotifier {
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>` if not empty.
+    pub token: String,
+}
+
+/// Publishes to an ntfy topic url, with approve and deny buttons.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NtfyNotifier {
+    pub url: String,
+    pub token: String,
+}
+
+impl Notifier {
+    fn secret(&mut self) -> Option<&mut String> {
+        match self {
+            Notifier::Email(n) => Some(&mut n.password),
+            Notifier::Webhook(n) => Some(&mut n.token),
+            Notifier::Ntfy(n) => Some(&mut n.token),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Notifier::Email(_) => "email",
+            Notifier::Webhook(_) => "webhook",
+            Notifier::Ntfy(_) => "ntfy",
+        }
+    }
+
+    async fn send(&self, attempt: &LoginAttempt) -> ResultType<()> {
+        match self {
+            Notifier::Email(n) => {
+                let (n, attempt) = (n.clone(), attempt.clone());
+                hbb_common::tokio::task::spawn_blocking(move || n.send(&attempt)).await?
+            }
+            Notifier::Webhook(n) => {
+                let body = serde_json::json!({
+                    "text": attempt.text(),
+                    "msgtype": "m.text",
+                    "body": attempt.text(),
+                    "id": attempt.id,
+                    "ip": attempt.ip,
+                    "peer_id": attempt.peer_id,
+                    "peer_name": attempt.peer_name,
+                    "approve_url": attempt.approve_url,
+                    "deny_url": attempt.deny_url,
+                });
+                let header = bearer(&n.token);
+                crate::post_request(n.url.clone(), body.to_string(), &header).await?;
+                Ok(())
+            }
+            Notifier::Ntfy(n) => {
+                let mut req = crate::hbbs_http::create_http_client_async_with_url(&n.url)
+                    .await
+                    .post(&n.url)
+                    .timeout(Duration::from_secs(12))
+                    .header("Title", attempt.title())
+                    .header("Tags", "lock")
+                    .body(attempt.text());
+                if let (Some(approve), Some(deny)) =
+                    (attempt.approve_url.as_ref(), attempt.deny_url.as_ref())
+                {
+                    req = req.header(
+                        "Actions",
+                        format!(
+                            "http, Approve, {}, method=POST, clear=true; http, Deny, {}, method=POST, clear=true",
+                            approve, deny
+                        ),
+                    );
+                }
+                if !n.token.is_empty() {
+                    req = req.bearer_auth(&n.token);
+                }
+                req.send().await?.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn bearer(token: &str) -> String {
+    if token.is_empty() {
+        "".to_owned()
+    } else {
+        format!("Authorization: Bearer {}", token)
+    }
+}
+
+pub fn get_notifiers() -> ResultType<Vec<Notifier>> {
+    let data = Config::get_option(OPTION_2FA_NOTIFIERS);
+    if data.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let mut notifiers = serde_json::from_str::<Vec<Notifier>>(&data)?;
+    let mut store = false;
+    for n in notifiers.iter_mut() {
+        if let Some(secret) = n.secret() {
+            let (s, _, should_store) = decrypt_str_or_original(secret, "00");
+            *secret = s;
+            store |= should_store;
+        }
+    }
+    if store {
+        let mut encrypted = notifiers.clone();
+        for n in encrypted.iter_mut() {
+            if let Some(secret) = n.secret() {
+                *secret = encrypt_str_or_original(secret, "00", 1024);
+            }
+        }
+        Config::set_option(
+            OPTION_2FA_NOTIFIERS.to_owned(),
+            serde_json::to_string(&encrypted)?,
+        );
+    }
+    Ok(notifiers)
+}
+
+/// Sends the attempt to the Telegram bot and all the notifiers.
+pub async fn notify(attempt: LoginAttempt) {
+    match TelegramBot::get() {
+        Ok(Some(bot)) => {
+            if let Err(err) = send_2fa_code_to_telegram(&attempt.text(), bot).await {
+                log::error!("Failed to send 2fa code to telegram bot: {}", err);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => log::error!("Failed to get telegram bot: {}", err),
+    }
+    let notifiers = match get_notifiers() {
+        Ok(notifiers) => notifiers,
+        Err(err) => {
+            log::error!("Invalid 2fa notifiers: {}", err);
+            return;
+        }
+    };
+    for n in notifiers.iter() {
+        if let Err(err) = n.send(&attempt).await {
+            log::error!("Failed to send 2fa notification by {}: {}", n.name(), err);
+        }
+    }
+}
+
+/// A minimal SMTP client, enough to submit a plain text mail to a relay.
+struct Smtp<S: Read + Write> {
+    reader: BufReader<S>,
+}
+
+impl<S: Read + Write> Smtp<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    /// Reads a possibly multiline reply, e.g. `250-...\r\n250 ...\r\n`.
+    fn expect(&mut self, code: u16) -> ResultType<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                bail!("SMTP connection closed");
+            }
+            reply += &line;
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        if !reply.starts_with(&code.to_string()) {
+            bail!("SMTP error: {}", reply.trim());
+        }
+        Ok(reply)
+    }
+
+    fn command(&mut self, cmd: &str, code: u16) -> ResultType<String> {
+        let stream = self.reader.get_mut();
+        stream.write_all(cmd.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(code)
+    }
+}
+
+impl EmailNotifier {
+    fn send(&self, attempt: &LoginAttempt) -> ResultType<()> {
+        if self.to.is_empty() {
+            bail!("no recipient");
+        }
+        let security = if self.security.is_empty() {
+            "starttls"
+        } else {
+            self.security.as_str()
+        };
+        let port = match (self.port, security) {
+            (0, "tls") => 465,
+            (0, "none") => 25,
+            (0, _) => 587,
+            (port, _) => port,
+        };
+        let tcp = TcpStream::connect((self.server.as_str(), port))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(30)))?;
+        tcp.set_write_timeout(Some(Duration::from_secs(30)))?;
+        let tls = native_tls::TlsConnector::new()?;
+        match security {
+            "tls" => self.submit(Smtp::new(tls.connect(&self.server, tcp)?), attempt),
+            "starttls" => {
+                let mut smtp = Smtp::new(tcp);
+                smtp.expect(220)?;
+                smtp.command("EHLO rustdesk", 250)?;
+                smtp.command("STARTTLS", 220)?;
+                // Nothing is buffered, the server waits for the handshake.
+                let tcp = smtp.reader.into_inner();
+                let mut smtp = Smtp::new(tls.connect(&self.server, tcp)?);
+                smtp.command("EHLO rustdesk", 250)?;
+                self.mail(smtp, attempt)
+            }
+            "none" => self.submit(Smtp::new(tcp), attempt),
+            _ => bail!("invalid security {}", security),
+        }
+    }
+
+    fn submit<S: Read + Write>(&self, mut smtp: Smtp<S>, attempt: &LoginAttempt) -> ResultType<()> {
+        smtp.expect(220)?;
+        smtp.command("EHLO rustdesk", 250)?;
+        self.mail(smtp, attempt)
+    }
+
+    fn mail<S: Read + Write>(&self, mut smtp: Smtp<S>, attempt: &LoginAttempt) -> ResultType<()> {
+        if !self.username.is_empty() {
+            let auth = base64_encode(format!("\0{}\0{}", self.username, self.password));
+            smtp.command(&format!("AUTH PLAIN {}", auth), 235)?;
+        }
+        let from = if self.from.is_empty() {
+            &self.username
+        } else {
+            &self.from
+        };
+        smtp.command(&format!("MAIL FROM:<{}>", from), 250)?;
+        for to in self.to.iter() {
+            smtp.command(&format!("RCPT TO:<{}>", to), 250)?;
+        }
+        smtp.command("DATA", 354)?;
+        let body = attempt
+            .text()
+            .lines()
+            .map(|l| {
+                if l.starts_with('.') {
+                    format!(".{}", l)
+                } else {
+                    l.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        let mail = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n.",
+            from,
+            self.to.join(", "),
+            attempt.title(),
+            chrono::Local::now().to_rfc2822(),
+            body
+        );
+        smtp.command(&mail, 250)?;
+        smtp.command("QUIT", 221).ok();
+        Ok(())
+    }
+}
+
+fn base64_encode(s: String) -> String {
+    hbb_common::sodiumoxide::base64::encode(s, hbb_common::sodiumoxide::base64::Variant::Original)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifiers() {
+        let notifiers = serde_json::from_str::<Vec<Notifier>>(
+            r#"[{"type": "ntfy", "url": "https://ntfy.sh/t"},
+                {"type": "email", "server": "smtp.example.com", "to": ["a@example.com"]}]"#,
+        )
+        .unwrap();
+        assert!(matches!(&notifiers[0], Notifier::Ntfy(n) if n.token.is_empty()));
+        assert!(matches!(&notifiers[1], Notifier::Email(n) if n.port == 0 && n.to.len() == 1));
+        let attempt = LoginAttempt {
+            id: "123".to_owned(),
+            code: Some("000111".to_owned()),
+            approve_url: Some("https://example.com/approve/t".to_owned()),
+            deny_url: Some("https://example.com/deny/t".to_owned()),
+            ..Default::default()
+        };
+        let text = attempt.text();
+        assert!(text.starts_with("2FA code: 000111\n"));
+        assert!(text.ends_with("Deny: https://example.com/deny/t"));
+    }
+}
@@ -1,24 +1,30 @@
 use crate::client::*;
 use async_trait::async_trait;
 use hbb_common::{
+    bail,
     config::PeerConfig,
-    config::READ_TIMEOUT,
+    config::{LocalConfig, READ_TIMEOUT},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tokio::{self, sync::mpsc},
-    Stream,
+    ResultType, Stream,
 };
 use std::sync::{Arc, RwLock};
 
+pub mod file_transfer;
+pub mod play_recording;
+
 #[derive(Clone)]
 pub struct Session {
     id: String,
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    // Fail instead of asking for a password on the terminal
+    headless: bool,
 }
 
 impl Session {
@@ -27,11 +33,21 @@ impl Session {
         if PeerConfig::load(id).password.is_empty() {
             password = rpassword::prompt_password("Enter password: ").unwrap();
         }
+        Self::with_password(id, sender, password, false)
+    }
+
+    fn with_password(
+        id: &str,
+        sender: mpsc::UnboundedSender<Data>,
+        password: String,
+        headless: bool,
+    ) -> Self {
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
             lc: Default::default(),
+            headless,
         };
         session.lc.write().unwrap().initialize(
             id.to_owned(),
@@ -40,6 +56,7 @@ impl Session {
             false,
             None,
             None,
+            None,
         );
         session
     }
@@ -47,7 +64,7 @@ impl Session {
 
 #[async_trait]
 impl Interface for Session {
-    fn get_login_config_handler(&self) -> Arc<RwLock<LoginConfigHandler>> {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
         return self.lc.clone();
     }
 
@@ -55,14 +72,20 @@ impl Interface for Session {
         match msgtype {
             "input-password" => {
                 self.sender
-                    .send(Data::Login((self.password.clone(), true)))
+                    .send(Data::Login((
+                        "".to_owned(),
+                        "".to_owned(),
+                        self.password.clone(),
+                        true,
+                    )))
                     .ok();
             }
             "re-input-password" => {
                 log::error!("{}: {}", title, text);
                 match rpassword::prompt_password("Enter password: ") {
                     Ok(password) => {
-                        let login_data = Data::Login((password, true));
+                        let login_data =
+                            Data::Login(("".to_owned(), "".to_owned(), password, true));
                         self.sender.send(login_data).ok();
                     }
                     Err(e) => {
@@ -80,6 +103,10 @@ impl Interface for Session {
     }
 
     fn handle_login_error(&self, err: &str) -> bool {
+        if self.headless {
+            log::error!("Failed to login to {}: {}", self.id, err);
+            return false;
+        }
         handle_login_error(self.lc.clone(), err, self)
     }
 
@@ -87,6 +114,8 @@ impl Interface for Session {
         self.lc.write().unwrap().handle_peer_info(&pi);
     }
 
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
     async fn handle_hash(&self, pass: &str, hash: Hash, peer: &mut Stream) {
         log::info!(
             "password={}",
@@ -125,13 +154,13 @@ impl Interface for Session {
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn connect_test(id: &str, key: String, token: String) {
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
     let handler = Session::new(&id, sender);
     match crate::client::Client::start(id, &key, &token, ConnType::PORT_FORWARD, handler).await {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
         }
-        Ok((mut stream, direct)) => {
+        Ok(((mut stream, direct, _pk, _kcp, _stream_type), _)) => {
             log::info!("direct: {}", direct);
             // rpassword::prompt_password("Input anything to exit").ok();
             loop {
@@ -144,7 +173,7 @@ pub async fn connect_test(id: &str, key: String, token: String) {
                         Ok(Some(Ok(bytes))) => {
                             if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                                 match msg_in.union {
-                                    Some(message::Union::Hash(hash)) => {
+                                    Some(message::Union::Hash(_)) => {
                                         log::info!("Got hash");
                                         break;
                                     }
@@ -171,7 +200,7 @@ pub async fn start_one_port_forward(
 ) {
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
     let handler = Session::new(&id, sender);
     if let Err(err) = crate::port_forward::listen(
         handler.id.clone(),
@@ -191,3 +220,55 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+/// Asks the online peer `id` to broadcast the Wake-on-LAN `packets` on its LAN,
+/// over a multiplexed port forward session logged in with the saved password.
+#[tokio::main(flavor = "current_thread")]
+pub async fn send_wol_via(id: String, packets: Vec<Vec<u8>>) -> ResultType<()> {
+    use crate::tunnel::{Frame, FrameDecoder, MUX_HOST};
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::with_password(&id, sender, "".to_owned(), true);
+    handler.lc.write().unwrap().port_forward = (MUX_HOST.to_owned(), 0);
+    let key = crate::get_key(true).await;
+    let token = LocalConfig::get_option("access_token");
+    let Some(mut stream) = crate::port_forward::connect_and_login(
+        &id,
+        "",
+        &mut receiver,
+        handler,
+        None,
+        &key,
+        &token,
+        false,
+    )
+    .await?
+    else {
+        bail!("Failed to login to {}", id);
+    };
+    let mut decoder = FrameDecoder::default();
+    for packet in packets {
+        stream
+            .send_bytes(
+                Frame::Wol {
+                    packet: packet.into(),
+                }
+                .encode(),
+            )
+            .await?;
+        // Wait for the answer, a close of channel 0.
+        loop {
+            if let Some(frame) = decoder.next()? {
+                match frame {
+                    Frame::Close { channel: 0, reason } if reason.is_empty() => break,
+                    Frame::Close { channel: 0, reason } => bail!("{}", reason),
+                    _ => continue,
+                }
+            }
+            match hbb_common::timeout(READ_TIMEOUT, stream.next()).await? {
+                Some(Ok(bytes)) => decoder.feed(&bytes),
+                _ => bail!("Reset by the peer"),
+            }
+        }
+    }
+    Ok(())
+}
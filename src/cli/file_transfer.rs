@@ -0,0 +1,626 @@
+//! Headless file transfer for `rustdesk --cli`.
+//!
+//! A [`CliHandler`] plugs into the regular [`Session`] / io_loop machinery in place of
+//! a GUI, so jobs run through exactly the same code paths as the file manager window.
+//! Progress and results are printed to stdout as one JSON object per line.
+
+use crate::{
+    client::{file_trait::FileManager, Interface, QualityStatus},
+    ui_session_interface::{InvokeUiSession, Session},
+};
+use hbb_common::{log, message_proto::*, rendezvous_proto::ConnType};
+use serde_json::json;
+use std::{
+    sync::{
+        atomic::AtomicUsize,
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_CONNECTION: i32 = 2;
+pub const EXIT_AUTH: i32 = 3;
+pub const EXIT_JOB_FAILED: i32 = 4;
+pub const EXIT_TIMEOUT: i32 = 5;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+// Jobs report progress every second, so a long silence means the peer is gone.
+const JOB_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+const PASSWORD_WARNING: &str = "Warning: --password is visible to other users and kept in \
+    the shell history, use --password-file or RUSTDESK_PASSWORD instead";
+
+const USAGE: &str = r#"Usage: rustdesk --cli <remote-id> [options] <command> [args]
+
+Options:
+  --password-file <path>  Read the password of the remote peer from the first line
+                          of a file, or of stdin with -
+  --password <password>   Visible to other users in the process list and kept in
+                          the shell history, prefer --password-file
+  --relay                 Force relay connection
+  --overwrite             Overwrite existing files without asking (default: skip)
+  --hidden                Include hidden files
+
+Commands:
+  ls <remote-dir>
+  upload <local-path> <remote-dir>
+  download <remote-path> <local-dir>
+  rm [-r] <remote-path>
+  mv <remote-path> <new-name>
+  mkdir <remote-dir>
+
+The password is taken from RUSTDESK_PASSWORD if neither option is given.
+
+Each line written to stdout is a JSON object with an "event" field.
+Exit codes: 0 ok, 1 usage, 2 connection, 3 authentication, 4 job failed, 5 timeout."#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    List(String),
+    Upload { local: String, remote_dir: String },
+    Download { remote: String, local_dir: String },
+    Remove { path: String, recursive: bool },
+    Rename { path: String, new_name: String },
+    Mkdir(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub id: String,
+    pub password: String,
+    pub force_relay: bool,
+    pub overwrite: bool,
+    pub include_hidden: bool,
+    pub command: Command,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut id = None;
+        let mut password = std::env::var("RUSTDESK_PASSWORD").unwrap_or_default();
+        let mut force_relay = false;
+        let mut overwrite = false;
+        let mut include_hidden = false;
+        let mut recursive = false;
+        let mut positional = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--password" => {
+                    eprintln!("{}", PASSWORD_WARNING);
+                    password = iter.next().ok_or("--password requires a value")?.clone();
+                }
+                "--password-file" => {
+                    let path = iter.next().ok_or("--password-file requires a path")?;
+                    password = read_password(path)?;
+                }
+                "--relay" => force_relay = true,
+                "--overwrite" => overwrite = true,
+                "--hidden" => include_hidden = true,
+                "-r" | "--recursive" => recursive = true,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => {
+                    if id.is_none() {
+                        id = Some(arg.clone());
+                    } else {
+                        positional.push(arg.clone());
+                    }
+                }
+            }
+        }
+        let id = id.ok_or("Missing remote id")?;
+        let (cmd, rest) = positional.split_first().ok_or("Missing command")?;
+        let arg = |i: usize| -> Result<String, String> {
+            rest.get(i)
+                .cloned()
+                .ok_or_else(|| format!("Missing argument for {}", cmd))
+        };
+        let command = match cmd.as_str() {
+            "ls" => Command::List(arg(0)?),
+            "upload" => Command::Upload {
+                local: arg(0)?,
+                remote_dir: arg(1)?,
+            },
+            "download" => Command::Download {
+                remote: arg(0)?,
+                local_dir: arg(1)?,
+            },
+            "rm" => Command::Remove {
+                path: arg(0)?,
+                recursive,
+            },
+            "mv" => Command::Rename {
+                path: arg(0)?,
+                new_name: arg(1)?,
+            },
+            "mkdir" => Command::Mkdir(arg(0)?),
+            _ => return Err(format!("Unknown command: {}", cmd)),
+        };
+        Ok(Self {
+            id,
+            password,
+            force_relay,
+            overwrite,
+            include_hidden,
+            command,
+        })
+    }
+}
+
+/// First line of the file at `path`, or of stdin for `-`.
+fn read_password(path: &str) -> Result<String, String> {
+    let res = if path == "-" {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    } else {
+        std::fs::read_to_string(path)
+    };
+    let text = res.map_err(|err| format!("Failed to read the password from {}: {}", path, err))?;
+    Ok(text.lines().next().unwrap_or_default().to_owned())
+}
+
+enum Event {
+    Connected,
+    Msgbox(String, String, String),
+    Folder(i32, Vec<FileEntry>, String, bool),
+    Progress(i32, i32, f64, f64),
+    Done(i32, i32),
+    Error(i32, String, i32),
+    OverrideConfirm(i32, i32, String, bool),
+    Closed,
+}
+
+/// `InvokeUiSession` implementation that forwards the file transfer callbacks to
+/// the driver thread and ignores everything display related.
+#[derive(Clone, Default)]
+pub struct CliHandler {
+    events: Arc<Mutex<Option<Sender<Event>>>>,
+}
+
+impl CliHandler {
+    fn post(&self, event: Event) {
+        if let Some(sender) = self.events.lock().unwrap().as_ref() {
+            sender.send(event).ok();
+        }
+    }
+}
+
+impl InvokeUiSession for CliHandler {
+    fn set_cursor_data(&self, _cd: CursorData) {}
+
+    fn set_cursor_id(&self, _id: String) {}
+
+    fn set_cursor_position(&self, _cp: CursorPosition) {}
+
+    fn set_display(&self, _x: i32, _y: i32, _w: i32, _h: i32, _cursor_embedded: bool, _scale: f64) {
+    }
+
+    fn switch_display(&self, _display: &SwitchDisplay) {}
+
+    fn set_peer_info(&self, _peer_info: &PeerInfo) {}
+
+    fn set_displays(&self, _displays: &Vec<DisplayInfo>) {}
+
+    fn set_platform_additions(&self, _data: &str) {}
+
+    fn on_connected(&self, _conn_type: ConnType) {
+        self.post(Event::Connected);
+    }
+
+    fn update_privacy_mode(&self) {}
+
+    fn set_permission(&self, _name: &str, _value: bool) {}
+
+    fn close_success(&self) {}
+
+    fn update_quality_status(&self, _qs: QualityStatus) {}
+
+    fn set_connection_type(&self, _is_secured: bool, _direct: bool, _stream_type: &str) {}
+
+    fn set_fingerprint(&self, _fingerprint: String) {}
+
+    fn job_error(&self, id: i32, err: String, file_num: i32) {
+        self.post(Event::Error(id, err, file_num));
+    }
+
+    fn job_done(&self, id: i32, file_num: i32) {
+        self.post(Event::Done(id, file_num));
+    }
+
+    fn clear_all_jobs(&self) {}
+
+    fn new_message(&self, _msg: String) {}
+
+    fn update_transfer_list(&self) {}
+
+    fn load_last_job(&self, _cnt: i32, _job_json: &str, _auto_start: bool) {}
+
+    fn update_folder_files(
+        &self,
+        id: i32,
+        entries: &Vec<FileEntry>,
+        path: String,
+        is_local: bool,
+        only_count: bool,
+    ) {
+        if !only_count {
+            self.post(Event::Folder(id, entries.clone(), path, is_local));
+        }
+    }
+
+    fn confirm_delete_files(&self, _id: i32, _i: i32, _name: String) {}
+
+    fn override_file_confirm(
+        &self,
+        id: i32,
+        file_num: i32,
+        to: String,
+        is_upload: bool,
+        _is_identical: bool,
+    ) {
+        self.post(Event::OverrideConfirm(id, file_num, to, is_upload));
+    }
+
+    fn update_block_input_state(&self, _on: bool) {}
+
+    fn job_progress(&self, id: i32, file_num: i32, speed: f64, finished_size: f64) {
+        self.post(Event::Progress(id, file_num, speed, finished_size));
+    }
+
+    fn adapt_size(&self) {}
+
+    fn on_rgba(&self, _display: usize, _rgba: &mut scrap::ImageRgb) {}
+
+    fn msgbox(&self, msgtype: &str, title: &str, text: &str, _link: &str, _retry: bool) {
+        self.post(Event::Msgbox(
+            msgtype.to_owned(),
+            title.to_owned(),
+            text.to_owned(),
+        ));
+    }
+
+    fn cancel_msgbox(&self, _tag: &str) {}
+
+    fn switch_back(&self, _id: &str) {}
+
+    fn portable_service_running(&self, _running: bool) {}
+
+    fn on_voice_call_started(&self) {}
+
+    fn on_voice_call_closed(&self, _reason: &str) {}
+
+    fn on_voice_call_waiting(&self) {}
+
+    fn on_voice_call_incoming(&self) {}
+
+    fn get_rgba(&self, _display: usize) -> *const u8 {
+        std::ptr::null()
+    }
+
+    fn next_rgba(&self, _display: usize) {}
+
+    #[cfg(all(feature = "vram", feature = "flutter"))]
+    fn on_texture(&self, _display: usize, _texture: *mut std::ffi::c_void) {}
+
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
+    fn set_current_display(&self, _disp_idx: i32) {}
+
+    #[cfg(feature = "flutter")]
+    fn is_multi_ui_session(&self) -> bool {
+        false
+    }
+
+    fn update_record_status(&self, _start: bool) {}
+
+    fn printer_request(&self, _id: i32, _path: String) {}
+
+    fn handle_screenshot_resp(&self, _sid: String, _msg: String) {}
+
+    fn handle_terminal_response(&self, _response: TerminalResponse) {}
+}
+
+fn emit(value: serde_json::Value) {
+    println!("{}", value);
+}
+
+fn entry_to_json(entry: &FileEntry) -> serde_json::Value {
+    let is_dir = matches!(
+        entry.entry_type.enum_value(),
+        Ok(FileType::Dir) | Ok(FileType::DirLink) | Ok(FileType::DirDrive)
+    );
+    json!({
+        "name": entry.name,
+        "is_dir": is_dir,
+        "is_hidden": entry.is_hidden,
+        "size": entry.size,
+        "modified_time": entry.modified_time,
+    })
+}
+
+fn file_name(path: &str) -> &str {
+    path.trim_end_matches(|c| c == '/' || c == '\\')
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or(path)
+}
+
+struct Driver {
+    session: Session<CliHandler>,
+    events: Receiver<Event>,
+    opts: Options,
+}
+
+impl Driver {
+    fn next(&self, timeout: Duration) -> Result<Event, i32> {
+        match self.events.recv_timeout(timeout) {
+            Ok(Event::Closed) | Err(RecvTimeoutError::Disconnected) => {
+                emit(json!({"event": "error", "message": "Connection closed"}));
+                Err(EXIT_CONNECTION)
+            }
+            Ok(Event::Msgbox(msgtype, title, text)) => Self::check_msgbox(&msgtype, &title, &text)
+                .map(|_| Event::Msgbox(msgtype, title, text)),
+            Ok(event) => Ok(event),
+            Err(RecvTimeoutError::Timeout) => {
+                emit(json!({"event": "error", "message": "Timeout"}));
+                Err(EXIT_TIMEOUT)
+            }
+        }
+    }
+
+    fn check_msgbox(msgtype: &str, title: &str, text: &str) -> Result<(), i32> {
+        log::info!("{}: {}: {}", msgtype, title, text);
+        if msgtype.contains("password") {
+            emit(json!({"event": "error", "message": "Wrong or missing password"}));
+            return Err(EXIT_AUTH);
+        }
+        if msgtype.contains("error") {
+            emit(json!({"event": "error", "title": title, "message": text}));
+            return Err(EXIT_CONNECTION);
+        }
+        Ok(())
+    }
+
+    fn wait_connected(&self) -> Result<(), i32> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if let Event::Connected = self.next(left)? {
+                emit(json!({"event": "connected", "id": self.opts.id}));
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the job with `id` to finish. With `last_file` set, per-file
+    /// completions are skipped until that file is reported done.
+    fn wait_job(&self, id: i32, last_file: Option<i32>) -> Result<(), i32> {
+        loop {
+            match self.next(JOB_IDLE_TIMEOUT)? {
+                Event::Progress(job, file_num, speed, finished_size) if job == id => {
+                    emit(json!({
+                        "event": "progress",
+                        "job": id,
+                        "file_num": file_num,
+                        "speed": speed,
+                        "finished_size": finished_size,
+                    }));
+                }
+                Event::OverrideConfirm(job, file_num, to, is_upload) if job == id => {
+                    emit(json!({
+                        "event": if self.opts.overwrite { "overwrite" } else { "skip" },
+                        "job": id,
+                        "file_num": file_num,
+                        "path": to,
+                    }));
+                    self.session.set_confirm_override_file(
+                        id,
+                        file_num,
+                        self.opts.overwrite,
+                        true,
+                        is_upload,
+                    );
+                }
+                Event::Done(job, file_num) if job == id => {
+                    if last_file.map_or(true, |n| file_num >= n) {
+                        emit(json!({"event": "done", "job": id, "file_num": file_num}));
+                        return Ok(());
+                    }
+                }
+                Event::Error(job, err, file_num) if job == id => {
+                    emit(json!({
+                        "event": "error",
+                        "job": id,
+                        "file_num": file_num,
+                        "message": err,
+                    }));
+                    return Err(EXIT_JOB_FAILED);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for a remote directory listing, `None` accepts a listing of any job.
+    fn wait_folder(&self, id: Option<i32>) -> Result<(Vec<FileEntry>, String), i32> {
+        loop {
+            match self.next(JOB_IDLE_TIMEOUT)? {
+                Event::Folder(job, entries, path, false) if id.map_or(true, |id| id == job) => {
+                    return Ok((entries, path));
+                }
+                Event::Error(job, err, _) if id.map_or(true, |id| id == job) => {
+                    emit(json!({"event": "error", "job": id, "message": err}));
+                    return Err(EXIT_JOB_FAILED);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn run(&self) -> Result<(), i32> {
+        self.wait_connected()?;
+        let session = &self.session;
+        let hidden = self.opts.include_hidden;
+        let id = 1;
+        match self.opts.command.clone() {
+            Command::List(path) => {
+                session.read_remote_dir(path, hidden);
+                let (entries, path) = self.wait_folder(None)?;
+                emit(json!({
+                    "event": "list",
+                    "path": path,
+                    "entries": entries.iter().map(entry_to_json).collect::<Vec<_>>(),
+                }));
+                Ok(())
+            }
+            Command::Upload { local, remote_dir } => {
+                let to = format!(
+                    "{}{}{}",
+                    remote_dir.trim_end_matches(|c| c == '/' || c == '\\'),
+                    session.get_path_sep(true),
+                    file_name(&local)
+                );
+                session.send_files(id, 0, local, to, 0, hidden, false);
+                self.wait_job(id, None)
+            }
+            Command::Download { remote, local_dir } => {
+                let to = std::path::Path::new(&local_dir).join(file_name(&remote));
+                let to = to.to_string_lossy().to_string();
+                session.send_files(id, 0, remote, to, 0, hidden, true);
+                self.wait_job(id, None)
+            }
+            Command::Remove {
+                path,
+                recursive: false,
+            } => {
+                session.remove_file(id, path, 0, true);
+                self.wait_job(id, None)
+            }
+            Command::Remove {
+                path,
+                recursive: true,
+            } => {
+                session.remove_dir_all(id, path.clone(), true, true);
+                let (entries, _) = self.wait_folder(Some(id))?;
+                if !entries.is_empty() {
+                    // The io_loop walks the remaining files itself once confirmations are off.
+                    session.send(crate::client::Data::SetNoConfirm(id));
+                    let sep = session.get_path_sep(true);
+                    session.remove_file(id, format!("{}{}{}", path, sep, entries[0].name), 0, true);
+                    self.wait_job(id, Some(entries.len() as i32 - 1))?;
+                }
+                session.remove_dir(id, path, true);
+                self.wait_job(id, None)
+            }
+            Command::Rename { path, new_name } => {
+                session.rename_file(id, path, new_name, true);
+                self.wait_job(id, None)
+            }
+            Command::Mkdir(path) => {
+                session.create_dir(id, path, true);
+                self.wait_job(id, None)
+            }
+        }
+    }
+}
+
+/// Entry of `rustdesk --cli`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let opts = match Options::parse(args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let (tx, rx) = channel();
+    let session: Session<CliHandler> = Session {
+        password: opts.password.clone(),
+        server_keyboard_enabled: Arc::new(RwLock::new(true)),
+        server_file_transfer_enabled: Arc::new(RwLock::new(true)),
+        server_clipboard_enabled: Arc::new(RwLock::new(true)),
+        reconnect_count: Arc::new(AtomicUsize::new(0)),
+        ..Default::default()
+    };
+    *session.ui_handler.events.lock().unwrap() = Some(tx.clone());
+    session.lc.write().unwrap().initialize(
+        opts.id.clone(),
+        ConnType::FILE_TRANSFER,
+        None,
+        opts.force_relay,
+        None,
+        None,
+        None,
+    );
+    let cloned = session.clone();
+    std::thread::spawn(move || {
+        crate::ui_session_interface::io_loop(cloned, 0);
+        tx.send(Event::Closed).ok();
+    });
+    let driver = Driver {
+        session,
+        events: rx,
+        opts,
+    };
+    let code = match driver.run() {
+        Ok(()) => EXIT_OK,
+        Err(code) => code,
+    };
+    driver.session.close();
+    // Give the io_loop a moment to deliver the close reason to the peer.
+    std::thread::sleep(Duration::from_millis(300));
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|x| x.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let opts = Options::parse(&args(
+            "123456789 --password pw --overwrite upload a.txt /tmp",
+        ))
+        .unwrap();
+        assert_eq!(opts.id, "123456789");
+        assert_eq!(opts.password, "pw");
+        assert!(opts.overwrite);
+        assert_eq!(
+            opts.command,
+            Command::Upload {
+                local: "a.txt".to_owned(),
+                remote_dir: "/tmp".to_owned()
+            }
+        );
+        let opts = Options::parse(&args("123 rm -r /tmp/x")).unwrap();
+        assert_eq!(
+            opts.command,
+            Command::Remove {
+                path: "/tmp/x".to_owned(),
+                recursive: true
+            }
+        );
+        assert!(Options::parse(&args("123")).is_err());
+        assert!(Options::parse(&args("123 upload a.txt")).is_err());
+        assert!(Options::parse(&args("123 --bogus ls /")).is_err());
+        let path = std::env::temp_dir().join(format!("rustdesk-pw-{}", std::process::id()));
+        std::fs::write(&path, "secret\r\nrest\n").unwrap();
+        let opts = Options::parse(&args(&format!(
+            "123 --password-file {} ls /",
+            path.display()
+        )))
+        .unwrap();
+        assert_eq!(opts.password, "secret");
+        std::fs::remove_file(&path).ok();
+        assert!(Options::parse(&args("123 --password-file /nonexistent/pw ls /")).is_err());
+        assert_eq!(file_name("C:\\a\\b.txt"), "b.txt");
+        assert_eq!(file_name("/a/dir/"), "dir");
+    }
+}
@@ -0,0 +1,189 @@
+//! Headless recording player for `rustdesk --play-recording`.
+//!
+//! Decodes a `.webm` session recording with the same decoders as a live session,
+//! to check its integrity, print its metadata or export frames as PNG. Nothing
+//! is shown on screen: without `--export-png` the frames are decoded as fast as
+//! possible and only checked. Output is one JSON object per line like
+//! [`super::file_transfer`].
+
+use scrap::playback::{find_events, Player};
+use serde_json::json;
+use std::{fs::File, path::PathBuf};
+
+const EXIT_OK: i32 = 0;
+const EXIT_USAGE: i32 = 1;
+const EXIT_FAILED: i32 = 4;
+
+const USAGE: &str = r#"Usage: rustdesk --play-recording <file> [options]
+
+Options:
+  --info                  Print the metadata and exit
+  --events                Print the session events, e.g. logins and file transfers
+  --find <text>           Print the events matching the text and start at the first
+  --seek <secs>           Start at the given position
+  --to <secs>             Stop at the given position
+  --export-png <dir>      Write frames as PNG instead of only decoding them
+  --interval <secs>       Time between exported frames (default: every frame)
+
+Without --info, --events or --export-png, every frame is decoded to check the
+recording, printing the position once per recorded second.
+
+Each line written to stdout is a JSON object with an "event" field.
+Exit codes: 0 ok, 1 usage, 4 failed."#;
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    file: String,
+    info: bool,
+    events: bool,
+    find: Option<String>,
+    seek: Option<f64>,
+    to: Option<f64>,
+    export_png: Option<String>,
+    interval: Option<f64>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut opts = Options::default();
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--info" => opts.info = true,
+                "--events" => opts.events = true,
+                "--find" => match it.next() {
+                    Some(text) => opts.find = Some(text.clone()),
+                    None => return Err("--find requires a text".to_owned()),
+                },
+                "--seek" => opts.seek = Some(number(&mut it, arg)?),
+                "--to" => opts.to = Some(number(&mut it, arg)?),
+                "--interval" => opts.interval = Some(number(&mut it, arg)?),
+                "--export-png" => match it.next() {
+                    Some(dir) => opts.export_png = Some(dir.clone()),
+                    None => return Err("--export-png requires a directory".to_owned()),
+                },
+                x if x.starts_with("--") => return Err(format!("Unknown option {}", x)),
+                x if opts.file.is_empty() => opts.file = x.to_owned(),
+                x => return Err(format!("Unexpected argument {}", x)),
+            }
+        }
+        if opts.file.is_empty() {
+            return Err("Missing recording file".to_owned());
+        }
+        Ok(opts)
+    }
+}
+
+fn number(it: &mut std::slice::Iter<String>, name: &str) -> Result<f64, String> {
+    it.next()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v >= 0.0)
+        .ok_or_else(|| format!("{} requires a non-negative number", name))
+}
+
+fn emit(value: serde_json::Value) {
+    println!("{}", value);
+}
+
+fn play(opts: &Options) -> Result<(), String> {
+    let mut player = Player::open(&opts.file).map_err(|e| e.to_string())?;
+    emit(json!({"event": "info", "info": player.info()}));
+    if opts.events {
+        for e in player.events() {
+            emit(json!({"event": "record_event", "record_event": e}));
+        }
+    }
+    let mut seek = opts.seek.map(|x| (x * 1000.0) as i64);
+    if let Some(text) = &opts.find {
+        let found = find_events(player.events(), text);
+        for e in found.iter() {
+            emit(json!({"event": "match", "record_event": e}));
+        }
+        match found.first() {
+            Some(e) => seek = seek.or(Some(e.ts)),
+            None => return Err(format!("No event matches {}", text)),
+        }
+    }
+    if opts.info || (opts.events && opts.find.is_none()) {
+        return Ok(());
+    }
+    if let Some(seek) = seek {
+        player.seek(seek).map_err(|e| e.to_string())?;
+    }
+    let to = opts.to.map(|x| (x * 1000.0) as i64);
+    match &opts.export_png {
+        Some(dir) => export_png(&mut player, dir, seek.is_some(), to, opts.interval),
+        None => check(&mut player, to),
+    }
+}
+
+/// Decodes the frames up to `to` without waiting between them.
+fn check(player: &mut Player, to: Option<i64>) -> Result<(), String> {
+    let mut reported = -1;
+    let mut frames = 0;
+    while let Some(pos) = player.next_frame().map_err(|e| e.to_string())? {
+        if to.map(|to| pos > to).unwrap_or(false) {
+            break;
+        }
+        frames += 1;
+        if pos / 1000 != reported {
+            reported = pos / 1000;
+            emit(json!({"event": "position", "ms": pos, "frames": frames}));
+        }
+    }
+    emit(json!({"event": "done", "ms": player.position(), "frames": frames}));
+    Ok(())
+}
+
+fn export_png(
+    player: &mut Player,
+    dir: &str,
+    seeked: bool,
+    to: Option<i64>,
+    interval: Option<f64>,
+) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let interval = interval.map(|x| (x * 1000.0) as i64).unwrap_or_default();
+    let mut next_due = player.position();
+    let mut exported = 0;
+    // A seek already decoded the first frame to export.
+    let mut pos = if seeked {
+        Some(player.position())
+    } else {
+        player.next_frame().map_err(|e| e.to_string())?
+    };
+    while let Some(ms) = pos {
+        if to.map(|to| ms > to).unwrap_or(false) {
+            break;
+        }
+        if ms >= next_due {
+            let path = PathBuf::from(dir).join(format!("frame_{:09}.png", ms));
+            let (w, h, rgba) = player.rgba();
+            let file = File::create(&path).map_err(|e| e.to_string())?;
+            repng::encode(file, w as _, h as _, &rgba).map_err(|e| e.to_string())?;
+            emit(json!({"event": "frame", "ms": ms, "path": path.to_string_lossy()}));
+            exported += 1;
+            next_due = ms + interval;
+        }
+        pos = player.next_frame().map_err(|e| e.to_string())?;
+    }
+    emit(json!({"event": "done", "exported": exported}));
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> i32 {
+    let opts = match Options::parse(args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    match play(&opts) {
+        Ok(()) => EXIT_OK,
+        Err(err) => {
+            emit(json!({"event": "error", "message": err}));
+            EXIT_FAILED
+        }
+    }
+}
@@ -75,7 +75,7 @@ use hbb_common::{
 pub use helper::*;
 use scrap::{
     codec::Decoder,
-    record::{Recorder, RecorderContext},
+    record::{RecordAudioFormat, Recorder, RecorderContext},
     CodecFormat, ImageFormat, ImageRgb, ImageTexture,
 };
 
@@ -91,6 +91,8 @@ pub use super::lang::*;
 
 pub mod file_trait;
 pub mod helper;
+pub mod host_key;
+pub mod input_macro;
 pub mod io_loop;
 pub mod screenshot;
 
@@ -251,33 +253,47 @@ impl Client {
             bail!("Incoming only mode");
         }
         // to-do: remember the port for each peer, so that we can retry easier
-        if hbb_common::is_ip_str(peer) {
-            return Ok((
-                (
-                    connect_tcp_local(check_port(peer, RELAY_PORT + 1), None, CONNECT_TIMEOUT)
-                        .await?,
-                    true,
-                    None,
+        let direct_addr = if hbb_common::is_ip_str(peer) {
+            Some(check_port(peer, RELAY_PORT + 1))
+        } else if hbb_common::is_domain_port_str(peer) {
+            // Allow connect to {domain}:{port}
+            Some(peer.to_owned())
+        } else {
+            None
+        };
+        if let Some(addr) = direct_addr {
+            let transport = interface
+                .get_lch()
+                .read()
+                .unwrap()
+                .get_option(crate::quic_stream::OPTION_TRANSPORT);
+            let mut quic = None;
+            if transport == "quic" {
+                match crate::quic_stream::QuicStream::connect(&addr, CONNECT_TIMEOUT).await {
+                    Ok(x) => quic = Some(x),
+                    Err(err) => log::warn!("QUIC to {} failed, fall back to TCP: {}", addr, err),
+                }
+            }
+            let (quic, mut conn, typ) = match quic {
+                Some((quic, conn)) => (Some(quic), conn, crate::quic_stream::TYPE),
+                None => (
                     None,
+                    connect_tcp_local(addr, None, CONNECT_TIMEOUT).await?,
                     "TCP",
                 ),
-                (0, "".to_owned()),
-                false,
-            ));
+            };
+            let pk = if crate::is_no_rendezvous() {
+                Some(
+                    host_key::secure_connection(interface.get_lch(), &mut conn, quic.as_ref())
+                        .await?,
+                )
+            } else {
+                None
+            };
+            return Ok(((conn, true, pk, None, typ), (0, "".to_owned()), false));
         }
-        // Allow connect to {domain}:{port}
-        if hbb_common::is_domain_port_str(peer) {
-            return Ok((
-                (
-                    connect_tcp_local(peer, None, CONNECT_TIMEOUT).await?,
-                    true,
-                    None,
-                    None,
-                    "TCP",
-                ),
-                (0, "".to_owned()),
-                false,
-            ));
+        if crate::is_no_rendezvous() {
+            bail!("No rendezvous server, please connect by ip");
         }
 
         let other_server = interface.get_lch().read().unwrap().other_server.clone();
@@ -1540,6 +1556,7 @@ pub struct VideoHandler {
     pub texture: ImageTexture,
     recorder: Arc<Mutex<Option<Recorder>>>,
     record: bool,
+    record_audio: Option<RecordAudioFormat>,
     _display: usize, // useful for debug
     fail_counter: usize,
     first_frame: bool,
@@ -1572,6 +1589,7 @@ impl VideoHandler {
             texture: Default::default(),
             recorder: Default::default(),
             record: false,
+            record_audio: None,
             _display,
             fail_counter: 0,
             first_frame: true,
@@ -1660,12 +1678,44 @@ impl VideoHandler {
                 tx: None,
             })
             .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
+            if let Some(format) = self.record_audio {
+                self.recorder
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .map(|r| r.set_audio_format(format));
+            }
         } else {
             self.recorder = Default::default();
         }
 
         self.record = start;
     }
+
+    /// Set the format of the audio frames passed to [`Self::record_audio`].
+    pub fn set_record_audio_format(&mut self, f: &AudioFormat) {
+        let format = RecordAudioFormat {
+            sample_rate: f.sample_rate,
+            channels: f.channels as _,
+        };
+        self.record_audio = Some(format);
+        self.recorder
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|r| r.set_audio_format(format));
+    }
+
+    /// Add the peer audio to the screen record.
+    pub fn record_audio(&mut self, frame: &AudioFrame) {
+        if self.record {
+            self.recorder
+                .lock()
+                .unwrap()
+                .as_mut()
+                .map(|r| r.write_audio(&frame.data));
+        }
+    }
 }
 
 // The source of sent password
@@ -2797,6 +2847,7 @@ pub fn start_video_thread<F, T>(
         sync_cpu_usage();
         get_hwcodec_config();
         let mut video_handler = None;
+        let mut audio_format = None;
         let mut count = 0;
         let mut duration = std::time::Duration::ZERO;
         let mut skip_beginning = 0;
@@ -2829,6 +2880,9 @@ pub fn start_video_thread<F, T>(
                         let format = CodecFormat::from(&vf);
                         if video_handler.is_none() {
                             let mut handler = VideoHandler::new(format, display);
+                            if let Some(f) = audio_format.as_ref() {
+                                handler.set_record_audio_format(f);
+                            }
                             let record_state = session.lc.read().unwrap().record_state;
                             let record_permission = session.lc.read().unwrap().record_permission;
                             let id = session.lc.read().unwrap().id.clone();
@@ -2917,6 +2971,18 @@ pub fn start_video_thread<F, T>(
                             handler.record_screen(start, id, display, is_view_camera);
                         }
                     }
+                    // Copies of the audio for the screen record, the audio thread plays it.
+                    MediaData::AudioFormat(f) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.set_record_audio_format(&f);
+                        }
+                        audio_format = Some(f);
+                    }
+                    MediaData::AudioFrame(af) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.record_audio(&af);
+                        }
+                    }
                     _ => {}
                 }
             } else {
@@ -3448,10 +3514,22 @@ pub async fn handle_hash(
         return;
     }
 
+    // The id sent as the username of the login request
+    let id = {
+        let lc = lc.read().unwrap();
+        match lc.other_server.as_ref() {
+            Some((id, _, _)) => id.clone(),
+            None => lc.id.clone(),
+        }
+    };
     let password = if password.is_empty() {
-        // login without password, the remote side can click accept
-        interface.msgbox("input-password", "Password Required", "", "");
-        Vec::new()
+        if let Some(signed) = crate::pubkey_auth::sign(&id, &hash.challenge) {
+            signed
+        } else {
+            // login without password, the remote side can click accept
+            interface.msgbox("input-password", "Password Required", "", "");
+            Vec::new()
+        }
     } else {
         let mut hasher = Sha256::new();
         hasher.update(&password);
@@ -3956,6 +4034,9 @@ pub mod peer_online {
     }
 
     async fn create_online_stream() -> ResultType<Stream> {
+        if crate::is_no_rendezvous() {
+            bail!("No rendezvous server");
+        }
         let (rendezvous_server, _servers, _contained) =
             crate::get_rendezvous_server(READ_TIMEOUT).await;
         let tmp: Vec<&str> = rendezvous_server.split(":").collect();
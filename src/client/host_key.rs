@@ -0,0 +1,193 @@
+//! Pinned host keys for the serverless mode, see [`crate::is_no_rendezvous`].
+//!
+//! Without a rendezvous server to sign the key of a peer, the host appends its
+//! Ed25519 public key to the box key of its `SignedId`, signed with itself.
+//! Like `known_hosts` of SSH, the key is trusted on first use and pinned in
+//! the `host-key` option of the peer, a changed key fails the connection until
+//! the pin is removed with [`forget`].
+
+use super::LoginConfigHandler;
+use crate::{common::create_symmetric_key_msg, quic_stream::QuicStream};
+use hbb_common::{
+    bail,
+    config::{PeerConfig, READ_TIMEOUT},
+    log,
+    message_proto::*,
+    protobuf::Message as _,
+    rendezvous_proto::IdPk,
+    sha2::{Digest, Sha256},
+    sodiumoxide::{
+        base64,
+        crypto::{box_, sign},
+    },
+    timeout, ResultType, Stream,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+pub const OPTION_HOST_KEY: &str = "host-key";
+
+pub const MSG_NO_HOST_KEY: &str =
+    "The host does not announce its host key, please enable no-rendezvous mode on it";
+pub const MSG_HOST_KEY_CHANGED: &str = "The host key has changed";
+
+/// `SHA256:...` like `ssh-keygen -l`.
+pub fn fingerprint(pk: &sign::PublicKey) -> String {
+    let blob = crate::pubkey_auth::ssh_key_blob(&pk.0);
+    format!(
+        "SHA256:{}",
+        base64::encode(Sha256::digest(&blob), base64::Variant::OriginalNoPadding)
+    )
+}
+
+/// Verifies the self-signed `SignedId`, returns the id, the host key and the
+/// box key of the host.
+fn open(signed: &[u8]) -> ResultType<(String, sign::PublicKey, [u8; box_::PUBLICKEYBYTES])> {
+    if signed.len() < sign::SIGNATUREBYTES {
+        bail!(MSG_NO_HOST_KEY);
+    }
+    let id_pk = IdPk::parse_from_bytes(&signed[sign::SIGNATUREBYTES..])?;
+    if id_pk.pk.len() != box_::PUBLICKEYBYTES + sign::PUBLICKEYBYTES {
+        bail!(MSG_NO_HOST_KEY);
+    }
+    let (box_pk, host_pk) = id_pk.pk.split_at(box_::PUBLICKEYBYTES);
+    let Some(host_pk) = sign::PublicKey::from_slice(host_pk) else {
+        bail!(MSG_NO_HOST_KEY);
+    };
+    if sign::verify(signed, &host_pk).is_err() {
+        bail!("Handshake failed: invalid signature of the host key");
+    }
+    let mut pk = [0u8; box_::PUBLICKEYBYTES];
+    pk.copy_from_slice(box_pk);
+    Ok((id_pk.id, host_pk, pk))
+}
+
+/// Checks `pk` against the pin in `options`, returns true if it was pinned
+/// now.
+fn check(
+    id: &str,
+    options: &mut HashMap<String, String>,
+    pk: &sign::PublicKey,
+) -> ResultType<bool> {
+    let known = options
+        .get(OPTION_HOST_KEY)
+        .map(|s| s.trim())
+        .unwrap_or_default();
+    if known.is_empty() {
+        options.insert(
+            OPTION_HOST_KEY.to_owned(),
+            crate::pubkey_auth::format_public_key(pk, id),
+        );
+        return Ok(true);
+    }
+    let known = match crate::pubkey_auth::parse_public_key(known) {
+        Ok(known) => known,
+        Err(err) => bail!("Invalid pinned host key of {}: {}", id, err),
+    };
+    if known != *pk {
+        bail!(
+            "{} for {}, expected {}, got {}",
+            MSG_HOST_KEY_CHANGED,
+            id,
+            fingerprint(&known),
+            fingerprint(pk)
+        );
+    }
+    Ok(false)
+}
+
+/// The handshake of [`super::Client::secure_connection`] with the host key
+/// pinned instead of signed by the rendezvous server, returns the host key.
+pub async fn secure_connection(
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    conn: &mut Stream,
+    quic: Option<&QuicStream>,
+) -> ResultType<Vec<u8>> {
+    let bytes = match timeout(READ_TIMEOUT, conn.next()).await? {
+        Some(res) => res?,
+        None => bail!("Reset by the peer"),
+    };
+    let Ok(msg_in) = Message::parse_from_bytes(&bytes) else {
+        bail!("Handshake failed: invalid message format");
+    };
+    let Some(message::Union::SignedId(si)) = msg_in.union else {
+        bail!(MSG_NO_HOST_KEY);
+    };
+    let (host_id, host_pk, their_pk_b) = open(&si.id)?;
+    {
+        let mut lc = lc.write().unwrap();
+        let id = lc.id.clone();
+        let mut config = lc.load_config();
+        if check(&id, &mut config.options, &host_pk)? {
+            log::info!(
+                "Pinned host key of {} ({}): {}",
+                id,
+                host_id,
+                fingerprint(&host_pk)
+            );
+            lc.save_config(config);
+        }
+    }
+    let (asymmetric_value, symmetric_value, key) = create_symmetric_key_msg(their_pk_b);
+    let mut msg_out = Message::new();
+    msg_out.set_public_key(PublicKey {
+        asymmetric_value,
+        symmetric_value,
+        ..Default::default()
+    });
+    timeout(READ_TIMEOUT, conn.send(&msg_out)).await??;
+    match quic {
+        Some(quic) => quic.set_key(key),
+        None => conn.set_key(key),
+    }
+    Ok(host_pk.0.to_vec())
+}
+
+/// Removes the pin, the next connection trusts whatever key the host has.
+pub fn forget(id: &str) -> ResultType<()> {
+    if !PeerConfig::exists(id) {
+        bail!("Unknown peer {}", id);
+    }
+    let mut config = PeerConfig::load(id);
+    if config.options.remove(OPTION_HOST_KEY).is_some() {
+        config.store(id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_key() {
+        let (pk, sk) = sign::gen_keypair();
+        let (box_pk, _) = box_::gen_keypair();
+        let mut both = box_pk.0.to_vec();
+        both.extend_from_slice(&pk.0);
+        let id_pk = IdPk {
+            id: "123".to_owned(),
+            pk: both.into(),
+            ..Default::default()
+        };
+        let signed = sign::sign(&id_pk.write_to_bytes().unwrap(), &sk);
+        let (id, host_pk, their_pk) = open(&signed).unwrap();
+        assert_eq!((id.as_str(), host_pk, their_pk), ("123", pk, box_pk.0));
+        let mut forged = signed.clone();
+        forged[0] ^= 1;
+        assert!(open(&forged).is_err());
+
+        let mut options = HashMap::new();
+        assert!(check("1.2.3.4", &mut options, &pk).unwrap());
+        assert!(!check("1.2.3.4", &mut options, &pk).unwrap());
+        let (other, _) = sign::gen_keypair();
+        let err = check("1.2.3.4", &mut options, &other)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(MSG_HOST_KEY_CHANGED));
+        assert!(err.contains(&fingerprint(&pk)));
+        assert!(fingerprint(&pk).starts_with("SHA256:"));
+    }
+}
@@ -0,0 +1,568 @@
+//! Recording and replay of input macros.
+//!
+//! While recording, the key and mouse events sent by a session are kept with
+//! the time since the previous one. A macro is saved by name either for the
+//! peer, in its `input-macros` option, or in the shared library file, see
+//! [`OPTION_INPUT_MACRO_LIBRARY`], and can be replayed against any connected
+//! peer, faster or slower, with `${name}` variables substituted in the typed
+//! text. Keys and buttons still held when a replay ends early are released.
+
+use super::{Data, LoginConfigHandler};
+use crate::input::{MOUSE_TYPE_DOWN, MOUSE_TYPE_UP};
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    config::{Config, LocalConfig},
+    log,
+    message_proto::*,
+    protobuf::EnumOrUnknown,
+    tokio::sync::mpsc::UnboundedSender,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Option of the peer, a JSON array of [`Macro`].
+pub const OPTION_INPUT_MACROS: &str = "input-macros";
+/// Path of the shared library, `input_macros.json` in the config directory by
+/// default. It may be on a network share to be used by a whole team.
+pub const OPTION_INPUT_MACRO_LIBRARY: &str = "input-macro-library";
+
+const MIN_SPEED: f64 = 0.1;
+const MAX_SPEED: f64 = 10.;
+// Granularity of stopping a playback.
+const STOP_CHECK: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Mouse {
+        mask: i32,
+        x: i32,
+        y: i32,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<i32>,
+    },
+    Key {
+        #[serde(default)]
+        down: bool,
+        #[serde(default)]
+        press: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        control_key: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chr: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        unicode: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        win2win_hotkey: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<i32>,
+        #[serde(default)]
+        mode: i32,
+    },
+    /// Typed text, `${name}` is replaced by the variable `name`.
+    Text {
+        text: String,
+        #[serde(default)]
+        mode: i32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Milliseconds after the previous step.
+    #[serde(default)]
+    pub delay: u64,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+fn modifiers_of(modifiers: &[EnumOrUnknown<ControlKey>]) -> Vec<i32> {
+    modifiers.iter().map(|m| m.value()).collect()
+}
+
+fn modifiers_to(modifiers: &[i32]) -> Vec<EnumOrUnknown<ControlKey>> {
+    modifiers
+        .iter()
+        .map(|m| EnumOrUnknown::from_i32(*m))
+        .collect()
+}
+
+impl Action {
+    fn from_message(msg: &Message) -> Option<Self> {
+        match &msg.union {
+            Some(message::Union::MouseEvent(evt)) => Some(Action::Mouse {
+                mask: evt.mask,
+                x: evt.x,
+                y: evt.y,
+                modifiers: modifiers_of(&evt.modifiers),
+            }),
+            Some(message::Union::KeyEvent(evt)) => {
+                let mode = evt.mode.value();
+                let mut action = Action::Key {
+                    down: evt.down,
+                    press: evt.press,
+                    control_key: None,
+                    chr: None,
+                    unicode: None,
+                    win2win_hotkey: None,
+                    modifiers: modifiers_of(&evt.modifiers),
+                    mode,
+                };
+                if let Action::Key {
+                    control_key,
+                    chr,
+                    unicode,
+                    win2win_hotkey,
+                    ..
+                } = &mut action
+                {
+                    match &evt.union {
+                        Some(key_event::Union::ControlKey(v)) => *control_key = Some(v.value()),
+                        Some(key_event::Union::Chr(v)) => *chr = Some(*v),
+                        Some(key_event::Union::Unicode(v)) => *unicode = Some(*v),
+                        Some(key_event::Union::Win2winHotkey(v)) => *win2win_hotkey = Some(*v),
+                        Some(key_event::Union::Seq(text)) => {
+                            return Some(Action::Text {
+                                text: text.clone(),
+                                mode,
+                            })
+                        }
+                        _ => return None,
+                    }
+                }
+                Some(action)
+            }
+            _ => None,
+        }
+    }
+
+    fn to_message(&self, vars: &HashMap<String, String>) -> ResultType<Message> {
+        let mut msg = Message::new();
+        match self {
+            Action::Mouse {
+                mask,
+                x,
+                y,
+                modifiers,
+            } => {
+                msg.set_mouse_event(MouseEvent {
+                    mask: *mask,
+                    x: *x,
+                    y: *y,
+                    modifiers: modifiers_to(modifiers),
+                    ..Default::default()
+                });
+            }
+            Action::Key {
+                down,
+                press,
+                control_key,
+                chr,
+                unicode,
+                win2win_hotkey,
+                modifiers,
+                mode,
+            } => {
+                let mut evt = KeyEvent {
+                    down: *down,
+                    press: *press,
+                    modifiers: modifiers_to(modifiers),
+                    mode: EnumOrUnknown::from_i32(*mode),
+                    ..Default::default()
+                };
+                evt.union = if let Some(v) = control_key {
+                    Some(key_event::Union::ControlKey(EnumOrUnknown::from_i32(*v)))
+                } else if let Some(v) = chr {
+                    Some(key_event::Union::Chr(*v))
+                } else if let Some(v) = unicode {
+                    Some(key_event::Union::Unicode(*v))
+                } else if let Some(v) = win2win_hotkey {
+                    Some(key_event::Union::Win2winHotkey(*v))
+                } else {
+                    bail!("Key step without a key");
+                };
+                msg.set_key_event(evt);
+            }
+            Action::Text { text, mode } => {
+                let mut evt = KeyEvent {
+                    press: true,
+                    mode: EnumOrUnknown::from_i32(*mode),
+                    ..Default::default()
+                };
+                evt.set_seq(substitute(text, vars)?);
+                msg.set_key_event(evt);
+            }
+        }
+        Ok(msg)
+    }
+}
+
+/// Replaces `${name}` in `text`, an unknown variable is an error.
+fn substitute(text: &str, vars: &HashMap<String, String>) -> ResultType<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed variable in {}", text);
+        };
+        let name = &rest[start + 2..start + end];
+        match vars.get(name) {
+            Some(v) => out.push_str(v),
+            None => bail!("Unknown variable {}", name),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The input events of a session while recording.
+pub struct Recorder {
+    last: Instant,
+    steps: Vec<Step>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, msg: &Message) {
+        if let Some(action) = Action::from_message(msg) {
+            let now = Instant::now();
+            self.steps.push(Step {
+                delay: now.duration_since(self.last).as_millis() as _,
+                action,
+            });
+            self.last = now;
+        }
+    }
+
+    pub fn finish(self, name: String) -> Macro {
+        Macro {
+            name,
+            steps: self.steps,
+        }
+    }
+}
+
+fn library_path() -> PathBuf {
+    let path = LocalConfig::get_option(OPTION_INPUT_MACRO_LIBRARY);
+    if path.is_empty() {
+        Config::path("input_macros.json")
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+fn parse_list(s: &str) -> Vec<Macro> {
+    if s.is_empty() {
+        return Vec::new();
+    }
+    serde_json::from_str(s).unwrap_or_else(|err| {
+        log::error!("Invalid input macros: {}", err);
+        Vec::new()
+    })
+}
+
+/// Macros of the peer, or of the shared library if `shared`.
+pub fn load(lc: &LoginConfigHandler, shared: bool) -> Vec<Macro> {
+    if shared {
+        parse_list(&std::fs::read_to_string(library_path()).unwrap_or_default())
+    } else {
+        parse_list(&lc.get_option(OPTION_INPUT_MACROS))
+    }
+}
+
+fn store(lc: &mut LoginConfigHandler, macros: &[Macro], shared: bool) -> ResultType<()> {
+    let s = serde_json::to_string(macros)?;
+    if shared {
+        let path = library_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, s)?;
+    } else {
+        let mut config = lc.load_config();
+        config.options.insert(OPTION_INPUT_MACROS.to_owned(), s);
+        lc.save_config(config);
+    }
+    Ok(())
+}
+
+/// Saves `m`, replacing the macro of the same name.
+pub fn save(lc: &mut LoginConfigHandler, m: Macro, shared: bool) -> ResultType<()> {
+    if m.name.trim().is_empty() {
+        bail!("Empty macro name");
+    }
+    let mut macros = load(lc, shared);
+    macros.retain(|x| x.name != m.name);
+    macros.push(m);
+    store(lc, &macros, shared)
+}
+
+pub fn remove(lc: &mut LoginConfigHandler, name: &str, shared: bool) -> ResultType<()> {
+    let mut macros = load(lc, shared);
+    let n = macros.len();
+    macros.retain(|x| x.name != name);
+    if macros.len() == n {
+        bail!("No macro named {}", name);
+    }
+    store(lc, &macros, shared)
+}
+
+/// The macro of the peer named `name`, else the shared one.
+pub fn find(lc: &LoginConfigHandler, name: &str) -> ResultType<Macro> {
+    load(lc, false)
+        .into_iter()
+        .chain(load(lc, true))
+        .find(|m| m.name == name)
+        .ok_or_else(|| anyhow!("No macro named {}", name))
+}
+
+/// Keys and mouse buttons a replay has pressed and not released yet.
+#[derive(Default)]
+struct Pressed {
+    keys: Vec<KeyEvent>,
+    buttons: i32,
+    x: i32,
+    y: i32,
+}
+
+impl Pressed {
+    fn track(&mut self, msg: &Message) {
+        match &msg.union {
+            Some(message::Union::MouseEvent(evt)) => {
+                let buttons = evt.mask >> 3;
+                match evt.mask & 0x7 {
+                    MOUSE_TYPE_DOWN => self.buttons |= buttons,
+                    MOUSE_TYPE_UP => self.buttons &= !buttons,
+                    _ => {}
+                }
+                self.x = evt.x;
+                self.y = evt.y;
+            }
+            Some(message::Union::KeyEvent(evt)) if !evt.press => {
+                self.keys
+                    .retain(|k| k.union != evt.union || k.mode != evt.mode);
+                if evt.down {
+                    self.keys.push(evt.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The up events of what is still held, the last pressed key first.
+    fn releases(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        for mut evt in self.keys.drain(..).rev() {
+            evt.down = false;
+            let mut msg = Message::new();
+            msg.set_key_event(evt);
+            msgs.push(msg);
+        }
+        if self.buttons != 0 {
+            let mut msg = Message::new();
+            msg.set_mouse_event(MouseEvent {
+                mask: (self.buttons << 3) | MOUSE_TYPE_UP,
+                x: self.x,
+                y: self.y,
+                ..Default::default()
+            });
+            msgs.push(msg);
+            self.buttons = 0;
+        }
+        msgs
+    }
+}
+
+/// A running replay, stopped when dropped or by [`Playback::stop`].
+pub struct Playback {
+    stop: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Playback {
+    /// Stops the replay and waits until what it holds is released.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().ok();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.stop.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Replays `m` to the session of `sender`, `speed` 2 being twice as fast.
+pub fn play(
+    m: &Macro,
+    speed: f64,
+    vars: &HashMap<String, String>,
+    sender: Arc<RwLock<Option<UnboundedSender<Data>>>>,
+) -> ResultType<Playback> {
+    let speed = if speed > 0. {
+        speed.clamp(MIN_SPEED, MAX_SPEED)
+    } else {
+        1.
+    };
+    // Fails before sending anything if a variable is missing.
+    let steps = m
+        .steps
+        .iter()
+        .map(|s| {
+            Ok((
+                Duration::from_millis(s.delay).div_f64(speed),
+                s.action.to_message(vars)?,
+            ))
+        })
+        .collect::<ResultType<Vec<_>>>()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let name = m.name.clone();
+    let thread = std::thread::spawn(move || {
+        log::info!("Play input macro {}, {} steps", name, steps.len());
+        let send = |msg| match sender.read().unwrap().as_ref() {
+            Some(sender) => sender.send(Data::Message(msg)).is_ok(),
+            None => false,
+        };
+        let mut pressed = Pressed::default();
+        for (delay, msg) in steps {
+            let deadline = Instant::now() + delay;
+            while !stopped.load(Ordering::SeqCst) && Instant::now() < deadline {
+                std::thread::sleep(STOP_CHECK.min(deadline - Instant::now()));
+            }
+            if stopped.load(Ordering::SeqCst) {
+                log::info!("Input macro {} stopped", name);
+                break;
+            }
+            pressed.track(&msg);
+            if !send(msg) {
+                log::info!("Input macro {} stopped, the session is closed", name);
+                break;
+            }
+        }
+        // A reconnected session gets the releases on its new sender.
+        for msg in pressed.releases() {
+            send(msg);
+        }
+        stopped.store(true, Ordering::SeqCst);
+    });
+    Ok(Playback {
+        stop,
+        thread: Mutex::new(Some(thread)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_macro() {
+        let mut recorder = Recorder::new();
+        let mut mouse = Message::new();
+        mouse.set_mouse_event(MouseEvent {
+            mask: 9,
+            x: 10,
+            y: 20,
+            modifiers: vec![ControlKey::Shift.into()],
+            ..Default::default()
+        });
+        let mut key = Message::new();
+        let mut evt = KeyEvent::new();
+        evt.down = true;
+        evt.set_control_key(ControlKey::Return);
+        key.set_key_event(evt);
+        let mut text = Message::new();
+        let mut evt = KeyEvent::new();
+        evt.press = true;
+        evt.set_seq("host ${host}".to_owned());
+        text.set_key_event(evt);
+        for msg in [&mouse, &key, &text, &Message::new()] {
+            recorder.record(msg);
+        }
+        let m = recorder.finish("setup".to_owned());
+        assert_eq!(m.steps.len(), 3);
+
+        let json = serde_json::to_string(&m).unwrap();
+        let m: Macro = serde_json::from_str(&json).unwrap();
+        let mut vars = HashMap::new();
+        assert!(m.steps[2].action.to_message(&vars).is_err());
+        vars.insert("host".to_owned(), "pc-1".to_owned());
+        assert_eq!(m.steps[0].action.to_message(&vars).unwrap(), mouse);
+        assert_eq!(m.steps[1].action.to_message(&vars).unwrap(), key);
+        let msg = m.steps[2].action.to_message(&vars).unwrap();
+        assert_eq!(msg.key_event().seq(), "host pc-1");
+        assert!(substitute("${host", &vars).is_err());
+    }
+
+    #[test]
+    fn test_release_pressed() {
+        let mut pressed = Pressed::default();
+        let mouse = |mask| {
+            let mut msg = Message::new();
+            msg.set_mouse_event(MouseEvent {
+                mask,
+                x: 5,
+                y: 6,
+                ..Default::default()
+            });
+            msg
+        };
+        let key = |down, key| {
+            let mut msg = Message::new();
+            let mut evt = KeyEvent::new();
+            evt.down = down;
+            evt.set_control_key(key);
+            msg.set_key_event(evt);
+            msg
+        };
+        pressed.track(&key(true, ControlKey::Control));
+        pressed.track(&key(true, ControlKey::Shift));
+        pressed.track(&key(false, ControlKey::Shift));
+        pressed.track(&mouse((0x01 << 3) | MOUSE_TYPE_DOWN));
+        pressed.track(&mouse((0x02 << 3) | MOUSE_TYPE_DOWN));
+        pressed.track(&mouse((0x02 << 3) | MOUSE_TYPE_UP));
+        let msgs = pressed.releases();
+        assert_eq!(
+            msgs,
+            vec![
+                key(false, ControlKey::Control),
+                mouse((0x01 << 3) | MOUSE_TYPE_UP)
+            ]
+        );
+        assert!(pressed.releases().is_empty());
+    }
+}
@@ -8,6 +8,9 @@ use crate::{
         QualityStatus, MILLI1, SEC30,
     },
     common::get_default_sound_input,
+    kcp_stream::KcpStream,
+    resumption::{self, Ticket},
+    traffic::{self, DownloadThrottle, TokenBucket},
     ui_session_interface::{InvokeUiSession, Session},
 };
 #[cfg(feature = "unix-file-copy-paste")]
@@ -20,8 +23,10 @@ use clipboard::ContextSend;
 use crossbeam_queue::ArrayQueue;
 #[cfg(not(target_os = "ios"))]
 use hbb_common::tokio::sync::mpsc::error::TryRecvError;
+#[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
+use hbb_common::tokio::sync::Mutex as TokioMutex;
 use hbb_common::{
-    allow_err,
+    allow_err, bail,
     config::{self, LocalConfig, PeerConfig, TransferSerde},
     fs::{
         self, can_enable_overwrite_detection, get_job, get_string, new_send_confirm,
@@ -37,10 +42,8 @@ use hbb_common::{
         sync::mpsc,
         time::{self, Duration, Instant},
     },
-    Stream,
+    ResultType, Stream,
 };
-#[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
-use hbb_common::{tokio::sync::Mutex as TokioMutex, ResultType};
 use scrap::CodecFormat;
 use std::{
     collections::HashMap,
@@ -71,13 +74,20 @@ pub struct Remote<T: InvokeUiSession> {
     #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
     client_conn_id: i32, // used for file clipboard
     data_count: Arc<AtomicUsize>,
+    download_throttle: DownloadThrottle,
+    upload_bucket: TokenBucket,
     video_format: CodecFormat,
     elevation_requested: bool,
     peer_info: ParsedPeerInfo,
     video_threads: HashMap<usize, VideoThread>,
     chroma: Arc<RwLock<Option<Chroma>>>,
     last_record_state: bool,
+    audio_format: Option<AudioFormat>,
     sent_close_reason: bool,
+    // The ticket to resume the session with, and the grace period
+    resume_ticket: Option<(Ticket, Duration)>,
+    // file job id -> blocks sent or received
+    file_blocks: HashMap<i32, u64>,
 }
 
 #[derive(Default)]
@@ -118,6 +128,8 @@ impl<T: InvokeUiSession> Remote<T> {
             #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
             client_conn_id: 0,
             data_count: Arc::new(AtomicUsize::new(0)),
+            download_throttle: Default::default(),
+            upload_bucket: TokenBucket::new(0),
             video_format: CodecFormat::Unknown,
             stop_voice_call_sender: None,
             voice_call_request_timestamp: None,
@@ -126,10 +138,27 @@ impl<T: InvokeUiSession> Remote<T> {
             video_threads: Default::default(),
             chroma: Default::default(),
             last_record_state: false,
+            audio_format: None,
             sent_close_reason: false,
+            resume_ticket: None,
+            file_blocks: Default::default(),
         }
     }
 
+    // The bandwidth caps of the peer and the global ones, whichever is lower.
+    fn refresh_bandwidth_caps(&mut self) {
+        let cap = |option: &str| {
+            traffic::min_cap(
+                traffic::parse_cap(&self.handler.lc.read().unwrap().get_option(option)),
+                traffic::parse_cap(&LocalConfig::get_option(option)),
+            )
+        };
+        let upload = cap(traffic::OPTION_UPLOAD_BANDWIDTH);
+        let download = cap(traffic::OPTION_DOWNLOAD_BANDWIDTH);
+        self.upload_bucket.set_rate(upload);
+        self.download_throttle.set_rate(download);
+    }
+
     pub async fn io_loop(&mut self, key: &str, token: &str, round: u32) {
         #[cfg(target_os = "windows")]
         let _file_clip_context_holder = {
@@ -174,14 +203,22 @@ impl<T: InvokeUiSession> Remote<T> {
         )
         .await
         {
-            Ok(((mut peer, direct, pk, kcp, stream_type), (feedback, rendezvous_server))) => {
+            Ok((
+                (mut peer, mut direct, pk, mut kcp, stream_type),
+                (feedback, rendezvous_server),
+            )) => {
                 self.handler
                     .connection_round_state
                     .lock()
                     .unwrap()
                     .set_connected();
+                *self.handler.traffic.lock().unwrap() = Default::default();
+                self.refresh_bandwidth_caps();
+                // QUIC applies the session key per channel, not on the stream.
+                let secured =
+                    peer.is_secured() || (stream_type == crate::quic_stream::TYPE && pk.is_some());
                 self.handler
-                    .set_connection_type(peer.is_secured(), direct, stream_type); // flutter -> connection_ready
+                    .set_connection_type(secured, direct, stream_type); // flutter -> connection_ready
                 self.handler.update_direct(Some(direct));
                 if conn_type == ConnType::DEFAULT_CONN || conn_type == ConnType::VIEW_CAMERA {
                     self.handler
@@ -222,22 +259,42 @@ impl<T: InvokeUiSession> Remote<T> {
 
                 loop {
                     tokio::select! {
-                        res = peer.next() => {
+                        _ = time::sleep(self.download_throttle.wait()), if self.download_throttle.has_delayed() => {
+                            let mut closed = false;
+                            while let Some(bytes) = self.download_throttle.pop_ready() {
+                                if !self.handle_msg_from_peer(&bytes, &mut peer).await {
+                                    closed = true;
+                                    break;
+                                }
+                            }
+                            if closed {
+                                break;
+                            }
+                        }
+                        res = peer.next(), if self.download_throttle.can_read() => {
                             if let Some(res) = res {
                                 match res {
                                     Err(err) => {
+                                        if let Some(resumed) = self.try_resume(key, token, conn_type).await {
+                                            (peer, direct, kcp) = resumed;
+                                            last_recv_time = Instant::now();
+                                            continue;
+                                        }
                                         self.handler.on_establish_connection_error(err.to_string());
                                         break;
                                     }
-                                    Ok(ref bytes) => {
+                                    Ok(bytes) => {
                                         last_recv_time = Instant::now();
                                         if !received {
                                             received = true;
                                             self.handler.update_received(true);
                                         }
                                         self.data_count.fetch_add(bytes.len(), Ordering::Relaxed);
-                                        if !self.handle_msg_from_peer(bytes, &mut peer).await {
-                                            break
+                                        self.handler.traffic.lock().unwrap().on_received(&bytes);
+                                        if let Some(bytes) = self.download_throttle.admit(bytes) {
+                                            if !self.handle_msg_from_peer(&bytes, &mut peer).await {
+                                                break
+                                            }
                                         }
                                     }
                                 }
@@ -246,6 +303,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                     log::info!("Restart remote device");
                                     self.handler.msgbox("restarting", "Restarting remote device", "remote_restarting_tip", "");
                                 } else {
+                                    if let Some(resumed) = self.try_resume(key, token, conn_type).await {
+                                        (peer, direct, kcp) = resumed;
+                                        last_recv_time = Instant::now();
+                                        continue;
+                                    }
                                     log::info!("Reset by the peer");
                                     self.handler.msgbox("error", "Connection Error", "Reset by the peer", "");
                                 }
@@ -265,14 +327,26 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         _ = self.timer.tick() => {
                             if last_recv_time.elapsed() >= SEC30 {
+                                if let Some(resumed) = self.try_resume(key, token, conn_type).await {
+                                    (peer, direct, kcp) = resumed;
+                                    last_recv_time = Instant::now();
+                                    continue;
+                                }
                                 self.handler.msgbox("error", "Connection Error", "Timeout", "");
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
+                                if !self.upload_bucket.ready() {
+                                    continue;
+                                }
+                                let transferred = crate::traffic::transferred(&self.read_jobs);
                                 if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
+                                let (blocks, bytes) = crate::traffic::count_blocks(&transferred, &self.read_jobs, &mut self.file_blocks);
+                                self.upload_bucket.consume(bytes);
+                                self.handler.traffic.lock().unwrap().add_sent("file_response", blocks, bytes);
                                 self.update_jobs_status();
                             } else {
                                 self.timer = crate::rustdesk_interval(time::interval_at(Instant::now() + SEC30, SEC30));
@@ -284,6 +358,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 continue;
                             }
                             fps_instant = Instant::now();
+                            self.refresh_bandwidth_caps();
                             let mut speed = self.data_count.swap(0, Ordering::Relaxed);
                             speed = speed * 1000 / elapsed as usize;
                             let speed = format!("{:.2}kB/s", speed as f32 / 1024 as f32);
@@ -407,6 +482,127 @@ impl<T: InvokeUiSession> Remote<T> {
         }
     }
 
+    async fn cancel_job(&mut self, id: i32, peer: &mut Stream) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_cancel(FileTransferCancel {
+            id: id,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        allow_err!(peer.send(&msg_out).await);
+        if let Some(job) = fs::remove_job(id, &mut self.write_jobs) {
+            job.remove_download_file();
+        }
+        let _ = fs::remove_job(id, &mut self.read_jobs);
+        self.remove_jobs.remove(&id);
+    }
+
+    // Connects again after the path to the peer broke, and resumes the
+    // session with the ticket of the login, see `crate::resumption`.
+    async fn try_resume(
+        &mut self,
+        key: &str,
+        token: &str,
+        conn_type: ConnType,
+    ) -> Option<(Stream, bool, Option<KcpStream>)> {
+        let (ticket, grace) = self.resume_ticket.clone()?;
+        let deadline = Instant::now() + grace;
+        log::info!(
+            "Path to {} broken, resuming the session",
+            self.handler.get_id()
+        );
+        while Instant::now() < deadline {
+            let res = time::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                self.resume(&ticket, key, token, conn_type),
+            )
+            .await;
+            match res {
+                Ok(Ok(resumed)) => return Some(resumed),
+                Ok(Err(err)) if err.to_string() == resumption::LOGIN_MSG_RESUME_FAILED => {
+                    log::warn!("{}", err);
+                    break;
+                }
+                Ok(Err(err)) => {
+                    log::info!("Failed to resume the session: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(_) => break,
+            }
+        }
+        self.resume_ticket = None;
+        None
+    }
+
+    async fn resume(
+        &mut self,
+        ticket: &Ticket,
+        key: &str,
+        token: &str,
+        conn_type: ConnType,
+    ) -> ResultType<(Stream, bool, Option<KcpStream>)> {
+        let ((mut peer, direct, pk, kcp, stream_type), _) = Client::start(
+            &self.handler.get_id(),
+            key,
+            token,
+            conn_type,
+            self.handler.clone(),
+        )
+        .await?;
+        loop {
+            let Some(bytes) = timeout(config::READ_TIMEOUT, peer.next()).await? else {
+                bail!("Reset by the peer");
+            };
+            let msg = Message::parse_from_bytes(&bytes?)?;
+            match msg.union {
+                Some(message::Union::Hash(hash)) => {
+                    let msg = self.handler.lc.read().unwrap().create_login_msg(
+                        "".to_owned(),
+                        "".to_owned(),
+                        ticket.answer(&hash.challenge),
+                    );
+                    peer.send(&msg).await?;
+                }
+                Some(message::Union::LoginResponse(lr)) => match lr.union {
+                    Some(login_response::Union::PeerInfo(pi)) => {
+                        let Some(blocks) = resumption::resumed(&pi.platform_additions) else {
+                            bail!("Unexpected login response");
+                        };
+                        // Blocks in flight on the broken path are lost.
+                        for id in resumption::lost_jobs(&self.file_blocks, &blocks) {
+                            if self
+                                .write_jobs
+                                .iter()
+                                .chain(self.read_jobs.iter())
+                                .any(|j| j.id() == id)
+                            {
+                                log::warn!("File job {} lost blocks, cancelled", id);
+                                self.cancel_job(id, &mut peer).await;
+                                self.handle_job_status(
+                                    id,
+                                    -1,
+                                    Some("Interrupted by a network change".to_owned()),
+                                );
+                            }
+                        }
+                        let secured = peer.is_secured()
+                            || (stream_type == crate::quic_stream::TYPE && pk.is_some());
+                        self.handler
+                            .set_connection_type(secured, direct, stream_type);
+                        self.handler.update_direct(Some(direct));
+                        self.handler.update_received(true);
+                        log::info!("Session resumed, direct: {}", direct);
+                        return Ok((peer, direct, kcp));
+                    }
+                    Some(login_response::Union::Error(err)) => bail!(err),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
@@ -566,6 +762,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     _ => {}
                 }
                 allow_err!(peer.send(&msg).await);
+                self.handler.traffic.lock().unwrap().on_sent(&msg);
             }
             Data::SendFiles((id, r#type, path, to, file_num, include_hidden, is_remote)) => {
                 log::info!("send files, is remote {}", is_remote);
@@ -845,19 +1042,7 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
             }
             Data::CancelJob(id) => {
-                let mut msg_out = Message::new();
-                let mut file_action = FileAction::new();
-                file_action.set_cancel(FileTransferCancel {
-                    id: id,
-                    ..Default::default()
-                });
-                msg_out.set_file_action(file_action);
-                allow_err!(peer.send(&msg_out).await);
-                if let Some(job) = fs::remove_job(id, &mut self.write_jobs) {
-                    job.remove_download_file();
-                }
-                let _ = fs::remove_job(id, &mut self.read_jobs);
-                self.remove_jobs.remove(&id);
+                self.cancel_job(id, peer).await;
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -1331,6 +1516,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                     }
                     Some(login_response::Union::PeerInfo(pi)) => {
+                        self.resume_ticket = resumption::ticket_of(&pi.platform_additions);
                         let peer_version = pi.version.clone();
                         let peer_platform = pi.platform.clone();
                         self.set_peer_info(&pi);
@@ -1612,6 +1798,7 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Block(block)) => {
+                            *self.file_blocks.entry(block.id).or_default() += 1;
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
                                 if let Err(_err) = job.write(block).await {
                                     // to-do: add "skip" for writing job
@@ -1692,6 +1879,10 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::Misc(misc)) => match misc.union {
                     Some(misc::Union::AudioFormat(f)) => {
+                        for (_, v) in self.video_threads.iter() {
+                            v.video_sender.send(MediaData::AudioFormat(f.clone())).ok();
+                        }
+                        self.audio_format = Some(f.clone());
                         self.audio_sender.send(MediaData::AudioFormat(f)).ok();
                     }
                     Some(misc::Union::ChatMessage(c)) => {
@@ -1908,6 +2099,12 @@ impl<T: InvokeUiSession> Remote<T> {
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::AudioFrame(frame)) => {
+                    if self.last_record_state {
+                        for (_, v) in self.video_threads.iter() {
+                            let frame = Box::new(frame.clone());
+                            v.video_sender.send(MediaData::AudioFrame(frame)).ok();
+                        }
+                    }
                     if !self.handler.lc.read().unwrap().disable_audio.v {
                         self.audio_sender
                             .send(MediaData::AudioFrame(Box::new(frame)))
@@ -2319,6 +2516,9 @@ impl<T: InvokeUiSession> Remote<T> {
         let decode_fps = Arc::new(RwLock::new(None));
         let frame_count = Arc::new(RwLock::new(0));
         let discard_queue = Arc::new(RwLock::new(false));
+        if let Some(f) = self.audio_format.as_ref() {
+            video_sender.send(MediaData::AudioFormat(f.clone())).ok();
+        }
         let video_thread = VideoThread {
             video_queue: video_queue.clone(),
             video_sender,
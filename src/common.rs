@@ -542,6 +542,9 @@ impl Drop for CheckTestNatType {
 }
 
 pub fn test_nat_type() {
+    if is_no_rendezvous() {
+        return;
+    }
     test_ipv6_sync();
     use std::sync::atomic::{AtomicBool, Ordering};
     std::thread::spawn(move || {
@@ -666,6 +669,11 @@ pub async fn get_rendezvous_server(ms_timeout: u64) -> (String, Vec<String>, boo
         a = b.pop().unwrap_or(a);
         false
     };
+    for x in crate::failover::alternates() {
+        if x != a && !b.contains(&x) {
+            b.push(x);
+        }
+    }
     (a, b, c)
 }
 
@@ -699,6 +707,10 @@ pub async fn get_nat_type(ms_timeout: u64) -> i32 {
 // used for client to test which server is faster in case stop-servic=Y
 #[tokio::main(flavor = "current_thread")]
 async fn test_rendezvous_server_() {
+    if crate::failover::is_enabled() {
+        crate::failover::check().await;
+        return;
+    }
     let servers = Config::get_rendezvous_servers();
     if servers.len() <= 1 {
         return;
@@ -727,6 +739,9 @@ async fn test_rendezvous_server_() {
 
 // #[cfg(any(target_os = "android", target_os = "ios", feature = "cli"))]
 pub fn test_rendezvous_server() {
+    if is_no_rendezvous() {
+        return;
+    }
     std::thread::spawn(test_rendezvous_server_);
 }
 
@@ -1500,6 +1515,19 @@ pub async fn get_key(sync: bool) -> String {
     key
 }
 
+pub const OPTION_NO_RENDEZVOUS: &str = "no-rendezvous";
+
+/// Serverless mode: the rendezvous server is never contacted, peers are
+/// reached by ip on the direct server and trusted by their pinned host key,
+/// see [`crate::client::host_key`].
+#[inline]
+pub fn is_no_rendezvous() -> bool {
+    config::option2bool(
+        OPTION_NO_RENDEZVOUS,
+        &Config::get_option(OPTION_NO_RENDEZVOUS),
+    )
+}
+
 pub fn pk_to_fingerprint(pk: Vec<u8>) -> String {
     let s: String = pk.iter().map(|u| format!("{:02x}", u)).collect();
     s.chars()
@@ -1873,6 +1901,24 @@ pub fn read_custom_client(config: &str) {
         }
     }
 
+    read_custom_client_settings(
+        data.remove("default-settings"),
+        data.remove("override-settings"),
+    );
+    for (k, v) in data {
+        if let Some(v) = v.as_str() {
+            config::HARD_SETTINGS
+                .write()
+                .unwrap()
+                .insert(k, v.to_owned());
+        };
+    }
+}
+
+fn read_custom_client_settings(
+    default_settings: Option<serde_json::Value>,
+    overwrite_settings: Option<serde_json::Value>,
+) {
     let mut map_display_settings = HashMap::new();
     for s in keys::KEYS_DISPLAY_SETTINGS {
         map_display_settings.insert(s.replace("_", "-"), s);
@@ -1889,7 +1935,7 @@ pub fn read_custom_client(config: &str) {
     for s in keys::KEYS_BUILDIN_SETTINGS {
         buildin_settings.insert(s.replace("_", "-"), s);
     }
-    if let Some(default_settings) = data.remove("default-settings") {
+    if let Some(default_settings) = default_settings {
         read_custom_client_advanced_settings(
             default_settings,
             &map_display_settings,
@@ -1899,7 +1945,7 @@ pub fn read_custom_client(config: &str) {
             false,
         );
     }
-    if let Some(overwrite_settings) = data.remove("override-settings") {
+    if let Some(overwrite_settings) = overwrite_settings {
         read_custom_client_advanced_settings(
             overwrite_settings,
             &map_display_settings,
@@ -1909,14 +1955,101 @@ pub fn read_custom_client(config: &str) {
             true,
         );
     }
-    for (k, v) in data {
-        if let Some(v) = v.as_str() {
-            config::HARD_SETTINGS
-                .write()
-                .unwrap()
-                .insert(k, v.to_owned());
-        };
+}
+
+/// The signed config bundle applied by `--import-config`.
+pub const OPTION_CONFIG_BUNDLE: &str = "config-bundle";
+/// The signer of config bundles, pinned by the first import unless the
+/// custom client has one.
+pub const OPTION_CONFIG_BUNDLE_KEY: &str = "config-bundle-key";
+pub const OPTION_CONFIG_BUNDLE_VERSION: &str = "config-bundle-version";
+/// JSON of the rendezvous servers of the bundle, by priority.
+pub const OPTION_RENDEZVOUS_SERVERS: &str = "custom-rendezvous-servers";
+
+fn config_bundle_trusted_keys() -> ResultType<Vec<sign::PublicKey>> {
+    let key = match config::HARD_SETTINGS
+        .read()
+        .unwrap()
+        .get(OPTION_CONFIG_BUNDLE_KEY)
+    {
+        Some(key) if !key.is_empty() => key.clone(),
+        _ => Config::get_option(OPTION_CONFIG_BUNDLE_KEY),
+    };
+    if key.is_empty() {
+        return Ok(vec![]);
     }
+    Ok(vec![crate::custom_server::decode_public_key(&key)?])
+}
+
+/// Imports a signed config bundle, refusing an expired one or one older than
+/// the bundle applied.
+pub fn import_config_bundle(data: &str) -> ResultType<()> {
+    let (bundle, pk) = crate::custom_server::open_bundle(data, &config_bundle_trusted_keys()?)?;
+    if bundle.is_expired(hbb_common::get_time() / 1000) {
+        bail!("The config bundle has expired");
+    }
+    let version = Config::get_option(OPTION_CONFIG_BUNDLE_VERSION)
+        .parse::<u64>()
+        .unwrap_or(0);
+    if bundle.version < version {
+        bail!(
+            "The config bundle is older than the one applied, version {} < {}",
+            bundle.version,
+            version
+        );
+    }
+    let Some(server) = bundle.servers.first() else {
+        bail!("No server in the config bundle");
+    };
+    set_option("custom-rendezvous-server".to_owned(), server.host.clone());
+    set_option("key".to_owned(), server.key.clone());
+    set_option("relay-server".to_owned(), server.relay.clone());
+    set_option("api-server".to_owned(), bundle.api.clone());
+    set_option(
+        OPTION_RENDEZVOUS_SERVERS.to_owned(),
+        serde_json::to_string(&bundle.servers)?,
+    );
+    set_option(OPTION_CONFIG_BUNDLE.to_owned(), data.to_owned());
+    set_option(
+        OPTION_CONFIG_BUNDLE_VERSION.to_owned(),
+        bundle.version.to_string(),
+    );
+    set_option(
+        OPTION_CONFIG_BUNDLE_KEY.to_owned(),
+        crate::custom_server::encode_key(&pk.0),
+    );
+    Ok(())
+}
+
+/// Applies the default and override settings of the imported config bundle,
+/// after those of the custom client.
+pub fn load_config_bundle() {
+    let data = Config::get_option(OPTION_CONFIG_BUNDLE);
+    if data.is_empty() {
+        return;
+    }
+    let trusted = match config_bundle_trusted_keys() {
+        Ok(trusted) if !trusted.is_empty() => trusted,
+        _ => {
+            log::error!("No trusted key of the config bundle");
+            return;
+        }
+    };
+    let bundle = match crate::custom_server::open_bundle(&data, &trusted) {
+        Ok((bundle, _)) => bundle,
+        Err(err) => {
+            log::error!("Failed to load the config bundle: {}", err);
+            return;
+        }
+    };
+    if bundle.is_expired(hbb_common::get_time() / 1000) {
+        log::warn!("The config bundle has expired, version {}", bundle.version);
+        return;
+    }
+    read_custom_client_settings(
+        serde_json::to_value(bundle.default_settings).ok(),
+        serde_json::to_value(bundle.override_settings).ok(),
+    );
 }
 
 #[inline]
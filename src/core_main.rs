@@ -33,6 +33,7 @@ pub fn core_main() -> Option<Vec<String>> {
         return None;
     }
     crate::load_custom_client();
+    crate::load_config_bundle();
     #[cfg(windows)]
     if !crate::platform::windows::bootstrap() {
         // return None to terminate the process
@@ -398,6 +399,21 @@ pub fn core_main() -> Option<Vec<String>> {
                 } else {
                     filepath = path.to_str().unwrap().to_string();
                 }
+                // A signed config bundle, the legacy one is a toml file.
+                if let Some(data) = std::fs::read_to_string(&filepath)
+                    .ok()
+                    .filter(|x| x.trim_start().starts_with('{'))
+                {
+                    if crate::platform::is_installed() && is_root() {
+                        match crate::import_config_bundle(&data) {
+                            Ok(()) => println!("Done!"),
+                            Err(err) => println!("{err}"),
+                        }
+                    } else {
+                        println!("Installation and administrative privileges required!");
+                    }
+                    return None;
+                }
                 import_config(&filepath);
             }
             return None;
@@ -599,6 +615,46 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--cli" {
+            std::process::exit(crate::cli::file_transfer::run(&args[1..]));
+        } else if args[0] == "--play-recording" {
+            std::process::exit(crate::cli::play_recording::run(&args[1..]));
+        } else if args[0] == "--gen-login-key" {
+            println!("{}", crate::pubkey_auth::gen_login_key());
+            return None;
+        } else if args[0] == "--forget-host-key" {
+            if args.len() == 2 {
+                if let Err(err) = crate::client::host_key::forget(&args[1]) {
+                    eprintln!("{}", err);
+                }
+            }
+            return None;
+        } else if args[0] == "--verify-audit-log" {
+            if args.len() == 2 {
+                match crate::server::audit::verify(std::path::Path::new(&args[1])) {
+                    Ok(n) => println!("{} records, hash chain intact", n),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            return None;
+        } else if args[0] == "--export-terminal-cast" {
+            if args.len() == 4 {
+                let terminal_id = args[2].parse().unwrap_or_default();
+                let out = std::path::Path::new(&args[3]);
+                match crate::server::terminal_history::export_cast(&args[1], terminal_id, out) {
+                    Ok(n) => println!("{} events written to {}", n, args[3]),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                eprintln!("Usage: --export-terminal-cast <service-id> <terminal-id> <file.cast>");
+            }
+            return None;
         } else if args[0] == "--check-hwcodec-config" {
             #[cfg(feature = "hwcodec")]
             crate::ipc::hwcodec_process();
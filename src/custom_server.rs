@@ -1,10 +1,14 @@
 use hbb_common::{
     bail,
-    base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _},
+    base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine as _,
+    },
     sodiumoxide::crypto::sign,
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
 pub struct CustomServer {
@@ -107,10 +111,154 @@ pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
     bail!("Failed to parse");
 }
 
+/// A rendezvous server of a [`ConfigBundle`].
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+pub struct BundleServer {
+    pub host: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub relay: String,
+    /// The lowest is preferred.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Server configuration distributed as a signed file instead of the name of
+/// the executable, see [`sign_bundle`] and [`open_bundle`].
+///
+/// `default-settings` and `override-settings` are the same as those of a
+/// custom client, the latter being locked in the settings.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigBundle {
+    /// A bundle older than the one applied is refused.
+    pub version: u64,
+    /// Unix time in seconds, 0 for never.
+    #[serde(default)]
+    pub expires: i64,
+    #[serde(default)]
+    pub servers: Vec<BundleServer>,
+    #[serde(default)]
+    pub api: String,
+    #[serde(default)]
+    pub default_settings: HashMap<String, String>,
+    #[serde(default)]
+    pub override_settings: HashMap<String, String>,
+}
+
+impl ConfigBundle {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires > 0 && now >= self.expires
+    }
+}
+
+/// The file format, base64 of the JSON of a [`ConfigBundle`] with the Ed25519
+/// signature of it and the public key of the signer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SignedBundle {
+    payload: String,
+    signature: String,
+    public_key: String,
+}
+
+pub fn encode_key(key: &[u8]) -> String {
+    STANDARD.encode(key)
+}
+
+pub fn decode_public_key(s: &str) -> ResultType<sign::PublicKey> {
+    match sign::PublicKey::from_slice(&STANDARD.decode(s.trim())?) {
+        Some(pk) => Ok(pk),
+        None => bail!("Invalid public key"),
+    }
+}
+
+pub fn decode_secret_key(s: &str) -> ResultType<sign::SecretKey> {
+    match sign::SecretKey::from_slice(&STANDARD.decode(s.trim())?) {
+        Some(sk) => Ok(sk),
+        None => bail!("Invalid secret key"),
+    }
+}
+
+pub fn sign_bundle(bundle: &ConfigBundle, sk: &sign::SecretKey) -> ResultType<String> {
+    let payload = serde_json::to_vec(bundle)?;
+    let signature = sign::sign_detached(&payload, sk);
+    let pk = sk.public_key();
+    Ok(serde_json::to_string_pretty(&SignedBundle {
+        payload: STANDARD.encode(&payload),
+        signature: STANDARD.encode(signature.to_bytes()),
+        public_key: encode_key(&pk.0),
+    })?)
+}
+
+/// Verifies a signed bundle, by one of `trusted` if not empty. Returns the
+/// bundle, with the servers by priority, and the key of the signer.
+pub fn open_bundle(
+    data: &str,
+    trusted: &[sign::PublicKey],
+) -> ResultType<(ConfigBundle, sign::PublicKey)> {
+    let signed: SignedBundle = serde_json::from_str(data)?;
+    let pk = decode_public_key(&signed.public_key)?;
+    if !trusted.is_empty() && !trusted.contains(&pk) {
+        bail!("The config bundle is signed by an untrusted key");
+    }
+    let payload = STANDARD.decode(&signed.payload)?;
+    let Ok(signature) = sign::Signature::from_bytes(&STANDARD.decode(&signed.signature)?) else {
+        bail!("Invalid signature of the config bundle");
+    };
+    if !sign::verify_detached(&signature, &payload, &pk) {
+        bail!("Invalid signature of the config bundle");
+    }
+    let mut bundle: ConfigBundle = serde_json::from_slice(&payload)?;
+    bundle.servers.sort_by_key(|s| s.priority);
+    Ok((bundle, pk))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_config_bundle() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut bundle = ConfigBundle {
+            version: 2,
+            expires: 100,
+            servers: vec![
+                BundleServer {
+                    host: "b.example.net".to_owned(),
+                    priority: 1,
+                    ..Default::default()
+                },
+                BundleServer {
+                    host: "a.example.net".to_owned(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        bundle.override_settings.insert(
+            "allow-remote-config-modification".to_owned(),
+            "N".to_owned(),
+        );
+        let signed = sign_bundle(&bundle, &sk).unwrap();
+        let (opened, signer) = open_bundle(&signed, &[]).unwrap();
+        assert_eq!(signer, pk);
+        assert_eq!(opened.servers[0].host, "a.example.net");
+        assert_eq!(opened.override_settings, bundle.override_settings);
+        assert!(!opened.is_expired(99) && opened.is_expired(100));
+        assert!(open_bundle(&signed, &[pk]).is_ok());
+
+        let (other, _) = sign::gen_keypair();
+        assert!(open_bundle(&signed, &[other]).is_err());
+        bundle.version = 3;
+        let mut forged: SignedBundle = serde_json::from_str(&signed).unwrap();
+        forged.payload = STANDARD.encode(serde_json::to_vec(&bundle).unwrap());
+        assert!(open_bundle(&serde_json::to_string(&forged).unwrap(), &[]).is_err());
+        assert_eq!(decode_secret_key(&encode_key(&sk.0)).unwrap(), sk);
+    }
+
     #[test]
     fn test_filename_license_string() {
         assert!(get_custom_server_from_string("rustdesk.exe").is_err());
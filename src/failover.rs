@@ -0,0 +1,323 @@
+//! Failover across the rendezvous servers of a config bundle, kept in the
+//! `custom-rendezvous-servers` option, see [`crate::custom_server::ConfigBundle`].
+//!
+//! The rendezvous and relay servers are probed in the background and ranked:
+//! reachable first, then with a reachable relay, then by priority, then by
+//! latency. The active one is the one in the `custom-rendezvous-server`, `key`
+//! and `relay-server` options, so everything else keeps using a single server.
+//! It is replaced when it goes down, or when one of a better priority is back.
+
+use crate::custom_server::BundleServer;
+use hbb_common::{
+    config::{Config, CONNECT_TIMEOUT, RELAY_PORT, RENDEZVOUS_PORT},
+    futures::future::join_all,
+    log, sleep, socket_client, tokio,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// The ipc config name of [`status`].
+pub const STATUS: &str = "failover-status";
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Consecutive failed probes before a server is down.
+const MAX_FAILS: u32 = 2;
+/// How long a server the rendezvous mediator failed on is down.
+const DOWN_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone)]
+struct Health {
+    /// Microseconds, 0 if unknown.
+    latency: i64,
+    fails: u32,
+    relay_fails: u32,
+    down_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_down(&self) -> bool {
+        self.fails >= MAX_FAILS || self.down_until.map_or(false, |x| x > Instant::now())
+    }
+
+    fn is_relay_down(&self) -> bool {
+        self.relay_fails >= MAX_FAILS
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.latency > 0 && !self.is_down()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref HEALTH: RwLock<HashMap<String, Health>> = Default::default();
+}
+
+fn rendezvous_addr(host: &str) -> String {
+    crate::check_port(host, RENDEZVOUS_PORT)
+}
+
+fn relay_addr(s: &BundleServer) -> String {
+    if s.relay.is_empty() {
+        crate::increase_port(rendezvous_addr(&s.host), 1)
+    } else {
+        crate::check_port(&s.relay, RELAY_PORT)
+    }
+}
+
+fn health_of(health: &HashMap<String, Health>, s: &BundleServer) -> Health {
+    health
+        .get(&rendezvous_addr(&s.host))
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn active_addr() -> String {
+    rendezvous_addr(&Config::get_option("custom-rendezvous-server"))
+}
+
+/// The servers by priority, none if there is only one.
+pub fn servers() -> Vec<BundleServer> {
+    let v = Config::get_option(crate::common::OPTION_RENDEZVOUS_SERVERS);
+    if v.is_empty() {
+        return vec![];
+    }
+    match serde_json::from_str::<Vec<BundleServer>>(&v) {
+        Ok(servers) if servers.len() > 1 => servers,
+        Ok(_) => vec![],
+        Err(err) => {
+            log::error!("Invalid rendezvous server list: {}", err);
+            vec![]
+        }
+    }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    !servers().is_empty()
+}
+
+fn rank(mut servers: Vec<BundleServer>, health: &HashMap<String, Health>) -> Vec<BundleServer> {
+    servers.sort_by_key(|s| {
+        let h = health_of(health, s);
+        let latency = if h.latency > 0 { h.latency } else { i64::MAX };
+        (h.is_down(), h.is_relay_down(), s.priority, latency)
+    });
+    servers
+}
+
+/// The server to switch to, if the active one is down or has its relay down,
+/// or a healthy one of a better priority is back. None if the active one is
+/// not in the list, e.g. changed by hand.
+fn choose(
+    ranked: &[BundleServer],
+    active: &str,
+    health: &HashMap<String, Health>,
+) -> Option<BundleServer> {
+    let best = ranked.first()?;
+    let current = ranked.iter().find(|s| rendezvous_addr(&s.host) == active)?;
+    if current == best {
+        return None;
+    }
+    let (best_h, current_h) = (health_of(health, best), health_of(health, current));
+    if !best_h.is_healthy() {
+        return None;
+    }
+    if current_h.is_down()
+        || (current_h.is_relay_down() && !best_h.is_relay_down())
+        || best.priority < current.priority
+    {
+        Some(best.clone())
+    } else {
+        None
+    }
+}
+
+fn switch_if_needed() -> bool {
+    let health = HEALTH.read().unwrap();
+    let Some(s) = choose(&rank(servers(), &health), &active_addr(), &health) else {
+        return false;
+    };
+    drop(health);
+    log::info!("Fail over to rendezvous server {}", s.host);
+    Config::set_option("custom-rendezvous-server".to_owned(), s.host);
+    Config::set_option("key".to_owned(), s.key);
+    Config::set_option("relay-server".to_owned(), s.relay);
+    #[cfg(not(target_os = "ios"))]
+    crate::RendezvousMediator::restart();
+    true
+}
+
+async fn probe(addr: String) -> Option<i64> {
+    let tm = Instant::now();
+    socket_client::connect_tcp(addr, CONNECT_TIMEOUT)
+        .await
+        .ok()
+        .map(|_| (tm.elapsed().as_micros() as i64).max(1))
+}
+
+/// Probes all the servers, then switches if needed.
+pub async fn check() {
+    let servers = servers();
+    if servers.is_empty() {
+        return;
+    }
+    let results = join_all(servers.iter().map(|s| {
+        let (rendezvous, relay) = (rendezvous_addr(&s.host), relay_addr(s));
+        async move {
+            let (latency, relay_latency) = (probe(rendezvous.clone()).await, probe(relay).await);
+            (rendezvous, latency, relay_latency.is_some())
+        }
+    }))
+    .await;
+    {
+        let mut health = HEALTH.write().unwrap();
+        for (addr, latency, relay_ok) in results {
+            let h = health.entry(addr).or_default();
+            if let Some(latency) = latency {
+                h.latency = latency;
+                h.fails = 0;
+            } else {
+                h.latency = 0;
+                h.fails += 1;
+            }
+            h.relay_fails = if relay_ok { 0 } else { h.relay_fails + 1 };
+        }
+    }
+    switch_if_needed();
+}
+
+/// Checks the servers periodically, run by the rendezvous mediator.
+pub async fn start() {
+    loop {
+        check().await;
+        sleep(CHECK_INTERVAL.as_secs_f32()).await;
+    }
+}
+
+/// The rendezvous mediator failed on `host`, it is down for a while.
+/// The servers are checked in the background, the mediator is restarted if
+/// switched to another server.
+pub fn on_failure(host: &str) {
+    if !is_enabled() {
+        return;
+    }
+    HEALTH
+        .write()
+        .unwrap()
+        .entry(rendezvous_addr(host))
+        .or_default()
+        .down_until = Some(Instant::now() + DOWN_PERIOD);
+    tokio::spawn(check());
+}
+
+/// The other servers sharing the key of the active one, the healthiest
+/// first, for the client to try if the active one fails.
+pub fn alternates() -> Vec<String> {
+    let active = active_addr();
+    let ranked = rank(servers(), &HEALTH.read().unwrap());
+    let Some(current) = ranked.iter().find(|s| rendezvous_addr(&s.host) == active) else {
+        return vec![];
+    };
+    ranked
+        .iter()
+        .filter(|s| s.key == current.key && rendezvous_addr(&s.host) != active)
+        .map(|s| rendezvous_addr(&s.host))
+        .collect()
+}
+
+/// A healthy relay server sharing the key of the active one, if the relay
+/// server of the latter is down.
+pub fn relay_server() -> Option<String> {
+    let health = HEALTH.read().unwrap();
+    let active = active_addr();
+    let ranked = rank(servers(), &health);
+    let current = ranked.iter().find(|s| rendezvous_addr(&s.host) == active)?;
+    if !health_of(&health, current).is_relay_down() {
+        return None;
+    }
+    ranked
+        .iter()
+        .find(|s| {
+            let h = health_of(&health, s);
+            s.key == current.key && h.is_healthy() && !h.is_relay_down()
+        })
+        .map(relay_addr)
+}
+
+/// `[{"host", "priority", "latency" (ms, 0 if unknown), "down", "relay_down",
+/// "active"}]`, ranked, empty if not enabled.
+pub fn status() -> String {
+    let servers = servers();
+    if servers.is_empty() {
+        return "".to_owned();
+    }
+    let health = HEALTH.read().unwrap();
+    let active = active_addr();
+    let v: Vec<_> = rank(servers, &health)
+        .iter()
+        .map(|s| {
+            let h = health_of(&health, s);
+            json!({
+                "host": s.host,
+                "priority": s.priority,
+                "latency": h.latency / 1000,
+                "down": h.is_down(),
+                "relay_down": h.is_relay_down(),
+                "active": rendezvous_addr(&s.host) == active,
+            })
+        })
+        .collect();
+    serde_json::to_string(&v).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let server = |host: &str, priority| BundleServer {
+            host: host.to_owned(),
+            priority,
+            ..Default::default()
+        };
+        let servers = vec![server("a", 0), server("b", 1), server("c", 1)];
+        let healthy = |latency| Health {
+            latency,
+            ..Default::default()
+        };
+        let mut health = HashMap::new();
+        health.insert(rendezvous_addr("a"), healthy(50_000));
+        health.insert(rendezvous_addr("b"), healthy(30_000));
+        health.insert(rendezvous_addr("c"), healthy(10_000));
+        let ranked = rank(servers.clone(), &health);
+        assert_eq!(ranked[0].host, "a");
+        assert_eq!(choose(&ranked, &rendezvous_addr("a"), &health), None);
+        // back to a better priority
+        assert_eq!(
+            choose(&ranked, &rendezvous_addr("b"), &health).map(|s| s.host),
+            Some("a".to_owned())
+        );
+        // a is down, the fastest of the next priority
+        health.get_mut(&rendezvous_addr("a")).unwrap().fails = MAX_FAILS;
+        let ranked = rank(servers.clone(), &health);
+        assert_eq!(
+            choose(&ranked, &rendezvous_addr("a"), &health).map(|s| s.host),
+            Some("c".to_owned())
+        );
+        // same priority, not switched for latency only
+        assert_eq!(choose(&ranked, &rendezvous_addr("b"), &health), None);
+        // not in the list
+        assert_eq!(choose(&ranked, &rendezvous_addr("d"), &health), None);
+        // relay of c is down
+        health.get_mut(&rendezvous_addr("c")).unwrap().relay_fails = MAX_FAILS;
+        let ranked = rank(servers, &health);
+        assert_eq!(
+            choose(&ranked, &rendezvous_addr("c"), &health).map(|s| s.host),
+            Some("b".to_owned())
+        );
+    }
+}
@@ -50,6 +50,7 @@ fn initialize(app_dir: &str, custom_client_config: &str) {
     } else {
         crate::read_custom_client(custom_client_config);
     }
+    crate::load_config_bundle();
     #[cfg(target_os = "android")]
     {
         // flexi_logger can't work when android_logger initialized.
@@ -1637,6 +1638,10 @@ pub fn main_get_fingerprint() -> String {
     get_fingerprint()
 }
 
+pub fn main_get_rendezvous_failover_status() -> String {
+    ui_interface::get_rendezvous_failover_status()
+}
+
 pub fn cm_get_clients_state() -> String {
     crate::ui_cm_interface::get_clients_state()
 }
@@ -1809,6 +1814,87 @@ pub fn session_send_note(session_id: SessionID, note: String) {
     }
 }
 
+pub fn session_get_traffic_stats(session_id: SessionID) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.get_traffic_stats())
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+pub fn session_start_macro_recording(session_id: SessionID) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.start_macro_recording();
+    }
+}
+
+pub fn session_is_macro_recording(session_id: SessionID) -> SyncReturn<bool> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.is_macro_recording())
+    } else {
+        SyncReturn(false)
+    }
+}
+
+pub fn session_stop_macro_recording(
+    session_id: SessionID,
+    name: String,
+    shared: bool,
+) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.stop_macro_recording(name, shared))
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+pub fn session_get_macros(session_id: SessionID) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.get_macros())
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+pub fn session_remove_macro(
+    session_id: SessionID,
+    name: String,
+    shared: bool,
+) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.remove_macro(name, shared))
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+pub fn session_play_macro(
+    session_id: SessionID,
+    name: String,
+    speed: f64,
+    vars: String,
+) -> SyncReturn<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.play_macro(name, speed, vars))
+    } else {
+        SyncReturn("".to_owned())
+    }
+}
+
+pub fn session_stop_macro(session_id: SessionID) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.stop_macro();
+    }
+}
+
+pub fn session_is_macro_playing(session_id: SessionID) -> SyncReturn<bool> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        SyncReturn(session.is_macro_playing())
+    } else {
+        SyncReturn(false)
+    }
+}
+
 pub fn session_get_last_audit_note(session_id: SessionID) -> SyncReturn<String> {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         SyncReturn(session.last_audit_note.lock().unwrap().clone())
@@ -1953,6 +2039,13 @@ pub fn main_wol(id: String) {
     crate::lan::send_wol(id)
 }
 
+pub fn main_get_wol_status(id: String) -> SyncReturn<String> {
+    #[cfg(not(any(target_os = "ios")))]
+    return SyncReturn(crate::lan::wol::status(&id));
+    #[cfg(target_os = "ios")]
+    SyncReturn("".to_owned())
+}
+
 pub fn main_create_shortcut(_id: String) {
     #[cfg(windows)]
     create_shortcut(_id);
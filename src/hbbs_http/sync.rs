@@ -96,7 +96,7 @@ async fn start_hbbs_sync_async() {
             _ = interval.tick() => {
                 let url = heartbeat_url();
                 let id = Config::get_id();
-                if url.is_empty() {
+                if url.is_empty() || crate::is_no_rendezvous() {
                     *PRO.lock().unwrap() = false;
                     continue;
                 }
@@ -297,6 +297,7 @@ fn handle_config_options(config_options: HashMap<String, String>) {
         })
         .count();
     Config::set_options(options);
+    crate::traffic::options_changed();
 }
 
 #[allow(unused)]
@@ -366,6 +366,7 @@ pub struct CheckIfRestart {
     disable_udp: String,
     allow_insecure_tls_fallback: String,
     api_server: String,
+    no_rendezvous: String,
 }
 
 impl CheckIfRestart {
@@ -381,6 +382,7 @@ impl CheckIfRestart {
                 config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK,
             ),
             api_server: Config::get_option("api-server"),
+            no_rendezvous: Config::get_option(crate::common::OPTION_NO_RENDEZVOUS),
         }
     }
 }
@@ -397,6 +399,7 @@ impl Drop for CheckIfRestart {
             || self.ws != Config::get_option(OPTION_ALLOW_WEBSOCKET)
             || self.disable_udp != Config::get_option(config::keys::OPTION_DISABLE_UDP)
             || self.api_server != Config::get_option("api-server")
+            || self.no_rendezvous != Config::get_option(crate::common::OPTION_NO_RENDEZVOUS)
         {
             if allow_insecure_tls_fallback_changed {
                 hbb_common::tls::reset_tls_cache();
@@ -543,6 +546,8 @@ async fn handle(data: Data, stream: &mut Connection) {
                     ));
                 } else if name == "rendezvous_servers" {
                     value = Some(Config::get_rendezvous_servers().join(","));
+                } else if name == crate::failover::STATUS {
+                    value = Some(crate::failover::status());
                 } else if name == "fingerprint" {
                     value = if Config::get_key_confirmed() {
                         Some(crate::common::pk_to_fingerprint(Config::get_key_pair().1))
@@ -599,6 +604,7 @@ async fn handle(data: Data, stream: &mut Connection) {
                     crate::privacy_mode::switch(v);
                 }
                 Config::set_options(value);
+                crate::traffic::options_changed();
                 allow_err!(stream.send(&Data::Options(None)).await);
             }
         },
@@ -611,6 +617,7 @@ async fn handle(data: Data, stream: &mut Connection) {
             let _chk = CheckIfRestart::new();
             Config::set(config);
             Config2::set(config2);
+            crate::traffic::options_changed();
             allow_err!(stream.send(&Data::SyncConfig(None)).await);
         }
         Data::SyncConfig(None) => {
@@ -1176,6 +1183,7 @@ pub async fn set_options(value: HashMap<String, String>) -> ResultType<()> {
         c.next_timeout(1000).await.ok();
     }
     Config::set_options(value);
+    crate::traffic::options_changed();
     Ok(())
 }
 
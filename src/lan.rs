@@ -21,6 +21,9 @@ use std::{
     time::Instant,
 };
 
+pub(crate) mod mdns;
+pub mod wol;
+
 type Message = RendezvousMessage;
 
 #[cfg(not(target_os = "ios"))]
@@ -83,25 +86,9 @@ pub async fn discover() -> ResultType<()> {
     Ok(())
 }
 
+/// Wakes the peer in the background, see [`wol::status`].
 pub fn send_wol(id: String) {
-    let interfaces = default_net::get_interfaces();
-    for peer in &config::LanPeers::load().peers {
-        if peer.id == id {
-            for (_, mac) in peer.ip_mac.iter() {
-                if let Ok(mac_addr) = mac.parse() {
-                    for interface in &interfaces {
-                        for ipv4 in &interface.ipv4 {
-                            // remove below mask check to avoid unexpected bug
-                            // if (u32::from(ipv4.addr) & u32::from(ipv4.netmask)) == (u32::from(peer_ip) & u32::from(ipv4.netmask))
-                            log::info!("Send wol to {mac_addr} of {}", ipv4.addr);
-                            allow_err!(wol::send_wol(mac_addr, None, Some(IpAddr::V4(ipv4.addr))));
-                        }
-                    }
-                }
-            }
-            break;
-        }
-    }
+    std::thread::spawn(move || wol::wake(id));
 }
 
 #[inline]
@@ -299,6 +286,9 @@ fn spawn_wait_responses(sockets: Vec<UdpSocket>) -> UnboundedReceiver<config::Di
             ));
         });
     }
+    std::thread::spawn(move || {
+        allow_err!(mdns::browse(tx));
+    });
     rx
 }
 
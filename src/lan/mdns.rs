@@ -0,0 +1,557 @@
+//! LAN discovery over mDNS / DNS-SD, RFC 6762 and RFC 6763.
+//!
+//! The server answers queries for `_rustdesk._tcp.local` with an instance
+//! named by its id, the direct access port in the SRV record and the id,
+//! hostname, username, platform and mac in the TXT record, so that
+//! `avahi-browse -r _rustdesk._tcp` or `dns-sd -B _rustdesk._tcp` list it.
+//! The client sends one-shot queries and merges the answers with the peers
+//! found by the broadcast ping.
+
+use super::{get_ipaddr_by_peer, get_mac};
+#[cfg(not(target_os = "ios"))]
+use hbb_common::whoami;
+use hbb_common::{
+    allow_err, bail,
+    config::{self, keys::OPTION_DIRECT_SERVER, Config},
+    log,
+    tokio::sync::mpsc::UnboundedSender,
+    ResultType,
+};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_rustdesk._tcp.local";
+const SERVICES: &str = "_services._dns-sd._udp.local";
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum RData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: String,
+    data: RData,
+}
+
+#[derive(Debug, Default)]
+struct Packet {
+    id: u16,
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+fn eq_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn read_name(buf: &[u8], pos: &mut usize) -> ResultType<String> {
+    let mut labels = vec![];
+    let mut p = *pos;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let Some(&len) = buf.get(p) else {
+            bail!("truncated name");
+        };
+        if len & 0xC0 == 0xC0 {
+            let Some(&low) = buf.get(p + 1) else {
+                bail!("truncated pointer");
+            };
+            if !jumped {
+                *pos = p + 2;
+            }
+            jumped = true;
+            p = ((len as usize & 0x3F) << 8) | low as usize;
+        } else if len == 0 {
+            if !jumped {
+                *pos = p + 1;
+            }
+            return Ok(labels.join("."));
+        } else {
+            let end = p + 1 + len as usize;
+            let Some(label) = buf.get(p + 1..end) else {
+                bail!("truncated label");
+            };
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            p = end;
+        }
+    }
+    bail!("name too long")
+}
+
+fn read_u16(buf: &[u8], pos: &mut usize) -> ResultType<u16> {
+    let Some(v) = buf.get(*pos..*pos + 2) else {
+        bail!("truncated");
+    };
+    *pos += 2;
+    Ok(u16::from_be_bytes([v[0], v[1]]))
+}
+
+impl Packet {
+    fn parse(buf: &[u8]) -> ResultType<Self> {
+        let mut pos = 0;
+        let id = read_u16(buf, &mut pos)?;
+        let flags = read_u16(buf, &mut pos)?;
+        let qd = read_u16(buf, &mut pos)?;
+        let mut n = 0;
+        for _ in 0..3 {
+            n += read_u16(buf, &mut pos)? as usize;
+        }
+        let mut packet = Packet {
+            id,
+            response: flags & 0x8000 != 0,
+            ..Default::default()
+        };
+        for _ in 0..qd {
+            let name = read_name(buf, &mut pos)?;
+            let typ = read_u16(buf, &mut pos)?;
+            read_u16(buf, &mut pos)?;
+            packet.questions.push((name, typ));
+        }
+        for _ in 0..n {
+            let name = read_name(buf, &mut pos)?;
+            let typ = read_u16(buf, &mut pos)?;
+            pos += 6; // class and ttl
+            let len = read_u16(buf, &mut pos)? as usize;
+            let Some(rdata) = buf.get(pos..pos + len) else {
+                bail!("truncated record");
+            };
+            let mut p = pos;
+            let data = match typ {
+                TYPE_A if len == 4 => {
+                    RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+                }
+                TYPE_PTR => RData::Ptr(read_name(buf, &mut p)?),
+                TYPE_SRV if len > 6 => {
+                    p += 4; // priority and weight
+                    let port = read_u16(buf, &mut p)?;
+                    RData::Srv {
+                        port,
+                        target: read_name(buf, &mut p)?,
+                    }
+                }
+                TYPE_TXT => {
+                    let mut strings = vec![];
+                    let mut i = 0;
+                    while i < rdata.len() {
+                        let end = (i + 1 + rdata[i] as usize).min(rdata.len());
+                        strings.push(String::from_utf8_lossy(&rdata[i + 1..end]).into_owned());
+                        i = end;
+                    }
+                    RData::Txt(strings)
+                }
+                _ => RData::Other,
+            };
+            pos += len;
+            packet.records.push(Record { name, data });
+        }
+        Ok(packet)
+    }
+
+    fn write(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&self.id.to_be_bytes());
+        let flags: u16 = if self.response { 0x8400 } else { 0 };
+        out.extend_from_slice(&flags.to_be_bytes());
+        out.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        let records = self
+            .records
+            .iter()
+            .filter(|r| r.data != RData::Other)
+            .collect::<Vec<_>>();
+        out.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        for (name, typ) in self.questions.iter() {
+            write_name(&mut out, name);
+            out.extend_from_slice(&typ.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        for r in records {
+            write_name(&mut out, &r.name);
+            let mut rdata = vec![];
+            let (typ, class) = match &r.data {
+                RData::A(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    (TYPE_A, CLASS_IN | CACHE_FLUSH)
+                }
+                RData::Ptr(name) => {
+                    write_name(&mut rdata, name);
+                    (TYPE_PTR, CLASS_IN)
+                }
+                RData::Txt(strings) => {
+                    for s in strings.iter() {
+                        let s = &s.as_bytes()[..s.len().min(255)];
+                        rdata.push(s.len() as u8);
+                        rdata.extend_from_slice(s);
+                    }
+                    (TYPE_TXT, CLASS_IN | CACHE_FLUSH)
+                }
+                RData::Srv { port, target } => {
+                    rdata.extend_from_slice(&[0, 0, 0, 0]);
+                    rdata.extend_from_slice(&port.to_be_bytes());
+                    write_name(&mut rdata, target);
+                    (TYPE_SRV, CLASS_IN | CACHE_FLUSH)
+                }
+                RData::Other => unreachable!(),
+            };
+            out.extend_from_slice(&typ.to_be_bytes());
+            out.extend_from_slice(&class.to_be_bytes());
+            out.extend_from_slice(&TTL.to_be_bytes());
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend_from_slice(&rdata);
+        }
+        out
+    }
+}
+
+fn txt(strings: &[String]) -> HashMap<&str, &str> {
+    strings.iter().filter_map(|s| s.split_once('=')).collect()
+}
+
+fn local_ipv4s() -> Vec<Ipv4Addr> {
+    #[cfg(not(target_os = "ios"))]
+    return default_net::get_interfaces()
+        .iter()
+        .flat_map(|i| i.ipv4.iter().map(|x| x.addr))
+        .filter(|ip| !ip.is_loopback())
+        .collect();
+    #[cfg(target_os = "ios")]
+    vec![]
+}
+
+/// The records of this device, `self_addr` is the address the querier
+/// reaches us on.
+fn own_records(self_addr: Ipv4Addr, direct_port: u16) -> Vec<Record> {
+    let id = Config::get_id();
+    let instance = format!("{}.{}", id, SERVICE);
+    let target = format!("rustdesk-{}.local", id);
+    let mut hostname = crate::whoami_hostname();
+    if hostname == "localhost" {
+        hostname = "unknown".to_owned();
+    }
+    #[cfg(not(target_os = "ios"))]
+    let platform = whoami::platform().to_string();
+    #[cfg(target_os = "ios")]
+    let platform = "iOS".to_owned();
+    vec![
+        Record {
+            name: SERVICE.to_owned(),
+            data: RData::Ptr(instance.clone()),
+        },
+        Record {
+            name: instance.clone(),
+            data: RData::Srv {
+                port: direct_port,
+                target: target.clone(),
+            },
+        },
+        Record {
+            name: instance,
+            data: RData::Txt(vec![
+                format!("id={}", id),
+                format!("hostname={}", hostname),
+                format!("username={}", crate::platform::get_active_username()),
+                format!("platform={}", platform),
+                format!("mac={}", get_mac(&IpAddr::V4(self_addr))),
+            ]),
+        },
+        Record {
+            name: target,
+            data: RData::A(self_addr),
+        },
+    ]
+}
+
+/// The answer to a query, `None` if it is not about us.
+fn answer(query: &Packet, self_addr: Ipv4Addr, direct_port: u16) -> Option<Packet> {
+    if query.response {
+        return None;
+    }
+    let records = own_records(self_addr, direct_port);
+    let instance = match &records[0].data {
+        RData::Ptr(instance) => instance.clone(),
+        _ => return None,
+    };
+    let mut answers = vec![];
+    for (name, typ) in query.questions.iter() {
+        let typ = *typ;
+        if eq_name(name, SERVICES) && (typ == TYPE_PTR || typ == TYPE_ANY) {
+            answers.push(Record {
+                name: SERVICES.to_owned(),
+                data: RData::Ptr(SERVICE.to_owned()),
+            });
+        } else if eq_name(name, SERVICE) && (typ == TYPE_PTR || typ == TYPE_ANY)
+            || eq_name(name, &instance)
+        {
+            // Answer with everything, a browser needs all of it to resolve us.
+            answers = records.clone();
+            break;
+        }
+    }
+    if answers.is_empty() {
+        return None;
+    }
+    // Known answer suppression of the PTR record, RFC 6762 7.1
+    let known = |r: &Record| query.records.iter().any(|k| k == r);
+    if answers.len() > 1 && known(&answers[0]) {
+        return None;
+    }
+    Some(Packet {
+        id: query.id,
+        response: true,
+        questions: vec![],
+        records: answers,
+    })
+}
+
+fn bind_mdns() -> ResultType<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Share the port with avahi or mDNSResponder.
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    let socket: UdpSocket = socket.into();
+    socket.set_multicast_loop_v4(true)?;
+    let mut joined = 0;
+    for ip in local_ipv4s() {
+        if socket.join_multicast_v4(&MDNS_ADDR, &ip).is_ok() {
+            joined += 1;
+        }
+    }
+    if joined == 0 {
+        socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    Ok(socket)
+}
+
+fn direct_port() -> u16 {
+    let enabled = config::option2bool(
+        OPTION_DIRECT_SERVER,
+        &Config::get_option(OPTION_DIRECT_SERVER),
+    );
+    if !enabled {
+        return 0;
+    }
+    let port = Config::get_option("direct-access-port")
+        .parse::<u16>()
+        .unwrap_or(0);
+    if port == 0 {
+        (config::RENDEZVOUS_PORT + 2) as _
+    } else {
+        port
+    }
+}
+
+/// Answers the DNS-SD queries for this device.
+pub(crate) fn advertise() -> ResultType<()> {
+    let socket = bind_mdns()?;
+    socket.set_read_timeout(Some(Duration::from_millis(1000)))?;
+    log::info!("mdns responder started");
+    let mut buf = [0; 9000];
+    loop {
+        let Ok((len, addr)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if !config::option2bool(
+            "enable-lan-discovery",
+            &Config::get_option("enable-lan-discovery"),
+        ) {
+            continue;
+        }
+        let Ok(query) = Packet::parse(&buf[..len]) else {
+            continue;
+        };
+        let Some(IpAddr::V4(self_addr)) = get_ipaddr_by_peer(&addr) else {
+            continue;
+        };
+        if let Some(mut response) = answer(&query, self_addr, direct_port()) {
+            if addr.port() == MDNS_PORT {
+                response.id = 0;
+                allow_err!(socket.send_to(&response.write(), (MDNS_ADDR, MDNS_PORT)));
+            } else {
+                // One-shot query, RFC 6762 6.7, reply to the sender only.
+                response.questions = query.questions.clone();
+                allow_err!(socket.send_to(&response.write(), addr));
+            }
+        }
+    }
+}
+
+fn peers_of(packet: &Packet, from: IpAddr, own_id: &str) -> Vec<config::DiscoveryPeer> {
+    let mut peers = vec![];
+    let mut instances: Vec<String> = packet
+        .records
+        .iter()
+        .filter_map(|r| match &r.data {
+            RData::Ptr(instance) if eq_name(&r.name, SERVICE) => Some(instance.clone()),
+            _ => None,
+        })
+        .collect();
+    // A responder may send the SRV and TXT records without the PTR.
+    for r in packet.records.iter() {
+        if matches!(r.data, RData::Txt(_))
+            && r.name.to_lowercase().ends_with(&format!(".{}", SERVICE))
+            && !instances.iter().any(|i| eq_name(i, &r.name))
+        {
+            instances.push(r.name.clone());
+        }
+    }
+    for instance in instances.iter() {
+        let Some(strings) = packet.records.iter().find_map(|r| match &r.data {
+            RData::Txt(strings) if eq_name(&r.name, instance) => Some(strings),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let txt = txt(strings);
+        let id = txt.get("id").copied().unwrap_or_default();
+        if id.is_empty() || id == own_id {
+            continue;
+        }
+        let target = packet.records.iter().find_map(|r| match &r.data {
+            RData::Srv { target, .. } if eq_name(&r.name, instance) => Some(target),
+            _ => None,
+        });
+        let ip = target
+            .and_then(|t| {
+                packet.records.iter().find_map(|r| match r.data {
+                    RData::A(ip) if eq_name(&r.name, t) => Some(IpAddr::V4(ip)),
+                    _ => None,
+                })
+            })
+            .unwrap_or(from);
+        let get = |k| txt.get(k).copied().unwrap_or_default().to_owned();
+        peers.push(config::DiscoveryPeer {
+            id: id.to_owned(),
+            ip_mac: HashMap::from([(ip.to_string(), get("mac"))]),
+            username: get("username"),
+            hostname: get("hostname"),
+            platform: get("platform"),
+            online: true,
+        });
+    }
+    peers
+}
+
+/// Sends a one-shot query on every interface and forwards the peers found
+/// in the answers during 3 seconds.
+pub(super) fn browse(tx: UnboundedSender<config::DiscoveryPeer>) -> ResultType<()> {
+    let query = Packet {
+        questions: vec![(SERVICE.to_owned(), TYPE_PTR)],
+        ..Default::default()
+    }
+    .write();
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let mut sent = 0;
+    for ip in local_ipv4s() {
+        if SockRef::from(&socket).set_multicast_if_v4(&ip).is_ok()
+            && socket.send_to(&query, (MDNS_ADDR, MDNS_PORT)).is_ok()
+        {
+            sent += 1;
+        }
+    }
+    if sent == 0 {
+        socket.send_to(&query, (MDNS_ADDR, MDNS_PORT))?;
+    }
+    log::info!("mdns query sent");
+    let own_id = Config::get_id();
+    let start = Instant::now();
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut buf = [0; 9000];
+    while start.elapsed() < Duration::from_secs(3) {
+        if let Ok((len, addr)) = socket.recv_from(&mut buf) {
+            if let Ok(packet) = Packet::parse(&buf[..len]) {
+                if packet.response {
+                    for peer in peers_of(&packet, addr.ip(), &own_id) {
+                        allow_err!(tx.send(peer));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns_packet() {
+        let ip = Ipv4Addr::new(192, 168, 1, 9);
+        let query = Packet::parse(
+            &Packet {
+                id: 7,
+                questions: vec![(SERVICE.to_owned(), TYPE_PTR)],
+                ..Default::default()
+            }
+            .write(),
+        )
+        .unwrap();
+        assert_eq!(query.questions, vec![(SERVICE.to_owned(), TYPE_PTR)]);
+        let response = answer(&query, ip, 21118).unwrap();
+        let parsed = Packet::parse(&response.write()).unwrap();
+        assert!(parsed.response);
+        assert_eq!(parsed.records, response.records);
+        assert!(matches!(
+            parsed.records[1].data,
+            RData::Srv { port: 21118, .. }
+        ));
+        let peers = peers_of(&parsed, IpAddr::V4(Ipv4Addr::LOCALHOST), "");
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, Config::get_id());
+        assert!(peers[0].ip_mac.contains_key("192.168.1.9"));
+        assert!(peers_of(&parsed, IpAddr::V4(ip), &Config::get_id()).is_empty());
+
+        // Known answer suppression
+        let mut known = query;
+        known.records.push(response.records[0].clone());
+        assert!(answer(&known, ip, 21118).is_none());
+
+        // A records of another responder, the second with a compressed name
+        let buf = [
+            0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0, // header
+            1, b'a', 5, b'l', b'o', b'c', b'a', b'l', 0, // a.local
+            0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 1, // A 10.0.0.1
+            1, b'b', 0xC0, 14, // b.local
+            0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 2, // A 10.0.0.2
+        ];
+        let p = Packet::parse(&buf).unwrap();
+        assert_eq!(p.records[0].name, "a.local");
+        assert_eq!(p.records[1].name, "b.local");
+        assert_eq!(p.records[1].data, RData::A(Ipv4Addr::new(10, 0, 0, 2)));
+        // Pointer loop
+        assert!(read_name(&[1, b'b', 0xC0, 0], &mut 0).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Wake-on-LAN.
+//!
+//! Besides the MACs seen by LAN discovery, a peer can be configured with these
+//! peer options:
+//! - `wol-mac`: MACs to wake, comma separated.
+//! - `wol-broadcast`: extra broadcast addresses, e.g. `192.168.2.255` or
+//!   `10.0.0.255:7`, for subnets which are not attached but routed.
+//! - `wol-password`: SecureOn password, 6 bytes like a MAC or 4 like an IPv4.
+//! - `wol-relay`: id of an online peer on the LAN of the target, which sends
+//!   the packets on our behalf over a port forward session.
+//!
+//! Magic packets go to the directed broadcast address of every local IPv4
+//! subnet and to `255.255.255.255`. Then the rendezvous status of the peer is
+//! polled until it is online, see [`status`].
+
+use hbb_common::{
+    bail,
+    config::{self, PeerConfig},
+    log, tokio, ResultType,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const OPTION_WOL_MAC: &str = "wol-mac";
+pub const OPTION_WOL_BROADCAST: &str = "wol-broadcast";
+pub const OPTION_WOL_PASSWORD: &str = "wol-password";
+pub const OPTION_WOL_RELAY: &str = "wol-relay";
+
+pub const STATUS_SENT: &str = "sent";
+pub const STATUS_ONLINE: &str = "online";
+pub const STATUS_TIMEOUT: &str = "timeout";
+
+const WOL_PORT: u16 = 9;
+const WAKE_TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    static ref STATUS: Mutex<HashMap<String, String>> = Default::default();
+}
+
+/// Status of the last wake of `id`: empty, [`STATUS_SENT`] while waiting for
+/// the peer, [`STATUS_ONLINE`], [`STATUS_TIMEOUT`] or an error.
+pub fn status(id: &str) -> String {
+    STATUS.lock().unwrap().get(id).cloned().unwrap_or_default()
+}
+
+fn set_status(id: &str, status: &str) {
+    STATUS
+        .lock()
+        .unwrap()
+        .insert(id.to_owned(), status.to_owned());
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.is_ascii() {
+        return None;
+    }
+    let parts: Vec<&str> = if s.contains(|c| c == ':' || c == '-') {
+        s.split(|c| c == ':' || c == '-').collect()
+    } else if s.len() == 12 {
+        (0..6).map(|i| &s[i * 2..i * 2 + 2]).collect()
+    } else {
+        return None;
+    };
+    parts
+        .iter()
+        .map(|p| u8::from_str_radix(p, 16).ok())
+        .collect()
+}
+
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    parse_hex_bytes(s)?.try_into().ok()
+}
+
+pub fn parse_password(s: &str) -> ResultType<Vec<u8>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    if let Ok(ip) = s.parse::<Ipv4Addr>() {
+        return Ok(ip.octets().to_vec());
+    }
+    match parse_mac(s) {
+        Some(mac) => Ok(mac.to_vec()),
+        None => bail!("invalid SecureOn password {}", s),
+    }
+}
+
+/// 6 bytes of 0xFF, the MAC 16 times, then the SecureOn password if any.
+pub fn magic_packet(mac: &[u8; 6], password: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    packet.extend_from_slice(password);
+    packet
+}
+
+pub fn is_magic_packet(packet: &[u8]) -> bool {
+    if !matches!(packet.len(), 102 | 106 | 108) || packet[..6] != [0xFF; 6] {
+        return false;
+    }
+    let mac = &packet[6..12];
+    packet[6..102].chunks(6).all(|c| c == mac)
+}
+
+fn directed_broadcasts() -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    let mut addrs = vec![];
+    #[cfg(not(target_os = "ios"))]
+    for interface in default_net::get_interfaces() {
+        for ipv4 in &interface.ipv4 {
+            if ipv4.addr.is_loopback() {
+                continue;
+            }
+            let mask = u32::from(ipv4.netmask);
+            let broadcast = Ipv4Addr::from(u32::from(ipv4.addr) | !mask);
+            addrs.push((ipv4.addr, broadcast));
+        }
+    }
+    addrs
+}
+
+fn parse_broadcast(s: &str) -> Option<SocketAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, WOL_PORT));
+    }
+    s.parse().ok()
+}
+
+/// Sends the packets on every local subnet and to `extra`, returns the number
+/// of datagrams sent.
+pub fn broadcast(packets: &[Vec<u8>], extra: &[SocketAddr]) -> ResultType<usize> {
+    let mut sent = 0;
+    let mut send = |socket: &UdpSocket, to: SocketAddr| {
+        for packet in packets {
+            match socket.send_to(packet, to) {
+                Ok(_) => sent += 1,
+                Err(err) => log::debug!("Failed to send wol to {}: {}", to, err),
+            }
+        }
+    };
+    for (local, directed) in directed_broadcasts() {
+        let Ok(socket) = UdpSocket::bind((local, 0)) else {
+            continue;
+        };
+        if socket.set_broadcast(true).is_err() {
+            continue;
+        }
+        log::info!("Send wol to {} from {}", directed, local);
+        send(&socket, SocketAddr::from((directed, WOL_PORT)));
+        send(&socket, SocketAddr::from((Ipv4Addr::BROADCAST, WOL_PORT)));
+    }
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    for to in extra {
+        log::info!("Send wol to {}", to);
+        send(&socket, *to);
+    }
+    if sent == 0 {
+        send(&socket, SocketAddr::from((Ipv4Addr::BROADCAST, WOL_PORT)));
+    }
+    if sent == 0 {
+        bail!("Failed to send the magic packets");
+    }
+    Ok(sent)
+}
+
+fn packets_of(id: &str, options: &HashMap<String, String>) -> ResultType<Vec<Vec<u8>>> {
+    let mut macs = vec![];
+    let mut add = |s: &str| {
+        if let Some(mac) = parse_mac(s) {
+            if !macs.contains(&mac) {
+                macs.push(mac);
+            }
+        }
+    };
+    if let Some(configured) = options.get(OPTION_WOL_MAC) {
+        configured.split(',').for_each(|m| add(m));
+    }
+    for peer in config::LanPeers::load().peers.iter().filter(|p| p.id == id) {
+        peer.ip_mac.values().for_each(|m| add(m));
+    }
+    if macs.is_empty() {
+        bail!("No MAC address of {}", id);
+    }
+    let password = parse_password(
+        options
+            .get(OPTION_WOL_PASSWORD)
+            .map(|s| s.as_str())
+            .unwrap_or_default(),
+    )?;
+    Ok(macs.iter().map(|m| magic_packet(m, &password)).collect())
+}
+
+fn send(id: &str) -> ResultType<()> {
+    let options = PeerConfig::load(id).options;
+    let packets = packets_of(id, &options)?;
+    let relay = options
+        .get(OPTION_WOL_RELAY)
+        .map(|s| s.trim())
+        .unwrap_or_default();
+    if relay.is_empty() {
+        let extra: Vec<SocketAddr> = options
+            .get(OPTION_WOL_BROADCAST)
+            .map(|s| s.split(',').filter_map(parse_broadcast).collect())
+            .unwrap_or_default();
+        broadcast(&packets, &extra)?;
+        return Ok(());
+    }
+    log::info!("Send wol to {} via {}", id, relay);
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    return crate::cli::send_wol_via(relay.to_owned(), packets);
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    bail!("Waking via a peer is not supported on this platform")
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn wait_online(id: &str) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < WAKE_TIMEOUT {
+        hbb_common::sleep(POLL_INTERVAL.as_secs_f32()).await;
+        let mut online = false;
+        crate::client::peer_online::query_online_states(vec![id.to_owned()], |onlines, _| {
+            online = onlines.iter().any(|x| x == id);
+        })
+        .await;
+        if online {
+            return true;
+        }
+    }
+    false
+}
+
+/// Sends the magic packets and waits for the peer to come online.
+pub fn wake(id: String) {
+    match send(&id) {
+        Ok(()) => set_status(&id, STATUS_SENT),
+        Err(err) => {
+            log::error!("Failed to wake {}: {}", id, err);
+            set_status(&id, &err.to_string());
+            return;
+        }
+    }
+    // Peers connected by ip have no rendezvous status.
+    if hbb_common::is_ip_str(&id) || hbb_common::is_domain_port_str(&id) {
+        return;
+    }
+    let status = if wait_online(&id) {
+        STATUS_ONLINE
+    } else {
+        STATUS_TIMEOUT
+    };
+    log::info!("Wake of {}: {}", id, status);
+    set_status(&id, status);
+}
+
+/// Handles the magic packet of a peer waking a device on our LAN.
+pub fn relay(packet: &[u8]) -> ResultType<()> {
+    if !is_magic_packet(packet) {
+        bail!("Not a magic packet");
+    }
+    let mac = &packet[6..12];
+    log::info!(
+        "Relay wol to {}",
+        mac.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    );
+    broadcast(&[packet.to_vec()], &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = parse_mac("00:11:22:aa:BB:cc").unwrap();
+        assert_eq!(mac, [0x00, 0x11, 0x22, 0xaa, 0xbb, 0xcc]);
+        assert_eq!(parse_mac("00-11-22-aa-bb-cc"), Some(mac));
+        assert_eq!(parse_mac("001122aabbcc"), Some(mac));
+        assert_eq!(parse_mac("00:11:22"), None);
+        assert_eq!(parse_password("").unwrap(), Vec::<u8>::new());
+        assert_eq!(parse_password("1.2.3.4").unwrap(), vec![1, 2, 3, 4]);
+        assert!(parse_password("secret").is_err());
+
+        let packet = magic_packet(&mac, &[]);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(&packet[96..102], &mac);
+        assert!(is_magic_packet(&packet));
+        let packet = magic_packet(&mac, &parse_password("01:02:03:04:05:06").unwrap());
+        assert_eq!(packet.len(), 108);
+        assert!(is_magic_packet(&packet));
+        let mut bad = packet.clone();
+        bad[50] ^= 1;
+        assert!(!is_magic_packet(&bad));
+        assert!(!is_magic_packet(&[0xFF; 20]));
+
+        assert_eq!(
+            parse_broadcast("192.168.2.255"),
+            Some(SocketAddr::from(([192, 168, 2, 255], 9)))
+        );
+        assert_eq!(
+            parse_broadcast(" 10.0.0.255:7"),
+            Some(SocketAddr::from(([10, 0, 0, 255], 7)))
+        );
+    }
+}
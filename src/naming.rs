@@ -1,28 +1,91 @@
 mod custom_server;
-use hbb_common::{ResultType, base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _}};
 use custom_server::*;
+use hbb_common::{
+    bail,
+    base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _},
+    sodiumoxide::crypto::sign,
+    ResultType,
+};
+
+const USAGE: &str = "\
+naming <key> <host> [api] [relay]      name of the executable with the server config
+naming <name>                          decode the name of an executable
+naming genkey <secret key file>        create a signing key, prints its public key
+naming sign <secret key file> <config.json> <signed file>
+                                       sign a config bundle for --import-config
+naming verify <signed file> [public key]";
 
 fn gen_name(lic: &CustomServer) -> ResultType<String> {
     let tmp = URL_SAFE_NO_PAD.encode(&serde_json::to_vec(lic)?);
     Ok(tmp.chars().rev().collect())
 }
 
+fn genkey(path: &str) -> ResultType<String> {
+    if std::path::Path::new(path).exists() {
+        bail!("{} already exists", path);
+    }
+    let (pk, sk) = sign::gen_keypair();
+    std::fs::write(path, encode_key(&sk.0))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(encode_key(&pk.0))
+}
+
+fn sign_file(sk_path: &str, path: &str, out: &str) -> ResultType<()> {
+    let sk = decode_secret_key(&std::fs::read_to_string(sk_path)?)?;
+    let bundle: ConfigBundle = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    std::fs::write(out, sign_bundle(&bundle, &sk)?)?;
+    Ok(())
+}
+
+fn verify_file(path: &str, pk: Option<&String>) -> ResultType<String> {
+    let trusted = match pk {
+        Some(pk) => vec![decode_public_key(pk)?],
+        None => vec![],
+    };
+    let (bundle, signer) = open_bundle(&std::fs::read_to_string(path)?, &trusted)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    Ok(format!(
+        "signed by {}{}\n{}",
+        encode_key(&signer.0),
+        if bundle.is_expired(now) {
+            ", expired"
+        } else {
+            ""
+        },
+        serde_json::to_string_pretty(&bundle)?
+    ))
+}
+
 fn main() {
     let args: Vec<_> = std::env::args().skip(1).collect();
-    let api = args.get(2).cloned().unwrap_or_default();
-    let relay = args.get(3).cloned().unwrap_or_default();
-    if args.len() >= 2 {
-        match gen_name(&CustomServer {
-            key: args[0].clone(),
-            host: args[1].clone(),
-            api,
-            relay,
-        }) {
-            Ok(name) => println!("rustdesk-custom_serverd-{}.exe", name),
-            Err(e) => println!("{:?}", e),
+    let res = match args.first().map(|x| x.as_str()) {
+        Some("genkey") if args.len() == 2 => genkey(&args[1]),
+        Some("sign") if args.len() == 4 => {
+            sign_file(&args[1], &args[2], &args[3]).map(|_| args[3].clone())
         }
-    }
-    if args.len() == 1 {
-        println!("{:?}", get_custom_server_from_string(&args[0]));
+        Some("verify") if args.len() >= 2 => verify_file(&args[1], args.get(2)),
+        Some("genkey" | "sign" | "verify") | None => Ok(USAGE.to_owned()),
+        Some(_) if args.len() >= 2 => {
+            let api = args.get(2).cloned().unwrap_or_default();
+            let relay = args.get(3).cloned().unwrap_or_default();
+            gen_name(&CustomServer {
+                key: args[0].clone(),
+                host: args[1].clone(),
+                api,
+                relay,
+            })
+            .map(|name| format!("rustdesk-custom_serverd-{}.exe", name))
+        }
+        Some(_) => Ok(format!("{:?}", get_custom_server_from_string(&args[0]))),
+    };
+    match res {
+        Ok(s) => println!("{}", s),
+        Err(e) => println!("{:?}", e),
     }
 }
@@ -1,6 +1,12 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
 
-use crate::client::*;
+use crate::{
+    client::*,
+    tunnel::{self, Frame, FrameDecoder, Tunnel},
+};
 use hbb_common::{
     allow_err, bail,
     config::READ_TIMEOUT,
@@ -10,11 +16,17 @@ use hbb_common::{
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        net::{TcpStream, UdpSocket},
+        sync::mpsc,
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
 
+mod proxy;
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -70,7 +82,7 @@ pub async fn listen(
                 let id = id.clone();
                 let password = password.clone();
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
+                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), Some(&mut forward), key, token, is_rdp).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
                         tokio::spawn(async move {
@@ -103,12 +115,334 @@ pub async fn listen(
     Ok(())
 }
 
-async fn connect_and_login(
+/// Peer option holding reverse forward rules, e.g. `9000:localhost:22,9001:localhost:80`.
+pub const OPTION_REVERSE_PORT_FORWARDS: &str = "reverse-port-forwards";
+/// Peer option holding local SOCKS5/HTTP CONNECT listen ports, e.g. `1080,3128`.
+pub const OPTION_DYNAMIC_PORT_FORWARDS: &str = "dynamic-port-forwards";
+/// Peer option holding UDP forward rules, e.g. `5353:localhost:53`.
+pub const OPTION_UDP_PORT_FORWARDS: &str = "udp-port-forwards";
+/// Peer option to carry the GUI forward rules over one multiplexed session.
+pub const OPTION_PORT_FORWARD_MUX: &str = "port-forward-mux";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardRule {
+    /// Listen on local `port` and connect to `remote_host:remote_port` on the peer.
+    Local {
+        port: i32,
+        remote_host: String,
+        remote_port: i32,
+    },
+    /// Listen on `port` of the peer and connect to `local_host:local_port` here.
+    Remote {
+        port: i32,
+        local_host: String,
+        local_port: i32,
+    },
+    /// SOCKS5 / HTTP CONNECT proxy on local `port`, the client picks the target.
+    Dynamic { port: i32 },
+    /// Forward datagrams of local UDP `port` to `remote_host:remote_port` on the peer.
+    Udp {
+        port: i32,
+        remote_host: String,
+        remote_port: i32,
+    },
+}
+
+impl ForwardRule {
+    /// Parses `port:[host:]port`, prefixed with `R:` for a reverse rule or `U:`
+    /// for a UDP rule, or `D:port` for a dynamic rule.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(port) = s.strip_prefix("D:") {
+            let port = port.parse::<u16>().ok().filter(|p| *p > 0)? as i32;
+            return Some(ForwardRule::Dynamic { port });
+        }
+        let (prefix, s) = match s.split_once(':') {
+            Some((p @ ("R" | "U"), rest)) => (p, rest),
+            _ => ("", s),
+        };
+        let v: Vec<&str> = s.split(':').collect();
+        let (port, host, target_port) = match v.len() {
+            2 => (v[0], "localhost", v[1]),
+            3 => (v[0], v[1], v[2]),
+            _ => return None,
+        };
+        let port = port.parse::<u16>().ok().filter(|p| *p > 0)? as i32;
+        let target_port = target_port.parse::<u16>().ok().filter(|p| *p > 0)? as i32;
+        let host = if host.is_empty() { "localhost" } else { host }.to_owned();
+        Some(match prefix {
+            "R" => ForwardRule::Remote {
+                port,
+                local_host: host,
+                local_port: target_port,
+            },
+            "U" => ForwardRule::Udp {
+                port,
+                remote_host: host,
+                remote_port: target_port,
+            },
+            _ => ForwardRule::Local {
+                port,
+                remote_host: host,
+                remote_port: target_port,
+            },
+        })
+    }
+
+    /// The rules saved for the peer, reverse, dynamic and UDP rules come from
+    /// [`OPTION_REVERSE_PORT_FORWARDS`], [`OPTION_DYNAMIC_PORT_FORWARDS`] and
+    /// [`OPTION_UDP_PORT_FORWARDS`].
+    pub fn load(lc: &LoginConfigHandler) -> Vec<Self> {
+        let mut rules: Vec<Self> = lc
+            .port_forwards
+            .iter()
+            .map(|(port, remote_host, remote_port)| ForwardRule::Local {
+                port: *port,
+                remote_host: remote_host.clone(),
+                remote_port: *remote_port,
+            })
+            .collect();
+        rules.extend(
+            lc.get_option(OPTION_REVERSE_PORT_FORWARDS)
+                .split(',')
+                .filter_map(|x| Self::parse(&format!("R:{}", x.trim()))),
+        );
+        rules.extend(
+            lc.get_option(OPTION_DYNAMIC_PORT_FORWARDS)
+                .split(',')
+                .filter_map(|x| Self::parse(&format!("D:{}", x.trim()))),
+        );
+        rules.extend(
+            lc.get_option(OPTION_UDP_PORT_FORWARDS)
+                .split(',')
+                .filter_map(|x| Self::parse(&format!("U:{}", x.trim()))),
+        );
+        rules
+    }
+}
+
+/// Runs all `rules` over a single authenticated session.
+pub async fn listen_multi(
+    id: String,
+    password: String,
+    rules: Vec<ForwardRule>,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+) -> ResultType<()> {
+    let mut tunnel = Tunnel::new(true);
+    let mut local_targets = HashMap::<u16, String>::new();
+    let mut reverse_targets = HashMap::<u16, String>::new();
+    let mut dynamic_ports = HashSet::<u16>::new();
+    let mut udp_ports = 0;
+    // Proxy clients waiting for the peer to connect to their target.
+    let mut pending = HashMap::<u32, (TcpStream, proxy::Protocol)>::new();
+    let (tx_proxy, mut rx_proxy) =
+        mpsc::unbounded_channel::<(TcpStream, proxy::Protocol, String)>();
+    for rule in rules {
+        match rule {
+            ForwardRule::Local {
+                port,
+                remote_host,
+                remote_port,
+            } => {
+                let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+                log::info!(
+                    "listening on port {}, forward to {}:{}",
+                    port,
+                    remote_host,
+                    remote_port
+                );
+                tunnel.listen(port as _, listener);
+                local_targets.insert(port as _, format!("{}:{}", remote_host, remote_port));
+            }
+            ForwardRule::Remote {
+                port,
+                local_host,
+                local_port,
+            } => {
+                reverse_targets.insert(port as _, format!("{}:{}", local_host, local_port));
+            }
+            ForwardRule::Dynamic { port } => {
+                let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
+                log::info!("SOCKS5/HTTP proxy listening on port {}", port);
+                tunnel.listen(port as _, listener);
+                dynamic_ports.insert(port as _);
+            }
+            ForwardRule::Udp {
+                port,
+                remote_host,
+                remote_port,
+            } => {
+                let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+                log::info!(
+                    "listening on udp port {}, forward to {}:{}",
+                    port,
+                    remote_host,
+                    remote_port
+                );
+                let target = format!("{}:{}", remote_host, remote_port);
+                tunnel.listen_udp(port as _, socket, target);
+                udp_ports += 1;
+            }
+        }
+    }
+    lc.write().unwrap().port_forward = (tunnel::MUX_HOST.to_owned(), 0);
+    let mut ui_receiver = ui_receiver;
+    let mut stream = match connect_and_login(
+        &id,
+        &password,
+        &mut ui_receiver,
+        interface.clone(),
+        None,
+        key,
+        token,
+        false,
+    )
+    .await
+    {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            interface.on_establish_connection_error(err.to_string());
+            return Ok(());
+        }
+    };
+    for port in reverse_targets.keys() {
+        stream
+            .send_bytes(Frame::Listen { port: *port }.encode())
+            .await?;
+    }
+    log::info!(
+        "multiplexed port forwarding started, {} local, {} reverse, {} dynamic and {} udp rules",
+        local_targets.len(),
+        reverse_targets.len(),
+        dynamic_ports.len(),
+        udp_ports
+    );
+    let mut decoder = FrameDecoder::default();
+    loop {
+        tokio::select! {
+            res = stream.next() => match res {
+                Some(Ok(bytes)) => {
+                    decoder.feed(&bytes);
+                    while let Some(frame) = decoder.next()? {
+                        match frame {
+                            Frame::Data { channel, data } => tunnel.write(channel, data).await,
+                            Frame::Close { channel, reason } => {
+                                if !reason.is_empty() {
+                                    log::warn!("channel {} closed: {}", channel, reason);
+                                }
+                                if let Some((mut socket, protocol)) = pending.remove(&channel) {
+                                    let reply = if reason == tunnel::NOT_ALLOWED {
+                                        proxy::Reply::NotAllowed
+                                    } else {
+                                        proxy::Reply::Failed
+                                    };
+                                    proxy::reply(&mut socket, protocol, reply).await.ok();
+                                } else {
+                                    tunnel.close(channel);
+                                }
+                            }
+                            Frame::Opened { channel } => {
+                                if let Some((mut socket, protocol)) = pending.remove(&channel) {
+                                    let reply = proxy::Reply::Succeeded;
+                                    match proxy::reply(&mut socket, protocol, reply).await {
+                                        Ok(()) => tunnel.attach(channel, socket),
+                                        Err(err) => {
+                                            let reason = err.to_string();
+                                            let frame = Frame::Close { channel, reason };
+                                            stream.send_bytes(frame.encode()).await?;
+                                        }
+                                    }
+                                }
+                            }
+                            Frame::Accepted { channel, port } => match reverse_targets.get(&port) {
+                                Some(target) => tunnel.connect(channel, target.clone()),
+                                None => {
+                                    let reason = format!("No reverse rule for port {}", port);
+                                    let frame = Frame::Close { channel, reason };
+                                    stream.send_bytes(frame.encode()).await?;
+                                }
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+                Some(Err(err)) => bail!("Connection closed: {}", err),
+                None => bail!("Reset by the peer"),
+            },
+            Some(event) = tunnel.next() => match event {
+                tunnel::Event::Outgoing(frame) => {
+                    // Only report closes of channels the peer still knows about.
+                    if let Frame::Close { channel, .. } = &frame {
+                        if !tunnel.close(*channel) {
+                            continue;
+                        }
+                    }
+                    stream.send_bytes(frame.encode()).await?;
+                }
+                tunnel::Event::Incoming(port, mut socket) => {
+                    if dynamic_ports.contains(&port) {
+                        let tx_proxy = tx_proxy.clone();
+                        tokio::spawn(async move {
+                            match proxy::handshake(&mut socket).await {
+                                Ok((protocol, target)) => {
+                                    tx_proxy.send((socket, protocol, target)).ok();
+                                }
+                                Err(err) => log::warn!("proxy handshake failed: {}", err),
+                            }
+                        });
+                    } else if let Some(target) = local_targets.get(&port) {
+                        let channel = tunnel.next_channel();
+                        let target = target.clone();
+                        stream.send_bytes(Frame::Open { channel, target }.encode()).await?;
+                        tunnel.attach(channel, socket);
+                    }
+                }
+            },
+            Some((socket, protocol, target)) = rx_proxy.recv() => {
+                let channel = tunnel.next_channel();
+                log::info!("proxy channel {} to {}", channel, target);
+                stream.send_bytes(Frame::Open { channel, target }.encode()).await?;
+                pending.insert(channel, (socket, protocol));
+            }
+            d = ui_receiver.recv() => match d {
+                Some(Data::AddPortForward((port, remote_host, remote_port))) => {
+                    if port <= 0 || remote_port <= 0 {
+                        continue;
+                    }
+                    match tcp::new_listener(format!("0.0.0.0:{}", port), true).await {
+                        Ok(listener) => {
+                            tunnel.listen(port as _, listener);
+                            let target = format!("{}:{}", remote_host, remote_port);
+                            local_targets.insert(port as _, target);
+                        }
+                        Err(err) => {
+                            let err = format!("Failed to listen on {}: {}", port, err);
+                            interface.msgbox("error", "Error", &err, "");
+                        }
+                    }
+                }
+                Some(Data::RemovePortForward(port)) => {
+                    tunnel.unlisten(port as _);
+                    local_targets.remove(&(port as _));
+                }
+                Some(Data::Close) | None => break,
+                _ => {}
+            },
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn connect_and_login(
     id: &str,
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -178,7 +512,12 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = async {
+                match forward.as_mut() {
+                    Some(forward) => forward.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
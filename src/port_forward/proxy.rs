@@ -0,0 +1,160 @@
+//! SOCKS5 and HTTP CONNECT handshakes of the dynamic port forward listener.
+//!
+//! Only the handshake happens here, the connection itself is carried by a
+//! [`crate::tunnel`] channel once the peer reports the target connected.
+
+use hbb_common::{
+    bail,
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    },
+    ResultType,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const MAX_HTTP_HEADER: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    HttpConnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    Succeeded,
+    Failed,
+    NotAllowed,
+}
+
+/// Reads the proxy request of a freshly accepted client and returns the
+/// requested target as `host:port`.
+pub async fn handshake(socket: &mut TcpStream) -> ResultType<(Protocol, String)> {
+    let first = socket.read_u8().await?;
+    if first == SOCKS_VERSION {
+        Ok((Protocol::Socks5, socks5_handshake(socket).await?))
+    } else {
+        Ok((Protocol::HttpConnect, http_handshake(socket, first).await?))
+    }
+}
+
+pub async fn reply(socket: &mut TcpStream, protocol: Protocol, reply: Reply) -> ResultType<()> {
+    match protocol {
+        Protocol::Socks5 => {
+            let rep = match reply {
+                Reply::Succeeded => 0,
+                Reply::Failed => 1,
+                Reply::NotAllowed => 2,
+            };
+            // The bound address is meaningless through the tunnel, report 0.0.0.0:0.
+            socket
+                .write_all(&[SOCKS_VERSION, rep, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await?;
+        }
+        Protocol::HttpConnect => {
+            let status = match reply {
+                Reply::Succeeded => "200 Connection established",
+                Reply::Failed => "502 Bad Gateway",
+                Reply::NotAllowed => "403 Forbidden",
+            };
+            socket
+                .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn socks5_handshake(socket: &mut TcpStream) -> ResultType<String> {
+    let n = socket.read_u8().await? as usize;
+    let mut methods = vec![0u8; n];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        socket
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])
+            .await?;
+        bail!("SOCKS5 client requires authentication");
+    }
+    socket.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await?;
+    let mut head = [0u8; 4];
+    socket.read_exact(&mut head).await?;
+    if head[0] != SOCKS_VERSION {
+        bail!("Invalid SOCKS version {}", head[0]);
+    }
+    let host = match head[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let len = socket.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        atyp => {
+            // 8: address type not supported
+            socket
+                .write_all(&[SOCKS_VERSION, 8, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await?;
+            bail!("Unsupported SOCKS address type {}", atyp);
+        }
+    };
+    let port = socket.read_u16().await?;
+    if head[1] != SOCKS_CMD_CONNECT {
+        // 7: command not supported
+        socket
+            .write_all(&[SOCKS_VERSION, 7, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+            .await?;
+        bail!("Unsupported SOCKS command {}", head[1]);
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+async fn http_handshake(socket: &mut TcpStream, first: u8) -> ResultType<String> {
+    let mut header = vec![first];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER {
+            bail!("HTTP proxy request too large");
+        }
+        header.push(socket.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    match parse_connect_request(&header) {
+        Some(target) => Ok(target),
+        None => {
+            socket
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+                .await?;
+            bail!("Only HTTP CONNECT is supported");
+        }
+    }
+}
+
+fn parse_connect_request(header: &str) -> Option<String> {
+    let line = header.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.eq_ignore_ascii_case("CONNECT") {
+        return None;
+    }
+    let target = parts.next()?;
+    let (host, port) = target.rsplit_once(':')?;
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return None;
+    }
+    Some(target.to_owned())
+}
@@ -0,0 +1,315 @@
+//! Public key login.
+//!
+//! The controlled side lists the Ed25519 keys allowed to log in in the
+//! `authorized-keys` option, one per line, either in the OpenSSH format
+//! `ssh-ed25519 AAAA... comment` or as a base64 public key.
+//!
+//! The client signs the challenge of the login [`Hash`](hbb_common::message_proto::Hash)
+//! and the id it connects to, with the key of the `login-key` local option, see
+//! `--gen-login-key`, or with the first Ed25519 key of the ssh-agent if the
+//! `login-ssh-agent` local option is `Y`, or the public key to use. The answer
+//! is sent as the password of the login request, [`PREFIX`] followed by the
+//! public key and the signature, which can not be confused with a password hash.
+
+use hbb_common::{
+    bail,
+    config::{Config, LocalConfig},
+    log,
+    sodiumoxide::{base64, crypto::sign},
+    ResultType,
+};
+use std::io::{Read, Write};
+
+pub const OPTION_AUTHORIZED_KEYS: &str = "authorized-keys";
+pub const OPTION_LOGIN_KEY: &str = "login-key";
+pub const OPTION_LOGIN_SSH_AGENT: &str = "login-ssh-agent";
+
+const PREFIX: &[u8] = b"RDPK";
+const CONTEXT: &[u8] = b"rustdesk-login-v1\0";
+const SSH_ED25519: &str = "ssh-ed25519";
+const LEN: usize = PREFIX.len() + sign::PUBLICKEYBYTES + sign::SIGNATUREBYTES;
+
+fn message(id: &str, challenge: &str) -> Vec<u8> {
+    let mut msg = CONTEXT.to_vec();
+    msg.extend_from_slice(id.as_bytes());
+    msg.push(0);
+    msg.extend_from_slice(challenge.as_bytes());
+    msg
+}
+
+fn ssh_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn read_ssh_string<'a>(data: &mut &'a [u8]) -> ResultType<&'a [u8]> {
+    if data.len() < 4 {
+        bail!("truncated");
+    }
+    let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if data.len() < 4 + len {
+        bail!("truncated");
+    }
+    let s = &data[4..4 + len];
+    *data = &data[4 + len..];
+    Ok(s)
+}
+
+pub(crate) fn ssh_key_blob(pk: &[u8]) -> Vec<u8> {
+    let mut blob = vec![];
+    ssh_string(&mut blob, SSH_ED25519.as_bytes());
+    ssh_string(&mut blob, pk);
+    blob
+}
+
+fn parse_ssh_key_blob(mut blob: &[u8]) -> ResultType<sign::PublicKey> {
+    if read_ssh_string(&mut blob)? != SSH_ED25519.as_bytes() {
+        bail!("not an ed25519 key");
+    }
+    match sign::PublicKey::from_slice(read_ssh_string(&mut blob)?) {
+        Some(pk) => Ok(pk),
+        None => bail!("invalid ed25519 key"),
+    }
+}
+
+/// Parses `ssh-ed25519 AAAA... comment` or a base64 public key.
+pub fn parse_public_key(s: &str) -> ResultType<sign::PublicKey> {
+    let mut parts = s.split_whitespace();
+    let (Some(first), second) = (parts.next(), parts.next()) else {
+        bail!("empty key");
+    };
+    let decode = |s: &str| match base64::decode(s, base64::Variant::Original) {
+        Ok(v) => Ok(v),
+        Err(_) => bail!("invalid base64"),
+    };
+    match (first, second) {
+        (SSH_ED25519, Some(blob)) => parse_ssh_key_blob(&decode(blob)?),
+        (key, _) => match sign::PublicKey::from_slice(&decode(key)?) {
+            Some(pk) => Ok(pk),
+            None => bail!("invalid ed25519 key"),
+        },
+    }
+}
+
+pub fn format_public_key(pk: &sign::PublicKey, comment: &str) -> String {
+    format!(
+        "{} {} {}",
+        SSH_ED25519,
+        base64::encode(ssh_key_blob(&pk.0), base64::Variant::Original),
+        comment
+    )
+}
+
+fn authorized_keys() -> Vec<(sign::PublicKey, String)> {
+    Config::get_option(OPTION_AUTHORIZED_KEYS)
+        .split(|c| c == '\n' || c == ',')
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| match parse_public_key(l) {
+            Ok(pk) => Some((pk, l.to_owned())),
+            Err(err) => {
+                log::error!("Invalid authorized key {}: {}", l, err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn has_authorized_keys() -> bool {
+    !authorized_keys().is_empty()
+}
+
+/// Verifies the answer of the login request, returns the authorized key
+/// which signed it.
+pub fn verify(password: &[u8], id: &str, challenge: &str) -> Option<String> {
+    if password.len() != LEN || !password.starts_with(PREFIX) {
+        return None;
+    }
+    let (pk, sig) = password[PREFIX.len()..].split_at(sign::PUBLICKEYBYTES);
+    let pk = sign::PublicKey::from_slice(pk)?;
+    let sig = sign::Signature::from_bytes(sig).ok()?;
+    let (_, line) = authorized_keys().into_iter().find(|(k, _)| *k == pk)?;
+    if sign::verify_detached(&sig, &message(id, challenge), &pk) {
+        Some(line)
+    } else {
+        log::warn!("Invalid signature of authorized key {}", line);
+        None
+    }
+}
+
+/// Signs the challenge with the configured key, `None` if there is none.
+pub fn sign(id: &str, challenge: &str) -> Option<Vec<u8>> {
+    let msg = message(id, challenge);
+    let agent = LocalConfig::get_option(OPTION_LOGIN_SSH_AGENT);
+    let signed = if !agent.is_empty() && agent != "N" {
+        let want = if agent == "Y" {
+            None
+        } else {
+            parse_public_key(&agent).ok()
+        };
+        agent_sign(&msg, want).map_err(|err| log::error!("ssh-agent: {}", err))
+    } else {
+        let key = LocalConfig::get_option(OPTION_LOGIN_KEY);
+        if key.is_empty() {
+            return None;
+        }
+        match base64::decode(&key, base64::Variant::Original)
+            .ok()
+            .and_then(|sk| sign::SecretKey::from_slice(&sk))
+        {
+            Some(sk) => Ok((sk.public_key(), sign::sign_detached(&msg, &sk))),
+            None => {
+                log::error!("Invalid login key");
+                return None;
+            }
+        }
+    };
+    let (pk, sig) = signed.ok()?;
+    let mut out = PREFIX.to_vec();
+    out.extend_from_slice(&pk.0);
+    out.extend_from_slice(&sig.to_bytes());
+    Some(out)
+}
+
+/// Generates the `login-key` if missing, returns its public key.
+pub fn gen_login_key() -> String {
+    let key = LocalConfig::get_option(OPTION_LOGIN_KEY);
+    let sk = base64::decode(&key, base64::Variant::Original)
+        .ok()
+        .and_then(|sk| sign::SecretKey::from_slice(&sk));
+    let pk = match sk {
+        Some(sk) => sk.public_key(),
+        None => {
+            let (pk, sk) = sign::gen_keypair();
+            LocalConfig::set_option(
+                OPTION_LOGIN_KEY.to_owned(),
+                base64::encode(&sk.0, base64::Variant::Original),
+            );
+            pk
+        }
+    };
+    format_public_key(&pk, &format!("rustdesk@{}", Config::get_id()))
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+fn agent_connect() -> ResultType<Box<dyn Stream>> {
+    let path = std::env::var("SSH_AUTH_SOCK").unwrap_or_default();
+    #[cfg(unix)]
+    {
+        if path.is_empty() {
+            bail!("SSH_AUTH_SOCK is not set");
+        }
+        Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+    }
+    #[cfg(windows)]
+    {
+        let path = if path.is_empty() {
+            r"\\.\pipe\openssh-ssh-agent".to_owned()
+        } else {
+            path
+        };
+        Ok(Box::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?,
+        ))
+    }
+    #[cfg(not(any(unix, windows)))]
+    bail!("ssh-agent is not supported on this platform")
+}
+
+fn agent_request(stream: &mut dyn Stream, msg: &[u8]) -> ResultType<Vec<u8>> {
+    let mut data = vec![];
+    ssh_string(&mut data, msg);
+    stream.write_all(&data)?;
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > 256 * 1024 {
+        bail!("invalid reply length {}", len);
+    }
+    let mut reply = vec![0u8; len];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+fn agent_sign(
+    msg: &[u8],
+    want: Option<sign::PublicKey>,
+) -> ResultType<(sign::PublicKey, sign::Signature)> {
+    let mut stream = agent_connect()?;
+    let reply = agent_request(&mut *stream, &[SSH_AGENTC_REQUEST_IDENTITIES])?;
+    if reply[0] != SSH_AGENT_IDENTITIES_ANSWER || reply.len() < 5 {
+        bail!("failed to list identities");
+    }
+    let n = u32::from_be_bytes([reply[1], reply[2], reply[3], reply[4]]);
+    let mut data = &reply[5..];
+    let mut found = None;
+    for _ in 0..n {
+        let blob = read_ssh_string(&mut data)?;
+        read_ssh_string(&mut data)?;
+        if let Ok(pk) = parse_ssh_key_blob(blob) {
+            if want.map_or(true, |w| w == pk) {
+                found = Some((pk, blob.to_vec()));
+                break;
+            }
+        }
+    }
+    let Some((pk, blob)) = found else {
+        bail!("no ed25519 identity");
+    };
+    let mut req = vec![SSH_AGENTC_SIGN_REQUEST];
+    ssh_string(&mut req, &blob);
+    ssh_string(&mut req, msg);
+    req.extend_from_slice(&0u32.to_be_bytes());
+    let reply = agent_request(&mut *stream, &req)?;
+    if reply[0] != SSH_AGENT_SIGN_RESPONSE {
+        bail!("signing refused");
+    }
+    let mut data = &reply[1..];
+    let mut sig = read_ssh_string(&mut data)?;
+    if read_ssh_string(&mut sig)? != SSH_ED25519.as_bytes() {
+        bail!("unexpected signature type");
+    }
+    match sign::Signature::from_bytes(read_ssh_string(&mut sig)?) {
+        Ok(sig) => Ok((pk, sig)),
+        Err(_) => bail!("invalid signature"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pubkey_auth() {
+        let (pk, sk) = sign::gen_keypair();
+        let line = format_public_key(&pk, "test");
+        assert!(line.starts_with("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5"));
+        assert_eq!(parse_public_key(&line).unwrap(), pk);
+        let raw = base64::encode(&pk.0, base64::Variant::Original);
+        assert_eq!(parse_public_key(&raw).unwrap(), pk);
+        assert!(parse_public_key("ssh-rsa AAAA").is_err());
+
+        let sig = sign::sign_detached(&message("123", "challenge"), &sk);
+        assert!(sign::verify_detached(
+            &sig,
+            &message("123", "challenge"),
+            &pk
+        ));
+        assert!(!sign::verify_detached(
+            &sig,
+            &message("1234", "challenge"),
+            &pk
+        ));
+    }
+}
@@ -0,0 +1,635 @@
+//! QUIC transport for peer connections.
+//!
+//! The frames of the single [`Stream`] of a session are spread by kind over
+//! QUIC unidirectional streams, so a large file copy or a burst of video does
+//! not hold up input or control messages behind it on lossy, high latency
+//! links. Each stream starts with its channel byte.
+//!
+//! Frames of different channels may be reordered, so the session key is not
+//! applied by the [`Stream`], whose nonces are sequential over all frames, but
+//! per channel here, see [`QuicStream::set_key`]. The nonces of a channel only
+//! go forward, each channel is a single stream and a peer opening another one
+//! for the same channel is closed, so sealed frames can't be replayed.
+//!
+//! The TLS certificate of QUIC is self-signed and not verified, the peer is
+//! authenticated by the key exchange of the session, whose key seals the
+//! frames.
+
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    bytes::{Bytes, BytesMut},
+    bytes_codec::BytesCodec,
+    futures::StreamExt,
+    log,
+    message_proto::Message,
+    protobuf::MessageFull,
+    sodiumoxide::crypto::secretbox,
+    tcp::{DynTcpStream, FramedStream},
+    tokio::{
+        self,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+        sync::{mpsc, watch},
+    },
+    tokio_util::{
+        codec::{Decoder, Encoder, Framed, FramedRead},
+        sync::PollSender,
+    },
+    ResultType, Stream,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+pub const TYPE: &str = "QUIC";
+/// Peer option of the client, `quic` to connect by QUIC.
+pub const OPTION_TRANSPORT: &str = "transport";
+/// Server option to accept QUIC on the UDP port of the direct server.
+pub const OPTION_DIRECT_QUIC: &str = "direct-access-quic";
+
+const SERVER_NAME: &str = "rustdesk";
+const ALPN: &[u8] = b"rustdesk";
+
+const CHANNEL_CONTROL: u8 = 0;
+const CHANNEL_VIDEO: u8 = 1;
+const CHANNEL_AUDIO: u8 = 2;
+const CHANNEL_FILE: u8 = 3;
+const CHANNEL_INPUT: u8 = 4;
+const CHANNELS: usize = 5;
+// Frames queued per channel before writes wait for QUIC to send them.
+const CHANNEL_CAPACITY: usize = 32;
+// Indexed by channel, higher is sent first.
+const PRIORITIES: [i32; CHANNELS] = [2, 0, 1, -1, 3];
+
+const FLAG_PLAIN: u8 = 0;
+const FLAG_SEALED: u8 = 1;
+
+lazy_static::lazy_static! {
+    // Field number of the `union` of a [`Message`] to its channel.
+    static ref CHANNEL_OF_FIELD: Vec<(i32, u8)> = {
+        let descriptor = Message::descriptor();
+        [
+            ("video_frame", CHANNEL_VIDEO),
+            ("audio_frame", CHANNEL_AUDIO),
+            ("file_action", CHANNEL_FILE),
+            ("file_response", CHANNEL_FILE),
+            ("mouse_event", CHANNEL_INPUT),
+            ("key_event", CHANNEL_INPUT),
+            ("pointer_device_event", CHANNEL_INPUT),
+        ]
+        .iter()
+        .filter_map(|(name, channel)| {
+            descriptor
+                .field_by_name(name)
+                .map(|f| (f.proto().number(), *channel))
+        })
+        .collect()
+    };
+}
+
+/// The channel of an encoded [`Message`], from the key of its first field.
+fn channel_of(frame: &[u8]) -> u8 {
+    let Some(field) = crate::traffic::field_number(frame) else {
+        return CHANNEL_CONTROL;
+    };
+    CHANNEL_OF_FIELD
+        .iter()
+        .find(|(f, _)| *f == field)
+        .map(|(_, c)| *c)
+        .unwrap_or(CHANNEL_CONTROL)
+}
+
+fn nonce(seq: u64, channel: u8, from_client: bool) -> secretbox::Nonce {
+    let mut nonce = secretbox::Nonce([0u8; secretbox::NONCEBYTES]);
+    nonce.0[..8].copy_from_slice(&seq.to_le_bytes());
+    nonce.0[8] = channel;
+    nonce.0[9] = from_client as u8;
+    nonce
+}
+
+/// Handle of a QUIC connection, to hand over the session key.
+#[derive(Clone)]
+pub struct QuicStream {
+    key: Arc<watch::Sender<Option<secretbox::Key>>>,
+}
+
+impl QuicStream {
+    /// Replaces [`Stream::set_key`] for QUIC connections.
+    pub fn set_key(&self, key: secretbox::Key) {
+        self.key.send_replace(Some(key));
+    }
+
+    fn create(
+        endpoint: quinn::Endpoint,
+        conn: quinn::Connection,
+        is_client: bool,
+    ) -> (Self, Stream) {
+        let (key_tx, key_rx) = watch::channel(None);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(read_channels(
+            conn.clone(),
+            incoming_tx,
+            key_rx.clone(),
+            !is_client,
+        ));
+        let outgoing = std::array::from_fn(|channel| {
+            let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+            tokio::spawn(write_channel(conn.clone(), channel as u8, rx));
+            PollSender::new(tx)
+        });
+        let local_addr = endpoint
+            .local_addr()
+            .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let io = QuicIo {
+            _endpoint: endpoint,
+            conn,
+            is_client,
+            key: key_rx,
+            outgoing,
+            pending: Default::default(),
+            send_seq: [0; CHANNELS],
+            incoming,
+            wbuf: BytesMut::new(),
+            rbuf: BytesMut::new(),
+            decoder: BytesCodec::new(),
+            encoder: BytesCodec::new(),
+        };
+        let stream = Stream::Tcp(FramedStream(
+            Framed::new(DynTcpStream(Box::new(io)), BytesCodec::new()),
+            local_addr,
+            None,
+            0,
+        ));
+        (
+            Self {
+                key: Arc::new(key_tx),
+            },
+            stream,
+        )
+    }
+
+    pub async fn connect(addr: &str, ms_timeout: u64) -> ResultType<(Self, Stream)> {
+        let Some(addr) = tokio::net::lookup_host(addr).await?.next() else {
+            bail!("Failed to resolve {}", addr);
+        };
+        let bind = if addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let mut endpoint = quinn::Endpoint::client(bind)?;
+        endpoint.set_default_client_config(client_config()?);
+        let connecting = endpoint.connect(addr, SERVER_NAME)?;
+        let conn = hbb_common::timeout(ms_timeout, connecting).await??;
+        log::info!("QUIC connected to {}", addr);
+        Ok(Self::create(endpoint, conn, true))
+    }
+}
+
+struct QuicIo {
+    _endpoint: quinn::Endpoint,
+    conn: quinn::Connection,
+    is_client: bool,
+    key: watch::Receiver<Option<secretbox::Key>>,
+    outgoing: [PollSender<Bytes>; CHANNELS],
+    // Frames not yet taken by their full channel
+    pending: [VecDeque<Bytes>; CHANNELS],
+    send_seq: [u64; CHANNELS],
+    incoming: mpsc::UnboundedReceiver<Bytes>,
+    wbuf: BytesMut,
+    rbuf: BytesMut,
+    decoder: BytesCodec,
+    encoder: BytesCodec,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "QUIC connection closed")
+}
+
+impl QuicIo {
+    fn queue(&mut self, frame: BytesMut) {
+        let channel = channel_of(&frame);
+        let key = self.key.borrow().clone();
+        let mut out = Vec::with_capacity(1 + secretbox::MACBYTES + frame.len());
+        match key {
+            Some(key) => {
+                let seq = &mut self.send_seq[channel as usize];
+                *seq += 1;
+                out.push(FLAG_SEALED);
+                out.extend(secretbox::seal(
+                    &frame,
+                    &nonce(*seq, channel, self.is_client),
+                    &key,
+                ));
+            }
+            None => {
+                out.push(FLAG_PLAIN);
+                out.extend_from_slice(&frame);
+            }
+        }
+        self.pending[channel as usize].push_back(out.into());
+    }
+
+    /// Moves the queued frames to their channels, ready once all are taken.
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut ready = true;
+        for (tx, pending) in self.outgoing.iter_mut().zip(self.pending.iter_mut()) {
+            while !pending.is_empty() {
+                match tx.poll_reserve(cx) {
+                    Poll::Ready(Ok(())) => {
+                        if let Some(frame) = pending.pop_front() {
+                            tx.send_item(frame).map_err(|_| closed())?;
+                        }
+                    }
+                    Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+                    Poll::Pending => {
+                        ready = false;
+                        break;
+                    }
+                }
+            }
+        }
+        if ready {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncRead for QuicIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.rbuf.is_empty() {
+            match this.incoming.poll_recv(cx) {
+                Poll::Ready(Some(frame)) => this.encoder.encode(frame, &mut this.rbuf)?,
+                // EOF
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(this.rbuf.len());
+        buf.put_slice(&this.rbuf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for QuicIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Wait until the previous frames are taken, so a full channel pushes back.
+        ready!(this.poll_send_pending(cx))?;
+        this.wbuf.extend_from_slice(data);
+        while let Some(frame) = this.decoder.decode(&mut this.wbuf)? {
+            this.queue(frame);
+        }
+        // The frames are accepted, the next write or flush waits for them.
+        let _ = this.poll_send_pending(cx)?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.close(0u32.into(), b"shutdown");
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for QuicIo {
+    fn drop(&mut self) {
+        self.conn.close(0u32.into(), b"closed");
+    }
+}
+
+async fn write_channel(conn: quinn::Connection, channel: u8, mut rx: mpsc::Receiver<Bytes>) {
+    let mut send: Option<quinn::SendStream> = None;
+    let mut codec = BytesCodec::new();
+    while let Some(frame) = rx.recv().await {
+        let res: ResultType<()> = async {
+            if send.is_none() {
+                let mut s = conn.open_uni().await?;
+                s.set_priority(PRIORITIES[channel as usize]).ok();
+                s.write_all(&[channel]).await?;
+                send = Some(s);
+            }
+            let mut buf = BytesMut::new();
+            codec.encode(frame, &mut buf)?;
+            if let Some(s) = send.as_mut() {
+                s.write_all(&buf).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = res {
+            log::debug!("QUIC channel {} write error: {}", channel, err);
+            conn.close(1u32.into(), b"write error");
+            return;
+        }
+    }
+    if let Some(mut s) = send {
+        s.finish().ok();
+    }
+}
+
+async fn read_channels(
+    conn: quinn::Connection,
+    tx: mpsc::UnboundedSender<Bytes>,
+    key: watch::Receiver<Option<secretbox::Key>>,
+    from_client: bool,
+) {
+    // The sequence of a channel restarts with its stream, so only one each.
+    let opened: Arc<Mutex<[bool; CHANNELS]>> = Default::default();
+    while let Ok(recv) = conn.accept_uni().await {
+        let (conn, tx, key, opened) = (conn.clone(), tx.clone(), key.clone(), opened.clone());
+        tokio::spawn(async move {
+            if let Err(err) = read_channel(recv, tx, key, from_client, opened).await {
+                log::error!("QUIC read error: {}", err);
+                conn.close(1u32.into(), b"read error");
+            }
+        });
+    }
+}
+
+async fn read_channel(
+    mut recv: quinn::RecvStream,
+    tx: mpsc::UnboundedSender<Bytes>,
+    mut key: watch::Receiver<Option<secretbox::Key>>,
+    from_client: bool,
+    opened: Arc<Mutex<[bool; CHANNELS]>>,
+) -> ResultType<()> {
+    let mut channel = [0u8];
+    recv.read_exact(&mut channel).await?;
+    let channel = channel[0];
+    if channel as usize >= CHANNELS {
+        bail!("invalid channel {}", channel);
+    }
+    if std::mem::replace(&mut opened.lock().unwrap()[channel as usize], true) {
+        bail!("channel {} opened twice", channel);
+    }
+    let mut framed = FramedRead::new(recv, BytesCodec::new());
+    let mut seq = 0u64;
+    while let Some(frame) = framed.next().await {
+        let frame = frame?;
+        let Some((&flag, data)) = frame.split_first() else {
+            bail!("empty frame");
+        };
+        let plain = match flag {
+            FLAG_PLAIN => {
+                if key.borrow().is_some() {
+                    bail!("plain frame after the key exchange");
+                }
+                Bytes::copy_from_slice(data)
+            }
+            FLAG_SEALED => {
+                seq += 1;
+                let k = key
+                    .wait_for(|k| k.is_some())
+                    .await?
+                    .clone()
+                    .ok_or_else(|| anyhow!("no key"))?;
+                match secretbox::open(data, &nonce(seq, channel, from_client), &k) {
+                    Ok(plain) => plain.into(),
+                    Err(()) => bail!("failed to decrypt on channel {}", channel),
+                }
+            }
+            _ => bail!("invalid frame flag {}", flag),
+        };
+        if tx.send(plain).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn transport_config() -> ResultType<Arc<quinn::TransportConfig>> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(Duration::from_secs(5)));
+    transport.max_idle_timeout(Some(Duration::from_secs(30).try_into()?));
+    // BBR copes better than loss based controllers with long, lossy links.
+    transport.congestion_controller_factory(Arc::new(quinn::congestion::BbrConfig::default()));
+    Ok(Arc::new(transport))
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config() -> ResultType<quinn::ClientConfig> {
+    let provider = crypto_provider();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config()?);
+    Ok(config)
+}
+
+fn server_config() -> ResultType<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key.into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config()?);
+    Ok(config)
+}
+
+pub struct QuicListener {
+    endpoint: quinn::Endpoint,
+}
+
+impl QuicListener {
+    pub fn bind(port: u16) -> ResultType<Self> {
+        let config = server_config()?;
+        let endpoint = match quinn::Endpoint::server(
+            config.clone(),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ) {
+            Ok(endpoint) => endpoint,
+            Err(_) => {
+                quinn::Endpoint::server(config, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?
+            }
+        };
+        Ok(Self { endpoint })
+    }
+
+    pub fn local_addr(&self) -> ResultType<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Accepts a connection attempt, without waiting for its handshake.
+    pub async fn accept(&self) -> ResultType<QuicIncoming> {
+        let Some(incoming) = self.endpoint.accept().await else {
+            bail!("QUIC endpoint closed");
+        };
+        Ok(QuicIncoming {
+            endpoint: self.endpoint.clone(),
+            incoming,
+        })
+    }
+}
+
+/// A connection attempt, whose handshake may take several round trips on high
+/// latency links, so each is run in its own task.
+pub struct QuicIncoming {
+    endpoint: quinn::Endpoint,
+    incoming: quinn::Incoming,
+}
+
+impl QuicIncoming {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.incoming.remote_address()
+    }
+
+    pub async fn handshake(self) -> ResultType<(QuicStream, Stream)> {
+        let conn = self.incoming.await?;
+        Ok(QuicStream::create(self.endpoint, conn, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::{message_proto::*, protobuf::Message as _};
+
+    #[test]
+    fn test_channel_of() {
+        let mut msg = Message::new();
+        msg.set_video_frame(VideoFrame::new());
+        assert_eq!(channel_of(&msg.write_to_bytes().unwrap()), CHANNEL_VIDEO);
+        msg.set_mouse_event(MouseEvent::new());
+        assert_eq!(channel_of(&msg.write_to_bytes().unwrap()), CHANNEL_INPUT);
+        msg.set_file_action(FileAction::new());
+        assert_eq!(channel_of(&msg.write_to_bytes().unwrap()), CHANNEL_FILE);
+        msg.set_login_request(LoginRequest::new());
+        assert_eq!(channel_of(&msg.write_to_bytes().unwrap()), CHANNEL_CONTROL);
+        assert_eq!(channel_of(&[]), CHANNEL_CONTROL);
+        assert_ne!(nonce(1, 0, true), nonce(1, 0, false));
+        assert_ne!(nonce(1, 0, true), nonce(1, 1, true));
+    }
+
+    #[tokio::test]
+    async fn test_replay_rejected() {
+        let listener = QuicListener::bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move { listener.accept().await?.handshake().await });
+        let mut endpoint =
+            quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        endpoint.set_default_client_config(client_config().unwrap());
+        let conn = endpoint
+            .connect(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), SERVER_NAME)
+            .unwrap()
+            .await
+            .unwrap();
+        let (quic, mut stream) = server.await.unwrap().unwrap();
+        let key = secretbox::gen_key();
+        quic.set_key(key.clone());
+
+        let mut msg = Message::new();
+        msg.set_key_event(KeyEvent::new());
+        let mut frame = vec![FLAG_SEALED];
+        frame.extend(secretbox::seal(
+            &msg.write_to_bytes().unwrap(),
+            &nonce(1, CHANNEL_INPUT, true),
+            &key,
+        ));
+        let mut buf = BytesMut::new();
+        BytesCodec::new()
+            .encode(Bytes::from(frame), &mut buf)
+            .unwrap();
+        let send = |data: BytesMut| {
+            let conn = conn.clone();
+            async move {
+                let mut s = conn.open_uni().await.unwrap();
+                s.write_all(&[CHANNEL_INPUT]).await.unwrap();
+                s.write_all(&data).await.unwrap();
+                s.finish().ok();
+            }
+        };
+
+        send(buf.clone()).await;
+        let received = hbb_common::timeout(3000, stream.next()).await.unwrap();
+        assert_eq!(
+            Message::parse_from_bytes(&received.unwrap().unwrap()).unwrap(),
+            msg
+        );
+        send(buf).await;
+        let replayed = hbb_common::timeout(3000, stream.next()).await.unwrap();
+        assert!(!matches!(replayed, Some(Ok(_))));
+    }
+}
@@ -77,6 +77,11 @@ impl RendezvousMediator {
         tokio::spawn(async move {
             direct_server(server_cloned).await;
         });
+        let server_cloned = server.clone();
+        tokio::spawn(async move {
+            quic_server(server_cloned).await;
+        });
+        tokio::spawn(crate::failover::start());
         #[cfg(target_os = "android")]
         let start_lan_listening = true;
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -85,6 +90,9 @@ impl RendezvousMediator {
             std::thread::spawn(move || {
                 allow_err!(super::lan::start_listening());
             });
+            std::thread::spawn(move || {
+                allow_err!(super::lan::mdns::advertise());
+            });
         }
         // It is ok to run xdesktop manager when the headless function is not allowed.
         #[cfg(target_os = "linux")]
@@ -96,6 +104,11 @@ impl RendezvousMediator {
             let timeout = Arc::new(RwLock::new(CONNECT_TIMEOUT));
             let conn_start_time = Instant::now();
             *SOLVING_PK_MISMATCH.lock().await = "".to_owned();
+            if crate::is_no_rendezvous() {
+                // Only the direct server serves, see `direct_server`.
+                sleep(1.).await;
+                continue;
+            }
             if !config::option2bool("stop-service", &Config::get_option("stop-service"))
                 && !crate::platform::installing_service()
             {
@@ -107,7 +120,7 @@ impl RendezvousMediator {
                     let server = server.clone();
                     let timeout = timeout.clone();
                     futs.push(tokio::spawn(async move {
-                        if let Err(err) = Self::start(server, host).await {
+                        if let Err(err) = Self::start(server, host.clone()).await {
                             let err = format!("rendezvous mediator error: {err}");
                             // When user reboot, there might be below error, waiting too long
                             // (CONNECT_TIMEOUT 18s) will make user think there is bug
@@ -117,6 +130,7 @@ impl RendezvousMediator {
                                 *timeout.write().unwrap() = 3000;
                             }
                             log::error!("{err}");
+                            crate::failover::on_failure(&host);
                         }
                         // SHOULD_EXIT here is to ensure once one exits, the others also exit.
                         SHOULD_EXIT.store(true, Ordering::SeqCst);
@@ -241,6 +255,9 @@ impl RendezvousMediator {
                             if fails >= MAX_FAILS2 {
                                 Config::update_latency(&host, -1);
                                 old_latency = 0;
+                                if fails == MAX_FAILS2 {
+                                    crate::failover::on_failure(&host);
+                                }
                                 if last_dns_check.elapsed().as_millis() as i64 > DNS_INTERVAL {
                                     // in some case of network reconnect (dial IP network),
                                     // old UDP socket not work any more after network recover
@@ -703,7 +720,8 @@ impl RendezvousMediator {
     }
 
     fn get_relay_server(&self, provided_by_rendezvous_server: String) -> String {
-        let mut relay_server = Config::get_option("relay-server");
+        let mut relay_server =
+            crate::failover::relay_server().unwrap_or_else(|| Config::get_option("relay-server"));
         if relay_server.is_empty() {
             relay_server = provided_by_rendezvous_server;
         }
@@ -728,10 +746,13 @@ async fn direct_server(server: ServerPtr) {
     let mut listener = None;
     let mut port = 0;
     loop {
-        let disabled = !option2bool(
-            OPTION_DIRECT_SERVER,
-            &Config::get_option(OPTION_DIRECT_SERVER),
-        ) || option2bool("stop-service", &Config::get_option("stop-service"));
+        let no_rendezvous = crate::is_no_rendezvous();
+        let disabled = !(no_rendezvous
+            || option2bool(
+                OPTION_DIRECT_SERVER,
+                &Config::get_option(OPTION_DIRECT_SERVER),
+            ))
+            || option2bool("stop-service", &Config::get_option("stop-service"));
         if !disabled && listener.is_none() {
             port = get_direct_port();
             match hbb_common::tcp::listen_any(port as _).await {
@@ -777,7 +798,8 @@ async fn direct_server(server: ServerPtr) {
                             server,
                             hbb_common::Stream::from(stream, local_addr),
                             addr,
-                            false,
+                            // Without rendezvous server, the client pins our host key.
+                            no_rendezvous,
                         )
                         .await
                     );
@@ -791,6 +813,82 @@ async fn direct_server(server: ServerPtr) {
     }
 }
 
+/// Accepts QUIC on the UDP port of the direct server, see [`crate::quic_stream`].
+async fn quic_server(server: ServerPtr) {
+    let mut listener: Option<crate::quic_stream::QuicListener> = None;
+    let mut port = 0;
+    loop {
+        let no_rendezvous = crate::is_no_rendezvous();
+        let disabled = !(no_rendezvous
+            || option2bool(
+                OPTION_DIRECT_SERVER,
+                &Config::get_option(OPTION_DIRECT_SERVER),
+            ))
+            || !option2bool(
+                crate::quic_stream::OPTION_DIRECT_QUIC,
+                &Config::get_option(crate::quic_stream::OPTION_DIRECT_QUIC),
+            )
+            || option2bool("stop-service", &Config::get_option("stop-service"));
+        if disabled || port != get_direct_port() {
+            if listener.take().is_some() {
+                log::info!("Exit QUIC direct access listen");
+            }
+        }
+        if !disabled && listener.is_none() {
+            port = get_direct_port();
+            match crate::quic_stream::QuicListener::bind(port as _) {
+                Ok(l) => {
+                    log::info!("QUIC direct server listening on: {:?}", l.local_addr());
+                    listener = Some(l);
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to start QUIC direct server on port: {}, error: {}",
+                        port,
+                        err
+                    );
+                    sleep(3.).await;
+                    continue;
+                }
+            }
+        }
+        let Some(l) = listener.as_ref() else {
+            sleep(1.).await;
+            continue;
+        };
+        match hbb_common::timeout(1000, l.accept()).await {
+            Ok(Ok(incoming)) => {
+                let addr = incoming.remote_address();
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let (quic, stream) = match incoming.handshake().await {
+                        Ok(x) => x,
+                        Err(err) => {
+                            log::debug!("QUIC handshake with {} failed: {}", addr, err);
+                            return;
+                        }
+                    };
+                    log::info!("QUIC direct access from {}", addr);
+                    allow_err!(
+                        crate::server::create_quic_connection(
+                            server,
+                            stream,
+                            addr,
+                            no_rendezvous,
+                            quic
+                        )
+                        .await
+                    );
+                });
+            }
+            Ok(Err(err)) => {
+                log::debug!("QUIC accept failed: {}", err);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
 enum Sink<'a> {
     Framed(&'a mut FramedSocket, &'a TargetAddr<'a>),
     Stream(&'a mut Stream),
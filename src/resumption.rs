@@ -0,0 +1,195 @@
+//! Resumption of a session over a new path.
+//!
+//! When the `session-resume-grace` option is set, in seconds, the controlled
+//! side hands out a ticket after the login of an encrypted session, in the
+//! `platform_additions` of the [`PeerInfo`](hbb_common::message_proto::PeerInfo),
+//! and keeps the session for the grace period when its path breaks, e.g. the relay server
+//! restarts or the network of either side changes. The client connects again
+//! with the ticket and the session moves to the new stream, without a new
+//! login or approval.
+//!
+//! The ticket is proved by answering the challenge of the login
+//! [`Hash`](hbb_common::message_proto::Hash): [`PREFIX`], the id of the ticket
+//! and the sha256 of its secret and the challenge, sent as the password of
+//! the login request. The secret itself is never sent again.
+//!
+//! Messages in flight when the path broke are lost. The video is refreshed
+//! and both sides exchange how many blocks each file job sent or received,
+//! the jobs which lost some are cancelled.
+
+use hbb_common::{
+    config::Config,
+    log,
+    sha2::{Digest, Sha256},
+    sodiumoxide::{randombytes, utils::memcmp},
+    tokio::time::Duration,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+pub const OPTION_SESSION_RESUME_GRACE: &str = "session-resume-grace";
+pub const LOGIN_MSG_RESUME_FAILED: &str = "Failed to resume the session";
+
+const KEY_TICKET: &str = "resume_ticket";
+const KEY_GRACE: &str = "resume_grace";
+const KEY_RESUMED: &str = "resumed";
+const PREFIX: &[u8] = b"RDRS";
+const ID_LEN: usize = 16;
+const SECRET_LEN: usize = 32;
+const PROOF_LEN: usize = 32;
+
+pub type TicketId = [u8; ID_LEN];
+
+/// The grace period of a broken session, none if disabled.
+pub fn grace() -> Option<Duration> {
+    match Config::get_option(OPTION_SESSION_RESUME_GRACE).parse::<u64>() {
+        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct Ticket {
+    id: TicketId,
+    secret: [u8; SECRET_LEN],
+}
+
+impl Ticket {
+    pub fn generate() -> Self {
+        let mut id = [0u8; ID_LEN];
+        let mut secret = [0u8; SECRET_LEN];
+        randombytes::randombytes_into(&mut id);
+        randombytes::randombytes_into(&mut secret);
+        Self { id, secret }
+    }
+
+    #[inline]
+    pub fn id(&self) -> TicketId {
+        self.id
+    }
+
+    fn encode(&self) -> String {
+        let mut v = self.id.to_vec();
+        v.extend_from_slice(&self.secret);
+        crate::encode64(v)
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let v = crate::decode64(s).ok()?;
+        if v.len() != ID_LEN + SECRET_LEN {
+            return None;
+        }
+        Some(Self {
+            id: v[..ID_LEN].try_into().ok()?,
+            secret: v[ID_LEN..].try_into().ok()?,
+        })
+    }
+
+    fn proof(&self, challenge: &str) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update(challenge);
+        hasher.finalize()[..].to_vec()
+    }
+
+    /// The password of the login request resuming the session.
+    pub fn answer(&self, challenge: &str) -> Vec<u8> {
+        let mut v = PREFIX.to_vec();
+        v.extend_from_slice(&self.id);
+        v.extend(self.proof(challenge));
+        v
+    }
+
+    pub fn verify(&self, answer: &[u8], challenge: &str) -> bool {
+        ticket_id(answer) == Some(self.id)
+            && memcmp(&answer[PREFIX.len() + ID_LEN..], &self.proof(challenge))
+    }
+}
+
+/// The ticket id of the password of a login request resuming a session.
+pub fn ticket_id(password: &[u8]) -> Option<TicketId> {
+    if password.len() != PREFIX.len() + ID_LEN + PROOF_LEN || !password.starts_with(PREFIX) {
+        return None;
+    }
+    password[PREFIX.len()..PREFIX.len() + ID_LEN]
+        .try_into()
+        .ok()
+}
+
+fn parse_additions(platform_additions: &str) -> Map<String, Value> {
+    if platform_additions.is_empty() {
+        return Map::new();
+    }
+    match serde_json::from_str(platform_additions) {
+        Ok(Value::Object(map)) => map,
+        _ => {
+            log::warn!("Invalid platform additions");
+            Map::new()
+        }
+    }
+}
+
+pub fn add_ticket(platform_additions: &mut String, ticket: &Ticket, grace: Duration) {
+    let mut map = parse_additions(platform_additions);
+    map.insert(KEY_TICKET.to_owned(), json!(ticket.encode()));
+    map.insert(KEY_GRACE.to_owned(), json!(grace.as_secs()));
+    *platform_additions = Value::Object(map).to_string();
+}
+
+pub fn ticket_of(platform_additions: &str) -> Option<(Ticket, Duration)> {
+    let map = parse_additions(platform_additions);
+    let ticket = Ticket::decode(map.get(KEY_TICKET)?.as_str()?)?;
+    let grace = map.get(KEY_GRACE)?.as_u64()?;
+    Some((ticket, Duration::from_secs(grace)))
+}
+
+/// The `platform_additions` of the login response of a resumed session, with
+/// the blocks of each file job sent or received by the controlled side.
+pub fn resumed_additions(blocks: &HashMap<i32, u64>) -> String {
+    json!({ KEY_RESUMED: blocks }).to_string()
+}
+
+pub fn resumed(platform_additions: &str) -> Option<HashMap<i32, u64>> {
+    let map = parse_additions(platform_additions);
+    serde_json::from_value(map.get(KEY_RESUMED)?.clone()).ok()
+}
+
+/// The file jobs which lost blocks, the counts of both sides differ.
+pub fn lost_jobs(local: &HashMap<i32, u64>, remote: &HashMap<i32, u64>) -> Vec<i32> {
+    let mut ids: Vec<i32> = local
+        .keys()
+        .chain(remote.keys())
+        .filter(|id| local.get(id).unwrap_or(&0) != remote.get(id).unwrap_or(&0))
+        .cloned()
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket() {
+        let ticket = Ticket::generate();
+        let answer = ticket.answer("challenge");
+        assert_eq!(ticket_id(&answer), Some(ticket.id()));
+        assert!(ticket.verify(&answer, "challenge"));
+        assert!(!ticket.verify(&answer, "other"));
+        assert!(!Ticket::generate().verify(&answer, "challenge"));
+        assert_eq!(ticket_id(b"password"), None);
+
+        let mut additions = r#"{"headless": 1}"#.to_owned();
+        add_ticket(&mut additions, &ticket, Duration::from_secs(30));
+        let (decoded, grace) = ticket_of(&additions).unwrap();
+        assert!(decoded.verify(&answer, "challenge"));
+        assert_eq!(grace, Duration::from_secs(30));
+        assert!(additions.contains("headless"));
+
+        let remote = resumed(&resumed_additions(&HashMap::from([(1, 5), (2, 3)]))).unwrap();
+        let local = HashMap::from([(1, 5), (2, 4), (3, 1)]);
+        assert_eq!(lost_jobs(&local, &remote), vec![2, 3]);
+    }
+}
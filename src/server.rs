@@ -34,6 +34,8 @@ use crate::ipc::Data;
 
 pub mod audio_service;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod terminal_history;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub mod terminal_service;
 cfg_if::cfg_if! {
 if #[cfg(not(target_os = "ios"))] {
@@ -64,10 +66,16 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+pub mod audit;
 mod connection;
 pub mod display_service;
+pub mod login_approval;
+pub mod metrics;
+pub mod policy;
 #[cfg(windows)]
 pub mod portable_service;
+pub mod schedule;
+mod send_scheduler;
 mod service;
 mod video_qos;
 pub mod video_service;
@@ -173,6 +181,26 @@ pub async fn create_tcp_connection(
     stream: Stream,
     addr: SocketAddr,
     secure: bool,
+) -> ResultType<()> {
+    create_connection(server, stream, addr, secure, None).await
+}
+
+pub async fn create_quic_connection(
+    server: ServerPtr,
+    stream: Stream,
+    addr: SocketAddr,
+    secure: bool,
+    quic: crate::quic_stream::QuicStream,
+) -> ResultType<()> {
+    create_connection(server, stream, addr, secure, Some(quic)).await
+}
+
+async fn create_connection(
+    server: ServerPtr,
+    stream: Stream,
+    addr: SocketAddr,
+    secure: bool,
+    quic: Option<crate::quic_stream::QuicStream>,
 ) -> ResultType<()> {
     let mut stream = stream;
     let id = server.write().unwrap().get_new_id();
@@ -183,11 +211,16 @@ pub async fn create_tcp_connection(
         let sk = sign::SecretKey(sk_);
         let mut msg_out = Message::new();
         let (our_pk_b, our_sk_b) = box_::gen_keypair();
+        let mut our_pk = our_pk_b.0.to_vec();
+        if crate::is_no_rendezvous() {
+            // No rendezvous server signed our key for the client, announce it.
+            our_pk.extend_from_slice(&pk);
+        }
         msg_out.set_signed_id(SignedId {
             id: sign::sign(
                 &IdPk {
                     id: Config::get_id(),
-                    pk: Bytes::from(our_pk_b.0.to_vec()),
+                    pk: Bytes::from(our_pk),
                     ..Default::default()
                 }
                 .write_to_bytes()
@@ -204,11 +237,15 @@ pub async fn create_tcp_connection(
                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                     if let Some(message::Union::PublicKey(pk)) = msg_in.union {
                         if pk.asymmetric_value.len() == box_::PUBLICKEYBYTES {
-                            stream.set_key(tcp::Encrypt::decode(
+                            let key = tcp::Encrypt::decode(
                                 &pk.symmetric_value,
                                 &pk.asymmetric_value,
                                 &our_sk_b,
-                            )?);
+                            )?;
+                            match quic.as_ref() {
+                                Some(quic) => quic.set_key(key),
+                                None => stream.set_key(key),
+                            }
                         } else if pk.asymmetric_value.is_empty() {
                             Config::set_key_confirmed(false);
                             log::info!("Force to update pk");
@@ -572,6 +609,8 @@ pub async fn start_server(is_server: bool, no_server: bool) {
         crate::platform::try_kill_broker();
         #[cfg(feature = "hwcodec")]
         scrap::hwcodec::start_check_process();
+        tokio::spawn(metrics::start());
+        tokio::spawn(login_approval::start());
         crate::RendezvousMediator::start_all().await;
     } else {
         match crate::ipc::connect(1000, "").await {
@@ -101,6 +101,7 @@ mod pa_impl {
             AUDIO_ZERO_COUNT = 0;
         }
         let mut encoder = Encoder::new(crate::platform::PA_SAMPLE_RATE, Stereo, LowDelay)?;
+        video_service::set_record_audio_format(crate::platform::PA_SAMPLE_RATE, 2);
         #[cfg(target_os = "linux")]
         allow_err!(
             stream
@@ -378,6 +379,7 @@ mod cpal_impl {
             f => bail!("unsupported audio format: {:?}", f),
         };
         stream.play()?;
+        video_service::set_record_audio_format(sample_rate, ch as _);
         Ok((
             Box::new(stream),
             Arc::new(create_format_msg(sample_rate, ch as _)),
@@ -496,6 +498,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
                     .encode_vec_float(&data[i * BATCH_SIZE..(i + 1) * BATCH_SIZE], BATCH_SIZE)
                 {
                     Ok(data) => {
+                        video_service::record_audio(&data);
                         let mut msg_out = Message::new();
                         msg_out.set_audio_frame(AudioFrame {
                             data: data.into(),
@@ -515,6 +518,7 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
     #[cfg(not(target_os = "android"))]
     match encoder.encode_vec_float(data, data.len() * 6) {
         Ok(data) => {
+            video_service::record_audio(&data);
             let mut msg_out = Message::new();
             msg_out.set_audio_frame(AudioFrame {
                 data: data.into(),
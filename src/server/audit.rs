@@ -0,0 +1,444 @@
+//! Local audit trail of connections, file transfers and alarms.
+//!
+//! The audit events are posted to the API server only if one is configured.
+//! The sinks here keep them locally as well, selected by the comma separated
+//! `audit-sinks` option, e.g. `jsonl,syslog`:
+//!
+//! - `jsonl`: append-only file, rotated by size, see [`OPTION_AUDIT_FILE`].
+//! - `syslog`: `/dev/log` datagrams, also collected by journald (unix only).
+//! - `webhook`: POST of each record to [`OPTION_AUDIT_WEBHOOK`].
+//!
+//! Records are chained: each one carries the HMAC-SHA256 of the previous record
+//! and its own as the last field, keyed with a secret kept in `audit.key` in the
+//! config directory, not in the audit file. [`verify`] checks the chain with it.
+//!
+//! What [`verify`] proves: every record was written by a holder of the key and
+//! no record was modified, removed or reordered between the first and the last
+//! one of the file. What it does not: that the newest records were not cut off,
+//! or the whole file replaced, nor anything against whoever can read the key.
+//! The `syslog` and `webhook` sinks get the hash of every record, so the head of
+//! the chain kept there shows a truncated file.
+
+use hbb_common::{
+    allow_err,
+    anyhow::anyhow,
+    bail,
+    config::Config,
+    log,
+    sodiumoxide::crypto::auth::hmacsha256::{self, Key},
+    ResultType,
+};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+};
+
+pub const OPTION_AUDIT_SINKS: &str = "audit-sinks";
+/// Path of the JSONL file, `audit.jsonl` in the log directory by default.
+pub const OPTION_AUDIT_FILE: &str = "audit-file";
+/// Size in MB at which the JSONL file is rotated.
+pub const OPTION_AUDIT_FILE_MAX_SIZE: &str = "audit-file-max-size";
+/// Number of rotated files to keep, `audit.jsonl.1` being the newest.
+pub const OPTION_AUDIT_FILE_MAX_FILES: &str = "audit-file-max-files";
+pub const OPTION_AUDIT_WEBHOOK: &str = "audit-webhook-url";
+
+const DEFAULT_MAX_SIZE_MB: u64 = 10;
+const DEFAULT_MAX_FILES: usize = 10;
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const HASH_FIELD: &str = ",\"hash\":\"";
+const KEY_FILE: &str = "audit.key";
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<Sender<Value>>> = Default::default();
+}
+
+pub trait AuditSink: Send {
+    fn write(&mut self, record: &str) -> ResultType<()>;
+}
+
+/// Queues an audit event for the configured sinks, a no-op if there is none.
+pub fn write(kind: &str, event: Value) {
+    if Config::get_option(OPTION_AUDIT_SINKS).is_empty() {
+        return;
+    }
+    let record = json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "kind": kind,
+        "host": Config::get_id(),
+        "event": event,
+    });
+    let mut lock = SENDER.lock().unwrap();
+    if let Some(tx) = lock.as_ref() {
+        if tx.send(record.clone()).is_ok() {
+            return;
+        }
+    }
+    let (tx, rx) = channel::<Value>();
+    std::thread::spawn(move || {
+        let mut auditor = Auditor::default();
+        while let Ok(record) = rx.recv() {
+            auditor.write(record);
+        }
+    });
+    allow_err!(tx.send(record));
+    *lock = Some(tx);
+}
+
+#[derive(Default)]
+struct Auditor {
+    conf: Vec<String>,
+    sinks: Vec<Box<dyn AuditSink>>,
+    key: Option<Key>,
+    prev_hash: String,
+}
+
+impl Auditor {
+    fn write(&mut self, mut record: Value) {
+        let conf = [
+            OPTION_AUDIT_SINKS,
+            OPTION_AUDIT_FILE,
+            OPTION_AUDIT_FILE_MAX_SIZE,
+            OPTION_AUDIT_FILE_MAX_FILES,
+            OPTION_AUDIT_WEBHOOK,
+        ]
+        .iter()
+        .map(|k| Config::get_option(k))
+        .collect::<Vec<_>>();
+        if conf != self.conf {
+            self.reload(&conf[0]);
+            self.conf = conf;
+        }
+        if self.sinks.is_empty() {
+            return;
+        }
+        let key = self.key.get_or_insert_with(|| {
+            load_key(true).unwrap_or_else(|err| {
+                // Still audit, but the records can not be verified later.
+                log::error!("Failed to load the audit key: {}", err);
+                hmacsha256::gen_key()
+            })
+        });
+        record["prev_hash"] = json!(self.prev_hash);
+        let (line, hash) = seal(&record, key);
+        self.prev_hash = hash;
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.write(&line) {
+                log::error!("Failed to write audit record: {}", err);
+            }
+        }
+    }
+
+    fn reload(&mut self, sinks: &str) {
+        self.sinks.clear();
+        for name in sinks.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match create_sink(name) {
+                Ok(sink) => self.sinks.push(sink),
+                Err(err) => log::error!("Failed to create audit sink {}: {}", name, err),
+            }
+        }
+        // Continue the chain of the file across restarts.
+        let path = file_path();
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        self.prev_hash = last_hash(&path)
+            .or_else(|| last_hash(Path::new(&rotated)))
+            .unwrap_or_else(|| GENESIS_HASH.to_owned());
+    }
+}
+
+fn create_sink(name: &str) -> ResultType<Box<dyn AuditSink>> {
+    Ok(match name {
+        "jsonl" => Box::new(JsonlSink::new(
+            file_path(),
+            option_number(OPTION_AUDIT_FILE_MAX_SIZE, DEFAULT_MAX_SIZE_MB) * 1024 * 1024,
+            option_number(OPTION_AUDIT_FILE_MAX_FILES, DEFAULT_MAX_FILES),
+        )?),
+        #[cfg(unix)]
+        "syslog" => Box::new(SyslogSink::new()?),
+        "webhook" => {
+            let url = Config::get_option(OPTION_AUDIT_WEBHOOK);
+            if url.is_empty() {
+                bail!("{} is not set", OPTION_AUDIT_WEBHOOK);
+            }
+            Box::new(WebhookSink { url })
+        }
+        _ => bail!("unsupported"),
+    })
+}
+
+fn option_number<T: std::str::FromStr>(key: &str, default: T) -> T {
+    Config::get_option(key).parse().unwrap_or(default)
+}
+
+fn file_path() -> PathBuf {
+    let path = Config::get_option(OPTION_AUDIT_FILE);
+    if path.is_empty() {
+        Config::log_path().join("audit.jsonl")
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// The key of the chain, created on first use if `create`.
+fn load_key(create: bool) -> ResultType<Key> {
+    let path = Config::path(KEY_FILE);
+    match fs::read_to_string(&path) {
+        Ok(s) => Key::from_slice(&hex::decode(s.trim())?)
+            .ok_or_else(|| anyhow!("invalid key in {}", path.display())),
+        Err(err) if create && err.kind() == std::io::ErrorKind::NotFound => {
+            let key = hmacsha256::gen_key();
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options
+                .open(&path)?
+                .write_all(hex::encode(&key.0[..]).as_bytes())?;
+            Ok(key)
+        }
+        Err(err) => bail!("failed to read {}: {}", path.display(), err),
+    }
+}
+
+fn mac(body: &str, key: &Key) -> String {
+    hex::encode(hmacsha256::authenticate(body.as_bytes(), key).0)
+}
+
+/// Serializes the record with its hash appended as the last field. The hash
+/// covers the serialized record without it, so [`verify`] can recompute it from
+/// the line without depending on the field order of a parsed map.
+fn seal(record: &Value, key: &Key) -> (String, String) {
+    let body = record.to_string();
+    let hash = mac(&body, key);
+    let line = format!("{}{}{}\"}}", &body[..body.len() - 1], HASH_FIELD, hash);
+    (line, hash)
+}
+
+/// Splits a line into the hashed body and the recorded hash.
+fn unseal(line: &str) -> Option<(String, &str)> {
+    let pos = line.rfind(HASH_FIELD)?;
+    let hash = line[pos + HASH_FIELD.len()..].strip_suffix("\"}")?;
+    Some((format!("{}}}", &line[..pos]), hash))
+}
+
+fn last_hash(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let line = BufReader::new(file)
+        .lines()
+        .filter_map(|l| l.ok())
+        .filter(|l| !l.trim().is_empty())
+        .last()?;
+    unseal(&line).map(|(_, hash)| hash.to_owned())
+}
+
+/// Checks the chain of a JSONL audit file with the key of this device and
+/// returns the number of records. The first record may link to a rotated file,
+/// so only the links after it are checked, see the module docs for the limits.
+pub fn verify(path: &Path) -> ResultType<usize> {
+    verify_with(path, &load_key(false)?)
+}
+
+fn verify_with(path: &Path, key: &Key) -> ResultType<usize> {
+    let file = File::open(path)?;
+    let mut prev: Option<String> = None;
+    let mut n = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let lineno = i + 1;
+        let (body, hash) = unseal(&line).ok_or_else(|| anyhow!("line {}: no hash", lineno))?;
+        if mac(&body, key) != hash {
+            bail!("line {}: hash mismatch, the record was modified", lineno);
+        }
+        let v: Value = serde_json::from_str(&body)?;
+        let prev_hash = v["prev_hash"].as_str().unwrap_or_default();
+        if let Some(prev) = prev.as_ref() {
+            if prev != prev_hash {
+                bail!(
+                    "line {}: chain broken, a record was removed or reordered",
+                    lineno
+                );
+            }
+        }
+        prev = Some(hash.to_owned());
+        n += 1;
+    }
+    Ok(n)
+}
+
+pub struct JsonlSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl JsonlSink {
+    pub fn new(path: PathBuf, max_size: u64, max_files: usize) -> ResultType<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = Self::open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn open(path: &Path) -> ResultType<File> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(options.open(path)?)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> ResultType<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::remove_file(self.rotated(self.max_files)).ok();
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = Self::open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl AuditSink for JsonlSink {
+    fn write(&mut self, record: &str) -> ResultType<()> {
+        let len = record.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", record).as_bytes())?;
+        self.file.sync_data()?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub struct SyslogSink {
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    // LOG_AUTHPRIV | LOG_INFO
+    const PRI: u8 = 10 << 3 | 6;
+
+    pub fn new() -> ResultType<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        #[cfg(target_os = "macos")]
+        socket.connect("/var/run/syslog")?;
+        #[cfg(not(target_os = "macos"))]
+        socket.connect("/dev/log")?;
+        Ok(Self { socket })
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for SyslogSink {
+    fn write(&mut self, record: &str) -> ResultType<()> {
+        let msg = format!(
+            "<{}>{}[{}]: {}",
+            Self::PRI,
+            crate::get_app_name().to_lowercase(),
+            std::process::id(),
+            record
+        );
+        self.socket.send(msg.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub struct WebhookSink {
+    url: String,
+}
+
+impl AuditSink for WebhookSink {
+    fn write(&mut self, record: &str) -> ResultType<()> {
+        crate::post_request_sync(self.url.clone(), record.to_owned(), "")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_chain() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-audit-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let path = dir.join("audit.jsonl");
+        let mut sink = JsonlSink::new(path.clone(), 1024, 2).unwrap();
+        let key = hmacsha256::gen_key();
+        let mut prev_hash = GENESIS_HASH.to_owned();
+        for i in 0..20 {
+            let record = json!({"kind": "conn", "event": {"n": i}, "prev_hash": prev_hash});
+            let (line, hash) = seal(&record, &key);
+            sink.write(&line).unwrap();
+            prev_hash = hash;
+        }
+        assert!(sink.rotated(1).exists());
+        assert!(sink.rotated(2).exists());
+        assert!(!sink.rotated(3).exists());
+        assert_eq!(last_hash(&path), Some(prev_hash));
+        let n = verify_with(&path, &key).unwrap();
+        assert!(n > 1);
+        assert!(verify_with(&sink.rotated(1), &key).is_ok());
+        // A chain rebuilt without the key does not verify.
+        assert!(verify_with(&path, &hmacsha256::gen_key()).is_err());
+
+        let lines = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        let tampered = lines[0].replace("\"n\":", "\"n\":1");
+        fs::write(
+            &path,
+            [&tampered, &lines[1..].join("\n")[..], ""].join("\n"),
+        )
+        .unwrap();
+        assert!(verify_with(&path, &key).is_err());
+        fs::write(&path, [lines[0], &lines[2..].join("\n")[..], ""].join("\n")).unwrap();
+        assert!(verify_with(&path, &key).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
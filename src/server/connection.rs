@@ -17,7 +17,11 @@ use crate::{
     client::{
         new_voice_call_request, new_voice_call_response, start_audio_thread, MediaData, MediaSender,
     },
-    display_service, ipc, privacy_mode, video_service, VERSION,
+    display_service, ipc, privacy_mode,
+    resumption::{self, Ticket, TicketId},
+    traffic::{self, DownloadThrottle, TrafficStats},
+    tunnel::{self, Frame, FrameDecoder, TargetAllowlist, Tunnel},
+    video_service, VERSION,
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
@@ -74,6 +78,14 @@ lazy_static::lazy_static! {
     pub static ref AUTHED_CONNS: Arc::<Mutex<Vec<AuthedConn>>> = Default::default();
     static ref SWITCH_SIDES_UUID: Arc::<Mutex<HashMap<String, (Instant, uuid::Uuid)>>> = Default::default();
     static ref WAKELOCK_SENDER: Arc::<Mutex<std::sync::mpsc::Sender<(usize, usize)>>> = Arc::new(Mutex::new(start_wakelock_thread()));
+    static ref RESUMABLE: Arc::<Mutex<HashMap<TicketId, Resumable>>> = Default::default();
+}
+
+// A session which can be resumed over a new path, see `crate::resumption`.
+struct Resumable {
+    ticket: Ticket,
+    peer_id: String,
+    tx: mpsc::UnboundedSender<Box<Connection>>,
 }
 
 #[cfg(any(target_os = "windows", target_os = "linux"))]
@@ -121,6 +133,15 @@ pub fn plugin_block_input(peer: &str, block: bool) -> bool {
     }
 }
 
+// Closes the session after inactivity, or when the access window ends.
+#[derive(Default)]
+struct AutoDisconnectTimer {
+    // Last activity, and the timeout in minutes
+    inactivity: Option<(Instant, u64)>,
+    // When the access window ends, and if the peer has been warned
+    schedule: Option<(Instant, bool)>,
+}
+
 #[derive(Clone, Default)]
 pub struct ConnInner {
     id: i32,
@@ -210,12 +231,23 @@ pub struct Connection {
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    send_scheduler: send_scheduler::SendScheduler,
+    traffic: TrafficStats,
+    // Totals already added to the metrics
+    traffic_reported: (u64, u64),
+    download_throttle: DownloadThrottle,
+    // The options generation the caps were read at, none to read them again
+    caps_generation: Option<usize>,
     timer: crate::RustDeskInterval,
     file_timer: crate::RustDeskInterval,
     file_transfer: Option<(String, bool)>,
     view_camera: bool,
     terminal: bool,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    // Multiplexed port forwarding, see `crate::tunnel`.
+    port_forward_mux: bool,
+    // terminal id -> input of the current line, for the record events.
+    terminal_input: HashMap<i32, Vec<u8>>,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
@@ -271,7 +303,7 @@ pub struct Connection {
     closed: bool,
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     start_cm_ipc_para: Option<StartCmIpcPara>,
-    auto_disconnect_timer: Option<(Instant, u64)>,
+    auto_disconnect_timer: AutoDisconnectTimer,
     authed_conn_id: Option<self::raii::AuthedConnID>,
     file_remove_log_control: FileRemoveLogControl,
     last_supported_encoding: Option<SupportedEncoding>,
@@ -295,6 +327,27 @@ pub struct Connection {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     terminal_user_token: Option<TerminalUserToken>,
     terminal_generic_service: Option<Box<GenericService>>,
+    policy: policy::Grants,
+    // The access policy loaded at login, and the connection type it applies to
+    access_policy: Option<(policy::Policy, AuthConnType)>,
+    // The peer passed 2FA: a code, the approve link, a trusted device or a
+    // recent 2FA session
+    two_factor_passed: bool,
+    // Logged in with the permanent password or a trusted device, see `schedule`
+    schedule_auth: bool,
+    outside_schedule: bool,
+    // Token of the approve link sent with the 2FA notification
+    approval_token: Option<String>,
+    // The ticket to resume the session with, and the grace period
+    resume_ticket: Option<(Ticket, Duration)>,
+    tx_resume: mpsc::UnboundedSender<Box<Connection>>,
+    // Set on the connection resuming another session, which takes it over
+    resume_to: Option<mpsc::UnboundedSender<Box<Connection>>>,
+    // Set while waiting for the session to be resumed after its path broke:
+    // the deadline, and the reason and lock of the close if not resumed
+    resume_deadline: Option<(Instant, String, bool)>,
+    // file job id -> blocks sent or received
+    file_blocks: HashMap<i32, u64>,
 }
 
 impl ConnInner {
@@ -360,6 +413,7 @@ impl Connection {
         let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
         let (tx_input, _rx_input) = std_mpsc::channel();
         let (tx_from_authed, mut rx_from_authed) = mpsc::unbounded_channel::<ipc::Data>();
+        let (tx_resume, mut rx_resume) = mpsc::unbounded_channel::<Box<Connection>>();
         let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let (tx_cm_stream_ready, _rx_cm_stream_ready) = mpsc::channel(1);
@@ -388,12 +442,19 @@ impl Connection {
             server,
             hash,
             read_jobs: Vec::new(),
+            send_scheduler: send_scheduler::SendScheduler::new(),
+            traffic: Default::default(),
+            traffic_reported: (0, 0),
+            download_throttle: Default::default(),
+            caps_generation: None,
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_transfer: None,
             view_camera: false,
             terminal: false,
             port_forward_socket: None,
+            port_forward_mux: false,
+            terminal_input: Default::default(),
             port_forward_address: "".to_owned(),
             tx_to_cm,
             authorized: false,
@@ -448,7 +509,7 @@ impl Connection {
                 rx_desktop_ready,
                 tx_cm_stream_ready,
             }),
-            auto_disconnect_timer: None,
+            auto_disconnect_timer: Default::default(),
             authed_conn_id: None,
             file_remove_log_control: FileRemoveLogControl::new(id),
             last_supported_encoding: None,
@@ -464,6 +525,17 @@ impl Connection {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             terminal_user_token: None,
             terminal_generic_service: None,
+            policy: Default::default(),
+            access_policy: None,
+            two_factor_passed: false,
+            schedule_auth: false,
+            outside_schedule: false,
+            approval_token: None,
+            resume_ticket: None,
+            tx_resume,
+            resume_to: None,
+            resume_deadline: None,
+            file_blocks: Default::default(),
         };
         let addr = hbb_common::try_into_v4(addr);
         if !conn.on_open(addr).await {
@@ -503,13 +575,7 @@ impl Connection {
             crate::rustdesk_interval(time::interval_at(Instant::now(), TEST_DELAY_TIMEOUT));
         let mut last_recv_time = Instant::now();
 
-        conn.stream.set_send_timeout(
-            if conn.file_transfer.is_some() || conn.port_forward_socket.is_some() || conn.terminal {
-                SEND_TIMEOUT_OTHER
-            } else {
-                SEND_TIMEOUT_VIDEO
-            },
-        );
+        conn.stream.set_send_timeout(conn.send_timeout());
 
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         std::thread::spawn(move || Self::handle_input(_rx_input, tx_cloned));
@@ -546,7 +612,7 @@ impl Connection {
                         ipc::Data::Authorize => {
                             conn.require_2fa.take();
                             conn.send_logon_response().await;
-                            if conn.port_forward_socket.is_some() {
+                            if conn.is_port_forward() {
                                 break;
                             }
                         }
@@ -577,6 +643,7 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            let enabled = enabled && conn.policy.allows_permission(&name);
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -720,41 +787,70 @@ impl Connection {
                         _ => {}
                     }
                 },
-                res = conn.stream.next() => {
+                _ = time::sleep(conn.download_throttle.wait()), if conn.download_throttle.has_delayed() => {
+                    let mut closed = false;
+                    while let Some(bytes) = conn.download_throttle.pop_ready() {
+                        if !conn.handle_frame(&bytes).await {
+                            closed = true;
+                            break;
+                        }
+                    }
+                    if closed {
+                        break;
+                    }
+                }
+                res = conn.stream.next(), if conn.download_throttle.can_read() && conn.resume_deadline.is_none() => {
                     if let Some(res) = res {
                         match res {
                             Err(err) => {
+                                if conn.wait_resume(&err.to_string(), true) {
+                                    continue;
+                                }
                                 conn.on_close(&err.to_string(), true).await;
                                 break;
                             },
                             Ok(bytes) => {
                                 last_recv_time = Instant::now();
+                                conn.traffic.on_received(&bytes);
                                 conn.session_last_recv_time.as_mut().map(|t| *t.lock().unwrap() = Instant::now());
-                                if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
-                                    if !conn.on_message(msg_in).await {
+                                if let Some(bytes) = conn.download_throttle.admit(bytes) {
+                                    if !conn.handle_frame(&bytes).await {
                                         break;
                                     }
-                                    if conn.port_forward_socket.is_some() && conn.authorized {
-                                        log::info!("Port forward, last_test_delay is none: {}", conn.last_test_delay.is_none());
-                                        // Avoid TestDelay reply injection into rdp data stream
-                                        if conn.last_test_delay.is_none() {
-                                            break;
-                                        }
-                                    }
                                 }
                             }
                         }
                     } else {
+                        if conn.wait_resume("Reset by the peer", true) {
+                            continue;
+                        }
                         conn.on_close("Reset by the peer", true).await;
                         break;
                     }
                 },
-                _ = conn.file_timer.tick() => {
+                Some(other) = rx_resume.recv() => {
+                    conn.resume_deadline = None;
+                    if !conn.resume(other).await {
+                        conn.on_close("Resume not allowed by the access policy", true).await;
+                        break;
+                    }
+                    last_recv_time = Instant::now();
+                }
+                _ = conn.file_timer.tick(), if conn.resume_deadline.is_none() => {
                     if !conn.read_jobs.is_empty() {
+                        if !conn.send_scheduler.file_ready() {
+                            continue;
+                        }
                         conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), fs::serialize_transfer_jobs(&conn.read_jobs))));
-                        match fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
+                        let transferred = crate::traffic::transferred(&conn.read_jobs);
+                        let res = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await;
+                        let (blocks, bytes) = crate::traffic::count_blocks(&transferred, &conn.read_jobs, &mut conn.file_blocks);
+                        conn.send_scheduler.file_sent(bytes);
+                        conn.traffic.add_sent("file_response", blocks, bytes);
+                        match res {
                             Ok(log) => {
                                 if !log.is_empty() {
+                                    conn.record_file_log(&log);
                                     conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
                                 }
                             }
@@ -775,15 +871,32 @@ impl Connection {
                     }
                 }
                 Some((instant, value)) = rx_video.recv() => {
-                    if !conn.video_ack_required {
+                    conn.send_scheduler.push(send_scheduler::Class::Video, instant, value);
+                    if conn.resume_deadline.is_some() {
+                        conn.send_scheduler.drop_stale();
+                    }
+                },
+                _ = std::future::ready(()), if !conn.send_scheduler.is_empty() && conn.resume_deadline.is_none() => {
+                    // Queue all pending video so that the most urgent goes first.
+                    while let Ok((instant, value)) = rx_video.try_recv() {
+                        conn.send_scheduler.push(send_scheduler::Class::Video, instant, value);
+                    }
+                    let Some((class, instant, value)) = conn.send_scheduler.pop() else {
+                        continue;
+                    };
+                    if class == send_scheduler::Class::Video && !conn.video_ack_required {
                         if let Some(message::Union::VideoFrame(vf)) = &value.union {
                             video_service::notify_video_frame_fetched(vf.display as usize, id, Some(instant.into()));
                         }
                     }
                     if let Err(err) = conn.stream.send(&value as &Message).await {
+                        if conn.wait_resume(&err.to_string(), false) {
+                            continue;
+                        }
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
+                    conn.traffic.on_sent(&value);
                 },
                 Some((instant, value)) = rx.recv() => {
                     let latency = instant.elapsed().as_millis() as i64;
@@ -840,10 +953,9 @@ impl Connection {
                         _ => {}
                     }
 
-                    let msg: &Message = &msg;
-                    if let Err(err) = conn.stream.send(msg).await {
-                        conn.on_close(&err.to_string(), false).await;
-                        break;
+                    conn.send_scheduler.push(send_scheduler::classify(&msg), instant, msg);
+                    if conn.resume_deadline.is_some() {
+                        conn.send_scheduler.drop_stale();
                     }
                 },
                 Some(data) = rx_from_authed.recv() => {
@@ -862,24 +974,46 @@ impl Connection {
                 _ = second_timer.tick() => {
                     #[cfg(windows)]
                     conn.portable_check();
-                    if let Some((instant, minute)) = conn.auto_disconnect_timer.as_ref() {
-                        if instant.elapsed().as_secs() > minute * 60 {
-                            conn.send_close_reason_no_retry("Connection failed due to inactivity").await;
-                            conn.on_close("auto disconnect", true).await;
+                    if let Some((deadline, reason, lock)) = conn.resume_deadline.clone() {
+                        if Instant::now() >= deadline {
+                            conn.on_close(&reason, lock).await;
+                            break;
+                        }
+                    }
+                    if let Some((msg, reason)) = conn.check_auto_disconnect().await {
+                        conn.send_close_reason_no_retry(msg).await;
+                        conn.on_close(reason, true).await;
+                        break;
+                    }
+                    if let Some(approved) = conn.approval_token.as_ref().and_then(|t| login_approval::poll(t)) {
+                        conn.approval_token = None;
+                        if approved {
+                            conn.approve_2fa().await;
+                        } else {
+                            conn.send_close_reason_no_retry(login_approval::LOGIN_MSG_DENIED).await;
+                            conn.on_close("login denied", true).await;
                             break;
                         }
                     }
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
+                    conn.refresh_bandwidth_caps();
+                    conn.report_traffic();
                     #[cfg(feature = "hwcodec")]
                     conn.update_supported_encoding();
                 }
                 _ = test_delay_timer.tick() => {
+                    if conn.resume_deadline.is_some() {
+                        continue;
+                    }
                     if last_recv_time.elapsed() >= SEC30 {
+                        if conn.wait_resume("Timeout", true) {
+                            continue;
+                        }
                         conn.on_close("Timeout", true).await;
                         break;
                     }
                     // The control end will jump out of the loop after receiving LoginResponse and will not reply to the TestDelay
-                    if conn.last_test_delay.is_none() && !(conn.port_forward_socket.is_some() && conn.authorized) {
+                    if conn.last_test_delay.is_none() && !(conn.is_port_forward() && conn.authorized) {
                         conn.last_test_delay = Some(Instant::now());
                         let mut msg_out = Message::new();
                         msg_out.set_test_delay(TestDelay{
@@ -910,6 +1044,15 @@ impl Connection {
             }
         }
 
+        if let Some(tx) = conn.resume_to.take() {
+            log::info!("#{} resumes another session", id);
+            allow_err!(tx.send(Box::new(conn)));
+            return;
+        }
+        if let Some((ticket, _)) = conn.resume_ticket.as_ref() {
+            RESUMABLE.lock().unwrap().remove(&ticket.id());
+        }
+
         #[cfg(feature = "unix-file-copy-paste")]
         {
             conn.try_empty_file_clipboard();
@@ -1064,6 +1207,9 @@ impl Connection {
         &mut self,
         rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
     ) -> ResultType<()> {
+        if self.port_forward_mux {
+            return self.port_forward_mux_loop(rx_from_cm).await;
+        }
         let mut last_recv_time = Instant::now();
         if let Some(mut forward) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
@@ -1116,6 +1262,147 @@ impl Connection {
         Ok(())
     }
 
+    async fn port_forward_mux_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        log::info!("Running multiplexed port forwarding loop");
+        self.stream.set_raw();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut tunnel = Tunnel::new(false);
+        let mut decoder = FrameDecoder::default();
+        let allowlist = TargetAllowlist::load();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                res = self.stream.next() => {
+                    if let Some(res) = res {
+                        last_recv_time = Instant::now();
+                        decoder.feed(&res?);
+                        while let Some(frame) = decoder.next()? {
+                            self.handle_tunnel_frame(&mut tunnel, &allowlist, frame).await?;
+                        }
+                    } else {
+                        bail!("Stream reset by the peer");
+                    }
+                },
+                Some(event) = tunnel.next() => match event {
+                    tunnel::Event::Outgoing(frame) => {
+                        last_recv_time = Instant::now();
+                        // Only report closes of channels the peer still knows about.
+                        if let Frame::Close { channel, .. } = &frame {
+                            if !tunnel.close(*channel) {
+                                continue;
+                            }
+                        }
+                        self.stream.send_bytes(frame.encode()).await?;
+                    }
+                    tunnel::Event::Incoming(port, socket) => {
+                        let channel = tunnel.next_channel();
+                        self.stream.send_bytes(Frame::Accepted { channel, port }.encode()).await?;
+                        tunnel.attach(channel, socket);
+                    }
+                },
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_reverse_forward_allowed(&self, allowlist: &TargetAllowlist, port: u16) -> bool {
+        let target = format!("127.0.0.1:{}", port);
+        Config::get_bool_option(tunnel::OPTION_ALLOW_REVERSE_PORT_FORWARD)
+            && allowlist.is_allowed(&target)
+            && self.policy.is_target_allowed(&target)
+    }
+
+    async fn handle_tunnel_frame(
+        &mut self,
+        tunnel: &mut Tunnel,
+        allowlist: &TargetAllowlist,
+        frame: Frame,
+    ) -> ResultType<()> {
+        match frame {
+            Frame::Open { channel, target } | Frame::OpenUdp { channel, target }
+                if !allowlist.is_allowed(&target) || !self.policy.is_target_allowed(&target) =>
+            {
+                log::warn!("Port forward to {} rejected by the allowlist", target);
+                let reason = tunnel::NOT_ALLOWED.to_owned();
+                self.stream
+                    .send_bytes(Frame::Close { channel, reason }.encode())
+                    .await?;
+            }
+            Frame::Open { channel, target } => {
+                log::info!("Port forward channel {} to {}", channel, target);
+                tunnel.connect(channel, target);
+            }
+            Frame::OpenUdp { channel, target } => {
+                log::info!("UDP port forward channel {} to {}", channel, target);
+                tunnel.connect_udp(channel, target);
+            }
+            Frame::Data { channel, data } => tunnel.write(channel, data).await,
+            Frame::Close { channel, .. } => {
+                tunnel.close(channel);
+            }
+            Frame::Listen { port } if !self.is_reverse_forward_allowed(allowlist, port) => {
+                log::warn!("Reverse port forward on {} rejected", port);
+                let reason = tunnel::NOT_ALLOWED.to_owned();
+                let frame = Frame::Close { channel: 0, reason };
+                self.stream.send_bytes(frame.encode()).await?;
+            }
+            Frame::Listen { port } => {
+                // Like `ssh -R`, reverse forwards are only reachable locally.
+                match hbb_common::tcp::new_listener(format!("127.0.0.1:{}", port), true).await {
+                    Ok(listener) => {
+                        log::info!("Reverse port forward listening on {}", port);
+                        tunnel.listen(port, listener);
+                    }
+                    Err(err) => {
+                        let reason = format!("Failed to listen on {}: {}", port, err);
+                        let frame = Frame::Close { channel: 0, reason };
+                        self.stream.send_bytes(frame.encode()).await?;
+                    }
+                }
+            }
+            Frame::Wol { packet } => {
+                let reason = match crate::lan::wol::relay(&packet) {
+                    Ok(()) => "".to_owned(),
+                    Err(err) => err.to_string(),
+                };
+                let frame = Frame::Close { channel: 0, reason };
+                self.stream.send_bytes(frame.encode()).await?;
+            }
+            Frame::Opened { .. } => {}
+            Frame::Accepted { channel, .. } => {
+                let reason = "Unexpected accepted channel".to_owned();
+                self.stream
+                    .send_bytes(Frame::Close { channel, reason }.encode())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
@@ -1138,6 +1425,138 @@ impl Connection {
         }
     }
 
+    fn evaluate_policy(&self, ip: &str, two_factor: bool) -> Option<policy::Grants> {
+        let (policy, conn_type) = self.access_policy.as_ref()?;
+        Some(policy.evaluate(&policy::Context {
+            peer_id: &self.lr.my_id,
+            ip: ip.parse().ok(),
+            conn_type: *conn_type,
+            two_factor,
+            time: policy::Policy::now(),
+        }))
+    }
+
+    fn deny_by_policy(&self, grants: &policy::Grants) -> bool {
+        self.access_policy.as_ref().map_or(false, |(_, conn_type)| {
+            !grants.allows(policy::Capability::of_conn_type(*conn_type))
+        })
+    }
+
+    async fn send_policy_denied(&mut self) {
+        self.send_login_error("Not allowed by the access policy")
+            .await;
+        let conn_type = self.access_policy.as_ref().map(|(_, t)| *t);
+        super::audit::write(
+            "policy",
+            json!({ "ip": self.ip, "peer_id": self.lr.my_id, "type": format!("{:?}", conn_type) }),
+        );
+    }
+
+    // Loads the access policy at login. The 2FA result is not known yet, so
+    // only the logins denied whatever it turns out to be are rejected here,
+    // the policy is applied once authorized, see `apply_policy`.
+    async fn check_policy(&mut self, lr: &LoginRequest) -> bool {
+        let policy = match policy::Policy::load() {
+            Ok(Some(policy)) => policy,
+            Ok(None) => {
+                self.access_policy = None;
+                return true;
+            }
+            Err(err) => {
+                log::error!("Failed to load the access policy: {}", err);
+                self.send_login_error("Invalid access policy").await;
+                return false;
+            }
+        };
+        let conn_type = match lr.union {
+            Some(login_request::Union::FileTransfer(_)) => AuthConnType::FileTransfer,
+            Some(login_request::Union::ViewCamera(_)) => AuthConnType::ViewCamera,
+            Some(login_request::Union::Terminal(_)) => AuthConnType::Terminal,
+            Some(login_request::Union::PortForward(_)) => AuthConnType::PortForward,
+            _ => AuthConnType::Remote,
+        };
+        self.access_policy = Some((policy, conn_type));
+        let outcomes = if self.require_2fa.is_some() {
+            vec![true, false]
+        } else {
+            vec![self.two_factor_passed]
+        };
+        let grants: Vec<policy::Grants> = outcomes
+            .into_iter()
+            .filter_map(|two_factor| self.evaluate_policy(&self.ip, two_factor))
+            .collect();
+        let denied = grants.iter().all(|g| self.deny_by_policy(g));
+        // Checks the port forward target until the policy is applied.
+        self.policy = grants.into_iter().next().unwrap_or_default();
+        if denied {
+            self.send_policy_denied().await;
+            return false;
+        }
+        true
+    }
+
+    // Applies the access policy once authorized, with the actual 2FA result.
+    async fn apply_policy(&mut self) -> bool {
+        // The caps depend on the policy, and the video cap on the authorization.
+        self.caps_generation = None;
+        let Some(grants) = self.evaluate_policy(&self.ip, self.two_factor_passed) else {
+            return true;
+        };
+        log::info!(
+            "Access policy rules matched for {}: {:?}",
+            self.lr.my_id,
+            grants.rules()
+        );
+        let target_denied = self.is_port_forward()
+            && !self.port_forward_mux
+            && !grants.is_target_allowed(&self.port_forward_address);
+        if self.deny_by_policy(&grants) || target_denied {
+            self.send_policy_denied().await;
+            return false;
+        }
+        self.policy = grants;
+        self.apply_policy_permissions().await;
+        true
+    }
+
+    async fn apply_policy_permissions(&mut self) {
+        let keyboard = self.keyboard;
+        self.keyboard &= self.policy.allows_permission("keyboard");
+        if keyboard && !self.keyboard {
+            self.send_permission(Permission::Keyboard, false).await;
+        }
+        let clipboard = self.clipboard;
+        self.clipboard &= self.policy.allows_permission("clipboard");
+        if clipboard && !self.clipboard {
+            self.send_permission(Permission::Clipboard, false).await;
+        }
+        let audio = self.audio;
+        self.audio &= self.policy.allows_permission("audio");
+        if audio && !self.audio {
+            self.send_permission(Permission::Audio, false).await;
+        }
+        let file = self.file;
+        self.file &= self.policy.allows_permission("file");
+        if file && !self.file {
+            self.send_permission(Permission::File, false).await;
+        }
+        let restart = self.restart;
+        self.restart &= self.policy.allows_permission("restart");
+        if restart && !self.restart {
+            self.send_permission(Permission::Restart, false).await;
+        }
+        let recording = self.recording;
+        self.recording &= self.policy.allows_permission("recording");
+        if recording && !self.recording {
+            self.send_permission(Permission::Recording, false).await;
+        }
+        let block_input = self.block_input;
+        self.block_input &= self.policy.allows_permission("block_input");
+        if block_input && !self.block_input {
+            self.send_permission(Permission::BlockInput, false).await;
+        }
+    }
+
     async fn check_whitelist(&mut self, addr: &SocketAddr) -> bool {
         let whitelist: Vec<String> = Config::get_option("whitelist")
             .split(",")
@@ -1205,15 +1624,16 @@ impl Connection {
     }
 
     fn post_conn_audit(&self, v: Value) {
-        if self.server_audit_conn.is_empty() {
-            return;
-        }
-        let url = self.server_audit_conn.clone();
         let mut v = v;
         v["id"] = json!(Config::get_id());
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["conn_id"] = json!(self.inner.id);
         v["session_id"] = json!(self.lr.session_id);
+        super::audit::write("conn", v.clone());
+        if self.server_audit_conn.is_empty() {
+            return;
+        }
+        let url = self.server_audit_conn.clone();
         allow_err!(self.tx_post_seq.send((url, v)));
     }
 
@@ -1240,10 +1660,6 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
-        if self.server_audit_file.is_empty() {
-            return;
-        }
-        let url = self.server_audit_file.clone();
         let file_num = files.len();
         let mut files = files;
         files.sort_by(|a, b| b.1.cmp(&a.1));
@@ -1263,12 +1679,23 @@ impl Connection {
             "is_file":is_file,
             "info":json!(info).to_string(),
         });
+        super::audit::write("file", v.clone());
+        if self.server_audit_file.is_empty() {
+            return;
+        }
+        let url = self.server_audit_file.clone();
         tokio::spawn(async move {
             allow_err!(Self::post_audit_async(url, v).await);
         });
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
+        let mut v = Value::default();
+        v["id"] = json!(Config::get_id());
+        v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
+        v["typ"] = json!(typ as i8);
+        v["info"] = serde_json::Value::String(info.to_string());
+        super::audit::write("alarm", v.clone());
         let url = crate::get_audit_server(
             Config::get_option("api-server"),
             Config::get_option("custom-rendezvous-server"),
@@ -1277,11 +1704,6 @@ impl Connection {
         if url.is_empty() {
             return;
         }
-        let mut v = Value::default();
-        v["id"] = json!(Config::get_id());
-        v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
-        v["typ"] = json!(typ as i8);
-        v["info"] = serde_json::Value::String(info.to_string());
         tokio::spawn(async move {
             allow_err!(Self::post_audit_async(url, v).await);
         });
@@ -1296,41 +1718,24 @@ impl Connection {
         if self.authorized {
             return;
         }
-        if self.require_2fa.is_some() && !self.is_recent_session(true) && !self.from_switch {
-            self.require_2fa.as_ref().map(|totp| {
-                let bot = crate::auth_2fa::TelegramBot::get();
-                let bot = match bot {
-                    Ok(Some(bot)) => bot,
-                    Err(err) => {
-                        log::error!("Failed to get telegram bot: {}", err);
-                        return;
-                    }
-                    _ => return,
-                };
-                let code = totp.generate_current();
-                if let Ok(code) = code {
-                    let text = format!(
-                        "2FA code: {}\n\nA new connection has been established to your device with ID {}. The source IP address is {}.",
-                        code,
-                        Config::get_id(),
-                        self.ip,
-                    );
-                    tokio::spawn(async move {
-                        if let Err(err) =
-                            crate::auth_2fa::send_2fa_code_to_telegram(&text, bot).await
-                        {
-                            log::error!("Failed to send 2fa code to telegram bot: {}", err);
-                        }
-                    });
-                }
-            });
-            self.send_login_error(crate::client::REQUIRE_2FA).await;
+        if self.require_2fa.is_some() && !self.from_switch {
+            if !self.is_recent_session(true) {
+                self.notify_2fa();
+                self.send_login_error(crate::client::REQUIRE_2FA).await;
+                return;
+            }
+            self.two_factor_passed = true;
+        }
+        if !self.apply_policy().await {
             return;
         }
         self.authorized = true;
+        if self.schedule_auth {
+            self.auto_disconnect_timer.schedule = Self::get_schedule_deadline();
+        }
         let (conn_type, auth_conn_type) = if self.file_transfer.is_some() {
             (1, AuthConnType::FileTransfer)
-        } else if self.port_forward_socket.is_some() {
+        } else if self.is_port_forward() {
             (2, AuthConnType::PortForward)
         } else if self.view_camera {
             (3, AuthConnType::ViewCamera)
@@ -1354,6 +1759,16 @@ impl Connection {
         self.post_conn_audit(
             json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
         );
+        video_service::record_login(
+            self.inner.id(),
+            json!({
+                "conn_id": self.inner.id(),
+                "peer_id": self.lr.my_id,
+                "name": self.lr.my_name,
+                "ip": self.ip,
+                "type": conn_type,
+            }),
+        );
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -1436,7 +1851,23 @@ impl Connection {
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
         }
 
-        if self.port_forward_socket.is_some() {
+        if let Some(grace) =
+            resumption::grace().filter(|_| !self.is_port_forward() && self.stream.is_secured())
+        {
+            let ticket = Ticket::generate();
+            resumption::add_ticket(&mut pi.platform_additions, &ticket, grace);
+            RESUMABLE.lock().unwrap().insert(
+                ticket.id(),
+                Resumable {
+                    ticket: ticket.clone(),
+                    peer_id: self.lr.my_id.clone(),
+                    tx: self.tx_resume.clone(),
+                },
+            );
+            self.resume_ticket = Some((ticket, grace));
+        }
+
+        if self.is_port_forward() {
             let mut msg_out = Message::new();
             res.set_peer_info(pi);
             msg_out.set_login_response(res);
@@ -1629,10 +2060,15 @@ impl Connection {
         }
     }
 
+    #[inline]
+    fn is_port_forward(&self) -> bool {
+        self.port_forward_socket.is_some() || self.port_forward_mux
+    }
+
     #[inline]
     fn is_remote(&self) -> bool {
         self.file_transfer.is_none()
-            && self.port_forward_socket.is_none()
+            && !self.is_port_forward()
             && !self.view_camera
             && !self.terminal
     }
@@ -1665,7 +2101,7 @@ impl Connection {
                 let mut s = s.write().unwrap();
                 #[cfg(not(any(target_os = "android", target_os = "ios")))]
                 let _h = try_start_record_cursor_pos();
-                self.auto_disconnect_timer = Self::get_auto_disconenct_timer();
+                self.auto_disconnect_timer.inactivity = Self::get_auto_disconenct_timer();
                 s.try_add_primay_video_service();
                 s.add_connection(self.inner.clone(), &noperms);
             }
@@ -1778,6 +2214,66 @@ impl Connection {
         self.tx_to_cm.send(data).ok();
     }
 
+    /// Adds an event of this connection to the incoming recordings.
+    fn record_event(&self, kind: &str, mut detail: Value) {
+        if !video_service::is_recording() {
+            return;
+        }
+        detail["conn_id"] = json!(self.inner.id());
+        video_service::record_event(kind, detail);
+    }
+
+    /// Records the start, done or error of a file job.
+    fn record_file_event(&self, action: &str, mut detail: Value) {
+        detail["action"] = json!(action);
+        self.record_event("file", detail);
+    }
+
+    /// Records a finished read job, from its transfer log.
+    fn record_file_log(&self, log: &str) {
+        if !video_service::is_recording() {
+            return;
+        }
+        let log: Value = serde_json::from_str(log).unwrap_or_default();
+        let error = log["error"].as_str().unwrap_or_default();
+        let action = if error.is_empty() { "done" } else { "error" };
+        self.record_file_event(action, log);
+    }
+
+    fn record_terminal_input(&mut self, action: &TerminalAction) {
+        let Some(terminal_action::Union::Data(data)) = &action.union else {
+            return;
+        };
+        let bytes = if data.compressed {
+            hbb_common::compress::decompress(&data.data)
+        } else {
+            data.data.to_vec()
+        };
+        let line = self.terminal_input.entry(data.terminal_id).or_default();
+        let mut commands = Vec::new();
+        for b in bytes {
+            match b {
+                b'\r' | b'\n' => {
+                    if !line.is_empty() {
+                        commands.push(String::from_utf8_lossy(line).to_string());
+                        line.clear();
+                    }
+                }
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    line.pop();
+                }
+                _ => line.push(b),
+            }
+        }
+        for command in commands {
+            self.record_event(
+                "terminal",
+                json!({"terminal_id": data.terminal_id, "command": command}),
+            );
+        }
+    }
+
     #[inline]
     fn send_fs(&mut self, data: ipc::FS) {
         self.send_to_cm(ipc::Data::FS(data));
@@ -1865,6 +2361,7 @@ impl Connection {
     }
 
     fn validate_password(&mut self) -> bool {
+        self.outside_schedule = false;
         if password::temporary_enabled() {
             let password = password::temporary_password();
             if self.validate_one_password(password.clone()) {
@@ -1878,12 +2375,68 @@ impl Connection {
         }
         if password::permanent_enabled() {
             if self.validate_one_password(Config::get_permanent_password()) {
+                if !schedule::is_open() {
+                    log::warn!("Permanent password rejected outside the access schedule");
+                    self.outside_schedule = true;
+                    return false;
+                }
+                self.schedule_auth = true;
                 return true;
             }
         }
+        if let Some(key) =
+            crate::pubkey_auth::verify(&self.lr.password, &self.lr.username, &self.hash.challenge)
+        {
+            if !schedule::is_open() {
+                log::warn!("Public key login rejected outside the access schedule");
+                self.outside_schedule = true;
+                return false;
+            }
+            log::info!("Public key login with {}", key);
+            self.schedule_auth = true;
+            return true;
+        }
         false
     }
 
+    // Sends the 2FA code, and the approve link if enabled, to the notifiers.
+    fn notify_2fa(&mut self) {
+        let Some(totp) = self.require_2fa.as_ref() else {
+            return;
+        };
+        let code = totp.generate_current().ok();
+        if let Some(token) = self.approval_token.take() {
+            login_approval::cancel(&token);
+        }
+        let links = login_approval::create(&self.lr.my_id, &self.ip);
+        let attempt = crate::auth_2fa::LoginAttempt {
+            id: Config::get_id(),
+            ip: self.ip.clone(),
+            peer_id: self.lr.my_id.clone(),
+            peer_name: self.lr.my_name.clone(),
+            code,
+            approve_url: links.as_ref().map(|l| l.approve.clone()),
+            deny_url: links.as_ref().map(|l| l.deny.clone()),
+        };
+        self.approval_token = links.map(|l| l.token);
+        tokio::spawn(crate::auth_2fa::notify(attempt));
+    }
+
+    // The owner approved the login by link, same as a correct 2FA code.
+    async fn approve_2fa(&mut self) {
+        if self.authorized || self.require_2fa.take().is_none() {
+            return;
+        }
+        self.two_factor_passed = true;
+        raii::AuthedConnID::set_session_2fa(self.session_key());
+        self.send_logon_response().await;
+        self.try_start_cm(
+            self.lr.my_id.to_owned(),
+            self.lr.my_name.to_owned(),
+            self.authorized,
+        );
+    }
+
     fn is_recent_session(&mut self, tfa: bool) -> bool {
         SESSIONS
             .lock()
@@ -1896,6 +2449,8 @@ impl Connection {
             .map(|s| s.to_owned());
         // last_recv_time is a mutex variable shared with connection, can be updated lively.
         if let Some(session) = session {
+            // Skipping 2FA is a trusted shortcut, not valid outside the access schedule.
+            let tfa = tfa && schedule::is_open();
             if !self.lr.password.is_empty()
                 && (tfa && session.tfa
                     || !tfa && self.validate_one_password(session.random_password.clone()))
@@ -1959,8 +2514,14 @@ impl Connection {
                     && device.name == lr.my_name
                     && device.platform == lr.my_platform
                 {
-                    log::info!("2FA bypassed by trusted devices");
-                    self.require_2fa = None;
+                    if schedule::is_open() {
+                        log::info!("2FA bypassed by trusted devices");
+                        self.require_2fa = None;
+                        self.two_factor_passed = true;
+                        self.schedule_auth = true;
+                    } else {
+                        log::info!("Trusted device ignored outside the access schedule");
+                    }
                 }
             }
         }
@@ -2013,10 +2574,17 @@ impl Connection {
         }
         // After handling CloseReason messages, proceed to process other message types
         if let Some(message::Union::LoginRequest(lr)) = msg.union {
+            if let Some(id) = resumption::ticket_id(&lr.password) {
+                return self.handle_resume_request(id, &lr).await;
+            }
             self.handle_login_request_without_validation(&lr).await;
             if self.authorized {
                 return true;
             }
+            if !self.check_policy(&lr).await {
+                sleep(1.).await;
+                return false;
+            }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
                     if !Connection::permission(keys::OPTION_ENABLE_FILE_TRANSFER) {
@@ -2096,26 +2664,44 @@ impl Connection {
                         pf.port = 3389;
                         is_rdp = true;
                     }
-                    if pf.host.is_empty() {
-                        pf.host = "localhost".to_owned();
-                    }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
-                    self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
-                        Ok(Ok(sock)) => {
-                            self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                    if pf.host == tunnel::MUX_HOST && pf.port == 0 {
+                        // Targets are chosen per channel after login.
+                        self.port_forward_address = pf.host;
+                        self.port_forward_mux = true;
+                    } else {
+                        if pf.host.is_empty() {
+                            pf.host = "localhost".to_owned();
                         }
-                        _ => {
-                            if is_rdp {
-                                addr = "RDP".to_owned();
-                            }
+                        let mut addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = addr.clone();
+                        if !TargetAllowlist::load().is_allowed(&addr)
+                            || !self.policy.is_target_allowed(&addr)
+                        {
+                            log::warn!("Port forward to {} rejected by the allowlist", addr);
                             self.send_login_error(format!(
-                                "Failed to access remote {}, please make sure if it is open",
+                                "Port forward to {} is not allowed",
                                 addr
                             ))
                             .await;
                             return false;
                         }
+                        match timeout(3000, TcpStream::connect(&addr)).await {
+                            Ok(Ok(sock)) => {
+                                self.port_forward_socket =
+                                    Some(Framed::new(sock, BytesCodec::new()));
+                            }
+                            _ => {
+                                if is_rdp {
+                                    addr = "RDP".to_owned();
+                                }
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    addr
+                                ))
+                                .await;
+                                return false;
+                            }
+                        }
                     }
                 }
                 _ => {
@@ -2173,7 +2759,9 @@ impl Connection {
             } else if (password::approve_mode() == ApproveMode::Click
                 && !(crate::get_builtin_option(keys::OPTION_ALLOW_LOGON_SCREEN_PASSWORD) == "Y"
                     && is_logon()))
-                || password::approve_mode() == ApproveMode::Both && !password::has_valid_password()
+                || password::approve_mode() == ApproveMode::Both
+                    && !password::has_valid_password()
+                    && !crate::pubkey_auth::has_authorized_keys()
             {
                 self.try_start_cm(lr.my_id, lr.my_name, false);
                 if hbb_common::get_version_number(&lr.version)
@@ -2208,7 +2796,10 @@ impl Connection {
                 }
                 if !self.validate_password() {
                     self.update_failure(failure, false, 0);
-                    if err_msg.is_empty() {
+                    if self.outside_schedule {
+                        self.send_login_error(schedule::LOGIN_MSG_OUTSIDE_SCHEDULE)
+                            .await;
+                    } else if err_msg.is_empty() {
                         self.send_login_error(crate::client::LOGIN_MSG_PASSWORD_WRONG)
                             .await;
                         self.try_start_cm(lr.my_id, lr.my_name, false);
@@ -2240,6 +2831,10 @@ impl Connection {
                     if res {
                         self.update_failure(failure, true, 1);
                         self.require_2fa.take();
+                        self.two_factor_passed = true;
+                        if let Some(token) = self.approval_token.take() {
+                            login_approval::cancel(&token);
+                        }
                         raii::AuthedConnID::set_session_2fa(self.session_key());
                         self.send_logon_response().await;
                         self.try_start_cm(
@@ -2305,7 +2900,7 @@ impl Connection {
                 }
             }
         } else if self.authorized {
-            if self.port_forward_socket.is_some() {
+            if self.is_port_forward() {
                 return true;
             }
             match msg.union {
@@ -2508,6 +3103,10 @@ impl Connection {
                 }
                 Some(message::Union::Clipboard(cb)) => {
                     if self.clipboard {
+                        self.record_event(
+                            "clipboard",
+                            json!({"format": cb.format.value(), "size": cb.content.len()}),
+                        );
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Host);
                         // ios as the controlled side is actually not supported for now.
@@ -2535,6 +3134,11 @@ impl Connection {
                     }
                 }
                 Some(message::Union::MultiClipboards(_mcb)) => {
+                    if self.clipboard {
+                        let formats: Vec<i32> =
+                            _mcb.clipboards.iter().map(|c| c.format.value()).collect();
+                        self.record_event("clipboard", json!({ "formats": formats }));
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_mcb.clipboards, ClipboardSide::Host);
@@ -2545,6 +3149,8 @@ impl Connection {
                 #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
                 Some(message::Union::Cliprdr(clip)) => {
                     if let Some(cliprdr::Union::Files(files)) = &clip.union {
+                        let names: Vec<&String> = files.files.iter().map(|f| &f.name).collect();
+                        self.record_event("clipboard_files", json!({ "files": names }));
                         self.post_file_audit(
                             FileAuditType::RemoteReceive,
                             "",
@@ -2658,6 +3264,46 @@ impl Connection {
                                 return true;
                             }
                         }
+                        let policy_job = match &fa.union {
+                            // Listings have no job, errors of job 0 are shown as is.
+                            Some(file_action::Union::ReadDir(_))
+                            | Some(file_action::Union::ReadEmptyDirs(_)) => {
+                                Some((0, policy::Capability::FileRead))
+                            }
+                            Some(file_action::Union::AllFiles(f)) => {
+                                Some((f.id, policy::Capability::FileRead))
+                            }
+                            Some(file_action::Union::Send(s)) => {
+                                Some((s.id, policy::Capability::FileRead))
+                            }
+                            Some(file_action::Union::Receive(r)) => {
+                                Some((r.id, policy::Capability::FileWrite))
+                            }
+                            Some(file_action::Union::RemoveFile(rf)) => {
+                                Some((rf.id, policy::Capability::FileWrite))
+                            }
+                            Some(file_action::Union::RemoveDir(rd)) => {
+                                Some((rd.id, policy::Capability::FileWrite))
+                            }
+                            Some(file_action::Union::Create(c)) => {
+                                Some((c.id, policy::Capability::FileWrite))
+                            }
+                            Some(file_action::Union::Rename(r)) => {
+                                Some((r.id, policy::Capability::FileWrite))
+                            }
+                            _ => None,
+                        };
+                        if let Some((job_id, capability)) = policy_job {
+                            if self.file_transfer.is_some() && !self.policy.allows(capability) {
+                                self.send(fs::new_error(
+                                    job_id,
+                                    "Not allowed by the access policy",
+                                    0,
+                                ))
+                                .await;
+                                return true;
+                            }
+                        }
                         match fa.union {
                             Some(file_action::Union::ReadEmptyDirs(rd)) => {
                                 self.read_empty_dirs(&rd.path, rd.include_hidden);
@@ -2716,9 +3362,17 @@ impl Connection {
                                     od,
                                 ) {
                                     Err(err) => {
+                                        self.record_file_event(
+                                            "error",
+                                            json!({"id": id, "path": path, "error": err.to_string()}),
+                                        );
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
                                     Ok(mut job) => {
+                                        self.record_file_event(
+                                            "start",
+                                            json!({"id": id, "path": path, "isRemote": true}),
+                                        );
                                         self.send(fs::new_dir(id, path, job.files().to_vec()))
                                             .await;
                                         let files = job.files().to_owned();
@@ -2763,6 +3417,10 @@ impl Connection {
                                     total_size: r.total_size,
                                     conn_id: self.inner.id(),
                                 });
+                                self.record_file_event(
+                                    "start",
+                                    json!({"id": r.id, "path": r.path, "isRemote": false}),
+                                );
                                 self.post_file_audit(
                                     FileAuditType::RemoteReceive,
                                     &r.path,
@@ -2806,9 +3464,11 @@ impl Connection {
                             Some(file_action::Union::Cancel(c)) => {
                                 self.send_fs(ipc::FS::CancelWrite { id: c.id });
                                 if let Some(job) = fs::remove_job(c.id, &mut self.read_jobs) {
+                                    let log = fs::serialize_transfer_job(&job, false, true, "");
+                                    self.record_file_log(&log);
                                     self.send_to_cm(ipc::Data::FileTransferLog((
                                         "transfer".to_string(),
-                                        fs::serialize_transfer_job(&job, false, true, ""),
+                                        log,
                                     )));
                                 }
                             }
@@ -2843,6 +3503,7 @@ impl Connection {
                 }
                 Some(message::Union::FileResponse(fr)) => match fr.union {
                     Some(file_response::Union::Block(block)) => {
+                        *self.file_blocks.entry(block.id).or_default() += 1;
                         self.send_fs(ipc::FS::WriteBlock {
                             id: block.id,
                             file_num: block.file_num,
@@ -2851,6 +3512,10 @@ impl Connection {
                         });
                     }
                     Some(file_response::Union::Done(d)) => {
+                        self.record_file_event(
+                            "done",
+                            json!({"id": d.id, "file_num": d.file_num, "isRemote": false}),
+                        );
                         self.send_fs(ipc::FS::WriteDone {
                             id: d.id,
                             file_num: d.file_num,
@@ -2865,6 +3530,10 @@ impl Connection {
                         is_resume: d.is_resume,
                     }),
                     Some(file_response::Union::Error(e)) => {
+                        self.record_file_event(
+                            "error",
+                            json!({"id": e.id, "file_num": e.file_num, "error": e.error}),
+                        );
                         self.send_fs(ipc::FS::WriteError {
                             id: e.id,
                             file_num: e.file_num,
@@ -3068,6 +3737,7 @@ impl Connection {
                     }
                 }
                 Some(message::Union::TerminalAction(action)) => {
+                    self.record_terminal_input(&action);
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     allow_err!(self.handle_terminal_action(action).await);
                     #[cfg(any(target_os = "android", target_os = "ios"))]
@@ -3347,6 +4017,10 @@ impl Connection {
     async fn handle_switch_display(&mut self, s: SwitchDisplay) {
         let display_idx = s.display as usize;
         if self.display_idx != display_idx {
+            self.record_event(
+                "switch_display",
+                json!({"from": self.display_idx, "to": display_idx}),
+            );
             if let Some(server) = self.server.upgrade() {
                 self.switch_display_to(display_idx, server.clone());
 
@@ -3505,6 +4179,7 @@ impl Connection {
     }
 
     async fn toggle_privacy_mode(&mut self, t: TogglePrivacyMode) {
+        self.record_event("privacy_mode", json!({"on": t.on, "impl_key": t.impl_key}));
         if t.on {
             self.turn_on_privacy(t.impl_key).await;
         } else {
@@ -3983,6 +4658,20 @@ impl Connection {
         // But it's not necessary now and we have to consider two audio services(client, server).
         crate::audio_service::set_voice_call_input_device(None, true);
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        video_service::record_logout(self.inner.id(), reason);
+        self.report_traffic();
+        if self.authorized {
+            super::audit::write(
+                "traffic",
+                json!({
+                    "conn_id": self.inner.id(),
+                    "session_id": self.lr.session_id,
+                    "peer_id": self.lr.my_id,
+                    "ip": self.ip,
+                    "stats": self.traffic.to_json(),
+                }),
+            );
+        }
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             lock_screen().await;
@@ -3997,6 +4686,7 @@ impl Connection {
         let data = ipc::Data::Close;
         self.tx_to_cm.send(data).ok();
         self.port_forward_socket.take();
+        self.port_forward_mux = false;
     }
 
     // The `reason` should be consistent with `check_if_retry` if not empty
@@ -4029,9 +4719,169 @@ impl Connection {
         });
     }
 
+    fn send_timeout(&self) -> u64 {
+        if self.file_transfer.is_some() || self.is_port_forward() || self.terminal {
+            SEND_TIMEOUT_OTHER
+        } else {
+            SEND_TIMEOUT_VIDEO
+        }
+    }
+
+    async fn handle_resume_request(&mut self, id: TicketId, lr: &LoginRequest) -> bool {
+        // The session must not go on in plain text.
+        let tx = RESUMABLE.lock().unwrap().get(&id).and_then(|r| {
+            (self.stream.is_secured()
+                && r.peer_id == lr.my_id
+                && !r.tx.is_closed()
+                && r.ticket.verify(&lr.password, &self.hash.challenge))
+            .then(|| r.tx.clone())
+        });
+        if tx.is_some() {
+            self.resume_to = tx;
+        } else {
+            log::warn!(
+                "#{} failed to resume a session of {}",
+                self.inner.id,
+                lr.my_id
+            );
+            self.send_login_error(resumption::LOGIN_MSG_RESUME_FAILED)
+                .await;
+            sleep(1.).await;
+        }
+        false
+    }
+
+    // Starts waiting for the peer to resume the session over a new path,
+    // after the current one broke. Returns false if the session can't be
+    // resumed, the connection is to be closed.
+    fn wait_resume(&mut self, reason: &str, lock: bool) -> bool {
+        let Some((_, grace)) = self.resume_ticket.as_ref() else {
+            return false;
+        };
+        log::info!(
+            "#{} {}, wait {:?} for the session to be resumed",
+            self.inner.id,
+            reason,
+            grace
+        );
+        self.resume_deadline = Some((Instant::now() + *grace, reason.to_owned(), lock));
+        true
+    }
+
+    // Takes the stream of the connection resuming this session. Messages in
+    // flight on the old stream are lost, the peer compares the file blocks and
+    // the video is refreshed. The access policy is applied again for the new
+    // address, returns false if it denies the session.
+    async fn resume(&mut self, mut other: Box<Connection>) -> bool {
+        let grants = self.evaluate_policy(&other.ip, self.two_factor_passed);
+        if let Some(grants) = grants.as_ref().filter(|g| self.deny_by_policy(g)) {
+            log::warn!(
+                "#{} session resume from {} denied by the access policy: {:?}",
+                self.inner.id,
+                other.ip,
+                grants.rules()
+            );
+            other
+                .send_login_error("Not allowed by the access policy")
+                .await;
+            super::audit::write(
+                "policy",
+                json!({ "ip": other.ip, "peer_id": self.lr.my_id, "action": "resume" }),
+            );
+            return false;
+        }
+        std::mem::swap(&mut self.stream, &mut other.stream);
+        self.stream.set_send_timeout(self.send_timeout());
+        log::info!("#{} session resumed from {}", self.inner.id, other.ip);
+        self.ip = other.ip.clone();
+        self.last_test_delay = None;
+        self.post_conn_audit(json!({
+            "ip": self.ip,
+            "action": "resume",
+        }));
+        let mut msg_out = Message::new();
+        msg_out.set_login_response(LoginResponse {
+            union: Some(login_response::Union::PeerInfo(PeerInfo {
+                platform_additions: resumption::resumed_additions(&self.file_blocks),
+                ..Default::default()
+            })),
+            ..Default::default()
+        });
+        self.send(msg_out).await;
+        if let Some(grants) = grants {
+            self.policy = grants;
+            self.caps_generation = None;
+            self.apply_policy_permissions().await;
+        }
+        self.refresh_video_display(None);
+        true
+    }
+
+    // Handles a frame of the peer, returns false to leave the loop.
+    async fn handle_frame(&mut self, bytes: &[u8]) -> bool {
+        let Ok(msg_in) = Message::parse_from_bytes(bytes) else {
+            return true;
+        };
+        if !self.on_message(msg_in).await {
+            return false;
+        }
+        if self.is_port_forward() && self.authorized {
+            log::info!(
+                "Port forward, last_test_delay is none: {}",
+                self.last_test_delay.is_none()
+            );
+            // Avoid TestDelay reply injection into rdp data stream
+            if self.last_test_delay.is_none() {
+                return false;
+            }
+        }
+        true
+    }
+
     #[inline]
     async fn send(&mut self, msg: Message) {
+        // The path is broken, the message would be lost anyway.
+        if self.resume_deadline.is_some() {
+            return;
+        }
         allow_err!(self.stream.send(&msg).await);
+        self.traffic.on_sent(&msg);
+    }
+
+    // Applies the bandwidth caps of the options and the access policy, once
+    // either changed.
+    fn refresh_bandwidth_caps(&mut self) {
+        let generation = traffic::options_generation();
+        if self.caps_generation == Some(generation) {
+            return;
+        }
+        self.caps_generation = Some(generation);
+        let (upload, download) = self.policy.bandwidth();
+        let cap = |option: &str, policy: Option<u64>| {
+            traffic::min_cap(
+                traffic::parse_cap(&Config::get_option(option)),
+                policy.unwrap_or(0) * 1024,
+            )
+        };
+        let upload = cap(traffic::OPTION_UPLOAD_BANDWIDTH, upload);
+        self.download_throttle
+            .set_rate(cap(traffic::OPTION_DOWNLOAD_BANDWIDTH, download));
+        self.send_scheduler.refresh(upload);
+        if self.authorized {
+            video_service::VIDEO_QOS
+                .lock()
+                .unwrap()
+                .user_bandwidth_cap(self.inner.id(), Some((upload * 8 / 1000) as u32));
+        }
+    }
+
+    fn report_traffic(&mut self) {
+        let sent = self.traffic.total_sent();
+        let received = self.traffic.total_received();
+        super::metrics::SENT_BYTES.fetch_add(sent - self.traffic_reported.0, Ordering::Relaxed);
+        super::metrics::RECEIVED_BYTES
+            .fetch_add(received - self.traffic_reported.1, Ordering::Relaxed);
+        self.traffic_reported = (sent, received);
     }
 
     pub fn alive_conns() -> Vec<i32> {
@@ -4108,12 +4958,57 @@ impl Connection {
         }
     }
 
+    fn get_schedule_deadline() -> Option<(Instant, bool)> {
+        schedule::Schedule::load()
+            .and_then(|s| s.remaining())
+            .map(|d| (Instant::now() + d, false))
+    }
+
     fn update_auto_disconnect_timer(&mut self) {
         self.auto_disconnect_timer
+            .inactivity
             .as_mut()
             .map(|t| t.0 = Instant::now());
     }
 
+    // Returns the close reason and the log reason if the session is inactive
+    // for too long or its access window has ended, warns the peer before the
+    // latter.
+    async fn check_auto_disconnect(&mut self) -> Option<(&'static str, &'static str)> {
+        if let Some((instant, minute)) = self.auto_disconnect_timer.inactivity {
+            if instant.elapsed().as_secs() > minute * 60 {
+                return Some(("Connection failed due to inactivity", "auto disconnect"));
+            }
+        }
+        let (deadline, warned) = self.auto_disconnect_timer.schedule?;
+        let now = Instant::now();
+        if now >= deadline {
+            // The schedule may have been changed or extended meanwhile.
+            let current = schedule::Schedule::load();
+            if current.as_ref().map_or(false, |s| s.remaining().is_none()) {
+                return Some((schedule::CLOSE_REASON_SCHEDULE_END, "access schedule"));
+            }
+            self.auto_disconnect_timer.schedule = current
+                .and_then(|s| s.remaining())
+                .map(|d| (now + d, false));
+        } else if !warned && deadline - now <= schedule::WARN_BEFORE {
+            self.auto_disconnect_timer.schedule = Some((deadline, true));
+            let mut msg_out = Message::new();
+            msg_out.set_message_box(MessageBox {
+                msgtype: "custom-nook-nocancel-hasclose".to_owned(),
+                title: "Unattended access".to_owned(),
+                text: format!(
+                    "The access window ends in {} minutes, the session will be closed.",
+                    ((deadline - now).as_secs() + 59) / 60
+                ),
+                link: "".to_owned(),
+                ..Default::default()
+            });
+            self.send(msg_out).await;
+        }
+        None
+    }
+
     #[cfg(feature = "hwcodec")]
     fn update_supported_encoding(&mut self) {
         let Some(last) = &self.last_supported_encoding else {
@@ -4861,6 +5756,7 @@ mod raii {
                 printer,
             });
             Self::check_wake_lock();
+            crate::server::metrics::CONNECTIONS.fetch_add(1, Ordering::Relaxed);
             use std::sync::Once;
             static _ONCE: Once = Once::new();
             _ONCE.call_once(|| {
@@ -5005,6 +5901,7 @@ mod raii {
                 scrap::wayland::pipewire::try_close_session();
             }
             Self::check_wake_lock();
+            video_service::record_logout(self.0, "closed");
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             {
                 use crate::whiteboard;
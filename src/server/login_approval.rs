@@ -0,0 +1,249 @@
+//! Approve a 2FA login from a link in the notification.
+//!
+//! Enabled when `2fa-approve-listen` is set, e.g. `127.0.0.1:21120` or just a
+//! port to listen on localhost, and `2fa-approve-url` is the public url of
+//! this listener, usually behind a reverse proxy, e.g.
+//! `https://desk.example.com/rustdesk`. The links end with
+//! `/approve/<token>` and `/deny/<token>`; opening one shows a page asking
+//! for confirmation, only a `POST` decides, so link previews can not approve.
+
+use hbb_common::{
+    config::Config,
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub const OPTION_2FA_APPROVE_LISTEN: &str = "2fa-approve-listen";
+pub const OPTION_2FA_APPROVE_URL: &str = "2fa-approve-url";
+pub const LOGIN_MSG_DENIED: &str = "The login was denied by the owner";
+
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const MAX_REQUEST: usize = 8 * 1024;
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref PENDING: Mutex<HashMap<String, Pending>> = Default::default();
+}
+
+struct Pending {
+    created: Instant,
+    peer: String,
+    ip: String,
+    decision: Option<bool>,
+}
+
+pub struct Links {
+    pub token: String,
+    pub approve: String,
+    pub deny: String,
+}
+
+/// Registers a login waiting for approval, `None` if approving by link is
+/// not enabled.
+pub fn create(peer: &str, ip: &str) -> Option<Links> {
+    let url = Config::get_option(OPTION_2FA_APPROVE_URL);
+    let url = url.trim_end_matches('/');
+    if url.is_empty() || !LISTENING.load(Ordering::SeqCst) {
+        return None;
+    }
+    let token = hex::encode(hbb_common::rand::random::<[u8; 32]>());
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.created.elapsed() < TIMEOUT);
+    pending.insert(
+        token.clone(),
+        Pending {
+            created: Instant::now(),
+            peer: peer.to_owned(),
+            ip: ip.to_owned(),
+            decision: None,
+        },
+    );
+    Some(Links {
+        approve: format!("{}/approve/{}", url, token),
+        deny: format!("{}/deny/{}", url, token),
+        token,
+    })
+}
+
+/// The decision of the owner, the token is used up once decided.
+pub fn poll(token: &str) -> Option<bool> {
+    let mut pending = PENDING.lock().unwrap();
+    let decision = pending.get(token)?.decision;
+    if decision.is_some() {
+        pending.remove(token);
+    }
+    decision
+}
+
+pub fn cancel(token: &str) {
+    PENDING.lock().unwrap().remove(token);
+}
+
+pub async fn start() {
+    let addr = Config::get_option(OPTION_2FA_APPROVE_LISTEN);
+    if addr.is_empty() {
+        return;
+    }
+    let addr = match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr,
+    };
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to listen login approval on {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Login approval listening on {}", addr);
+    LISTENING.store(true, Ordering::SeqCst);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream).await {
+                        log::debug!("Login approval request failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                log::error!("Failed to accept login approval connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn handle(method: &str, path: &str) -> (&'static str, String) {
+    let path = path.split('?').next().unwrap_or_default();
+    let mut segments = path.rsplit('/');
+    let (Some(token), Some(action)) = (segments.next(), segments.next()) else {
+        return ("404 Not Found", "Not Found".to_owned());
+    };
+    let approve = match action {
+        "approve" => true,
+        "deny" => false,
+        _ => return ("404 Not Found", "Not Found".to_owned()),
+    };
+    let mut pending = PENDING.lock().unwrap();
+    let Some(p) = pending
+        .get_mut(token)
+        .filter(|p| p.created.elapsed() < TIMEOUT && p.decision.is_none())
+    else {
+        return (
+            "404 Not Found",
+            "The link has expired or been used".to_owned(),
+        );
+    };
+    let action = if approve { "Approve" } else { "Deny" };
+    match method {
+        "GET" => (
+            "200 OK",
+            format!(
+                "<p>{} the login of {} from {} to {}?</p><form method=\"post\"><button type=\"submit\">{}</button></form>",
+                action,
+                escape(&p.peer),
+                escape(&p.ip),
+                escape(&Config::get_id()),
+                action
+            ),
+        ),
+        "POST" => {
+            p.decision = Some(approve);
+            log::info!("Login of {} from {} {}d by link", p.peer, p.ip, action);
+            super::audit::write(
+                "2fa",
+                serde_json::json!({"peer_id": p.peer, "ip": p.ip, "approved": approve}),
+            );
+            (
+                "200 OK",
+                format!("<p>{}d, you can close this page.</p>", action),
+            )
+        }
+        _ => ("405 Method Not Allowed", "Method Not Allowed".to_owned()),
+    }
+}
+
+async fn serve(mut stream: TcpStream) -> ResultType<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST {
+        let n = hbb_common::timeout(3_000, stream.read(&mut chunk)).await??;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.split_whitespace();
+    let (status, body) = handle(
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let body = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>RustDesk</title></head><body>{}</body></html>",
+        body
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nCache-Control: no-store\r\nReferrer-Policy: no-referrer\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approve() {
+        let token = "t".repeat(64);
+        PENDING.lock().unwrap().insert(
+            token.clone(),
+            Pending {
+                created: Instant::now(),
+                peer: "<b>".to_owned(),
+                ip: "1.2.3.4".to_owned(),
+                decision: None,
+            },
+        );
+        let path = format!("/rustdesk/approve/{}", token);
+        let (status, body) = handle("GET", &path);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("&lt;b&gt;"));
+        assert_eq!(poll(&token), None);
+        assert_eq!(handle("POST", &path).0, "200 OK");
+        assert_eq!(
+            handle("POST", &format!("/deny/{}", token)).0,
+            "404 Not Found"
+        );
+        assert_eq!(poll(&token), Some(true));
+        assert_eq!(poll(&token), None);
+        assert_eq!(handle("GET", "/approve/unknown").0, "404 Not Found");
+    }
+}
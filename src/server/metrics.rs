@@ -0,0 +1,371 @@
+//! Prometheus metrics of the server process.
+//!
+//! Disabled unless the `metrics-listen` option is set, e.g. `127.0.0.1:9464`
+//! or just a port to listen on localhost. `GET /metrics` returns the text
+//! exposition format. The option is read when the server starts.
+
+use super::{video_service::VIDEO_QOS, AuthConnType, AUTHED_CONNS};
+use hbb_common::{
+    config::{self, Config},
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    ResultType,
+};
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+pub const OPTION_METRICS_LISTEN: &str = "metrics-listen";
+
+const MAX_REQUEST: usize = 8 * 1024;
+
+lazy_static::lazy_static! {
+    pub static ref ENCODE_SECONDS: Histogram = Histogram::new(
+        "rustdesk_video_encode_seconds",
+        "Time to encode a video frame.",
+        &[0.002, 0.005, 0.01, 0.02, 0.033, 0.05, 0.1, 0.25, 0.5],
+    );
+    pub static ref FRAME_BYTES: Histogram = Histogram::new(
+        "rustdesk_video_frame_bytes",
+        "Size of an encoded video frame.",
+        &[1e3, 4e3, 16e3, 64e3, 256e3, 1e6, 4e6],
+    );
+    pub static ref NETWORK_DELAY: Histogram = Histogram::new(
+        "rustdesk_network_delay_seconds",
+        "Delay of the test delay round trip reported by the peers.",
+        &[0.01, 0.025, 0.05, 0.1, 0.15, 0.2, 0.3, 0.5, 1., 2., 5.],
+    );
+}
+
+pub static ENCODE_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub static SENT_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static RECEIVED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Default)]
+struct HistogramData {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(name: &'static str, help: &'static str, bounds: &'static [f64]) -> Self {
+        Self {
+            name,
+            help,
+            bounds,
+            data: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let mut data = self.data.lock().unwrap();
+        if let Some(i) = self.bounds.iter().position(|b| v <= *b) {
+            data.counts[i] += 1;
+        }
+        data.sum += v;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let data = self.data.lock().unwrap();
+        header(out, self.name, self.help, "histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(data.counts.iter()) {
+            cumulative += count;
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            )
+            .ok();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, data.count).ok();
+        writeln!(out, "{}_sum {}", self.name, data.sum).ok();
+        writeln!(out, "{}_count {}", self.name, data.count).ok();
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, typ: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, typ).ok();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, v: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, v).ok();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, v: &AtomicU64) {
+    header(out, name, help, "counter");
+    writeln!(out, "{} {}", name, v.load(Ordering::Relaxed)).ok();
+}
+
+fn render() -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "rustdesk_connections",
+        "Authorized connections by type.",
+        "gauge",
+    );
+    let conns = AUTHED_CONNS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| c.conn_type)
+        .collect::<Vec<_>>();
+    for (typ, label) in [
+        (AuthConnType::Remote, "remote"),
+        (AuthConnType::FileTransfer, "file_transfer"),
+        (AuthConnType::PortForward, "port_forward"),
+        (AuthConnType::ViewCamera, "view_camera"),
+        (AuthConnType::Terminal, "terminal"),
+    ] {
+        let n = conns.iter().filter(|c| **c == typ).count();
+        writeln!(out, "rustdesk_connections{{type=\"{}\"}} {}", label, n).ok();
+    }
+    counter(
+        &mut out,
+        "rustdesk_connections_total",
+        "Authorized connections since start.",
+        &CONNECTIONS,
+    );
+    counter(
+        &mut out,
+        "rustdesk_sent_bytes_total",
+        "Bytes sent to the peers.",
+        &SENT_BYTES,
+    );
+    counter(
+        &mut out,
+        "rustdesk_received_bytes_total",
+        "Bytes received from the peers.",
+        &RECEIVED_BYTES,
+    );
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    {
+        let services = super::terminal_service::list_services();
+        gauge(
+            &mut out,
+            "rustdesk_terminal_services",
+            "Terminal services, including persistent ones without a connection.",
+            services.len(),
+        );
+        gauge(
+            &mut out,
+            "rustdesk_terminal_sessions",
+            "Open terminal sessions.",
+            services.iter().map(|s| s.terminal_count).sum::<usize>(),
+        );
+    }
+
+    let (fps, ratio, bitrate, users) = {
+        let mut qos = VIDEO_QOS.lock().unwrap();
+        (qos.fps(), qos.ratio(), qos.bitrate(), qos.user_stats())
+    };
+    gauge(&mut out, "rustdesk_video_fps", "Target video fps.", fps);
+    gauge(
+        &mut out,
+        "rustdesk_video_ratio",
+        "Bitrate ratio of the video quality.",
+        ratio,
+    );
+    gauge(
+        &mut out,
+        "rustdesk_video_bitrate_kbps",
+        "Bitrate of the video encoder.",
+        bitrate,
+    );
+    header(
+        &mut out,
+        "rustdesk_user_fps",
+        "Fps adjusted to the network of the peer.",
+        "gauge",
+    );
+    for u in users.iter() {
+        if let Some(fps) = u.fps {
+            writeln!(
+                out,
+                "rustdesk_user_fps{{conn_id=\"{}\"}} {}",
+                u.conn_id, fps
+            )
+            .ok();
+        }
+    }
+    header(
+        &mut out,
+        "rustdesk_user_delay_ms",
+        "Last test delay of the peer.",
+        "gauge",
+    );
+    for u in users.iter() {
+        if let Some(delay) = u.delay {
+            writeln!(
+                out,
+                "rustdesk_user_delay_ms{{conn_id=\"{}\"}} {}",
+                u.conn_id, delay
+            )
+            .ok();
+        }
+    }
+    header(
+        &mut out,
+        "rustdesk_user_rtt_ms",
+        "Estimated round trip time to the peer.",
+        "gauge",
+    );
+    for u in users.iter() {
+        if let Some(rtt) = u.rtt {
+            writeln!(
+                out,
+                "rustdesk_user_rtt_ms{{conn_id=\"{}\"}} {}",
+                u.conn_id, rtt
+            )
+            .ok();
+        }
+    }
+    header(
+        &mut out,
+        "rustdesk_user_stalled",
+        "1 if the peer has not answered a test delay for more than 2 seconds.",
+        "gauge",
+    );
+    for u in users.iter() {
+        writeln!(
+            out,
+            "rustdesk_user_stalled{{conn_id=\"{}\"}} {}",
+            u.conn_id, u.response_delayed as u8
+        )
+        .ok();
+    }
+
+    ENCODE_SECONDS.render(&mut out);
+    FRAME_BYTES.render(&mut out);
+    counter(
+        &mut out,
+        "rustdesk_video_encode_errors_total",
+        "Failed video frame encodings.",
+        &ENCODE_ERRORS,
+    );
+    NETWORK_DELAY.render(&mut out);
+
+    gauge(
+        &mut out,
+        "rustdesk_nat_type",
+        "Detected NAT type, 0 unknown, 1 asymmetric, 2 symmetric.",
+        Config::get_nat_type(),
+    );
+    gauge(
+        &mut out,
+        "rustdesk_rendezvous_registered",
+        "1 if registered to the rendezvous server.",
+        (config::get_online_state() > 0) as u8,
+    );
+    out
+}
+
+pub async fn start() {
+    let addr = Config::get_option(OPTION_METRICS_LISTEN);
+    if addr.is_empty() {
+        return;
+    }
+    let addr = match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr,
+    };
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to listen metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Metrics listening on {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream).await {
+                        log::debug!("Metrics request failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                log::error!("Failed to accept metrics connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream) -> ResultType<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST {
+        let n = hbb_common::timeout(3_000, stream.read(&mut chunk)).await??;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new("test_seconds", "Test.", &[0.1, 1.]);
+        h.observe(0.25);
+        h.observe(0.5);
+        h.observe(3.);
+        let mut out = String::new();
+        h.render(&mut out);
+        assert!(out.contains("# TYPE test_seconds histogram\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 3.75\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+}
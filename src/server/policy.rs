@@ -0,0 +1,472 @@
+//! Access policy for incoming connections.
+//!
+//! The `access-policy-file` option points to a JSON file of rules, evaluated at
+//! login on top of the permission options, e.g.
+//!
+//! ```json
+//! {
+//!   "default": "allow",
+//!   "rules": [
+//!     {
+//!       "name": "desk-a",
+//!       "peer_ids": ["123*"],
+//!       "ips": ["10.1.0.0/16"],
+//!       "conn_types": ["remote", "file_transfer"],
+//!       "time": ["Mon-Fri 08:00-18:00"],
+//!       "two_factor": true,
+//!       "allow": ["keyboard", "clipboard", "file_read"],
+//!       "deny": ["file_write", "recording"]
+//!     },
+//!     { "ips": ["0.0.0.0/0"], "deny": ["terminal", "port_forward"] }
+//!   ]
+//! }
+//! ```
+//!
+//! All the conditions of a rule must match, a missing condition matches anything.
+//! For each capability, the first matching rule listing it decides, otherwise
+//! `default`. `port_forward_targets` restricts the targets like
+//! `port-forward-allowlist`, `upload_bandwidth` and `download_bandwidth` cap the
+//! traffic of the session in KB/s, the first matching rule setting them decides.
+//! `two_factor` matches whether the peer passed 2FA, by a code, the approve
+//! link, a trusted device or a recent 2FA session, so the rules are applied
+//! once the login is authorized, a login approved by click never passed it.
+//! `ReadDir`, `AllFiles` and the other listings need `file_read`.
+//! A policy file that cannot be read denies every login.
+
+use crate::tunnel::TargetAllowlist;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike, Weekday};
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{anyhow::anyhow, bail, config::Config, ResultType};
+use serde_derive::Deserialize;
+use std::{net::IpAddr, str::FromStr};
+
+use super::AuthConnType;
+
+pub const OPTION_ACCESS_POLICY_FILE: &str = "access-policy-file";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    #[serde(rename = "*")]
+    All,
+    // Connection types
+    Remote,
+    ViewCamera,
+    FileTransfer,
+    Terminal,
+    PortForward,
+    // Permissions in a session
+    Keyboard,
+    Clipboard,
+    Audio,
+    FileRead,
+    FileWrite,
+    Restart,
+    Recording,
+    BlockInput,
+}
+
+impl Capability {
+    pub fn of_conn_type(conn_type: AuthConnType) -> Self {
+        match conn_type {
+            AuthConnType::Remote => Self::Remote,
+            AuthConnType::ViewCamera => Self::ViewCamera,
+            AuthConnType::FileTransfer => Self::FileTransfer,
+            AuthConnType::Terminal => Self::Terminal,
+            AuthConnType::PortForward => Self::PortForward,
+        }
+    }
+
+    fn matches(&self, other: Capability) -> bool {
+        *self == Self::All || *self == other
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Decision {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    default: Decision,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    peer_ids: Vec<String>,
+    #[serde(default)]
+    ips: Vec<String>,
+    #[serde(default)]
+    conn_types: Vec<String>,
+    #[serde(default)]
+    time: Vec<String>,
+    two_factor: Option<bool>,
+    #[serde(default)]
+    allow: Vec<Capability>,
+    #[serde(default)]
+    deny: Vec<Capability>,
+    port_forward_targets: Option<String>,
+    upload_bandwidth: Option<u64>,
+    download_bandwidth: Option<u64>,
+    #[serde(skip)]
+    cidrs: Vec<IpCidr>,
+    #[serde(skip)]
+    windows: Vec<TimeWindow>,
+}
+
+/// What a policy is evaluated against.
+pub struct Context<'a> {
+    pub peer_id: &'a str,
+    pub ip: Option<IpAddr>,
+    pub conn_type: AuthConnType,
+    pub two_factor: bool,
+    pub time: NaiveDateTime,
+}
+
+/// A weekly window in local time, e.g. `Mon-Fri 09:00-18:00`, `Sat,Sun` or
+/// `09:00-12:00` for every day. An end before the start spans midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeWindow {
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+impl FromStr for TimeWindow {
+    type Err = hbb_common::anyhow::Error;
+
+    fn from_str(s: &str) -> ResultType<Self> {
+        let s = s.trim();
+        let (days, hours) = match s.split_once(' ') {
+            Some((days, hours)) => (days.trim(), hours.trim()),
+            None if s.contains(':') => ("*", s),
+            None => (s, ""),
+        };
+        let mut window = TimeWindow {
+            days: [days == "*"; 7],
+            start: 0,
+            end: 24 * 60,
+        };
+        if days != "*" {
+            for part in days.split(',') {
+                let (first, last) = match part.split_once('-') {
+                    Some((first, last)) => (parse_weekday(first)?, parse_weekday(last)?),
+                    None => (parse_weekday(part)?, parse_weekday(part)?),
+                };
+                let mut day = first;
+                loop {
+                    window.days[day.num_days_from_monday() as usize] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = day.succ();
+                }
+            }
+        }
+        if !hours.is_empty() {
+            let (start, end) = hours
+                .split_once('-')
+                .ok_or_else(|| anyhow!("invalid hours {}", hours))?;
+            window.start = parse_minutes(start)?;
+            window.end = parse_minutes(end)?;
+        }
+        Ok(window)
+    }
+}
+
+fn parse_weekday(s: &str) -> ResultType<Weekday> {
+    s.trim()
+        .parse::<Weekday>()
+        .map_err(|_| anyhow!("invalid day {}", s))
+}
+
+fn parse_minutes(s: &str) -> ResultType<u32> {
+    let (h, m) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid time {}", s))?;
+    let (h, m) = (h.parse::<u32>()?, m.parse::<u32>()?);
+    if h > 24 || m > 59 || h * 60 + m > 24 * 60 {
+        bail!("invalid time {}", s);
+    }
+    Ok(h * 60 + m)
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: &NaiveDateTime) -> bool {
+        let day = time.weekday().num_days_from_monday() as usize;
+        let minute = time.hour() * 60 + time.minute();
+        if self.start <= self.end {
+            self.days[day] && minute >= self.start && minute < self.end
+        } else {
+            // The part after midnight belongs to the window of the day before.
+            (self.days[day] && minute >= self.start)
+                || (self.days[(day + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+/// The capabilities granted to a connection.
+#[derive(Debug, Default)]
+pub struct Grants {
+    decisions: Vec<(Capability, Decision)>,
+    default: Decision,
+    targets: Option<TargetAllowlist>,
+    upload_bandwidth: Option<u64>,
+    download_bandwidth: Option<u64>,
+    rules: Vec<String>,
+}
+
+impl Grants {
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.decisions
+            .iter()
+            .find(|(c, _)| c.matches(capability))
+            .map(|(_, d)| *d)
+            .unwrap_or(self.default)
+            == Decision::Allow
+    }
+
+    /// Checks a permission switched by the connection manager.
+    pub fn allows_permission(&self, name: &str) -> bool {
+        match name {
+            "keyboard" => self.allows(Capability::Keyboard),
+            "clipboard" => self.allows(Capability::Clipboard),
+            "audio" => self.allows(Capability::Audio),
+            "file" => self.allows(Capability::FileRead) && self.allows(Capability::FileWrite),
+            "restart" => self.allows(Capability::Restart),
+            "recording" => self.allows(Capability::Recording),
+            "block_input" => self.allows(Capability::BlockInput),
+            _ => true,
+        }
+    }
+
+    pub fn is_target_allowed(&self, target: &str) -> bool {
+        self.targets
+            .as_ref()
+            .map(|t| t.is_allowed(target))
+            .unwrap_or(true)
+    }
+
+    /// Upload and download caps in KB/s.
+    pub fn bandwidth(&self) -> (Option<u64>, Option<u64>) {
+        (self.upload_bandwidth, self.download_bandwidth)
+    }
+
+    /// Names of the matched rules, for logging.
+    pub fn rules(&self) -> &[String] {
+        &self.rules
+    }
+}
+
+impl Policy {
+    /// Loads the policy file, `None` if there is none configured.
+    pub fn load() -> ResultType<Option<Self>> {
+        let path = Config::get_option(OPTION_ACCESS_POLICY_FILE);
+        if path.is_empty() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        Self::parse(&content).map(Some)
+    }
+
+    pub fn parse(content: &str) -> ResultType<Self> {
+        let mut policy: Policy = serde_json::from_str(content)?;
+        for (i, rule) in policy.rules.iter_mut().enumerate() {
+            if rule.name.is_empty() {
+                rule.name = format!("#{}", i + 1);
+            }
+            for ip in rule.ips.iter() {
+                rule.cidrs.push(
+                    IpCidr::from_str(ip)
+                        .map_err(|_| anyhow!("rule {}: invalid ip {}", rule.name, ip))?,
+                );
+            }
+            for window in rule.time.iter() {
+                rule.windows.push(
+                    window
+                        .parse()
+                        .map_err(|e| anyhow!("rule {}: {}", rule.name, e))?,
+                );
+            }
+            for typ in rule.conn_types.iter() {
+                if !matches!(
+                    serde_json::from_value::<Capability>(serde_json::json!(typ)),
+                    Ok(Capability::Remote
+                        | Capability::ViewCamera
+                        | Capability::FileTransfer
+                        | Capability::Terminal
+                        | Capability::PortForward)
+                ) {
+                    bail!("rule {}: invalid connection type {}", rule.name, typ);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn evaluate(&self, ctx: &Context) -> Grants {
+        let mut grants = Grants {
+            default: self.default,
+            ..Default::default()
+        };
+        for rule in self.rules.iter().filter(|r| r.matches(ctx)) {
+            grants.rules.push(rule.name.clone());
+            grants
+                .decisions
+                .extend(rule.deny.iter().map(|c| (*c, Decision::Deny)));
+            grants
+                .decisions
+                .extend(rule.allow.iter().map(|c| (*c, Decision::Allow)));
+            if grants.targets.is_none() {
+                grants.targets = rule
+                    .port_forward_targets
+                    .as_ref()
+                    .map(|t| TargetAllowlist::parse(t));
+            }
+            grants.upload_bandwidth = grants.upload_bandwidth.or(rule.upload_bandwidth);
+            grants.download_bandwidth = grants.download_bandwidth.or(rule.download_bandwidth);
+        }
+        grants
+    }
+
+    pub fn now() -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+impl Rule {
+    fn matches(&self, ctx: &Context) -> bool {
+        let conn_type = Capability::of_conn_type(ctx.conn_type);
+        (self.peer_ids.is_empty() || self.peer_ids.iter().any(|p| wildcard(p, ctx.peer_id)))
+            && (self.cidrs.is_empty()
+                || ctx
+                    .ip
+                    .map_or(false, |ip| self.cidrs.iter().any(|c| c.contains(ip))))
+            && (self.conn_types.is_empty()
+                || self.conn_types.iter().any(|t| {
+                    serde_json::from_value::<Capability>(serde_json::json!(t)).ok()
+                        == Some(conn_type)
+                }))
+            && (self.windows.is_empty() || self.windows.iter().any(|w| w.contains(&ctx.time)))
+            && self.two_factor.map_or(true, |x| x == ctx.two_factor)
+    }
+}
+
+/// Matches a pattern where `*` stands for any characters.
+fn wildcard(pattern: &str, s: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == s;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !s.starts_with(first) || s.len() < first.len() + last.len() || !s.ends_with(last) {
+        return false;
+    }
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::parse(
+            r#"{
+                "default": "deny",
+                "rules": [
+                    {
+                        "name": "desk-a",
+                        "peer_ids": ["12*"],
+                        "ips": ["10.1.0.0/16"],
+                        "time": ["Mon-Fri 09:00-18:00"],
+                        "allow": ["remote", "file_transfer", "keyboard", "file_read"],
+                        "deny": ["file_write"]
+                    },
+                    {
+                        "conn_types": ["port_forward"],
+                        "two_factor": true,
+                        "allow": ["port_forward"],
+                        "port_forward_targets": "10.1.0.5:22",
+                        "upload_bandwidth": 512
+                    },
+                    { "allow": ["*"] , "deny": ["terminal"], "ips": ["127.0.0.1/32"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        // Wednesday
+        let work = NaiveDateTime::parse_from_str("2024-05-15 10:00", "%Y-%m-%d %H:%M").unwrap();
+        let night = NaiveDateTime::parse_from_str("2024-05-15 20:00", "%Y-%m-%d %H:%M").unwrap();
+        let ctx = |peer_id, ip: &str, conn_type, two_factor, time| Context {
+            peer_id,
+            ip: ip.parse().ok(),
+            conn_type,
+            two_factor,
+            time,
+        };
+
+        let g = policy.evaluate(&ctx("123", "10.1.2.3", AuthConnType::Remote, false, work));
+        assert_eq!(g.rules(), ["desk-a"]);
+        assert!(g.allows(Capability::Remote));
+        assert!(g.allows(Capability::FileRead));
+        assert!(!g.allows(Capability::FileWrite));
+        assert!(!g.allows(Capability::Clipboard));
+
+        let g = policy.evaluate(&ctx("123", "10.1.2.3", AuthConnType::Remote, false, night));
+        assert!(!g.allows(Capability::Remote));
+        let g = policy.evaluate(&ctx("223", "10.1.2.3", AuthConnType::Remote, false, work));
+        assert!(!g.allows(Capability::Remote));
+
+        let pf = AuthConnType::PortForward;
+        let g = policy.evaluate(&ctx("9", "10.2.0.1", pf, true, night));
+        assert!(g.allows(Capability::PortForward));
+        assert!(g.is_target_allowed("10.1.0.5:22"));
+        assert!(!g.is_target_allowed("10.1.0.6:22"));
+        assert_eq!(g.bandwidth(), (Some(512), None));
+        let g = policy.evaluate(&ctx("9", "10.2.0.1", pf, false, night));
+        assert!(!g.allows(Capability::PortForward));
+
+        let g = policy.evaluate(&ctx("9", "127.0.0.1", AuthConnType::Terminal, false, night));
+        assert!(g.allows(Capability::Clipboard));
+        assert!(!g.allows(Capability::Terminal));
+
+        assert!(Policy::parse(r#"{"rules": [{"ips": ["x"]}]}"#).is_err());
+        assert!(Policy::parse(r#"{"rules": [{"allow": ["fly"]}]}"#).is_err());
+        assert!(Policy::parse(r#"{"rules": [{"conn_types": ["keyboard"]}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let at = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        let w: TimeWindow = "Fri 22:00-06:00".parse().unwrap();
+        assert!(w.contains(&at("2024-05-17 23:00")));
+        assert!(w.contains(&at("2024-05-18 05:59")));
+        assert!(!w.contains(&at("2024-05-18 23:00")));
+        let w: TimeWindow = "Sat,Sun".parse().unwrap();
+        assert!(w.contains(&at("2024-05-19 12:00")));
+        assert!(!w.contains(&at("2024-05-20 12:00")));
+        assert!("Mon-Fri 9-17".parse::<TimeWindow>().is_err());
+        assert!(wildcard("1*3*", "12345"));
+        assert!(!wildcard("1*3", "12345"));
+    }
+}
@@ -0,0 +1,163 @@
+//! Weekly windows for unattended access.
+//!
+//! When the `access-schedule` option is set, e.g. `Sat,Sun; Mon-Fri 22:00-06:00`,
+//! the permanent password and trusted devices are only accepted inside these
+//! windows, and sessions they opened are closed when the window ends. The
+//! temporary password and click approval are not restricted.
+//!
+//! The windows are in local time, or in the fixed offset of the
+//! `access-schedule-timezone` option, e.g. `UTC`, `+02:00` or `-05:30`. Zone
+//! names such as `CET` or `Europe/Berlin` are not supported, so a fixed offset
+//! does not follow daylight saving time and has to be updated when it changes.
+//! Leave the option empty to follow the local time of the device instead.
+
+use super::policy::TimeWindow;
+use chrono::{FixedOffset, Local, NaiveDateTime, Timelike, Utc};
+use hbb_common::{anyhow::anyhow, bail, config::Config, log, ResultType};
+use std::time::Duration;
+
+pub const OPTION_ACCESS_SCHEDULE: &str = "access-schedule";
+/// A fixed UTC offset, not a zone name, see the module docs.
+pub const OPTION_ACCESS_SCHEDULE_TIMEZONE: &str = "access-schedule-timezone";
+pub const LOGIN_MSG_OUTSIDE_SCHEDULE: &str = "Unattended access is not allowed at this time";
+pub const CLOSE_REASON_SCHEDULE_END: &str = "The unattended access window has ended";
+/// Sessions are warned this long before the window ends.
+pub const WARN_BEFORE: Duration = Duration::from_secs(5 * 60);
+
+const WEEK_MINUTES: i64 = 7 * 24 * 60;
+
+pub struct Schedule {
+    windows: Vec<TimeWindow>,
+    offset: Option<FixedOffset>,
+}
+
+impl Schedule {
+    /// The configured schedule, `None` if access is not restricted.
+    pub fn load() -> Option<Self> {
+        let windows = Config::get_option(OPTION_ACCESS_SCHEDULE);
+        if windows.trim().is_empty() {
+            return None;
+        }
+        let timezone = Config::get_option(OPTION_ACCESS_SCHEDULE_TIMEZONE);
+        match Self::parse(&windows, &timezone) {
+            Ok(schedule) => Some(schedule),
+            Err(err) => {
+                // Fail closed, a typo must not open the access.
+                log::error!("Invalid access schedule {}: {}", windows, err);
+                Some(Self {
+                    windows: vec![],
+                    offset: None,
+                })
+            }
+        }
+    }
+
+    pub fn parse(windows: &str, timezone: &str) -> ResultType<Self> {
+        let windows = windows
+            .split(';')
+            .map(|w| w.trim())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.parse())
+            .collect::<ResultType<Vec<TimeWindow>>>()?;
+        Ok(Self {
+            windows,
+            offset: parse_offset(timezone)?,
+        })
+    }
+
+    fn now(&self) -> NaiveDateTime {
+        match self.offset {
+            Some(offset) => Utc::now().with_timezone(&offset).naive_local(),
+            None => Local::now().naive_local(),
+        }
+    }
+
+    fn is_open_at(&self, time: &NaiveDateTime) -> bool {
+        self.windows.iter().any(|w| w.contains(time))
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(&self.now())
+    }
+
+    /// Time left until the access closes, `None` if it is closed. Windows that
+    /// follow each other are merged, a schedule open all the week returns a week.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_at(&self.now())
+    }
+
+    fn remaining_at(&self, now: &NaiveDateTime) -> Option<Duration> {
+        if !self.is_open_at(now) {
+            return None;
+        }
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        let end = (1..=WEEK_MINUTES)
+            .map(|m| minute + chrono::Duration::minutes(m))
+            .find(|t| !self.is_open_at(t))
+            .unwrap_or(minute + chrono::Duration::minutes(WEEK_MINUTES));
+        (end - *now).to_std().ok()
+    }
+}
+
+fn parse_offset(s: &str) -> ResultType<Option<FixedOffset>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let s = s.strip_prefix("UTC").unwrap_or(s);
+    if s.is_empty() || s == "Z" {
+        return Ok(FixedOffset::east_opt(0));
+    }
+    let (sign, rest) = match s.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => bail!(
+            "invalid timezone {}, only fixed UTC offsets are supported",
+            s
+        ),
+    };
+    let (h, m) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (h, m) = (h.parse::<i32>()?, m.parse::<i32>()?);
+    if h > 14 || m > 59 {
+        bail!("invalid timezone {}", s);
+    }
+    FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+        .map(Some)
+        .ok_or_else(|| anyhow!("invalid timezone {}", s))
+}
+
+/// Whether the permanent password and trusted devices are accepted now.
+pub fn is_open() -> bool {
+    Schedule::load().map_or(true, |s| s.is_open())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let at = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        let s = Schedule::parse("Sat,Sun; Fri 20:00-24:00", "+02:00").unwrap();
+        assert_eq!(s.offset, FixedOffset::east_opt(7200));
+        // Friday
+        assert!(s.remaining_at(&at("2024-05-17 19:00")).is_none());
+        assert_eq!(
+            s.remaining_at(&at("2024-05-17 21:00")),
+            Some(Duration::from_secs((3 + 48) * 3600))
+        );
+        assert_eq!(
+            s.remaining_at(&at("2024-05-19 23:30")),
+            Some(Duration::from_secs(30 * 60))
+        );
+        let s = Schedule::parse("*", "").unwrap();
+        assert_eq!(
+            s.remaining_at(&at("2024-05-19 23:30")),
+            Some(Duration::from_secs(WEEK_MINUTES as u64 * 60))
+        );
+        assert!(Schedule::parse("Sat", "+2:00").is_ok());
+        assert!(Schedule::parse("Sat", "CET").is_err());
+        assert!(Schedule::parse("Sat", "Europe/Berlin").is_err());
+        assert!(Schedule::parse("Someday", "").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Send order of the messages of a connection.
+//!
+//! Queued messages go out by class, control and cursor first, then audio,
+//! video, clipboard and file transfer. Messages changing the displays go with
+//! the video, so no frame of the old display is sent after them. File blocks are read only when nothing
+//! else is waiting, and at most at `file-transfer-bandwidth` KB/s or the
+//! upload cap of the session if set, so a large copy does not make the session
+//! sluggish.
+
+use crate::traffic::{self, TokenBucket};
+use hbb_common::{config::Config, message_proto::*};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const OPTION_FILE_TRANSFER_BANDWIDTH: &str = "file-transfer-bandwidth";
+
+const AUDIO_MAX_LATENCY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Control = 0,
+    Audio,
+    Video,
+    Clipboard,
+    File,
+}
+
+const CLASSES: usize = Class::File as usize + 1;
+
+pub fn classify(msg: &Message) -> Class {
+    match &msg.union {
+        Some(message::Union::AudioFrame(_)) => Class::Audio,
+        Some(message::Union::VideoFrame(_)) | Some(message::Union::PeerInfo(_)) => Class::Video,
+        Some(message::Union::Misc(misc))
+            if matches!(
+                misc.union,
+                Some(misc::Union::SwitchDisplay(_)) | Some(misc::Union::FollowCurrentDisplay(_))
+            ) =>
+        {
+            Class::Video
+        }
+        Some(message::Union::Clipboard(_))
+        | Some(message::Union::MultiClipboards(_))
+        | Some(message::Union::Cliprdr(_)) => Class::Clipboard,
+        Some(message::Union::FileResponse(_)) | Some(message::Union::FileAction(_)) => Class::File,
+        _ => Class::Control,
+    }
+}
+
+pub struct SendScheduler {
+    queues: [VecDeque<(Instant, Arc<Message>)>; CLASSES],
+    file_bucket: TokenBucket,
+}
+
+impl SendScheduler {
+    pub fn new() -> Self {
+        let mut s = Self {
+            queues: Default::default(),
+            file_bucket: TokenBucket::new(0),
+        };
+        s.refresh(0);
+        s
+    }
+
+    /// Rereads the bandwidth cap, `upload_cap` in bytes per second.
+    pub fn refresh(&mut self, upload_cap: u64) {
+        let cap = traffic::parse_cap(&Config::get_option(OPTION_FILE_TRANSFER_BANDWIDTH));
+        self.file_bucket.set_rate(traffic::min_cap(cap, upload_cap));
+    }
+
+    pub fn push(&mut self, class: Class, instant: Instant, msg: Arc<Message>) {
+        self.queues[class as usize].push_back((instant, msg));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    /// The most urgent message, stale audio is dropped.
+    pub fn pop(&mut self) -> Option<(Class, Instant, Arc<Message>)> {
+        loop {
+            let (i, queue) = self
+                .queues
+                .iter_mut()
+                .enumerate()
+                .find(|(_, q)| !q.is_empty())?;
+            let (instant, msg) = queue.pop_front()?;
+            let class = match i {
+                0 => Class::Control,
+                1 => Class::Audio,
+                2 => Class::Video,
+                3 => Class::Clipboard,
+                _ => Class::File,
+            };
+            if class == Class::Audio && instant.elapsed() > AUDIO_MAX_LATENCY {
+                continue;
+            }
+            return Some((class, instant, msg));
+        }
+    }
+
+    /// Drops what would be stale once a broken path is back: audio and video
+    /// frames, and all but the latest message of each type of the other
+    /// classes but file transfer.
+    pub fn drop_stale(&mut self) {
+        for (i, queue) in self.queues.iter_mut().enumerate() {
+            if i == Class::Audio as usize {
+                queue.clear();
+            } else if i != Class::File as usize {
+                let mut types = HashSet::new();
+                let mut latest: Vec<_> = queue
+                    .drain(..)
+                    .rev()
+                    .filter(|(_, msg)| {
+                        !matches!(msg.union, Some(message::Union::VideoFrame(_)))
+                            && types.insert(traffic::message_type(msg))
+                    })
+                    .collect();
+                latest.reverse();
+                queue.extend(latest);
+            }
+        }
+    }
+
+    /// Whether file blocks may be read and sent now.
+    pub fn file_ready(&mut self) -> bool {
+        self.is_empty() && self.file_bucket.ready()
+    }
+
+    pub fn file_sent(&mut self, bytes: u64) {
+        self.file_bucket.consume(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_scheduler() {
+        let mut s = SendScheduler::new();
+        s.file_bucket.set_rate(0);
+        assert!(s.file_ready());
+        let msg = |f: fn(&mut Message)| {
+            let mut m = Message::new();
+            f(&mut m);
+            Arc::new(m)
+        };
+        let file = msg(|m| m.set_file_response(FileResponse::new()));
+        let video = msg(|m| m.set_video_frame(VideoFrame::new()));
+        let cursor = msg(|m| m.set_cursor_position(CursorPosition::new()));
+        let clip = msg(|m| m.set_clipboard(Clipboard::new()));
+        for m in [&file, &video, &clip, &cursor] {
+            s.push(classify(m), Instant::now(), m.clone());
+        }
+        s.push(
+            Class::Audio,
+            Instant::now() - Duration::from_secs(2),
+            msg(|m| m.set_audio_frame(AudioFrame::new())),
+        );
+        assert!(!s.file_ready());
+        let order: Vec<Class> = std::iter::from_fn(|| s.pop().map(|x| x.0)).collect();
+        assert_eq!(
+            order,
+            vec![Class::Control, Class::Video, Class::Clipboard, Class::File]
+        );
+
+        for m in [&video, &cursor, &clip, &cursor, &clip] {
+            s.push(classify(m), Instant::now(), m.clone());
+        }
+        s.drop_stale();
+        let order: Vec<Class> = std::iter::from_fn(|| s.pop().map(|x| x.0)).collect();
+        assert_eq!(order, vec![Class::Control, Class::Clipboard]);
+
+        let mut switch = Message::new();
+        let mut misc = Misc::new();
+        misc.set_switch_display(SwitchDisplay::new());
+        switch.set_misc(misc);
+        let switch = Arc::new(switch);
+        for m in [&video, &switch, &cursor] {
+            s.push(classify(m), Instant::now(), m.clone());
+        }
+        let order: Vec<_> = std::iter::from_fn(|| s.pop().map(|x| x.2)).collect();
+        assert_eq!(order, vec![cursor.clone(), video.clone(), switch]);
+
+        s.file_bucket.set_rate(1000);
+        assert!(s.file_ready());
+        s.file_sent(5000);
+        assert!(!s.file_ready());
+    }
+}
@@ -0,0 +1,406 @@
+//! On-disk scrollback of terminal sessions.
+//!
+//! Enabled with the `terminal-history` option. The output of each terminal is
+//! kept as asciicast v2 events in `terminal-history/<service_id>/<terminal_id>.hist`
+//! under the config directory, so a persistent service can replay it after the
+//! server restarts, and [`export_cast`] can write a `.cast` file for asciinema.
+//!
+//! The file is a sequence of zstd compressed chunks, each prefixed with its
+//! length as u32 le. The first chunk of a file is the asciicast header. When a
+//! file reaches half of `terminal-history-max-size`, it is moved to `.hist.1`,
+//! replacing the older one.
+
+use hbb_common::{bail, compress, config::Config, log, ResultType};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const OPTION_TERMINAL_HISTORY: &str = "terminal-history";
+/// Size in MB of the history kept per terminal.
+pub const OPTION_TERMINAL_HISTORY_MAX_SIZE: &str = "terminal-history-max-size";
+/// Days to keep the history of services that are gone.
+pub const OPTION_TERMINAL_HISTORY_RETENTION: &str = "terminal-history-retention-days";
+
+const DEFAULT_MAX_SIZE_MB: u64 = 8;
+const DEFAULT_RETENTION_DAYS: u64 = 7;
+const FLUSH_SIZE: usize = 32 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const EXT: &str = "hist";
+
+pub fn enabled() -> bool {
+    Config::get_option(OPTION_TERMINAL_HISTORY) == "Y"
+}
+
+/// Bytes of history kept per terminal.
+fn max_size() -> u64 {
+    Config::get_option(OPTION_TERMINAL_HISTORY_MAX_SIZE)
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_MAX_SIZE_MB)
+        * 1024
+        * 1024
+}
+
+fn root() -> PathBuf {
+    Config::path("terminal-history")
+}
+
+fn service_dir(service_id: &str) -> PathBuf {
+    // Service ids come from the peer, keep them inside the root.
+    let name: String = service_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect();
+    root().join(name)
+}
+
+fn history_path(service_id: &str, terminal_id: i32) -> PathBuf {
+    service_dir(service_id).join(format!("{}.{}", terminal_id, EXT))
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{}.1", EXT))
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn create_dir(dir: &Path) -> ResultType<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Appends the output of a terminal to its history file.
+pub struct History {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    timestamp: u64,
+    start: f64,
+    rows: u16,
+    cols: u16,
+    pending: Vec<u8>,
+    // Bytes of a UTF-8 sequence split between two reads.
+    partial: Vec<u8>,
+    last_flush: Instant,
+}
+
+impl History {
+    /// Opens the history of a terminal, continuing an existing one.
+    /// Returns `None` if the history is disabled or cannot be written.
+    pub fn open(service_id: &str, terminal_id: i32, rows: u16, cols: u16) -> Option<Self> {
+        if !enabled() {
+            return None;
+        }
+        match Self::open_(history_path(service_id, terminal_id), rows, cols) {
+            Ok(history) => Some(history),
+            Err(err) => {
+                log::error!("Failed to open terminal history: {}", err);
+                None
+            }
+        }
+    }
+
+    fn open_(path: PathBuf, rows: u16, cols: u16) -> ResultType<Self> {
+        if let Some(dir) = path.parent() {
+            create_dir(dir)?;
+        }
+        let max_size = max_size();
+        let timestamp = read_header(&rotated_path(&path))
+            .or_else(|| read_header(&path))
+            .and_then(|h| h["timestamp"].as_u64())
+            .unwrap_or(now_secs() as u64);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let mut history = Self {
+            path,
+            file,
+            size,
+            max_size,
+            timestamp,
+            start: timestamp as f64,
+            rows,
+            cols,
+            pending: Vec::new(),
+            partial: Vec::new(),
+            last_flush: Instant::now(),
+        };
+        if size == 0 {
+            history.write_header()?;
+        } else {
+            history.event("r", &format!("{}x{}", cols, rows));
+        }
+        Ok(history)
+    }
+
+    fn write_header(&mut self) -> ResultType<()> {
+        let header = json!({
+            "version": 2,
+            "width": self.cols,
+            "height": self.rows,
+            "timestamp": self.timestamp,
+            "env": {"TERM": "xterm-256color"},
+        });
+        self.write_chunk(format!("{}\n", header).as_bytes())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> ResultType<()> {
+        let compressed = compress::compress(data);
+        self.file
+            .write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.file.write_all(&compressed)?;
+        self.size += 4 + compressed.len() as u64;
+        Ok(())
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        let line = json!([now_secs() - self.start, code, data]);
+        self.pending.extend_from_slice(line.to_string().as_bytes());
+        self.pending.push(b'\n');
+    }
+
+    pub fn output(&mut self, data: &[u8]) {
+        let mut data = [std::mem::take(&mut self.partial).as_slice(), data].concat();
+        let valid = match std::str::from_utf8(&data) {
+            Ok(_) => data.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => data.len(),
+        };
+        self.partial = data.split_off(valid);
+        if !data.is_empty() {
+            self.event("o", &String::from_utf8_lossy(&data));
+        }
+        if self.pending.len() >= FLUSH_SIZE || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        self.rows = rows;
+        self.cols = cols;
+        self.event("r", &format!("{}x{}", cols, rows));
+    }
+
+    pub fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        if let Err(err) = self.rotate().and_then(|_| self.write_chunk(&pending)) {
+            log::error!("Failed to write terminal history: {}", err);
+        }
+    }
+
+    fn rotate(&mut self) -> ResultType<()> {
+        if self.size < self.max_size / 2 {
+            return Ok(());
+        }
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.write_header()
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn read_chunks(path: &Path) -> Vec<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let Ok(mut file) = File::open(path) else {
+        return chunks;
+    };
+    let mut len = [0u8; 4];
+    // A chunk cut by a crash ends the history.
+    while file.read_exact(&mut len).is_ok() {
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        if file.read_exact(&mut data).is_err() {
+            break;
+        }
+        chunks.push(compress::decompress(&data));
+    }
+    chunks
+}
+
+fn read_header(path: &Path) -> Option<Value> {
+    let chunks = read_chunks(path);
+    serde_json::from_slice(chunks.first()?).ok()
+}
+
+/// Events of a terminal, oldest first, without the headers.
+fn read_events(service_id: &str, terminal_id: i32) -> (Option<Value>, Vec<String>) {
+    let path = history_path(service_id, terminal_id);
+    let mut header = None;
+    let mut events = Vec::new();
+    for path in [rotated_path(&path), path] {
+        for (i, chunk) in read_chunks(&path).iter().enumerate() {
+            let text = String::from_utf8_lossy(chunk);
+            if i == 0 {
+                header = header.or_else(|| serde_json::from_str(text.trim()).ok());
+                continue;
+            }
+            events.extend(text.lines().map(|l| l.to_owned()));
+        }
+    }
+    (header, events)
+}
+
+/// The last `max_bytes` of `output`, from the start of a line if there is one.
+fn tail(mut output: Vec<u8>, max_bytes: usize) -> Vec<u8> {
+    if output.len() <= max_bytes {
+        return output;
+    }
+    let cut = output.len() - max_bytes;
+    let start = output[cut..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|i| cut + i + 1)
+        .unwrap_or(cut);
+    output.split_off(start)
+}
+
+/// Output of all terminals of a service kept on disk, to replay after a restart,
+/// at most `terminal-history-max-size` per terminal.
+pub fn load(service_id: &str) -> Vec<(i32, Vec<u8>)> {
+    if !enabled() {
+        return vec![];
+    }
+    let max_bytes = max_size() as usize;
+    let Ok(entries) = fs::read_dir(service_dir(service_id)) else {
+        return vec![];
+    };
+    let mut terminals: Vec<i32> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let path = e.path();
+            if path.extension()? != EXT {
+                return None;
+            }
+            path.file_stem()?.to_str()?.parse().ok()
+        })
+        .collect();
+    terminals.sort();
+    terminals
+        .into_iter()
+        .map(|terminal_id| {
+            let mut output = Vec::new();
+            for event in read_events(service_id, terminal_id).1 {
+                if let Ok(Value::Array(v)) = serde_json::from_str(&event) {
+                    if v.get(1).and_then(|c| c.as_str()) == Some("o") {
+                        if let Some(data) = v.get(2).and_then(|d| d.as_str()) {
+                            output.extend_from_slice(data.as_bytes());
+                        }
+                    }
+                }
+            }
+            (terminal_id, tail(output, max_bytes))
+        })
+        .filter(|(_, output)| !output.is_empty())
+        .collect()
+}
+
+/// Writes the history of a terminal as an asciicast v2 file and returns the
+/// number of events.
+pub fn export_cast(service_id: &str, terminal_id: i32, out: &Path) -> ResultType<usize> {
+    let (header, events) = read_events(service_id, terminal_id);
+    let Some(header) = header else {
+        bail!(
+            "No history of terminal {} of service {}",
+            terminal_id,
+            service_id
+        );
+    };
+    let mut file = File::create(out)?;
+    writeln!(file, "{}", header)?;
+    for event in events.iter() {
+        writeln!(file, "{}", event)?;
+    }
+    Ok(events.len())
+}
+
+/// Removes the history of services not in `active` and not written for the
+/// retention period.
+pub fn remove_stale(active: &[String]) {
+    let days = Config::get_option(OPTION_TERMINAL_HISTORY_RETENTION)
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    let retention = Duration::from_secs(days * 24 * 3600);
+    let Ok(entries) = fs::read_dir(root()) else {
+        return;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if active.iter().any(|id| service_dir(id).ends_with(&name)) {
+            continue;
+        }
+        let modified = fs::read_dir(entry.path())
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok()?.metadata().ok()?.modified().ok())
+            .max();
+        let stale = modified
+            .and_then(|m| m.elapsed().ok())
+            .map(|age| age > retention)
+            .unwrap_or(true);
+        if stale {
+            log::info!("Removing stale terminal history: {}", name);
+            fs::remove_dir_all(entry.path()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_rotation() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-term-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let path = dir.join(format!("1.{}", EXT));
+        let mut history = History::open_(path.clone(), 24, 80).unwrap();
+        history.max_size = 1024;
+        // "é" split between two reads
+        history.output(b"hello \xc3");
+        history.output(b"\xa9\r\n");
+        history.flush();
+        let chunk = String::from_utf8(read_chunks(&path)[1].clone()).unwrap();
+        assert!(chunk.contains("\"hello \"") && chunk.contains("\"é\\r\\n\""));
+        for i in 0..200 {
+            history.output(format!("line {}\r\n", i).as_bytes());
+            history.flush();
+        }
+        drop(history);
+        assert!(rotated_path(&path).exists());
+        let header = read_header(&path).unwrap();
+        assert_eq!(header["version"], 2);
+        assert_eq!(header["width"], 80);
+        let last = read_chunks(&path).pop().unwrap();
+        let last: Value = serde_json::from_slice(&last).unwrap();
+        assert_eq!(last[1], "o");
+        assert_eq!(last[2], "line 199\r\n");
+        assert_eq!(tail(b"ab\ncd\nef".to_vec(), 4), b"ef");
+        assert_eq!(tail(b"abcdef".to_vec(), 4), b"cdef");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,4 +1,4 @@
-use super::*;
+use super::{terminal_history::History, *};
 use hbb_common::{
     anyhow::{anyhow, Context, Result},
     compress,
@@ -203,10 +203,16 @@ pub fn cleanup_inactive_services() {
     }
 
     // Remove outside of iteration to avoid deadlock
+    let active: Vec<String> = services
+        .keys()
+        .filter(|id| !to_remove.contains(id))
+        .cloned()
+        .collect();
     drop(services);
     for id in to_remove {
         remove_service(&id);
     }
+    super::terminal_history::remove_stale(&active);
 }
 
 /// Add a child process to the zombie reaper
@@ -451,6 +457,10 @@ pub struct TerminalSession {
     reader_thread: Option<thread::JoinHandle<()>>,
     writer_thread: Option<thread::JoinHandle<()>>,
     output_buffer: OutputBuffer,
+    // Output kept on disk, see `terminal_history`
+    history: Option<History>,
+    // History restored from disk, sent once the terminal is opened
+    replay: Option<Vec<u8>>,
     title: String,
     pid: u32,
     rows: u16,
@@ -473,6 +483,8 @@ impl TerminalSession {
             reader_thread: None,
             writer_thread: None,
             output_buffer: OutputBuffer::new(),
+            history: None,
+            replay: None,
             title: format!("Terminal {}", terminal_id),
             pid: 0,
             rows,
@@ -538,6 +550,10 @@ impl TerminalSession {
             let _ = child.kill();
             add_to_reaper(child);
         }
+
+        if let Some(history) = self.history.as_mut() {
+            history.flush();
+        }
     }
 }
 
@@ -557,10 +573,15 @@ pub struct PersistentTerminalService {
     pub is_persistent: bool,
     needs_session_sync: bool,
     is_specified_user: bool,
+    // Output of the terminals before the server restarted
+    restored: HashMap<i32, Vec<u8>>,
 }
 
 impl PersistentTerminalService {
     pub fn new(service_id: String, is_persistent: bool, is_specified_user: bool) -> Self {
+        let restored = super::terminal_history::load(&service_id)
+            .into_iter()
+            .collect();
         Self {
             service_id,
             sessions: HashMap::new(),
@@ -569,6 +590,7 @@ impl PersistentTerminalService {
             is_persistent,
             needs_session_sync: false,
             is_specified_user,
+            restored,
         }
     }
 
@@ -589,10 +611,17 @@ impl PersistentTerminalService {
 
     /// Get buffered output for a terminal
     pub fn get_terminal_buffer(&self, terminal_id: i32, max_bytes: usize) -> Option<Vec<u8>> {
-        self.sessions.get(&terminal_id).map(|session| {
-            let session = session.lock().unwrap();
-            session.output_buffer.get_recent(max_bytes)
-        })
+        match self.sessions.get(&terminal_id) {
+            Some(session) => {
+                let session = session.lock().unwrap();
+                Some(session.output_buffer.get_recent(max_bytes))
+            }
+            None => self.restored.get(&terminal_id).map(|data| {
+                let mut buffer = OutputBuffer::new();
+                buffer.append(data);
+                buffer.get_recent(max_bytes)
+            }),
+        }
     }
 
     /// Get terminal info for recovery
@@ -796,6 +825,21 @@ impl TerminalServiceProxy {
             .context("Failed to get reader")?;
 
         session.pid = child.process_id().unwrap_or(0) as u32;
+        if let Some(data) = service.restored.remove(&open.terminal_id) {
+            log::info!(
+                "Replaying {} bytes of history to terminal {}",
+                data.len(),
+                open.terminal_id
+            );
+            session.output_buffer.append(&data);
+            session.replay = Some(data);
+        }
+        session.history = History::open(
+            &service.service_id,
+            open.terminal_id,
+            open.rows as u16,
+            open.cols as u16,
+        );
 
         // Create channels for input/output
         let (input_tx, input_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
@@ -925,6 +969,9 @@ impl TerminalServiceProxy {
             session.update_activity();
             session.rows = resize.rows as u16;
             session.cols = resize.cols as u16;
+            if let Some(history) = session.history.as_mut() {
+                history.resize(resize.rows as u16, resize.cols as u16);
+            }
 
             if let Some(pty_pair) = &session.pty_pair {
                 pty_pair.master.resize(PtySize {
@@ -1038,6 +1085,8 @@ impl TerminalServiceProxy {
                 // Read from output channel
                 let mut has_activity = false;
                 let mut received_data = Vec::new();
+                // The restored history goes first, it is already in `output_buffer`
+                let replay = session.replay.take();
                 if let Some(output_rx) = &session.output_rx {
                     // Try to read all available data
                     while let Ok(data) = output_rx.try_recv() {
@@ -1049,6 +1098,12 @@ impl TerminalServiceProxy {
                 // Update buffer after reading
                 for data in &received_data {
                     session.output_buffer.append(data);
+                    if let Some(history) = session.history.as_mut() {
+                        history.output(data);
+                    }
+                }
+                if let Some(replay) = replay {
+                    received_data.insert(0, replay);
                 }
 
                 // Process received data for responses
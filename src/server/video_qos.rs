@@ -93,6 +93,7 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    bandwidth_cap: Option<u32>, // kbps
 }
 
 #[derive(Default, Debug, Clone)]
@@ -101,6 +102,15 @@ struct DisplayData {
     support_changing_quality: bool,
 }
 
+#[derive(Debug, Clone)]
+pub struct UserStats {
+    pub conn_id: i32,
+    pub fps: Option<u32>,
+    pub delay: Option<u32>,
+    pub rtt: Option<u32>,
+    pub response_delayed: bool,
+}
+
 // Main QoS controller structure
 pub struct VideoQoS {
     fps: u32,
@@ -108,6 +118,7 @@ pub struct VideoQoS {
     users: HashMap<i32, UserData>,
     displays: HashMap<String, DisplayData>,
     bitrate_store: u32,
+    kbps_per_ratio: f32,
     adjust_ratio_instant: Instant,
     abr_config: bool,
     new_user_instant: Instant,
@@ -121,6 +132,7 @@ impl Default for VideoQoS {
             users: Default::default(),
             displays: Default::default(),
             bitrate_store: 0,
+            kbps_per_ratio: 0.,
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
             new_user_instant: Instant::now(),
@@ -148,6 +160,10 @@ impl VideoQoS {
     // Store bitrate for later use
     pub fn store_bitrate(&mut self, bitrate: u32) {
         self.bitrate_store = bitrate;
+        // The bitrate is proportional to the ratio it was encoded with
+        if bitrate > 0 && self.ratio > 0. {
+            self.kbps_per_ratio = bitrate as f32 / self.ratio;
+        }
     }
 
     // Get stored bitrate
@@ -155,14 +171,29 @@ impl VideoQoS {
         self.bitrate_store
     }
 
-    // Get current bitrate ratio with bounds checking
+    // Get current bitrate ratio with bounds checking, capped by the upload bandwidth
     pub fn ratio(&mut self) -> f32 {
         if self.ratio < BR_MIN_HIGH_RESOLUTION || self.ratio > BR_MAX {
             self.ratio = BR_BALANCED;
         }
+        if let Some(cap) = self.bandwidth_cap() {
+            if self.kbps_per_ratio > 0. {
+                // Rounded down to avoid changing the quality for float noise
+                let max = ((cap as f32 / self.kbps_per_ratio * 100.).floor() / 100.)
+                    .max(BR_MIN_HIGH_RESOLUTION);
+                if self.ratio > max {
+                    self.ratio = max;
+                }
+            }
+        }
         self.ratio
     }
 
+    // The lowest upload bandwidth cap of all users in kbps
+    fn bandwidth_cap(&self) -> Option<u32> {
+        self.users.values().filter_map(|u| u.bandwidth_cap).min()
+    }
+
     // Check if any user is in recording mode
     pub fn record(&self) -> bool {
         self.users.iter().any(|u| u.1.record)
@@ -178,6 +209,20 @@ impl VideoQoS {
     pub fn in_vbr_state(&self) -> bool {
         self.abr_config && self.displays.iter().all(|e| e.1.support_changing_quality)
     }
+
+    // Per user network state, exported as metrics
+    pub fn user_stats(&self) -> Vec<UserStats> {
+        self.users
+            .iter()
+            .map(|(id, user)| UserStats {
+                conn_id: *id,
+                fps: user.delay.fps,
+                delay: user.delay.delay_history.back().copied(),
+                rtt: user.delay.rtt_calculator.get_rtt(),
+                response_delayed: user.delay.response_delayed,
+            })
+            .collect()
+    }
 }
 
 // User session management
@@ -237,6 +282,12 @@ impl VideoQoS {
         }
     }
 
+    pub fn user_bandwidth_cap(&mut self, id: i32, kbps: Option<u32>) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.bandwidth_cap = kbps.filter(|v| *v > 0);
+        }
+    }
+
     pub fn user_record(&mut self, id: i32, v: bool) {
         if let Some(user) = self.users.get_mut(&id) {
             user.record = v;
@@ -260,6 +311,7 @@ impl VideoQoS {
         let dividend_ms = DELAY_THRESHOLD_150MS * min_fps;
 
         let mut adjust_ratio = false;
+        super::metrics::NETWORK_DELAY.observe(delay as f64 / 1000.);
         if let Some(user) = self.users.get_mut(&id) {
             let delay = delay.max(10);
             let old_avg_delay = user.delay.avg_delay();
@@ -47,7 +47,7 @@ use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
-    record::{Recorder, RecorderContext},
+    record::{RecordAudioFormat, Recorder, RecorderContext},
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
 };
@@ -78,6 +78,11 @@ lazy_static::lazy_static! {
     pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
     pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
     static ref SCREENSHOTS: Mutex<HashMap<usize, Screenshot>> = Default::default();
+    // Incoming recorders of all displays, which also record the audio service.
+    static ref RECORDERS: Mutex<Vec<std::sync::Weak<Mutex<Option<Recorder>>>>> = Default::default();
+    static ref RECORD_AUDIO_FORMAT: Mutex<Option<RecordAudioFormat>> = Default::default();
+    // conn id -> login event, so that recordings started later name their viewers.
+    static ref RECORD_LOGINS: Mutex<HashMap<i32, serde_json::Value>> = Default::default();
 }
 
 struct Screenshot {
@@ -1062,10 +1067,84 @@ fn get_recorder(
     } else {
         Default::default()
     };
+    if let Some(r) = recorder.lock().unwrap().as_mut() {
+        if let Some(format) = *RECORD_AUDIO_FORMAT.lock().unwrap() {
+            r.set_audio_format(format);
+        }
+        for (_, detail) in RECORD_LOGINS.lock().unwrap().iter() {
+            r.write_event("login", detail.clone());
+        }
+    }
+    if recorder.lock().unwrap().is_some() {
+        let mut recorders = RECORDERS.lock().unwrap();
+        recorders.retain(|x| x.strong_count() > 0);
+        recorders.push(Arc::downgrade(&recorder));
+    }
 
     recorder
 }
 
+/// Called by the audio service when it (re)starts encoding.
+pub fn set_record_audio_format(sample_rate: u32, channels: u16) {
+    let format = RecordAudioFormat {
+        sample_rate,
+        channels,
+    };
+    *RECORD_AUDIO_FORMAT.lock().unwrap() = Some(format);
+    for r in RECORDERS.lock().unwrap().iter().filter_map(|x| x.upgrade()) {
+        r.lock()
+            .unwrap()
+            .as_mut()
+            .map(|r| r.set_audio_format(format));
+    }
+}
+
+/// Whether a recording is running, to skip building its events otherwise.
+pub fn is_recording() -> bool {
+    RECORDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|x| x.upgrade())
+        .any(|r| r.lock().unwrap().is_some())
+}
+
+/// Adds a session event to the incoming recordings, see `scrap::record::RecordEvent`.
+pub fn record_event(kind: &str, detail: serde_json::Value) {
+    for r in RECORDERS.lock().unwrap().iter().filter_map(|x| x.upgrade()) {
+        r.lock()
+            .unwrap()
+            .as_mut()
+            .map(|r| r.write_event(kind, detail.clone()));
+    }
+}
+
+/// Records the login of `conn_id`, also at the start of later recordings while
+/// it is connected.
+pub fn record_login(conn_id: i32, detail: serde_json::Value) {
+    RECORD_LOGINS
+        .lock()
+        .unwrap()
+        .insert(conn_id, detail.clone());
+    record_event("login", detail);
+}
+
+pub fn record_logout(conn_id: i32, reason: &str) {
+    if RECORD_LOGINS.lock().unwrap().remove(&conn_id).is_some() {
+        record_event(
+            "logout",
+            serde_json::json!({"conn_id": conn_id, "reason": reason}),
+        );
+    }
+}
+
+/// Adds an encoded audio frame to the incoming recordings.
+pub fn record_audio(data: &[u8]) {
+    for r in RECORDERS.lock().unwrap().iter().filter_map(|x| x.upgrade()) {
+        r.lock().unwrap().as_mut().map(|r| r.write_audio(data));
+    }
+}
+
 #[cfg(target_os = "android")]
 fn check_change_scale(hardware: bool) -> ResultType<()> {
     use hbb_common::config::keys::OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE as SCALE_SOFT;
@@ -1150,12 +1229,15 @@ fn handle_one_frame(
     let mut send_conn_ids: HashSet<i32> = Default::default();
     let first = *first_frame;
     *first_frame = false;
+    let encode_start = Instant::now();
     match encoder.encode_to_message(frame, ms) {
         Ok(mut vf) => {
             *encode_fail_counter = 0;
             vf.display = display as _;
             let mut msg = Message::new();
             msg.set_video_frame(vf);
+            metrics::ENCODE_SECONDS.observe(encode_start.elapsed().as_secs_f64());
+            metrics::FRAME_BYTES.observe(msg.compute_size() as f64);
             recorder
                 .lock()
                 .unwrap()
@@ -1164,6 +1246,7 @@ fn handle_one_frame(
             send_conn_ids = sp.send_video_frame(msg);
         }
         Err(e) => {
+            metrics::ENCODE_ERRORS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             *encode_fail_counter += 1;
             // Encoding errors are not frequent except on Android
             if !cfg!(target_os = "android") {
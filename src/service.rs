@@ -6,6 +6,7 @@ fn main() {}
 #[cfg(target_os = "macos")]
 fn main() {
     crate::common::load_custom_client();
+    crate::common::load_config_bundle();
     hbb_common::init_log(false, "service");
     crate::start_os_service();
 }
@@ -0,0 +1,375 @@
+//! Traffic accounting and bandwidth caps of a session.
+//!
+//! Bytes are counted per message type, the field set in the `union` of a
+//! [`Message`], in both directions. The caps are in KB/s, `0` or empty for none:
+//!
+//! - Controlled side: the `upload-bandwidth` and `download-bandwidth` options,
+//!   or the `upload_bandwidth` and `download_bandwidth` of the matching access
+//!   policy rule, whichever is lower. The upload cap is enforced on file
+//!   transfer, but only best-effort on the video: it lowers the target bitrate
+//!   of the video QoS, which does not go below its minimum, and audio is not
+//!   capped.
+//! - Controlling side: the same options, of the peer or global, throttle
+//!   reading and file uploads.
+//!
+//! The download cap only holds back the bulk frames, video, audio, clipboard
+//! and file blocks, so input and control messages are never stuck behind a
+//! large transfer. Reading stops once too many frames are held back.
+
+use hbb_common::{
+    bytes::BytesMut,
+    fs::TransferJob,
+    message_proto::*,
+    protobuf::{reflect::FieldDescriptor, Message as _, MessageFull},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const OPTION_UPLOAD_BANDWIDTH: &str = "upload-bandwidth";
+pub const OPTION_DOWNLOAD_BANDWIDTH: &str = "download-bandwidth";
+
+const UNKNOWN: &str = "unknown";
+// Types held back by the download cap.
+const BULK_TYPES: &[&str] = &[
+    "video_frame",
+    "audio_frame",
+    "file_response",
+    "clipboard",
+    "multi_clipboards",
+    "cliprdr",
+];
+// Frames held back by the download cap before reading stops.
+const MAX_DELAYED: usize = 64;
+
+static OPTIONS_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Called when the options are set, for the caps to be read again.
+pub fn options_changed() {
+    OPTIONS_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn options_generation() -> usize {
+    OPTIONS_GENERATION.load(Ordering::SeqCst)
+}
+
+lazy_static::lazy_static! {
+    static ref FIELDS: Vec<(i32, String, FieldDescriptor)> = Message::descriptor()
+        .fields()
+        .map(|f| (f.proto().number(), f.name().to_owned(), f))
+        .collect();
+}
+
+/// The type of a message, `unknown` if none is set.
+pub fn message_type(msg: &Message) -> &'static str {
+    FIELDS
+        .iter()
+        .find(|(_, _, f)| f.has_field(msg))
+        .map(|(_, name, _)| name.as_str())
+        .unwrap_or(UNKNOWN)
+}
+
+/// The field number of the first field of an encoded message, from its varint
+/// key, which is the one set in the `union` of a [`Message`].
+pub fn field_number(frame: &[u8]) -> Option<i32> {
+    let mut key = 0u64;
+    for (i, b) in frame.iter().take(5).enumerate() {
+        key |= ((b & 0x7F) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((key >> 3) as i32);
+        }
+    }
+    None
+}
+
+/// The type of an encoded message, from the key of its first field.
+pub fn frame_type(frame: &[u8]) -> &'static str {
+    let Some(number) = field_number(frame) else {
+        return UNKNOWN;
+    };
+    FIELDS
+        .iter()
+        .find(|(n, _, _)| *n == number)
+        .map(|(_, name, _)| name.as_str())
+        .unwrap_or(UNKNOWN)
+}
+
+/// The transferred bytes of each read job, before `fs::handle_read_jobs`.
+pub fn transferred(jobs: &[TransferJob]) -> HashMap<i32, u64> {
+    jobs.iter().map(|j| (j.id(), j.transferred())).collect()
+}
+
+/// Counts the file blocks sent by `fs::handle_read_jobs` in the block counters
+/// of the jobs, returns the blocks and their bytes. A call sends at most one
+/// block per job, the jobs which transferred more than `before`.
+pub fn count_blocks(
+    before: &HashMap<i32, u64>,
+    jobs: &[TransferJob],
+    counters: &mut HashMap<i32, u64>,
+) -> (u64, u64) {
+    let (mut blocks, mut bytes) = (0, 0);
+    for job in jobs {
+        let n = job
+            .transferred()
+            .saturating_sub(before.get(&job.id()).copied().unwrap_or_default());
+        if n > 0 {
+            *counters.entry(job.id()).or_default() += 1;
+            blocks += 1;
+            bytes += n;
+        }
+    }
+    (blocks, bytes)
+}
+
+#[inline]
+pub fn is_bulk(frame: &[u8]) -> bool {
+    BULK_TYPES.contains(&frame_type(frame))
+}
+
+/// Parses a cap option in KB/s, returns bytes per second.
+pub fn parse_cap(v: &str) -> u64 {
+    v.trim().parse::<u64>().unwrap_or(0) * 1024
+}
+
+/// The lower of two caps in bytes per second, `0` being none.
+pub fn min_cap(a: u64, b: u64) -> u64 {
+    match (a, b) {
+        (0, x) | (x, 0) => x,
+        (a, b) => a.min(b),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counter {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficStats {
+    start: Instant,
+    sent: BTreeMap<&'static str, Counter>,
+    received: BTreeMap<&'static str, Counter>,
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            sent: Default::default(),
+            received: Default::default(),
+        }
+    }
+}
+
+impl TrafficStats {
+    pub fn on_sent(&mut self, msg: &Message) {
+        self.add_sent(message_type(msg), 1, msg.compute_size());
+    }
+
+    pub fn add_sent(&mut self, typ: &'static str, messages: u64, bytes: u64) {
+        let c = self.sent.entry(typ).or_default();
+        c.messages += messages;
+        c.bytes += bytes;
+    }
+
+    pub fn on_received(&mut self, frame: &[u8]) {
+        let c = self.received.entry(frame_type(frame)).or_default();
+        c.messages += 1;
+        c.bytes += frame.len() as u64;
+    }
+
+    pub fn total_sent(&self) -> u64 {
+        self.sent.values().map(|c| c.bytes).sum()
+    }
+
+    pub fn total_received(&self) -> u64 {
+        self.received.values().map(|c| c.bytes).sum()
+    }
+
+    /// `{"secs", "sent": {"bytes", "types": {type: {"messages", "bytes"}}}, "received": ...}`
+    pub fn to_json(&self) -> Value {
+        let side = |m: &BTreeMap<&'static str, Counter>| {
+            let types: serde_json::Map<String, Value> = m
+                .iter()
+                .map(|(k, c)| {
+                    (
+                        k.to_string(),
+                        json!({"messages": c.messages, "bytes": c.bytes}),
+                    )
+                })
+                .collect();
+            json!({
+                "bytes": m.values().map(|c| c.bytes).sum::<u64>(),
+                "types": types,
+            })
+        };
+        json!({
+            "secs": self.start.elapsed().as_secs(),
+            "sent": side(&self.sent),
+            "received": side(&self.received),
+        })
+    }
+}
+
+/// Bytes per second, refilled continuously, at most one second ahead.
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: 0.,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        if rate != self.rate {
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+
+    pub fn ready(&mut self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.refill();
+        self.tokens > 0.
+    }
+
+    /// How long until [`Self::ready`].
+    pub fn wait(&mut self) -> Duration {
+        if self.ready() {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((-self.tokens + 1.) / self.rate as f64)
+    }
+
+    // May go into debt, the next ones wait until it is paid.
+    pub fn consume(&mut self, bytes: u64) {
+        if self.rate > 0 {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// The download cap, on the bulk frames only.
+pub struct DownloadThrottle {
+    bucket: TokenBucket,
+    delayed: VecDeque<BytesMut>,
+}
+
+impl Default for DownloadThrottle {
+    fn default() -> Self {
+        Self {
+            bucket: TokenBucket::new(0),
+            delayed: Default::default(),
+        }
+    }
+}
+
+impl DownloadThrottle {
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.bucket.set_rate(rate);
+    }
+
+    #[inline]
+    pub fn can_read(&self) -> bool {
+        self.delayed.len() < MAX_DELAYED
+    }
+
+    #[inline]
+    pub fn has_delayed(&self) -> bool {
+        !self.delayed.is_empty()
+    }
+
+    /// How long until the next held back frame may be handled.
+    pub fn wait(&mut self) -> Duration {
+        self.bucket.wait()
+    }
+
+    /// The frame if it is to be handled now, otherwise it is held back.
+    pub fn admit(&mut self, frame: BytesMut) -> Option<BytesMut> {
+        if !is_bulk(&frame) {
+            return Some(frame);
+        }
+        if self.delayed.is_empty() && self.bucket.ready() {
+            self.bucket.consume(frame.len() as _);
+            return Some(frame);
+        }
+        self.delayed.push_back(frame);
+        None
+    }
+
+    /// The next held back frame, once the cap allows it.
+    pub fn pop_ready(&mut self) -> Option<BytesMut> {
+        if self.delayed.is_empty() || !self.bucket.ready() {
+            return None;
+        }
+        let frame = self.delayed.pop_front()?;
+        self.bucket.consume(frame.len() as _);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_stats() {
+        let mut msg = Message::new();
+        msg.set_video_frame(VideoFrame::new());
+        assert_eq!(message_type(&msg), "video_frame");
+        assert_eq!(message_type(&Message::new()), UNKNOWN);
+        let frame = msg.write_to_bytes().unwrap();
+        assert_eq!(frame_type(&frame), "video_frame");
+
+        let mut stats = TrafficStats::default();
+        stats.on_sent(&msg);
+        stats.add_sent("file_response", 2, 100);
+        stats.on_received(&frame);
+        assert_eq!(stats.total_sent(), frame.len() as u64 + 100);
+        assert_eq!(stats.total_received(), frame.len() as u64);
+        let v = stats.to_json();
+        assert_eq!(v["sent"]["types"]["file_response"]["messages"], 2);
+        assert_eq!(v["received"]["types"]["video_frame"]["messages"], 1);
+
+        assert_eq!(min_cap(0, 5), 5);
+        assert_eq!(min_cap(7, 5), 5);
+        assert_eq!(parse_cap("2"), 2048);
+
+        let mut bucket = TokenBucket::new(1000);
+        bucket.consume(3000);
+        assert!(!bucket.ready());
+        assert!(bucket.wait() > Duration::from_secs(1));
+
+        let mut throttle = DownloadThrottle::default();
+        throttle.set_rate(1000);
+        throttle.bucket.consume(3000);
+        let mut key = Message::new();
+        key.set_key_event(KeyEvent::new());
+        let key = BytesMut::from(&key.write_to_bytes().unwrap()[..]);
+        assert!(throttle.admit(BytesMut::from(&frame[..])).is_none());
+        assert!(throttle.has_delayed());
+        assert_eq!(throttle.admit(key.clone()), Some(key));
+        assert!(throttle.pop_ready().is_none());
+    }
+}
@@ -0,0 +1,864 @@
+//! Multiplexed port forwarding.
+//!
+//! A port forward login with host [`MUX_HOST`] and port 0 switches the raw stream
+//! into a framed mode carrying many forwarded connections ("channels") at once, so
+//! the controlling side only authenticates once for all its forward rules.
+//!
+//! Frame layout: `kind: u8 | channel: u32 | len: u32 | payload`, big endian.
+//!
+//! UDP channels carry exactly one datagram per `Data` frame and are closed after
+//! [`UDP_IDLE_TIMEOUT`] without traffic in either direction.
+//!
+//! The sockets are read only while less than [`OUT_WINDOW`] bytes wait to be
+//! written to the peer stream, and data of the peer is queued for at most
+//! [`CHANNEL_QUEUE`] chunks per channel, after which the peer stream is not
+//! read until the socket takes it, so a slow stream or a slow target pushes
+//! back instead of buffering without limit.
+//!
+//! Reverse forwards, [`Frame::Listen`], are only accepted by the controlled
+//! side with [`OPTION_ALLOW_REVERSE_PORT_FORWARD`], for ports allowed by the
+//! allowlist and the access policy as `127.0.0.1:port`.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    futures::{SinkExt, StreamExt},
+    log, timeout,
+    tokio::{
+        self,
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+        time::{self, Duration, Instant},
+    },
+    tokio_util::codec::{BytesCodec, Framed},
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+pub const MUX_HOST: &str = "MUX";
+/// Controlled side option restricting forward targets, see [`TargetAllowlist`].
+pub const OPTION_PORT_FORWARD_ALLOWLIST: &str = "port-forward-allowlist";
+/// Controlled side option to accept reverse forwards.
+pub const OPTION_ALLOW_REVERSE_PORT_FORWARD: &str = "allow-reverse-port-forward";
+/// Close reason of a channel whose target is rejected by the allowlist.
+pub const NOT_ALLOWED: &str = "Target not allowed";
+/// Bytes read off the sockets not yet taken by [`Tunnel::next`].
+pub const OUT_WINDOW: usize = 1024 * 1024;
+/// Chunks of the peer queued per channel until its socket takes them.
+pub const CHANNEL_QUEUE: usize = 64;
+
+const CONNECT_TIMEOUT: u64 = 3000;
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_DATAGRAM: usize = 65536;
+const HEADER_LEN: usize = 9;
+const MAX_PAYLOAD: usize = 8 * 1024 * 1024;
+
+const KIND_OPEN: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+const KIND_DATA: u8 = 3;
+const KIND_LISTEN: u8 = 4;
+const KIND_ACCEPTED: u8 = 5;
+const KIND_OPENED: u8 = 6;
+const KIND_OPEN_UDP: u8 = 7;
+const KIND_WOL: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// Connect to `target` ("host:port") on the receiving side.
+    Open {
+        channel: u32,
+        target: String,
+    },
+    /// The channel is gone, `reason` is empty on a normal close.
+    Close {
+        channel: u32,
+        reason: String,
+    },
+    Data {
+        channel: u32,
+        data: Bytes,
+    },
+    /// Ask the controlled side to listen on `port` and hand accepted connections back.
+    Listen {
+        port: u16,
+    },
+    /// A connection accepted on a reverse listener of `port`.
+    Accepted {
+        channel: u32,
+        port: u16,
+    },
+    /// The target of an `Open` is connected.
+    Opened {
+        channel: u32,
+    },
+    /// Like `Open`, but for a UDP flow to `target`.
+    OpenUdp {
+        channel: u32,
+        target: String,
+    },
+    /// Broadcast a Wake-on-LAN magic `packet` on the LAN of the receiving side,
+    /// answered with a `Close` of channel 0.
+    Wol {
+        packet: Bytes,
+    },
+}
+
+impl Frame {
+    pub fn encode(&self) -> Bytes {
+        let (kind, channel, payload) = match self {
+            Frame::Open { channel, target } => (
+                KIND_OPEN,
+                *channel,
+                Bytes::copy_from_slice(target.as_bytes()),
+            ),
+            Frame::Close { channel, reason } => (
+                KIND_CLOSE,
+                *channel,
+                Bytes::copy_from_slice(reason.as_bytes()),
+            ),
+            Frame::Data { channel, data } => (KIND_DATA, *channel, data.clone()),
+            Frame::Listen { port } => (KIND_LISTEN, 0, Bytes::copy_from_slice(&port.to_be_bytes())),
+            Frame::Accepted { channel, port } => (
+                KIND_ACCEPTED,
+                *channel,
+                Bytes::copy_from_slice(&port.to_be_bytes()),
+            ),
+            Frame::Opened { channel } => (KIND_OPENED, *channel, Bytes::new()),
+            Frame::OpenUdp { channel, target } => (
+                KIND_OPEN_UDP,
+                *channel,
+                Bytes::copy_from_slice(target.as_bytes()),
+            ),
+            Frame::Wol { packet } => (KIND_WOL, 0, packet.clone()),
+        };
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+        buf.put_u8(kind);
+        buf.put_u32(channel);
+        buf.put_u32(payload.len() as _);
+        buf.put_slice(&payload);
+        buf.freeze()
+    }
+}
+
+/// Reassembles frames from the arbitrary chunks read off a raw stream.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next(&mut self) -> ResultType<Option<Frame>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let kind = self.buf[0];
+        let channel = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]);
+        let len = u32::from_be_bytes([self.buf[5], self.buf[6], self.buf[7], self.buf[8]]) as usize;
+        if len > MAX_PAYLOAD {
+            bail!("Tunnel frame too large: {}", len);
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        self.buf.advance(HEADER_LEN);
+        let payload = self.buf.split_to(len).freeze();
+        let read_port = |payload: &Bytes| -> ResultType<u16> {
+            if payload.len() != 2 {
+                bail!("Invalid tunnel port payload");
+            }
+            Ok(u16::from_be_bytes([payload[0], payload[1]]))
+        };
+        let frame = match kind {
+            KIND_OPEN => Frame::Open {
+                channel,
+                target: String::from_utf8_lossy(&payload).to_string(),
+            },
+            KIND_CLOSE => Frame::Close {
+                channel,
+                reason: String::from_utf8_lossy(&payload).to_string(),
+            },
+            KIND_DATA => Frame::Data {
+                channel,
+                data: payload,
+            },
+            KIND_LISTEN => Frame::Listen {
+                port: read_port(&payload)?,
+            },
+            KIND_ACCEPTED => Frame::Accepted {
+                channel,
+                port: read_port(&payload)?,
+            },
+            KIND_OPENED => Frame::Opened { channel },
+            KIND_OPEN_UDP => Frame::OpenUdp {
+                channel,
+                target: String::from_utf8_lossy(&payload).to_string(),
+            },
+            KIND_WOL => Frame::Wol { packet: payload },
+            _ => bail!("Unknown tunnel frame kind: {}", kind),
+        };
+        Ok(Some(frame))
+    }
+}
+
+pub enum Event {
+    /// A frame produced by a local channel, to be written to the peer stream.
+    Outgoing(Frame),
+    /// A connection accepted by a listener registered with [`Tunnel::listen`].
+    Incoming(u16, TcpStream),
+}
+
+/// Frames to the peer stream. Data frames hold bytes of the window until taken
+/// by [`Tunnel::next`], so the sockets are not read faster than the stream is
+/// written.
+#[derive(Clone)]
+struct Outgoing {
+    tx: mpsc::UnboundedSender<(Frame, Option<OwnedSemaphorePermit>)>,
+    window: Arc<Semaphore>,
+}
+
+impl Outgoing {
+    fn send(&self, frame: Frame) -> bool {
+        self.tx.send((frame, None)).is_ok()
+    }
+
+    async fn reserve(&self, len: usize) -> Option<OwnedSemaphorePermit> {
+        let n = len.clamp(1, OUT_WINDOW) as u32;
+        self.window.clone().acquire_many_owned(n).await.ok()
+    }
+
+    async fn send_data(&self, channel: u32, data: Bytes) -> bool {
+        let Some(permit) = self.reserve(data.len()).await else {
+            return false;
+        };
+        let frame = Frame::Data { channel, data };
+        self.tx.send((frame, Some(permit))).is_ok()
+    }
+}
+
+type Datagram = (u16, SocketAddr, Bytes, Option<OwnedSemaphorePermit>);
+
+struct UdpListener {
+    socket: Arc<UdpSocket>,
+    target: String,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Book-keeping of the forwarded connections of one side of a tunnel.
+pub struct Tunnel {
+    channels: HashMap<u32, mpsc::Sender<Bytes>>,
+    next_channel: u32,
+    tx_out: Outgoing,
+    rx_out: mpsc::UnboundedReceiver<(Frame, Option<OwnedSemaphorePermit>)>,
+    tx_incoming: mpsc::UnboundedSender<(u16, TcpStream)>,
+    rx_incoming: mpsc::UnboundedReceiver<(u16, TcpStream)>,
+    listeners: HashMap<u16, tokio::task::JoinHandle<()>>,
+    tx_datagram: mpsc::UnboundedSender<Datagram>,
+    rx_datagram: mpsc::UnboundedReceiver<Datagram>,
+    udp_listeners: HashMap<u16, UdpListener>,
+    // Local UDP flows of the listeners: (listen port, source) -> channel.
+    udp_flows: HashMap<(u16, SocketAddr), u32>,
+    // Last activity of every UDP channel, for the idle timeout.
+    udp_activity: HashMap<u32, Instant>,
+    udp_sweep: time::Interval,
+}
+
+impl Tunnel {
+    /// The controlling side allocates odd channel ids and the controlled side even
+    /// ones, so both can open channels without negotiating.
+    pub fn new(is_controlling: bool) -> Self {
+        let (tx_out, rx_out) = mpsc::unbounded_channel();
+        let (tx_incoming, rx_incoming) = mpsc::unbounded_channel();
+        let (tx_datagram, rx_datagram) = mpsc::unbounded_channel();
+        Self {
+            channels: Default::default(),
+            next_channel: if is_controlling { 1 } else { 2 },
+            tx_out: Outgoing {
+                tx: tx_out,
+                window: Arc::new(Semaphore::new(OUT_WINDOW)),
+            },
+            rx_out,
+            tx_incoming,
+            rx_incoming,
+            listeners: Default::default(),
+            tx_datagram,
+            rx_datagram,
+            udp_listeners: Default::default(),
+            udp_flows: Default::default(),
+            udp_activity: Default::default(),
+            udp_sweep: time::interval(UDP_SWEEP_INTERVAL),
+        }
+    }
+
+    pub fn next_channel(&mut self) -> u32 {
+        let channel = self.next_channel;
+        self.next_channel = self.next_channel.wrapping_add(2);
+        channel
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub async fn next(&mut self) -> Option<Event> {
+        loop {
+            tokio::select! {
+                // The permit is released once the frame is taken.
+                Some((frame, _permit)) = self.rx_out.recv() => {
+                    if let Frame::Data { channel, .. } = &frame {
+                        self.touch_udp(*channel);
+                    }
+                    return Some(Event::Outgoing(frame));
+                }
+                Some((port, socket)) = self.rx_incoming.recv() => {
+                    return Some(Event::Incoming(port, socket));
+                }
+                Some((port, src, data, permit)) = self.rx_datagram.recv() => {
+                    self.on_datagram(port, src, data, permit);
+                }
+                _ = self.udp_sweep.tick() => {
+                    self.close_idle_udp();
+                }
+            }
+        }
+    }
+
+    /// Accepts connections on `listener` in the background, reported as
+    /// [`Event::Incoming`] tagged with `port`.
+    pub fn listen(&mut self, port: u16, listener: TcpListener) {
+        let tx = self.tx_incoming.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        log::info!("tunnel: new connection from {:?} on port {}", addr, port);
+                        if tx.send((port, socket)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("tunnel: failed to accept on port {}: {}", port, err);
+                        break;
+                    }
+                }
+            }
+        });
+        if let Some(old) = self.listeners.insert(port, handle) {
+            old.abort();
+        }
+    }
+
+    /// Stops the listener registered for `port`, established channels are kept.
+    pub fn unlisten(&mut self, port: u16) {
+        if let Some(listener) = self.listeners.remove(&port) {
+            listener.abort();
+        }
+        if let Some(listener) = self.udp_listeners.remove(&port) {
+            listener.task.abort();
+        }
+    }
+
+    /// Forwards datagrams received on `socket` to `target` on the peer, with one
+    /// channel per source address.
+    pub fn listen_udp(&mut self, port: u16, socket: UdpSocket, target: String) {
+        let socket = Arc::new(socket);
+        let reader = socket.clone();
+        let tx = self.tx_datagram.clone();
+        let tx_out = self.tx_out.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                match reader.recv_from(&mut buf).await {
+                    Ok((n, src)) => {
+                        let permit = tx_out.reserve(n).await;
+                        let data = Bytes::copy_from_slice(&buf[..n]);
+                        if tx.send((port, src, data, permit)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        // Windows reports ICMP port unreachable of earlier sends here.
+                        log::debug!("tunnel: udp recv on port {}: {}", port, err);
+                    }
+                }
+            }
+        });
+        let listener = UdpListener {
+            socket,
+            target,
+            task,
+        };
+        if let Some(old) = self.udp_listeners.insert(port, listener) {
+            old.task.abort();
+        }
+    }
+
+    /// Sends datagrams of `channel` to `target` from a fresh local socket, and the
+    /// replies back through the channel.
+    pub fn connect_udp(&mut self, channel: u32, target: String) {
+        let rx = self.add_channel(channel);
+        self.udp_activity.insert(channel, Instant::now());
+        let tx_out = self.tx_out.clone();
+        tokio::spawn(async move {
+            let res = match connect_udp(&target).await {
+                Ok(socket) => {
+                    tx_out.send(Frame::Opened { channel });
+                    pump_udp(channel, socket, rx, tx_out.clone()).await
+                }
+                Err(err) => Err(err),
+            };
+            let reason = res.err().map(|e| e.to_string()).unwrap_or_default();
+            if !reason.is_empty() {
+                log::warn!(
+                    "tunnel: udp channel {} to {} failed: {}",
+                    channel,
+                    target,
+                    reason
+                );
+            }
+            tx_out.send(Frame::Close { channel, reason });
+        });
+    }
+
+    fn on_datagram(
+        &mut self,
+        port: u16,
+        src: SocketAddr,
+        data: Bytes,
+        permit: Option<OwnedSemaphorePermit>,
+    ) {
+        let channel = match self.udp_flows.get(&(port, src)) {
+            Some(channel) => *channel,
+            None => {
+                let Some(listener) = self.udp_listeners.get(&port) else {
+                    return;
+                };
+                let socket = listener.socket.clone();
+                let target = listener.target.clone();
+                let channel = self.next_channel();
+                let mut rx = self.add_channel(channel);
+                tokio::spawn(async move {
+                    while let Some(data) = rx.recv().await {
+                        if let Err(err) = socket.send_to(&data, src).await {
+                            log::debug!("tunnel: udp send to {}: {}", src, err);
+                        }
+                    }
+                });
+                log::info!(
+                    "tunnel: new udp flow {} from {} to {}",
+                    channel,
+                    src,
+                    target
+                );
+                self.udp_flows.insert((port, src), channel);
+                self.udp_activity.insert(channel, Instant::now());
+                self.tx_out.send(Frame::OpenUdp { channel, target });
+                channel
+            }
+        };
+        self.touch_udp(channel);
+        self.tx_out
+            .tx
+            .send((Frame::Data { channel, data }, permit))
+            .ok();
+    }
+
+    fn touch_udp(&mut self, channel: u32) {
+        if let Some(t) = self.udp_activity.get_mut(&channel) {
+            *t = Instant::now();
+        }
+    }
+
+    /// Emits `Close` for UDP channels idle for [`UDP_IDLE_TIMEOUT`], the channel is
+    /// dropped when the owner handles it like any other close.
+    fn close_idle_udp(&mut self) {
+        let idle: Vec<u32> = self
+            .udp_activity
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= UDP_IDLE_TIMEOUT)
+            .map(|(channel, _)| *channel)
+            .collect();
+        for channel in idle {
+            self.udp_activity.remove(&channel);
+            let reason = String::new();
+            self.tx_out.send(Frame::Close { channel, reason });
+        }
+    }
+
+    /// Pumps an already connected socket through `channel`.
+    pub fn attach(&mut self, channel: u32, socket: TcpStream) {
+        let rx = self.add_channel(channel);
+        let tx_out = self.tx_out.clone();
+        tokio::spawn(async move {
+            let reason = pump(channel, socket, rx, tx_out.clone())
+                .await
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            tx_out.send(Frame::Close { channel, reason });
+        });
+    }
+
+    /// Connects to `target` and pumps it through `channel`. Data arriving for the
+    /// channel while connecting is queued.
+    pub fn connect(&mut self, channel: u32, target: String) {
+        let rx = self.add_channel(channel);
+        let tx_out = self.tx_out.clone();
+        tokio::spawn(async move {
+            let res = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&target)).await {
+                Ok(Ok(socket)) => {
+                    tx_out.send(Frame::Opened { channel });
+                    pump(channel, socket, rx, tx_out.clone()).await
+                }
+                Ok(Err(err)) => Err(err.into()),
+                Err(_) => Err(anyhow!("Timeout connecting to {}", target)),
+            };
+            let reason = res.err().map(|e| e.to_string()).unwrap_or_default();
+            if !reason.is_empty() {
+                log::warn!(
+                    "tunnel: channel {} to {} failed: {}",
+                    channel,
+                    target,
+                    reason
+                );
+            }
+            tx_out.send(Frame::Close { channel, reason });
+        });
+    }
+
+    /// Queues data of the peer for `channel`, waits while its queue is full.
+    pub async fn write(&mut self, channel: u32, data: Bytes) {
+        self.touch_udp(channel);
+        if let Some(tx) = self.channels.get(&channel).cloned() {
+            if tx.send(data).await.is_err() {
+                self.channels.remove(&channel);
+            }
+        }
+    }
+
+    /// Drops the channel, its socket is shut down once the queued data is written.
+    pub fn close(&mut self, channel: u32) -> bool {
+        self.udp_activity.remove(&channel);
+        self.udp_flows.retain(|_, c| *c != channel);
+        self.channels.remove(&channel).is_some()
+    }
+
+    fn add_channel(&mut self, channel: u32) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(CHANNEL_QUEUE);
+        self.channels.insert(channel, tx);
+        rx
+    }
+}
+
+/// Comma separated `host:port` patterns limiting where forwarded connections may
+/// go. The host is `*`, an exact name, a `*.suffix` wildcard or an IP/CIDR (which
+/// only matches IP literals); the port is `*`, a number or a `low-high` range.
+/// An empty list allows everything.
+#[derive(Debug, Default, Clone)]
+pub struct TargetAllowlist {
+    rules: Vec<(HostPattern, u16, u16)>,
+}
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    Any,
+    Exact(String),
+    Suffix(String),
+    Cidr(IpCidr),
+}
+
+impl TargetAllowlist {
+    pub fn parse(s: &str) -> Self {
+        let rules = s
+            .split(|c| c == ',' || c == '\n' || c == ' ')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .filter_map(|x| {
+                let res = Self::parse_rule(x);
+                if res.is_none() {
+                    log::warn!("Invalid port forward allowlist rule: {}", x);
+                }
+                res
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn load() -> Self {
+        Self::parse(&hbb_common::config::Config::get_option(
+            OPTION_PORT_FORWARD_ALLOWLIST,
+        ))
+    }
+
+    fn parse_rule(rule: &str) -> Option<(HostPattern, u16, u16)> {
+        let (host, ports) = split_host_port(rule)?;
+        let (low, high) = if ports == "*" {
+            (1, u16::MAX)
+        } else if let Some((low, high)) = ports.split_once('-') {
+            (low.parse().ok()?, high.parse().ok()?)
+        } else {
+            let port = ports.parse().ok()?;
+            (port, port)
+        };
+        let host = host.to_lowercase();
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(suffix) = host.strip_prefix("*.") {
+            HostPattern::Suffix(format!(".{}", suffix))
+        } else if let Ok(cidr) = IpCidr::from_str(&host) {
+            HostPattern::Cidr(cidr)
+        } else {
+            HostPattern::Exact(host)
+        };
+        Some((host, low, high))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_allowed(&self, target: &str) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        let Some((host, port)) = split_host_port(target) else {
+            return false;
+        };
+        let Ok(port) = port.parse::<u16>() else {
+            return false;
+        };
+        let host = host.to_lowercase();
+        let ip = IpAddr::from_str(&host).ok();
+        self.rules.iter().any(|(pattern, low, high)| {
+            if port < *low || port > *high {
+                return false;
+            }
+            match pattern {
+                HostPattern::Any => true,
+                HostPattern::Exact(h) => *h == host,
+                HostPattern::Suffix(suffix) => host.ends_with(suffix.as_str()),
+                HostPattern::Cidr(cidr) => ip.map_or(false, |ip| cidr.contains(ip)),
+            }
+        })
+    }
+}
+
+/// Splits `host:port`, where an IPv6 host is written in brackets.
+fn split_host_port(s: &str) -> Option<(&str, &str)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() || port.is_empty() {
+        return None;
+    }
+    Some((host, port))
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        // Release the listening ports with the session.
+        for (_, listener) in self.listeners.drain() {
+            listener.abort();
+        }
+        for (_, listener) in self.udp_listeners.drain() {
+            listener.task.abort();
+        }
+    }
+}
+
+async fn pump(
+    channel: u32,
+    socket: TcpStream,
+    mut rx: mpsc::Receiver<Bytes>,
+    tx_out: Outgoing,
+) -> ResultType<()> {
+    let mut socket = Framed::new(socket, BytesCodec::new());
+    loop {
+        tokio::select! {
+            res = socket.next() => match res {
+                Some(res) => {
+                    let data = res?.freeze();
+                    if !tx_out.send_data(channel, data).await {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            data = rx.recv() => match data {
+                Some(data) => socket.send(data).await?,
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+async fn connect_udp(target: &str) -> ResultType<UdpSocket> {
+    let addr = match tokio::net::lookup_host(target).await?.next() {
+        Some(addr) => addr,
+        None => bail!("Failed to resolve {}", target),
+    };
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+async fn pump_udp(
+    channel: u32,
+    socket: UdpSocket,
+    mut rx: mpsc::Receiver<Bytes>,
+    tx_out: Outgoing,
+) -> ResultType<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            res = socket.recv(&mut buf) => match res {
+                Ok(n) => {
+                    let data = Bytes::copy_from_slice(&buf[..n]);
+                    if !tx_out.send_data(channel, data).await {
+                        break;
+                    }
+                }
+                // e.g. ICMP port unreachable, the target may come up later.
+                Err(err) => log::debug!("tunnel: udp channel {} recv: {}", channel, err),
+            },
+            data = rx.recv() => match data {
+                Some(data) => {
+                    socket.send(&data).await?;
+                }
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frames = vec![
+            Frame::Open {
+                channel: 1,
+                target: "localhost:22".to_owned(),
+            },
+            Frame::Data {
+                channel: 3,
+                data: Bytes::from_static(b"hello"),
+            },
+            Frame::Close {
+                channel: 3,
+                reason: "".to_owned(),
+            },
+            Frame::Listen { port: 8080 },
+            Frame::Accepted {
+                channel: 2,
+                port: 8080,
+            },
+            Frame::Opened { channel: 5 },
+            Frame::OpenUdp {
+                channel: 7,
+                target: "127.0.0.1:53".to_owned(),
+            },
+            Frame::Wol {
+                packet: Bytes::from_static(&[0xff; 102]),
+            },
+        ];
+        let mut bytes = BytesMut::new();
+        for f in frames.iter() {
+            bytes.extend_from_slice(&f.encode());
+        }
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = Vec::new();
+        // Feed in small chunks to exercise reassembly.
+        for chunk in bytes.chunks(4) {
+            decoder.feed(chunk);
+            while let Some(f) = decoder.next().unwrap() {
+                decoded.push(f);
+            }
+        }
+        assert_eq!(frames, decoded);
+        let mut decoder = FrameDecoder::default();
+        decoder.feed(&[9, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(decoder.next().is_err());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let list =
+            TargetAllowlist::parse("localhost:*, *.corp.local:443,10.0.0.0/8:80-90 [::1]:22");
+        assert!(list.is_allowed("localhost:3389"));
+        assert!(list.is_allowed("LOCALHOST:22"));
+        assert!(list.is_allowed("wiki.corp.local:443"));
+        assert!(!list.is_allowed("wiki.corp.local:80"));
+        assert!(!list.is_allowed("corp.local:443"));
+        assert!(list.is_allowed("10.1.2.3:85"));
+        assert!(!list.is_allowed("10.1.2.3:91"));
+        assert!(!list.is_allowed("192.168.1.1:85"));
+        assert!(list.is_allowed("[::1]:22"));
+        assert!(!list.is_allowed("example.com:443"));
+        assert!(!list.is_allowed("localhost"));
+        assert!(TargetAllowlist::parse("").is_allowed("example.com:443"));
+    }
+
+    #[tokio::test]
+    async fn test_udp_forward() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, src)) = echo.recv_from(&mut buf).await {
+                echo.send_to(&buf[..n], src).await.ok();
+            }
+        });
+        let listen = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listen.local_addr().unwrap();
+        let mut client = Tunnel::new(true);
+        let mut server = Tunnel::new(false);
+        client.listen_udp(listen_addr.port(), listen, echo_addr.to_string());
+        let user = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        user.send_to(b"ping", listen_addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let res = hbb_common::timeout(3000, async {
+            loop {
+                tokio::select! {
+                    Some(Event::Outgoing(frame)) = client.next() => match frame {
+                        Frame::OpenUdp { channel, target } => server.connect_udp(channel, target),
+                        Frame::Data { channel, data } => server.write(channel, data).await,
+                        _ => {}
+                    },
+                    Some(Event::Outgoing(frame)) = server.next() => {
+                        if let Frame::Data { channel, data } = frame {
+                            client.write(channel, data).await;
+                        }
+                    }
+                    res = user.recv_from(&mut buf) => break res.unwrap().0,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(&buf[..res], b"ping");
+    }
+}
@@ -481,6 +481,10 @@ impl UI {
         crate::lan::send_wol(id)
     }
 
+    fn get_wol_status(&mut self, id: String) -> String {
+        crate::lan::wol::status(&id)
+    }
+
     fn new_remote(&mut self, id: String, remote_type: String, force_relay: bool) {
         new_remote(id, remote_type, force_relay)
     }
@@ -525,6 +529,10 @@ impl UI {
         get_fingerprint()
     }
 
+    fn get_rendezvous_failover_status(&self) -> String {
+        get_rendezvous_failover_status()
+    }
+
     fn get_app_name(&self) -> String {
         get_app_name()
     }
@@ -725,6 +733,7 @@ impl sciter::EventHandler for UI {
         fn get_size();
         fn new_remote(String, String, bool);
         fn send_wol(String);
+        fn get_wol_status(String);
         fn remove_peer(String);
         fn remove_discovered(String);
         fn get_connect_status();
@@ -771,6 +780,7 @@ impl sciter::EventHandler for UI {
         fn get_new_version();
         fn get_version();
         fn get_fingerprint();
+        fn get_rendezvous_failover_status();
         fn update_me(String);
         fn show_run_without_install();
         fn run_without_install();
@@ -483,6 +483,15 @@ impl sciter::EventHandler for SciterSession {
 
     sciter::dispatch_script_call! {
         fn get_audit_server(String);
+        fn get_traffic_stats();
+        fn start_macro_recording();
+        fn is_macro_recording();
+        fn stop_macro_recording(String, bool);
+        fn get_macros();
+        fn remove_macro(String, bool);
+        fn play_macro(String, f64, String);
+        fn stop_macro();
+        fn is_macro_playing();
         fn send_note(String);
         fn is_xfce();
         fn get_id();
@@ -77,6 +77,7 @@ lazy_static::lazy_static! {
     static ref OPTIONS : Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(Config::get_options()));
     pub static ref SENDER : Mutex<mpsc::UnboundedSender<ipc::Data>> = Mutex::new(check_connect_status(true));
     static ref CHILDREN : Children = Default::default();
+    static ref FAILOVER_STATUS : Arc<Mutex<String>> = Default::default();
 }
 
 const INIT_ASYNC_JOB_STATUS: &str = " ";
@@ -581,6 +582,15 @@ pub fn temporary_password() -> String {
     return TEMPORARY_PASSWD.lock().unwrap().clone();
 }
 
+/// The rendezvous servers of the config bundle, see [`crate::failover::status`].
+#[inline]
+pub fn get_rendezvous_failover_status() -> String {
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    return crate::failover::status();
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    return FAILOVER_STATUS.lock().unwrap().clone();
+}
+
 #[inline]
 pub fn update_temporary_password() {
     #[cfg(any(target_os = "android", target_os = "ios"))]
@@ -1210,6 +1220,8 @@ async fn check_connect_status_(reconnect: bool, rx: mpsc::UnboundedReceiver<ipc:
                                     }
                                 } else if name == "temporary-password" {
                                     *TEMPORARY_PASSWD.lock().unwrap() = value;
+                                } else if name == crate::failover::STATUS {
+                                    *FAILOVER_STATUS.lock().unwrap() = value;
                                 }
                             }
                             #[cfg(feature = "flutter")]
@@ -1247,6 +1259,7 @@ async fn check_connect_status_(reconnect: bool, rx: mpsc::UnboundedReceiver<ipc:
                         c.send(&ipc::Data::Options(None)).await.ok();
                         c.send(&ipc::Data::Config(("id".to_owned(), None))).await.ok();
                         c.send(&ipc::Data::Config(("temporary-password".to_owned(), None))).await.ok();
+                        c.send(&ipc::Data::Config((crate::failover::STATUS.to_owned(), None))).await.ok();
                         #[cfg(feature = "flutter")]
                         c.send(&ipc::Data::VideoConnCount(None)).await.ok();
                     }
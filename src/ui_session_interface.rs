@@ -40,8 +40,8 @@ use uuid::Uuid;
 use crate::client::io_loop::Remote;
 use crate::client::{
     check_if_retry, handle_hash, handle_login_error, handle_login_from_ui, handle_test_delay,
-    input_os_password, send_mouse, send_pointer_device_event, FileManager, Key, LoginConfigHandler,
-    QualityStatus, KEY_MAP,
+    input_macro, input_os_password, send_mouse, send_pointer_device_event, FileManager, Key,
+    LoginConfigHandler, QualityStatus, KEY_MAP,
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use crate::common::GrabState;
@@ -69,6 +69,9 @@ pub struct Session<T: InvokeUiSession> {
     pub reconnect_count: Arc<AtomicUsize>,
     pub last_audit_note: Arc<Mutex<String>>,
     pub audit_guid: Arc<Mutex<String>>,
+    pub traffic: Arc<Mutex<crate::traffic::TrafficStats>>,
+    pub macro_recorder: Arc<Mutex<Option<input_macro::Recorder>>>,
+    pub macro_playback: Arc<Mutex<Option<input_macro::Playback>>>,
 }
 
 #[derive(Clone)]
@@ -647,6 +650,21 @@ impl<T: InvokeUiSession> Session<T> {
         self.lc.read().unwrap().restarting_remote_device
     }
 
+    /// Whether the saved forward rules run over one multiplexed session, which
+    /// is also required for reverse, dynamic and UDP rules.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    pub fn is_port_forward_mux(&self) -> bool {
+        use crate::port_forward::{
+            OPTION_DYNAMIC_PORT_FORWARDS, OPTION_PORT_FORWARD_MUX, OPTION_REVERSE_PORT_FORWARDS,
+            OPTION_UDP_PORT_FORWARDS,
+        };
+        let lc = self.lc.read().unwrap();
+        lc.get_option(OPTION_PORT_FORWARD_MUX) == "Y"
+            || !lc.get_option(OPTION_REVERSE_PORT_FORWARDS).is_empty()
+            || !lc.get_option(OPTION_DYNAMIC_PORT_FORWARDS).is_empty()
+            || !lc.get_option(OPTION_UDP_PORT_FORWARDS).is_empty()
+    }
+
     #[inline]
     pub fn peer_platform(&self) -> String {
         self.lc.read().unwrap().info.platform.clone()
@@ -673,6 +691,12 @@ impl<T: InvokeUiSession> Session<T> {
         input_os_password(pass, activate, self.clone());
     }
 
+    /// Traffic of the current connection by message type, see
+    /// [`crate::traffic::TrafficStats::to_json`].
+    pub fn get_traffic_stats(&self) -> String {
+        self.traffic.lock().unwrap().to_json().to_string()
+    }
+
     #[cfg(not(feature = "flutter"))]
     pub fn get_chatbox(&self) -> String {
         #[cfg(feature = "inline")]
@@ -766,6 +790,85 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    pub fn start_macro_recording(&self) {
+        *self.macro_recorder.lock().unwrap() = Some(input_macro::Recorder::new());
+    }
+
+    pub fn is_macro_recording(&self) -> bool {
+        self.macro_recorder.lock().unwrap().is_some()
+    }
+
+    /// Saves the recording as `name`, discards it if `name` is empty. Returns
+    /// the error if any.
+    pub fn stop_macro_recording(&self, name: String, shared: bool) -> String {
+        let Some(recorder) = self.macro_recorder.lock().unwrap().take() else {
+            return "Not recording".to_owned();
+        };
+        if name.is_empty() {
+            return "".to_owned();
+        }
+        let m = recorder.finish(name);
+        match input_macro::save(&mut self.lc.write().unwrap(), m, shared) {
+            Ok(()) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// `{"peer": [name], "shared": [name]}`
+    pub fn get_macros(&self) -> String {
+        let lc = self.lc.read().unwrap();
+        let names = |shared| {
+            input_macro::load(&lc, shared)
+                .into_iter()
+                .map(|m| m.name)
+                .collect::<Vec<_>>()
+        };
+        serde_json::json!({ "peer": names(false), "shared": names(true) }).to_string()
+    }
+
+    pub fn remove_macro(&self, name: String, shared: bool) -> String {
+        match input_macro::remove(&mut self.lc.write().unwrap(), &name, shared) {
+            Ok(()) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Replays the macro `name` of the peer or the shared one, `vars` being a
+    /// JSON object of the variables. Returns the error if any.
+    pub fn play_macro(&self, name: String, speed: f64, vars: String) -> String {
+        let vars: HashMap<String, String> = if vars.is_empty() {
+            Default::default()
+        } else {
+            match serde_json::from_str(&vars) {
+                Ok(vars) => vars,
+                Err(err) => return err.to_string(),
+            }
+        };
+        let m = match input_macro::find(&self.lc.read().unwrap(), &name) {
+            Ok(m) => m,
+            Err(err) => return err.to_string(),
+        };
+        match input_macro::play(&m, speed, &vars, self.sender.clone()) {
+            Ok(playback) => {
+                *self.macro_playback.lock().unwrap() = Some(playback);
+                "".to_owned()
+            }
+            Err(err) => err.to_string(),
+        }
+    }
+
+    pub fn stop_macro(&self) {
+        self.macro_playback.lock().unwrap().take();
+    }
+
+    pub fn is_macro_playing(&self) -> bool {
+        self.macro_playback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |p| p.is_running())
+    }
+
     pub fn send_chat(&self, text: String) {
         let mut misc = Misc::new();
         misc.set_chat_message(ChatMessage {
@@ -1389,6 +1492,8 @@ impl<T: InvokeUiSession> Session<T> {
     }
 
     pub fn close(&self) {
+        // Releases what a running macro holds before the session goes.
+        self.macro_playback.lock().unwrap().take();
         self.send(Data::Close);
     }
 
@@ -1745,6 +1850,11 @@ impl<T: InvokeUiSession> Interface for Session<T> {
     }
 
     fn send(&self, data: Data) {
+        if let Data::Message(msg) = &data {
+            if let Some(recorder) = self.macro_recorder.lock().unwrap().as_mut() {
+                recorder.record(msg);
+            }
+        }
         if let Some(sender) = self.sender.read().unwrap().as_ref() {
             sender.send(data).ok();
         }
@@ -1938,6 +2048,9 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
             );
             log::info!("Remote rdp port: {}", port);
             start_one_port_forward(handler, 0, "".to_owned(), port, receiver, &key, &token).await;
+        } else if handler.args.len() == 0 && handler.is_port_forward_mux() {
+            let rules = crate::port_forward::ForwardRule::load(&handler.lc.read().unwrap());
+            start_port_forward_mux(handler, rules, receiver, &key, &token).await;
         } else if handler.args.len() == 0 {
             let pfs = handler.lc.read().unwrap().port_forwards.clone();
             let mut queues = HashMap::<i32, mpsc::UnboundedSender<Data>>::new();
@@ -1984,6 +2097,18 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
                     _ => {}
                 }
             }
+        } else if handler.args.len() != 3 || handler.args[0].contains(':') {
+            // rustdesk --port-forward remote-id rule..., see `ForwardRule::parse`
+            let rules: Vec<_> = handler
+                .args
+                .iter()
+                .filter_map(|x| crate::port_forward::ForwardRule::parse(x))
+                .collect();
+            if rules.is_empty() || rules.len() != handler.args.len() {
+                handler.on_error("Invalid arguments, usage:<br><br> rustdesk --port-forward remote-id listen-port:remote-host:remote-port [R:remote-listen-port:local-host:local-port] [D:socks-port] [U:listen-port:remote-host:remote-port]...");
+                return;
+            }
+            start_port_forward_mux(handler, rules, receiver, &key, &token).await;
         } else {
             let port = handler.args[0].parse::<i32>().unwrap_or(0);
             if handler.args.len() != 3
@@ -2041,6 +2166,31 @@ async fn start_one_port_forward<T: InvokeUiSession>(
     log::info!("port forward (:{}) exit", port);
 }
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+async fn start_port_forward_mux<T: InvokeUiSession>(
+    handler: Session<T>,
+    rules: Vec<crate::port_forward::ForwardRule>,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) {
+    if let Err(err) = crate::port_forward::listen_multi(
+        handler.get_id(),
+        handler.password.clone(),
+        rules,
+        handler.clone(),
+        receiver,
+        key,
+        token,
+        handler.lc.clone(),
+    )
+    .await
+    {
+        handler.on_error(&format!("Port forwarding failed: {}", err));
+    }
+    log::info!("multiplexed port forward exit");
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_note(url: String, id: String, sid: u64, note: String) {
     let body = serde_json::json!({ "id": id, "session_id": sid, "note": note });